@@ -0,0 +1,44 @@
+use super::Actor;
+use crate::{
+    clock::SharedClock,
+    config::SharedConfig,
+    storage::Storage,
+    types::{AuditEntry, Error},
+};
+
+/// Deletes audit entries older than `audit_retention`.
+pub struct AuditCompactor {
+    storage: Storage,
+    config: SharedConfig,
+    clock: SharedClock,
+}
+
+impl AuditCompactor {
+    pub fn new(storage: Storage, config: SharedConfig, clock: SharedClock) -> Self {
+        Self {
+            storage,
+            config,
+            clock,
+        }
+    }
+}
+
+#[async_trait::async_trait]
+impl Actor for AuditCompactor {
+    type Message = ();
+
+    type Response = ();
+
+    async fn handle(&mut self, _message: Self::Message) -> Result<Self::Response, Error> {
+        let retention = self.config.load().audit_retention as i64;
+        let cutoff = self.clock.now() - chrono::Duration::seconds(retention);
+        let deleted = self
+            .storage
+            .delete_range::<AuditEntry>("", &AuditEntry::id_prefix(cutoff.timestamp_nanos()))
+            .await?;
+        if deleted > 0 {
+            println!("compacted {} audit entries", deleted);
+        }
+        Ok(())
+    }
+}
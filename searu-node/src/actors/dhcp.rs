@@ -0,0 +1,275 @@
+use std::{collections::HashMap, net::Ipv4Addr, path::Path, process::Stdio};
+
+use super::{Actor, Handle};
+use crate::{
+    config::{Config, SharedConfig},
+    storage::{Event, Storage},
+    types::{DhcpLease, DhcpRange, DhcpReservation, Error, Object, Vpc},
+};
+use serde::Serialize;
+use tokio::{
+    process::{Child, Command},
+    sync::watch,
+    task::JoinHandle,
+};
+
+/// How dnsmasq is run for a VPC: its arguments along with the settings they were built from.
+#[derive(Serialize, Debug)]
+pub struct DhcpdConfig {
+    pub args: Vec<String>,
+    pub range: DhcpRange,
+    /// The default route handed to guests, only set with `nat_gateway`.
+    pub router: Option<Ipv4Addr>,
+    pub dns_servers: Vec<Ipv4Addr>,
+}
+
+impl DhcpdConfig {
+    /// Builds the configuration for `vpc` from the node's config and the VPC's reservations,
+    /// skipping reservations that don't fit the VPC.
+    pub async fn new(storage: &Storage, config: &Config, vpc: &Vpc) -> Result<Self, Error> {
+        let host_ip = vpc
+            .spec
+            .host_ip()
+            .ok_or_else(|| Error::NotFound("host ip".to_string()))?;
+        let range = vpc.spec.dhcp_range()?;
+        let host_name = vpc.metadata.host_name();
+        let lease_file = config.dhcp_lease_file(&host_name);
+        let mut args = vec![
+            "--keep-in-foreground".to_string(),
+            "--conf-file=/dev/null".to_string(),
+            "--port=0".to_string(),
+            "--bind-interfaces".to_string(),
+            "--except-interface=lo".to_string(),
+            format!("--interface=b{}", host_name),
+            format!("--listen-address={}", host_ip),
+            format!("--pid-file=/tmp/searu-dnsmasq-{}.pid", host_name),
+            format!("--dhcp-leasefile={}", lease_file.display()),
+            format!(
+                "--dhcp-range={},{},{},12h",
+                range.start,
+                range.end,
+                vpc.spec.subnet.netmask()
+            ),
+        ];
+        // dnsmasq advertises its own address as the router unless told otherwise
+        let router = Some(host_ip).filter(|_| vpc.spec.nat_gateway);
+        match router {
+            Some(router) => args.push(format!("--dhcp-option=option:router,{}", router)),
+            None => args.push("--dhcp-option=option:router".to_string()),
+        }
+        let dns_servers = config.dns_servers.clone();
+        if !dns_servers.is_empty() {
+            let dns_servers: Vec<String> = dns_servers.iter().map(|ip| ip.to_string()).collect();
+            args.push(format!(
+                "--dhcp-option=option:dns-server,{}",
+                dns_servers.join(",")
+            ));
+        }
+        let reservations: Vec<DhcpReservation> = storage.list().await?;
+        for reservation in reservations {
+            if reservation.vpc_key_name() != vpc.key_name() {
+                continue;
+            }
+            if let Err(err) = reservation.spec.validate(&vpc.spec) {
+                println!(
+                    "skipping reservation {}: {}",
+                    reservation.metadata.name, err
+                );
+                continue;
+            }
+            args.push(format!(
+                "--dhcp-host={},{}",
+                reservation.spec.mac, reservation.spec.ip
+            ));
+        }
+        Ok(Self {
+            args,
+            range,
+            router,
+            dns_servers,
+        })
+    }
+}
+
+/// Runs a dnsmasq instance on the bridge of every VPC configured on this node.
+pub struct DHCPActor {
+    storage: Storage,
+    config: SharedConfig,
+    /// The running dnsmasq instances, by their VPC's host name.
+    servers: HashMap<String, Child>,
+    /// The key name of each reservation's VPC.
+    reservations: HashMap<String, String>,
+    /// The `dns_servers` the running instances were last restarted for.
+    dns_servers: Vec<Ipv4Addr>,
+}
+
+impl DHCPActor {
+    pub fn new(storage: Storage, config: SharedConfig) -> Self {
+        Self {
+            dns_servers: config.load().dns_servers.clone(),
+            storage,
+            config,
+            servers: HashMap::default(),
+            reservations: HashMap::default(),
+        }
+    }
+
+    async fn spawn_dhcpd(&self, vpc: &Vpc) -> Result<Child, Error> {
+        let config = self.config.load_full();
+        let lease_file = config.dhcp_lease_file(&vpc.metadata.host_name());
+        if let Some(dir) = lease_file.parent() {
+            tokio::fs::create_dir_all(dir).await?;
+        }
+        let dhcpd = DhcpdConfig::new(&self.storage, &config, vpc).await?;
+        let child = Command::new("dnsmasq")
+            .kill_on_drop(true)
+            .args(dhcpd.args)
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .stdin(Stdio::null())
+            .spawn()?;
+        Ok(child)
+    }
+
+    async fn start(&mut self, vpc: Vpc) -> Result<(), Error> {
+        let host_name = vpc.metadata.host_name();
+        self.stop(&host_name).await;
+        let child = self.spawn_dhcpd(&vpc).await?;
+        self.servers.insert(host_name, child);
+        Ok(())
+    }
+
+    async fn stop(&mut self, host_name: &str) {
+        if let Some(mut child) = self.servers.remove(host_name) {
+            let _ = child.kill().await;
+        }
+    }
+
+    /// Stops dnsmasq for a deleted VPC and drops its leases.
+    async fn remove(&mut self, host_name: &str) -> Result<(), Error> {
+        self.stop(host_name).await;
+        let lease_file = self.config.load().dhcp_lease_file(host_name);
+        match tokio::fs::remove_file(lease_file).await {
+            Err(err) if err.kind() != std::io::ErrorKind::NotFound => Err(err.into()),
+            _ => Ok(()),
+        }
+    }
+
+    /// Restarts dnsmasq for the VPC whose key name is `vpc`, if it runs on this node, so it picks
+    /// up a changed set of reservations.
+    async fn reload(&mut self, vpc: &str) -> Result<(), Error> {
+        let vpc: Vpc = match self.storage.get(vpc).await? {
+            Some(vpc) => vpc,
+            None => return Ok(()),
+        };
+        if !self.servers.contains_key(&vpc.metadata.host_name()) {
+            return Ok(());
+        }
+        self.start(vpc).await
+    }
+
+    /// Restarts every running dnsmasq if the reloaded config advertises other DNS servers, as
+    /// dnsmasq only reads its options on start. Leases are kept in the lease files.
+    async fn reload_config(&mut self) -> Result<(), Error> {
+        let dns_servers = self.config.load().dns_servers.clone();
+        if dns_servers == self.dns_servers {
+            return Ok(());
+        }
+        self.dns_servers = dns_servers;
+        let mut errors = vec![];
+        for vpc in self.storage.list::<Vpc>().await? {
+            if self.servers.contains_key(&vpc.metadata.host_name()) {
+                if let Err(err) = self.start(vpc).await {
+                    errors.push(err);
+                }
+            }
+        }
+        Error::collect(errors)
+    }
+}
+
+pub enum DhcpMessage {
+    Start(Vpc),
+    /// Stops dnsmasq for the VPC with this host name.
+    Stop(String),
+    Reservation(Event<DhcpReservation>),
+    /// The node's config was reloaded.
+    ConfigReloaded,
+}
+
+/// Passes every config reload on to the DHCP actor.
+pub fn spawn_config_reload(
+    dhcp: Handle<DHCPActor>,
+    mut reloaded: watch::Receiver<()>,
+) -> JoinHandle<Result<(), anyhow::Error>> {
+    tokio::spawn(async move {
+        while reloaded.changed().await.is_ok() {
+            if let Err(err) = dhcp.send(DhcpMessage::ConfigReloaded).await {
+                println!("error: {:?}", err);
+            }
+        }
+        Ok(())
+    })
+}
+
+#[async_trait::async_trait]
+impl Actor for DHCPActor {
+    type Message = DhcpMessage;
+
+    type Response = ();
+
+    async fn handle(
+        &mut self,
+        message: Self::Message,
+    ) -> Result<Self::Response, crate::types::Error> {
+        match message {
+            DhcpMessage::Start(vpc) => self.start(vpc).await?,
+            DhcpMessage::Stop(vpc) => self.remove(&vpc).await?,
+            DhcpMessage::ConfigReloaded => self.reload_config().await?,
+            DhcpMessage::Reservation(event) => match event {
+                Event::New(reservation)
+                | Event::Update {
+                    new: reservation, ..
+                } => {
+                    let vpc = reservation.vpc_key_name();
+                    if let Some(old_vpc) = self
+                        .reservations
+                        .insert(reservation.metadata.name, vpc.clone())
+                    {
+                        if old_vpc != vpc {
+                            self.reload(&old_vpc).await?;
+                        }
+                    }
+                    self.reload(&vpc).await?;
+                }
+                Event::Delete(name) => {
+                    if let Some(vpc) = self.reservations.remove(&name) {
+                        self.reload(&vpc).await?;
+                    }
+                }
+            },
+        }
+        Ok(())
+    }
+
+    async fn init(&mut self) -> Result<(), Error> {
+        let reservations: Vec<DhcpReservation> = self.storage.list().await?;
+        for reservation in reservations {
+            self.reservations.insert(
+                reservation.metadata.name.clone(),
+                reservation.vpc_key_name(),
+            );
+        }
+        Ok(())
+    }
+}
+
+/// Reads the leases from a dnsmasq lease file, skipping lines it can't parse.
+pub async fn read_leases(path: &Path) -> Result<Vec<DhcpLease>, Error> {
+    let contents = match tokio::fs::read_to_string(path).await {
+        Ok(contents) => contents,
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(vec![]),
+        Err(err) => return Err(err.into()),
+    };
+    Ok(contents.lines().filter_map(DhcpLease::parse).collect())
+}
@@ -0,0 +1,202 @@
+use std::time::Duration;
+
+use chrono::{DateTime, Utc};
+
+use tokio::{sync::watch, task::JoinHandle};
+
+use super::Actor;
+use crate::{clock::SharedClock, storage::Storage, types::Error};
+
+/// Seconds the leader's lease lasts without being kept alive. A node that dies stays leader for
+/// at most this long.
+const LEADER_TTL: i64 = 15;
+
+/// How often the leader keeps its lease alive and other nodes retry their campaign, by default.
+const CAMPAIGN_INTERVAL: Duration = Duration::from_secs(LEADER_TTL as u64 / 3);
+
+/// Elects one node to run a cluster-wide singleton, like audit compaction, that would duplicate
+/// work if every node ran it. The leader holds `leader/<election>` under a lease it keeps
+/// alive, and once the lease lapses another node takes over.
+pub struct LeaderElection {
+    storage: Storage,
+    key: String,
+    node_name: String,
+    clock: SharedClock,
+    /// How often the leader keeps its lease alive and other nodes retry their campaign.
+    interval: Duration,
+}
+
+impl LeaderElection {
+    pub fn new(storage: Storage, election: &str, clock: SharedClock) -> Result<Self, Error> {
+        Ok(Self {
+            storage,
+            key: format!("leader/{}", election),
+            node_name: sys_info::hostname()?,
+            clock,
+            interval: CAMPAIGN_INTERVAL,
+        })
+    }
+
+    /// Like [`Actor::repeat`], but only runs `actor` while this node is the leader. Leadership
+    /// is checked before every run, so a node that loses its lease stops at the next one. A run
+    /// that fails is logged and retried at the next interval.
+    pub fn run<A>(self, mut actor: A, interval: Duration) -> JoinHandle<Result<(), anyhow::Error>>
+    where
+        A: Actor + Send + Sync + 'static,
+        A::Message: Send + Default,
+    {
+        let key = self.key.clone();
+        let leader = self.campaign();
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(interval);
+            loop {
+                interval.tick().await;
+                if !*leader.borrow() {
+                    continue;
+                }
+                if let Err(err) = actor.handle(Default::default()).await {
+                    println!("error running {}: {:?}", key, err);
+                }
+            }
+        })
+    }
+
+    /// Campaigns in the background, returning whether this node is currently the leader. For
+    /// actors driven by events rather than [`LeaderElection::run`], which check it per message.
+    pub fn campaign(self) -> watch::Receiver<bool> {
+        let (tx, rx) = watch::channel(false);
+        tokio::spawn(async move {
+            loop {
+                let granted = self.clock.now();
+                match self.lead().await {
+                    Ok(Some(lease)) => {
+                        println!("leading {}", self.key);
+                        let _ = tx.send(true);
+                        self.keep_leading(lease, granted).await;
+                        println!("lost leadership of {}", self.key);
+                        let _ = tx.send(false);
+                    }
+                    Ok(None) => {}
+                    Err(err) => println!("error campaigning for {}: {:?}", self.key, err),
+                }
+                tokio::time::sleep(self.interval).await;
+            }
+        });
+        rx
+    }
+
+    /// Tries to become the leader, returning the lease it holds the key under if it did. The
+    /// lease of a lost campaign is revoked, so retries don't leave one behind each.
+    async fn lead(&self) -> Result<Option<i64>, Error> {
+        let lease = self.storage.grant_lease(LEADER_TTL).await?;
+        if self
+            .storage
+            .campaign(&self.key, &self.node_name, lease)
+            .await?
+        {
+            Ok(Some(lease))
+        } else {
+            self.storage.revoke_lease(lease).await?;
+            Ok(None)
+        }
+    }
+
+    /// Keeps `lease`, last refreshed at `refreshed`, alive until it lapses. A keep alive that
+    /// fails, e.g. because etcd can't be reached, is retried as long as the lease is sure to
+    /// outlast the next attempt, since another node may take over once it lapses.
+    async fn keep_leading(&self, lease: i64, mut refreshed: DateTime<Utc>) {
+        let ttl = chrono::Duration::seconds(LEADER_TTL);
+        let interval = chrono::Duration::from_std(self.interval).expect("valid interval");
+        loop {
+            tokio::time::sleep(self.interval).await;
+            let sent = self.clock.now();
+            match self.storage.keep_alive(lease).await {
+                Ok(Some(_)) => refreshed = sent,
+                Ok(None) => return,
+                Err(err) => {
+                    println!("error keeping {} alive: {:?}", self.key, err);
+                    if self.clock.now() + interval >= refreshed + ttl {
+                        return;
+                    }
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::{Backend, MemoryBackend};
+    use std::sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    };
+
+    const INTERVAL: Duration = Duration::from_millis(10);
+
+    fn election(storage: &Storage, node_name: &str) -> LeaderElection {
+        LeaderElection {
+            storage: storage.clone(),
+            key: "leader/test".to_string(),
+            node_name: node_name.to_string(),
+            clock: crate::clock::system(),
+            interval: INTERVAL,
+        }
+    }
+
+    /// Counts its runs, each of which fails.
+    struct Failing(Arc<AtomicUsize>);
+
+    #[async_trait::async_trait]
+    impl Actor for Failing {
+        type Message = ();
+
+        type Response = ();
+
+        async fn handle(&mut self, _message: ()) -> Result<(), Error> {
+            self.0.fetch_add(1, Ordering::SeqCst);
+            Err(Error::NotReady("failing".to_string()))
+        }
+    }
+
+    async fn until(mut done: impl FnMut() -> bool) {
+        for _ in 0..100 {
+            if done() {
+                return;
+            }
+            tokio::time::sleep(INTERVAL).await;
+        }
+        panic!("timed out");
+    }
+
+    #[tokio::test]
+    async fn another_node_leads_once_the_lease_expires() {
+        let backend = MemoryBackend::new();
+        let storage = Storage::new(backend.clone());
+        let (a_runs, b_runs) = (Arc::new(AtomicUsize::new(0)), Arc::new(AtomicUsize::new(0)));
+
+        let _a = election(&storage, "a").run(Failing(a_runs.clone()), INTERVAL);
+        // Failed runs don't stop the leader
+        until(|| a_runs.load(Ordering::SeqCst) > 1).await;
+        let _b = election(&storage, "b").run(Failing(b_runs.clone()), INTERVAL);
+        tokio::time::sleep(INTERVAL * 5).await;
+        assert_eq!(b_runs.load(Ordering::SeqCst), 0);
+        // b's lost campaigns don't leave leases behind
+        assert_eq!(backend.leases(), 1);
+
+        let (kvs, _) = backend
+            .range("leader/test", None, None, None)
+            .await
+            .unwrap();
+        backend.expire_lease(kvs[0].lease);
+        until(|| b_runs.load(Ordering::SeqCst) > 0).await;
+        let leaders = storage.claims("leader/").await.unwrap();
+        assert_eq!(leaders.get("test").map(String::as_str), Some("b"));
+        tokio::time::sleep(INTERVAL * 2).await;
+        let a_stopped = a_runs.load(Ordering::SeqCst);
+        tokio::time::sleep(INTERVAL * 5).await;
+        assert_eq!(a_runs.load(Ordering::SeqCst), a_stopped);
+        assert_eq!(backend.leases(), 1);
+    }
+}
@@ -1,19 +1,33 @@
+mod audit_compactor;
+mod dhcp;
+mod leader;
 mod node_info;
+mod operations;
 mod scheduler;
 mod vm_supervisor;
 mod vpc_supervisor;
 mod watcher;
+pub use audit_compactor::*;
+pub use dhcp::*;
+pub use leader::*;
 pub use node_info::*;
+pub use operations::*;
 pub use scheduler::*;
 pub use vm_supervisor::*;
 pub use vpc_supervisor::*;
 pub use watcher::*;
 
-use std::time::Duration;
+use std::{
+    collections::HashMap,
+    future::Future,
+    panic::AssertUnwindSafe,
+    time::{Duration, Instant},
+};
 
+use futures::FutureExt;
 use tokio::{
     sync::{
-        mpsc::{self, Sender},
+        mpsc::{self, error::TrySendError, Receiver, Sender, UnboundedSender},
         oneshot,
     },
     task::JoinHandle,
@@ -26,27 +40,78 @@ pub trait Actor {
     type Message;
     type Response;
 
+    /// How many messages the actor's mailbox holds before [`Handle::send`] waits and
+    /// [`Handle::try_send`] fails with [`Error::ActorBusy`].
+    const MAILBOX_CAPACITY: usize = DEFAULT_MAILBOX_CAPACITY;
+
     async fn handle(&mut self, message: Self::Message) -> Result<Self::Response, Error>;
 
     async fn init(&mut self) -> Result<(), Error> {
         Ok(())
     }
 
-    fn spawn(mut self) -> (Handle<Self>, JoinHandle<Result<(), anyhow::Error>>)
+    /// Runs when the node shuts down, after the messages already in the mailbox are handled,
+    /// for actors with work of their own to wind down.
+    async fn shutdown(&mut self) -> Result<(), Error> {
+        Ok(())
+    }
+
+    fn spawn(self) -> (Handle<Self>, JoinHandle<Result<(), anyhow::Error>>)
+    where
+        Self: Send + Sync + Sized + 'static,
+        Self::Message: Send + Sync,
+        Self::Response: Send + Sync,
+    {
+        let (tx, mut rx) = mpsc::channel(Self::MAILBOX_CAPACITY);
+        let task = tokio::spawn(async move { serve(self, &mut rx).await });
+        (Handle(tx), task)
+    }
+
+    /// Like [`Actor::spawn`], but when the actor fails or panics it is rebuilt with `new` and
+    /// started again after a [`backoff`], rather than ending the task. Messages sent meanwhile
+    /// wait in the mailbox, so handles keep working across restarts. The task only fails once
+    /// the actor has been restarted [`MAX_RESTARTS`] times without running for
+    /// [`RESTART_RESET`] in between.
+    fn spawn_supervised<F>(
+        name: &'static str,
+        mut new: F,
+    ) -> (Handle<Self>, JoinHandle<Result<(), anyhow::Error>>)
     where
         Self: Send + Sync + Sized + 'static,
         Self::Message: Send + Sync,
         Self::Response: Send + Sync,
+        F: FnMut() -> Result<Self, Error> + Send + 'static,
     {
-        let (tx, mut rx) = mpsc::channel(100);
+        let (tx, mut rx) = mpsc::channel(Self::MAILBOX_CAPACITY);
         let task = tokio::spawn(async move {
-            self.init().await?;
-            while let Some(pair) = rx.recv().await {
-                let (msg, resp_tx): (_, oneshot::Sender<Result<Self::Response, Error>>) = pair;
-                let resp = self.handle(msg).await;
-                let _ = resp_tx.send(resp);
+            let mut restarts = 0;
+            loop {
+                let started = Instant::now();
+                let result = match new() {
+                    Ok(actor) => AssertUnwindSafe(serve(actor, &mut rx))
+                        .catch_unwind()
+                        .await
+                        .unwrap_or_else(|_| Err(anyhow::anyhow!("panicked"))),
+                    Err(err) => Err(err.into()),
+                };
+                let err = match result {
+                    Ok(()) => return Ok(()),
+                    Err(err) => err,
+                };
+                if started.elapsed() >= RESTART_RESET {
+                    restarts = 0;
+                }
+                restarts += 1;
+                if restarts > MAX_RESTARTS {
+                    return Err(err);
+                }
+                let delay = backoff(restarts);
+                println!(
+                    "actor {} failed: {:?}, restarting in {:?}",
+                    name, err, delay
+                );
+                tokio::time::sleep(delay).await;
             }
-            Ok(())
         });
         (Handle(tx), task)
     }
@@ -64,9 +129,68 @@ pub trait Actor {
             }
         })
     }
+
+    /// Like [`Actor::repeat`], but re-reads the delay before every run.
+    fn repeat_with<F>(mut self, delay: F) -> JoinHandle<Result<(), anyhow::Error>>
+    where
+        Self: Send + Sync + Sized + 'static,
+        Self::Message: Send + Default,
+        F: Fn() -> Duration + Send + Sync + 'static,
+    {
+        tokio::spawn(async move {
+            loop {
+                let _ = self.handle(Default::default()).await?;
+                tokio::time::sleep(delay()).await;
+            }
+        })
+    }
 }
 
-type ActorSender<Message, Response> = Sender<(Message, oneshot::Sender<Result<Response, Error>>)>;
+/// The [`Actor::MAILBOX_CAPACITY`] of actors that don't set their own.
+pub const DEFAULT_MAILBOX_CAPACITY: usize = 100;
+
+/// How many times in a row [`Actor::spawn_supervised`] restarts a failing actor before giving
+/// up.
+pub const MAX_RESTARTS: u32 = 5;
+
+/// How long a supervised actor has to run before its restarts stop counting as in a row.
+pub const RESTART_RESET: Duration = Duration::from_secs(10 * 60);
+
+/// What an actor's mailbox holds.
+enum Mail<Message, Response> {
+    Message(Message, oneshot::Sender<Result<Response, Error>>),
+    /// Sent by [`Handle::shutdown`].
+    Shutdown(oneshot::Sender<Result<(), Error>>),
+}
+
+type ActorSender<Message, Response> = Sender<Mail<Message, Response>>;
+
+/// Initializes `actor` and hands it messages from `rx` until every handle is dropped or it is
+/// shut down.
+async fn serve<A>(
+    mut actor: A,
+    rx: &mut Receiver<Mail<A::Message, A::Response>>,
+) -> Result<(), anyhow::Error>
+where
+    A: Actor + Send,
+    A::Message: Send,
+    A::Response: Send,
+{
+    actor.init().await?;
+    while let Some(mail) = rx.recv().await {
+        match mail {
+            Mail::Message(msg, resp_tx) => {
+                let resp = actor.handle(msg).await;
+                let _ = resp_tx.send(resp);
+            }
+            Mail::Shutdown(resp_tx) => {
+                let _ = resp_tx.send(actor.shutdown().await);
+                break;
+            }
+        }
+    }
+    Ok(())
+}
 pub struct Handle<A: Actor>(ActorSender<A::Message, A::Response>);
 
 impl<A: Actor> Clone for Handle<A> {
@@ -76,10 +200,308 @@ impl<A: Actor> Clone for Handle<A> {
 }
 
 impl<A: Actor> Handle<A> {
+    /// The number of messages waiting in the actor's mailbox, and how many it can hold.
+    pub fn mailbox(&self) -> (usize, usize) {
+        (A::MAILBOX_CAPACITY - self.0.capacity(), A::MAILBOX_CAPACITY)
+    }
+
     async fn send(&self, msg: A::Message) -> Result<A::Response, Error> {
         let (tx, rx) = oneshot::channel();
-        self.0.send((msg, tx)).await.map_err(|_| Error::ActorSend)?;
+        self.0
+            .send(Mail::Message(msg, tx))
+            .await
+            .map_err(|_| Error::ActorSend)?;
         let resp = rx.await?;
         resp
     }
+
+    /// Like [`Handle::send`], but fails with [`Error::ActorBusy`] instead of waiting when the
+    /// mailbox is full. The reply is still waited for.
+    async fn try_send(&self, msg: A::Message) -> Result<A::Response, Error> {
+        let (tx, rx) = oneshot::channel();
+        self.0
+            .try_send(Mail::Message(msg, tx))
+            .map_err(|err| match err {
+                TrySendError::Full(_) => Error::ActorBusy,
+                TrySendError::Closed(_) => Error::ActorSend,
+            })?;
+        rx.await?
+    }
+
+    /// Like [`Handle::send`], but waits at most `timeout` for room in the mailbox before failing
+    /// with [`Error::ActorBusy`]. The reply is still waited for.
+    async fn send_timeout(&self, msg: A::Message, timeout: Duration) -> Result<A::Response, Error> {
+        let permit = tokio::time::timeout(timeout, self.0.reserve())
+            .await
+            .map_err(|_| Error::ActorBusy)?
+            .map_err(|_| Error::ActorSend)?;
+        let (tx, rx) = oneshot::channel();
+        permit.send(Mail::Message(msg, tx));
+        rx.await?
+    }
+
+    /// Stops the actor once it has handled the messages ahead in its mailbox, running
+    /// [`Actor::shutdown`] first. Messages sent after are dropped.
+    pub async fn shutdown(&self) -> Result<(), Error> {
+        let (tx, rx) = oneshot::channel();
+        self.0
+            .send(Mail::Shutdown(tx))
+            .await
+            .map_err(|_| Error::ActorSend)?;
+        rx.await?
+    }
+}
+
+const BACKOFF_BASE: Duration = Duration::from_secs(1);
+const BACKOFF_MAX: Duration = Duration::from_secs(300);
+
+/// The delay before retrying an object that has failed `failures` times in a row, doubling
+/// with every failure up to [`BACKOFF_MAX`].
+pub fn backoff(failures: u32) -> Duration {
+    match failures {
+        0 => Duration::default(),
+        n => BACKOFF_BASE
+            .checked_mul(1 << (n - 1).min(16))
+            .map_or(BACKOFF_MAX, |delay| delay.min(BACKOFF_MAX)),
+    }
+}
+
+/// Work an actor handed to a task of its own, so it can take its next message while the work
+/// runs. [`KeyedQueue`] waits for it before sending the next message for the same key.
+pub struct Pending(Option<JoinHandle<Result<(), Error>>>);
+
+impl Pending {
+    /// The reply to a message that was handled in full.
+    pub fn done() -> Self {
+        Pending(None)
+    }
+
+    pub fn spawn<F>(work: F) -> Self
+    where
+        F: Future<Output = Result<(), Error>> + Send + 'static,
+    {
+        Pending(Some(tokio::spawn(work)))
+    }
+}
+
+/// An actor's reply as [`KeyedQueue`] sees it: done with once it settles.
+#[async_trait::async_trait]
+pub trait Reply: Send {
+    async fn settle(self) -> Result<(), Error>;
+}
+
+#[async_trait::async_trait]
+impl Reply for () {
+    async fn settle(self) -> Result<(), Error> {
+        Ok(())
+    }
+}
+
+#[async_trait::async_trait]
+impl Reply for Pending {
+    async fn settle(self) -> Result<(), Error> {
+        match self.0 {
+            Some(task) => task.await?,
+            None => Ok(()),
+        }
+    }
+}
+
+struct Queue<M> {
+    tx: UnboundedSender<M>,
+    task: JoinHandle<()>,
+}
+
+/// Forwards messages to an actor with one queue per key, so a slow message for one key doesn't
+/// hold up the messages queued behind it for other keys, while messages sharing a key are still
+/// delivered in order. A message counts as delivered once the actor's [`Reply`] settles, so
+/// keys only make progress side by side with actors that reply with [`Pending`] work. When the
+/// actor fails to handle a message, the next message for that key is held back by [`backoff`],
+/// so a persistently failing object can't retry in a tight loop.
+pub struct KeyedQueue<A: Actor> {
+    handle: Handle<A>,
+    queues: HashMap<String, Queue<A::Message>>,
+    retired: HashMap<String, JoinHandle<()>>,
+}
+
+impl<A> KeyedQueue<A>
+where
+    A: Actor + 'static,
+    A::Message: Send + 'static,
+    A::Response: Reply + 'static,
+{
+    pub fn new(handle: Handle<A>) -> Self {
+        Self {
+            handle,
+            queues: HashMap::default(),
+            retired: HashMap::default(),
+        }
+    }
+
+    pub fn send(&mut self, key: &str, msg: A::Message) {
+        if !self.queues.contains_key(key) {
+            let prev = self.retired.remove(key);
+            let (tx, mut rx) = mpsc::unbounded_channel();
+            let handle = self.handle.clone();
+            let task = tokio::spawn(async move {
+                // Anything still queued under a retired queue for this key goes first
+                if let Some(prev) = prev {
+                    let _ = prev.await;
+                }
+                let mut failures = 0;
+                while let Some(msg) = rx.recv().await {
+                    let result = match handle.send(msg).await {
+                        Ok(reply) => reply.settle().await,
+                        Err(err) => Err(err),
+                    };
+                    match result {
+                        Ok(()) => failures = 0,
+                        Err(err) => {
+                            failures += 1;
+                            let delay = backoff(failures);
+                            println!("error: {:?}, retrying in {:?}", err, delay);
+                            tokio::time::sleep(delay).await;
+                        }
+                    }
+                }
+            });
+            self.queues.insert(key.to_string(), Queue { tx, task });
+        }
+        if let Some(queue) = self.queues.get(key) {
+            let _ = queue.tx.send(msg);
+        }
+    }
+
+    /// Closes the queue for `key` once its pending messages have been delivered.
+    pub fn retire(&mut self, key: &str) {
+        if let Some(queue) = self.queues.remove(key) {
+            self.retired.insert(key.to_string(), queue.task);
+        }
+        self.retired.retain(|_, task| task.now_or_never().is_none());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+    use tokio::sync::{Notify, Semaphore};
+
+    /// Records each message once its work is done, holding creating `a` until `gate` opens.
+    struct Worker {
+        done: UnboundedSender<String>,
+        gate: Arc<Notify>,
+    }
+
+    #[async_trait::async_trait]
+    impl Actor for Worker {
+        type Message = (&'static str, &'static str);
+        type Response = Pending;
+
+        async fn handle(&mut self, (op, key): Self::Message) -> Result<Pending, Error> {
+            let (done, gate) = (self.done.clone(), self.gate.clone());
+            Ok(Pending::spawn(async move {
+                if (op, key) == ("create", "a") {
+                    gate.notified().await;
+                }
+                let _ = done.send(format!("{} {}", op, key));
+                Ok(())
+            }))
+        }
+    }
+
+    #[tokio::test]
+    async fn keys_progress_side_by_side_in_order() {
+        let (done, mut rx) = mpsc::unbounded_channel();
+        let gate = Arc::new(Notify::new());
+        let (handle, _) = Worker {
+            done,
+            gate: gate.clone(),
+        }
+        .spawn();
+        let mut queue = KeyedQueue::new(handle);
+        queue.send("a", ("create", "a"));
+        queue.send("a", ("delete", "a"));
+        queue.send("b", ("create", "b"));
+        let timeout = Duration::from_secs(5);
+
+        // b is done while a's create still holds up a's delete
+        let next = tokio::time::timeout(timeout, rx.recv()).await.unwrap();
+        assert_eq!(next.as_deref(), Some("create b"));
+        gate.notify_one();
+        let next = tokio::time::timeout(timeout, rx.recv()).await.unwrap();
+        assert_eq!(next.as_deref(), Some("create a"));
+        let next = tokio::time::timeout(timeout, rx.recv()).await.unwrap();
+        assert_eq!(next.as_deref(), Some("delete a"));
+    }
+
+    /// Handles one message at a time, each waiting for a permit from `gate`.
+    struct Blocked {
+        started: UnboundedSender<u32>,
+        gate: Arc<Semaphore>,
+    }
+
+    #[async_trait::async_trait]
+    impl Actor for Blocked {
+        type Message = u32;
+        type Response = ();
+
+        const MAILBOX_CAPACITY: usize = 2;
+
+        async fn handle(&mut self, message: u32) -> Result<(), Error> {
+            let _ = self.started.send(message);
+            self.gate.acquire().await.unwrap().forget();
+            Ok(())
+        }
+    }
+
+    #[tokio::test]
+    async fn a_full_mailbox_holds_senders_at_its_capacity() {
+        let (started, mut handling) = mpsc::unbounded_channel();
+        let gate = Arc::new(Semaphore::new(0));
+        let (handle, _) = Blocked {
+            started,
+            gate: gate.clone(),
+        }
+        .spawn();
+        let timeout = Duration::from_secs(5);
+        let send = |message| {
+            let handle = handle.clone();
+            tokio::spawn(async move { handle.send(message).await })
+        };
+
+        // One message being handled and two waiting fill the mailbox
+        let first = send(0);
+        let next = tokio::time::timeout(timeout, handling.recv())
+            .await
+            .unwrap();
+        assert_eq!(next, Some(0));
+        let queued = vec![send(1), send(2)];
+        while handle.mailbox() != (2, 2) {
+            tokio::task::yield_now().await;
+        }
+
+        assert!(matches!(handle.try_send(3).await, Err(Error::ActorBusy)));
+        assert!(matches!(
+            handle.send_timeout(3, Duration::from_millis(10)).await,
+            Err(Error::ActorBusy)
+        ));
+        let mut blocked = send(3);
+        assert!(
+            tokio::time::timeout(Duration::from_millis(50), &mut blocked)
+                .await
+                .is_err(),
+            "a send went past a full mailbox"
+        );
+
+        gate.add_permits(4);
+        for sent in std::iter::once(first).chain(queued).chain(Some(blocked)) {
+            tokio::time::timeout(timeout, sent)
+                .await
+                .unwrap()
+                .unwrap()
+                .unwrap();
+        }
+        assert_eq!(handle.mailbox(), (0, 2));
+    }
 }
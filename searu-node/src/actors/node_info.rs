@@ -1,14 +1,34 @@
-use crate::{storage::Storage, types::Node};
+use crate::{clock::SharedClock, config::SharedConfig, storage::Storage, types::Node};
 
 use super::Actor;
 
+/// Registers this node and heartbeats it. The node is stored under a lease every heartbeat
+/// keeps alive, so a node that stops heartbeating expires after `node_ttl` and the scheduler
+/// moves its VMs elsewhere.
 pub struct NodeInfo {
     storage: Storage,
+    config: SharedConfig,
+    clock: SharedClock,
 }
 
 impl NodeInfo {
-    pub fn new(storage: Storage) -> Self {
-        Self { storage }
+    pub fn new(storage: Storage, config: SharedConfig, clock: SharedClock) -> Self {
+        Self {
+            storage,
+            config,
+            clock,
+        }
+    }
+
+    /// Keeps `lease` alive, or grants a new one if there isn't one or it already lapsed.
+    async fn lease(&self, lease: Option<i64>) -> Result<i64, crate::types::Error> {
+        if let Some(lease) = lease {
+            if self.storage.keep_alive(lease).await?.is_some() {
+                return Ok(lease);
+            }
+        }
+        let ttl = self.config.load().node_ttl as i64;
+        self.storage.grant_lease(ttl).await
     }
 }
 
@@ -24,7 +44,7 @@ impl Actor for NodeInfo {
     ) -> Result<Self::Response, crate::types::Error> {
         let hostname = sys_info::hostname()?;
         let memory = sys_info::mem_info()?;
-        let node = Node {
+        let mut node = Node {
             metadata: crate::types::Metadata {
                 name: hostname,
                 ..Default::default()
@@ -32,8 +52,71 @@ impl Actor for NodeInfo {
             cpu_count: sys_info::cpu_num()? as usize,
             cpu_freq: sys_info::cpu_speed()?,
             memory: memory.total,
+            cordoned: false,
+            last_heartbeat: Some(self.clock.now().timestamp()),
         };
-        self.storage.store(&node).await?;
-        Ok(())
+        // Labels and cordons are set through the API, so keep them and only write over the
+        // version they were read at. A conflicting change is picked up by the next heartbeat.
+        match self.storage.get::<Node>(&node.metadata.name).await? {
+            Some(current) => {
+                node.metadata.labels = current.metadata.labels;
+                node.cordoned = current.cordoned;
+                node.metadata.version = current.metadata.version;
+                node.metadata.lease = Some(self.lease(current.metadata.lease).await?);
+                self.storage.update(&node).await
+            }
+            None => {
+                node.metadata.lease = Some(self.lease(None).await?);
+                self.storage.create(&node).await
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{clock::FixedClock, config::Config, storage::MemoryBackend};
+    use arc_swap::ArcSwap;
+    use chrono::{TimeZone, Utc};
+    use std::sync::Arc;
+
+    #[tokio::test]
+    async fn heartbeats_keep_labels_and_cordons() {
+        let storage = Storage::new(MemoryBackend::new());
+        let config: Config = serde_json::from_value(serde_json::json!({
+            "etcd_addr": "localhost:2379",
+            "jwt_secret": "secret",
+        }))
+        .unwrap();
+        let clock = FixedClock::new(Utc.timestamp(1_600_000_000, 0));
+        let mut info = NodeInfo::new(
+            storage.clone(),
+            Arc::new(ArcSwap::from_pointee(config)),
+            clock.clone(),
+        );
+        let name = sys_info::hostname().unwrap();
+
+        info.handle(()).await.unwrap();
+        let mut node: Node = storage.get(&name).await.unwrap().unwrap();
+        let lease = node.metadata.lease;
+        assert!(lease.is_some());
+        assert_eq!(node.last_heartbeat, Some(1_600_000_000));
+        node.metadata
+            .labels
+            .insert("rack".to_string(), "a1".to_string());
+        node.cordoned = true;
+        storage.update(&node).await.unwrap();
+
+        clock.set(Utc.timestamp(1_600_000_060, 0));
+        info.handle(()).await.unwrap();
+        let beat: Node = storage.get(&name).await.unwrap().unwrap();
+        assert_eq!(
+            beat.metadata.labels.get("rack").map(String::as_str),
+            Some("a1")
+        );
+        assert!(beat.cordoned);
+        assert_eq!(beat.metadata.lease, lease);
+        assert_eq!(beat.last_heartbeat, Some(1_600_000_060));
     }
 }
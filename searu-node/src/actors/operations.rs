@@ -0,0 +1,233 @@
+use std::{collections::HashSet, time::Duration};
+
+use futures::StreamExt;
+use tokio::task::JoinHandle;
+
+use super::{Actor, Handle, KeyedQueue};
+use crate::{
+    clock::SharedClock,
+    storage::{Event, Storage},
+    types::{BatchResult, Error, Object, Operation, OperationKind, OperationState, Vm, VmState},
+};
+
+/// How long an evacuation waits for a VM to be running on another node before giving up on it.
+const EVACUATE_TIMEOUT: Duration = Duration::from_secs(5 * 60);
+
+/// How many VMs an evacuation moves at once.
+const EVACUATE_CONCURRENCY: usize = 4;
+
+/// Carries out the operations accepted by this node's API. Each operation runs in its own task,
+/// so a long evacuation doesn't hold up the mailbox.
+pub struct OperationRunner {
+    storage: Storage,
+    clock: SharedClock,
+    node_name: String,
+    running: HashSet<String>,
+}
+
+impl OperationRunner {
+    pub fn new(storage: Storage, clock: SharedClock) -> Result<Self, Error> {
+        Ok(Self {
+            storage,
+            clock,
+            node_name: sys_info::hostname()?,
+            running: HashSet::default(),
+        })
+    }
+
+    fn run(&mut self, op: Operation) {
+        if op.runner != self.node_name
+            || op.state != OperationState::Running
+            || !self.running.insert(op.metadata.name.clone())
+        {
+            return;
+        }
+        let storage = self.storage.clone();
+        let clock = self.clock.clone();
+        tokio::spawn(async move {
+            let name = op.metadata.name.clone();
+            if let Err(err) = run(&storage, &clock, op).await {
+                println!("error running operation {}: {:?}", name, err);
+            }
+        });
+    }
+}
+
+#[async_trait::async_trait]
+impl Actor for OperationRunner {
+    type Message = Event<Operation>;
+    type Response = ();
+
+    async fn handle(&mut self, message: Self::Message) -> Result<Self::Response, Error> {
+        match message {
+            Event::New(op) => self.run(op),
+            Event::Delete(name) => {
+                self.running.remove(&name);
+            }
+            Event::Update { .. } => {}
+        }
+        Ok(())
+    }
+
+    /// Resumes the operations this node was running when it stopped.
+    async fn init(&mut self) -> Result<(), Error> {
+        for op in self.storage.list::<Operation>().await? {
+            self.run(op);
+        }
+        Ok(())
+    }
+}
+
+pub struct OperationWatcher {
+    storage: Storage,
+    runner: Handle<OperationRunner>,
+}
+
+impl OperationWatcher {
+    pub fn new(storage: Storage, runner: Handle<OperationRunner>) -> Self {
+        Self { storage, runner }
+    }
+
+    pub fn spawn(self) -> JoinHandle<Result<(), anyhow::Error>> {
+        tokio::spawn(async move {
+            let mut stream = self.storage.watch::<Operation>().await?;
+            let mut queue = KeyedQueue::new(self.runner.clone());
+            while let Some(event) = stream.next().await {
+                let name = event.key_name();
+                let delete = matches!(event, Event::Delete(_));
+                queue.send(&name, event);
+                if delete {
+                    queue.retire(&name);
+                }
+            }
+            Ok(())
+        })
+    }
+}
+
+async fn run(storage: &Storage, clock: &SharedClock, mut op: Operation) -> Result<(), Error> {
+    // Only the runner writes an operation once it's created, so progress is stored as is
+    op.metadata.version = None;
+    op.metadata.mod_revision = None;
+    match op.kind.clone() {
+        OperationKind::Evacuate { node } => evacuate(storage, clock, &mut op, &node).await?,
+    }
+    op.state = if op.results.iter().all(|result| result.error.is_none()) {
+        OperationState::Succeeded
+    } else {
+        OperationState::Failed
+    };
+    op.finished_at = Some(clock.now().timestamp());
+    storage.store(&op).await
+}
+
+/// Moves every VM off `node`, recording each one's outcome as it finishes. VMs already in the
+/// results, from before a restart, are skipped.
+async fn evacuate(
+    storage: &Storage,
+    clock: &SharedClock,
+    op: &mut Operation,
+    node: &str,
+) -> Result<(), Error> {
+    let vms: Vec<String> = storage
+        .list::<Vm>()
+        .await?
+        .into_iter()
+        .filter(|vm| vm.status.node.as_deref() == Some(node))
+        .map(|vm| vm.key_name())
+        .filter(|name| !op.results.iter().any(|result| result.name == *name))
+        .collect();
+    op.total = op.results.len() + vms.len();
+    storage.store(op).await?;
+    let mut moves = futures::stream::iter(vms)
+        .map(|name| async move {
+            let error = move_vm(storage, clock, &name, node).await.err();
+            BatchResult { name, error }
+        })
+        .buffer_unordered(EVACUATE_CONCURRENCY);
+    while let Some(result) = moves.next().await {
+        if let Some(ref error) = result.error {
+            println!("error evacuating vm {}: {}", result.name, error);
+        }
+        op.results.push(result);
+        storage.store(op).await?;
+    }
+    Ok(())
+}
+
+/// Unschedules a VM from `node` and waits for it to be running somewhere else. There is no live
+/// migration, as overlays are local to the node, so the VM boots again from the base image on
+/// its new node.
+async fn move_vm(
+    storage: &Storage,
+    clock: &SharedClock,
+    name: &str,
+    node: &str,
+) -> Result<(), String> {
+    let mut stream = storage
+        .watch_one::<Vm>(name)
+        .await
+        .map_err(|err| err.to_string())?;
+    let mut attempts = 0;
+    let mut vm = loop {
+        let mut vm = match storage
+            .get::<Vm>(name)
+            .await
+            .map_err(|err| err.to_string())?
+        {
+            Some(vm) if vm.status.node.as_deref() == Some(node) => vm,
+            // Deleted or already moved by someone else
+            _ => return Ok(()),
+        };
+        // The old node tears the VM down once it sees it unscheduled, so it starts over
+        vm.status.node = None;
+        vm.status
+            .transition(VmState::PoweredOff, clock.now().timestamp())
+            .map_err(|err| err.to_string())?;
+        vm.status.failures = 0;
+        vm.status.last_error = None;
+        vm.status.console_pty = None;
+        vm.status.serial_pty = None;
+        vm.status.cloud_init_status = None;
+        vm.status.message = Some(format!("evacuating node {}", node));
+        match storage.update(&vm).await {
+            Err(Error::Conflict(_)) if attempts < 3 => attempts += 1,
+            Err(err) => return Err(err.to_string()),
+            Ok(()) => break Some(vm),
+        }
+    };
+    let timeout = tokio::time::sleep(EVACUATE_TIMEOUT);
+    tokio::pin!(timeout);
+    loop {
+        match vm {
+            None => return Ok(()),
+            Some(ref vm) => match vm.status.node {
+                Some(ref to) if to != node && vm.status.state == VmState::PoweredOn => {
+                    return Ok(())
+                }
+                Some(ref to) if to != node && vm.status.failures > 0 => {
+                    return Err(format!(
+                        "failed to start on node {}: {}",
+                        to,
+                        vm.status.last_error.as_deref().unwrap_or_default()
+                    ))
+                }
+                _ => {}
+            },
+        }
+        tokio::select! {
+            event = stream.next() => match event {
+                Some(Event::New(new)) | Some(Event::Update { new, .. }) => vm = Some(new),
+                Some(Event::Delete(_)) => vm = None,
+                None => return Err("watch ended".to_string()),
+            },
+            _ = &mut timeout => {
+                let message = vm.and_then(|vm| vm.status.message);
+                return Err(match message {
+                    Some(message) => format!("timed out: {}", message),
+                    None => "timed out".to_string(),
+                });
+            }
+        }
+    }
+}
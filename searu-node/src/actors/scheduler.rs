@@ -1,19 +1,279 @@
-use std::{collections::HashSet, net::Ipv4Addr, num::Wrapping};
+use std::{collections::HashSet, net::Ipv4Addr};
+
+use serde::Deserialize;
+use tokio::{sync::watch, task::JoinHandle};
 
 use crate::{
+    allocator::{spread, Allocator},
+    clock::SharedClock,
+    config::SharedConfig,
     storage::{Event, Storage},
-    types::{Node, Vm, Vpc},
+    types::{
+        Capacity, ClusterConfig, Error, Node, NodeCandidate, Object, SchedulingDecision, Vm,
+        VmState, Vpc,
+    },
 };
 
-use super::Actor;
+use super::{Actor, Handle};
+
+/// Ranks the nodes a VM could be placed on. The scheduler places the VM on the node with the
+/// highest score.
+pub trait Scorer: Send + Sync {
+    /// Scores placing `vm` on `node`, where `committed` is what the VMs already on the node use,
+    /// or returns `None` if the VM doesn't fit.
+    fn score(&self, node: &Node, committed: Capacity, vm: &Vm) -> Option<f64>;
+}
+
+/// The fraction of a node's cpus and memory that would be in use with `vm` placed on it, or
+/// `None` if it doesn't fit.
+fn utilization(node: &Node, committed: Capacity, vm: &Vm) -> Option<(f64, f64)> {
+    let capacity = node.capacity();
+    let used = committed + Capacity::from(&vm.spec);
+    if used.cpus > capacity.cpus || used.memory > capacity.memory {
+        return None;
+    }
+    Some((
+        used.cpus as f64 / capacity.cpus as f64,
+        used.memory as f64 / capacity.memory as f64,
+    ))
+}
+
+/// Spreads VMs out by preferring the node left with the most free cpus and memory.
+pub struct LeastAllocated;
+
+impl Scorer for LeastAllocated {
+    fn score(&self, node: &Node, committed: Capacity, vm: &Vm) -> Option<f64> {
+        let (cpus, memory) = utilization(node, committed, vm)?;
+        Some(((1.0 - cpus) + (1.0 - memory)) / 2.0)
+    }
+}
+
+/// Bin-packs VMs by preferring the node left with the least free cpus and memory.
+pub struct MostAllocated;
+
+impl Scorer for MostAllocated {
+    fn score(&self, node: &Node, committed: Capacity, vm: &Vm) -> Option<f64> {
+        let (cpus, memory) = utilization(node, committed, vm)?;
+        Some((cpus + memory) / 2.0)
+    }
+}
+
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum ScorerKind {
+    LeastAllocated,
+    MostAllocated,
+}
 
+impl Default for ScorerKind {
+    fn default() -> Self {
+        ScorerKind::LeastAllocated
+    }
+}
+
+impl ScorerKind {
+    pub fn scorer(self) -> Box<dyn Scorer> {
+        match self {
+            ScorerKind::LeastAllocated => Box::new(LeastAllocated),
+            ScorerKind::MostAllocated => Box::new(MostAllocated),
+        }
+    }
+}
+
+/// Places VMs onto nodes and allocates VPCs their multicast IP and VNI. Every node runs one, but
+/// only the elected leader acts, so nodes don't race to place the same VM.
 pub struct Scheduler {
     storage: Storage,
+    config: SharedConfig,
+    multicast_ips: Allocator,
+    vnis: Allocator,
+    leader: watch::Receiver<bool>,
+    clock: SharedClock,
 }
 
 impl Scheduler {
-    pub fn new(storage: Storage) -> Self {
-        Self { storage }
+    pub fn new(
+        storage: Storage,
+        config: SharedConfig,
+        leader: watch::Receiver<bool>,
+        clock: SharedClock,
+    ) -> Self {
+        Self {
+            multicast_ips: Allocator::new(storage.clone(), "multicast_ip"),
+            vnis: Allocator::new(storage.clone(), "vni"),
+            storage,
+            config,
+            leader,
+            clock,
+        }
+    }
+
+    /// Picks the highest scoring node `vm` fits on, recording every node considered. Nodes
+    /// ruled out by the VM's `node_selector` or `anti_affinity_group` aren't scored.
+    async fn place(&self, vm: &Vm) -> Result<SchedulingDecision, crate::types::Error> {
+        let nodes: Vec<Node> = self.storage.list().await?;
+        let vms: Vec<Vm> = self.storage.list().await?;
+        let scorer = self.config.load().scheduler_scorer.scorer();
+        let candidates: Vec<NodeCandidate> = nodes
+            .iter()
+            .map(|node| {
+                let committed = node.allocated(&vms);
+                let rejected = if node.cordoned {
+                    Some("node is cordoned".to_string())
+                } else if let Some(selected) = vm
+                    .spec
+                    .node_selector
+                    .as_ref()
+                    .filter(|selected| **selected != node.metadata.name)
+                {
+                    Some(format!("node_selector is {}", selected))
+                } else {
+                    anti_affine(node, &vms, vm).or_else(|| shortfall(node, committed, vm))
+                };
+                NodeCandidate {
+                    node: node.metadata.name.clone(),
+                    score: rejected
+                        .is_none()
+                        .then(|| scorer.score(node, committed, vm))
+                        .flatten(),
+                    rejected,
+                }
+            })
+            .collect();
+        let best = candidates
+            .iter()
+            .filter_map(|candidate| Some((candidate.score?, candidate)))
+            .max_by(|(a, _), (b, _)| a.partial_cmp(b).unwrap_or(std::cmp::Ordering::Equal));
+        let (node, reason) = match best {
+            Some((score, candidate)) => (
+                Some(candidate.node.clone()),
+                format!("highest score {:.3} of {} nodes", score, candidates.len()),
+            ),
+            None => (None, unschedulable_reason(vm, &candidates)),
+        };
+        Ok(SchedulingDecision {
+            node,
+            reason,
+            candidates,
+        })
+    }
+
+    /// Places a VM that has no node yet. A VM that fits nowhere, e.g. because no nodes have
+    /// joined, is left `Pending` with the reason, and retried when it changes or a node joins.
+    async fn schedule(&self, mut vm: Vm) -> Result<(), Error> {
+        let cluster: ClusterConfig = self
+            .storage
+            .get(ClusterConfig::NAME)
+            .await?
+            .unwrap_or_default();
+        if cluster.scheduling_paused {
+            let message = Some("scheduling is paused".to_string());
+            if vm.status.message != message {
+                vm.status.message = message;
+                self.storage.store(&vm).await?;
+            }
+            return Ok(());
+        }
+        let decision = self.place(&vm).await?;
+        println!(
+            "scheduling vm {}: {:?} ({})",
+            vm.metadata.name, decision.node, decision.reason
+        );
+        match decision.node {
+            Some(ref node) => {
+                vm.status.node = Some(node.clone());
+                vm.status.message = None;
+                vm.status.scheduling = Some(decision);
+                self.storage.store(&vm).await?;
+            }
+            None => {
+                let message = Some(decision.reason.clone());
+                // Only store a changed decision, or every retry would trigger another one
+                let pending = vm.status.state.can_transition_to(&VmState::Pending);
+                if vm.status.scheduling.as_ref() != Some(&decision)
+                    || (pending && vm.status.state != VmState::Pending)
+                {
+                    if pending {
+                        let now = self.clock.now().timestamp();
+                        vm.status.transition(VmState::Pending, now)?;
+                    }
+                    vm.status.message = message;
+                    vm.status.scheduling = Some(decision);
+                    self.storage.store(&vm).await?;
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Why `vm` doesn't fit on `node`, or `None` if it does.
+fn shortfall(node: &Node, committed: Capacity, vm: &Vm) -> Option<String> {
+    let free = node.capacity().saturating_sub(committed);
+    if (vm.spec.cpus as usize) > free.cpus {
+        Some(format!(
+            "insufficient cpus: {} free, {} requested",
+            free.cpus, vm.spec.cpus
+        ))
+    } else if (vm.spec.memory as u64) > free.memory {
+        Some(format!(
+            "insufficient memory: {} MiB free, {} MiB requested",
+            free.memory, vm.spec.memory
+        ))
+    } else {
+        None
+    }
+}
+
+/// The VM in `vm`'s anti-affinity group already placed on `node`, if any.
+fn anti_affine(node: &Node, vms: &[Vm], vm: &Vm) -> Option<String> {
+    let group = vm.spec.anti_affinity_group.as_ref()?;
+    vms.iter()
+        .find(|other| {
+            other.key_name() != vm.key_name()
+                && other.status.node.as_ref() == Some(&node.metadata.name)
+                && other.spec.anti_affinity_group.as_ref() == Some(group)
+        })
+        .map(|other| {
+            format!(
+                "runs vm {} of anti_affinity_group {}",
+                other.metadata.name, group
+            )
+        })
+}
+
+/// Summarizes why no candidate could take the VM.
+fn unschedulable_reason(vm: &Vm, candidates: &[NodeCandidate]) -> String {
+    if candidates.is_empty() {
+        return "no nodes are registered".to_string();
+    }
+    if let Some(ref selected) = vm.spec.node_selector {
+        return match candidates
+            .iter()
+            .find(|candidate| candidate.node == *selected)
+        {
+            Some(candidate) => format!(
+                "selected node {}: {}",
+                selected,
+                candidate.rejected.as_deref().unwrap_or_default()
+            ),
+            None => format!("selected node {} isn't registered", selected),
+        };
+    }
+    let all = |prefix: &str| {
+        candidates.iter().all(|candidate| {
+            candidate
+                .rejected
+                .as_deref()
+                .map_or(false, |reason| reason.starts_with(prefix))
+        })
+    };
+    if all("insufficient cpus") {
+        "insufficient cpus on all nodes".to_string()
+    } else if all("insufficient memory") {
+        "insufficient memory on all nodes".to_string()
+    } else {
+        "no node has room for the vm".to_string()
     }
 }
 
@@ -23,80 +283,101 @@ impl Actor for Scheduler {
 
     type Response = ();
 
+    /// Every VM and VPC change on the cluster passes through the scheduler, and most are
+    /// handled quickly, so it gets room for bursts like a leader takeover.
+    const MAILBOX_CAPACITY: usize = 1000;
+
     async fn handle(
         &mut self,
         message: Self::Message,
     ) -> Result<Self::Response, crate::types::Error> {
+        // Only the leader places and allocates. Releases are idempotent, so every node applies
+        // them and none are lost while leadership changes hands
+        let leader = *self.leader.borrow();
         match message {
+            Events::VmEvent(_)
+            | Events::VpcEvent(Event::New(_))
+            | Events::VpcEvent(Event::Update { .. })
+            | Events::NodeAdded(_)
+            | Events::NodeDeleted(_)
+                if !leader => {}
             Events::VmEvent(message) => match message {
-                Event::New(mut vm) | Event::Update { new: mut vm, .. } => {
+                Event::New(vm) | Event::Update { new: vm, .. } => {
                     if vm.status.node.is_none() {
-                        let nodes: Vec<Node> = self.storage.list().await?;
-                        let node = &nodes[0];
-                        vm.status.node = Some(node.metadata.name.clone());
-                        self.storage.store(&vm).await?;
+                        self.schedule(vm).await?;
                     }
                 }
                 Event::Delete(_) => {}
             },
             Events::VpcEvent(message) => match message {
                 Event::New(mut vpc) | Event::Update { new: mut vpc, .. } => {
-                    if vpc.spec.multicast_ip.is_none() {
-                        let mut used_ips: HashSet<Ipv4Addr> = HashSet::default();
-                        let vpcs: Vec<Vpc> = self.storage.list().await?;
-                        let mut largest_octet = Wrapping(0);
-                        for vpc in &vpcs {
-                            if let Some(ip) = vpc.spec.multicast_ip {
-                                used_ips.insert(ip);
-                                let octet = Wrapping(ip.octets()[3]);
-                                if largest_octet < octet {
-                                    largest_octet = octet;
-                                }
-                            }
+                    let owner = vpc.key_name();
+                    // The API claims supplied values, but record values set before the allocator
+                    // existed too, so they aren't handed out again
+                    if let Some(ip) = vpc.spec.multicast_ip {
+                        if !self.multicast_ips.claim(&owner, &ip).await? {
+                            println!("vpc {} shares multicast_ip {} with another vpc", owner, ip);
                         }
-                        let mut attempts: u16 = 0;
-                        largest_octet += Wrapping(1);
-                        let mut ip = Ipv4Addr::from([239, 1, 1, largest_octet.0]);
-                        while used_ips.contains(&ip) && attempts < 512 {
-                            attempts += 1;
-                            largest_octet += Wrapping(1);
-                            ip = Ipv4Addr::from([239, 1, 1, largest_octet.0]);
+                    }
+                    if let Some(vni) = vpc.spec.vni {
+                        if !self.vnis.claim(&owner, &vni).await? {
+                            println!("vpc {} shares vni {} with another vpc", owner, vni);
                         }
-                        if attempts >= 512 {
-                            // TODO: Handle failure to schedule
-                            return Ok(());
+                    }
+                    if vpc.spec.multicast_ip.is_some() && vpc.spec.vni.is_some() {
+                        return Ok(());
+                    }
+                    if vpc.spec.multicast_ip.is_none() {
+                        let range = self.config.load().multicast_range;
+                        let base = u32::from(range.network());
+                        let size = 1u64 << (32 - range.prefix_len());
+                        let candidates =
+                            spread(&owner, size).map(|offset| Ipv4Addr::from(base + offset as u32));
+                        match self.multicast_ips.allocate(&owner, candidates).await? {
+                            Some(ip) => vpc.spec.multicast_ip = Some(ip),
+                            None => {
+                                // TODO: Handle failure to schedule
+                                return Ok(());
+                            }
                         }
-                        vpc.spec.multicast_ip = Some(ip);
-                        self.storage.store(&vpc).await?;
                     }
                     if vpc.spec.vni.is_none() {
-                        let mut used_vnis: HashSet<u16> = HashSet::default();
-                        let vpcs: Vec<Vpc> = self.storage.list().await?;
-                        let mut largest_vni = Wrapping(0);
-                        for vpc in &vpcs {
-                            if let Some(vni) = vpc.spec.vni {
-                                used_vnis.insert(vni);
-                                if largest_vni.0 < vni {
-                                    largest_vni = Wrapping(vni);
-                                }
+                        let candidates =
+                            spread(&owner, u16::MAX as u64).map(|offset| offset as u16 + 1);
+                        match self.vnis.allocate(&owner, candidates).await? {
+                            Some(vni) => vpc.spec.vni = Some(vni),
+                            None => {
+                                // TODO: Handle failure to schedule
+                                return Ok(());
                             }
                         }
-                        let mut attempts: u16 = 0;
-                        largest_vni += Wrapping(1);
-                        while used_vnis.contains(&largest_vni.0) && attempts < 1024 {
-                            attempts += 1;
-                            largest_vni += Wrapping(1);
-                        }
-                        if attempts >= 512 {
-                            // TODO: Handle failure to schedule
-                            return Ok(());
-                        }
-                        vpc.spec.vni = Some(largest_vni.0);
-                        self.storage.store(&vpc).await?;
                     }
+                    self.storage.store(&vpc).await?;
+                }
+                Event::Delete(name) => {
+                    self.multicast_ips.release(&name).await?;
+                    self.vnis.release(&name).await?;
                 }
-                Event::Delete(_) => {}
             },
+            Events::NodeAdded(node) => {
+                for vm in self.storage.list::<Vm>().await? {
+                    if vm.status.node.is_none() && vm.status.state != VmState::Failed {
+                        println!("retrying vm {} as node {} joined", vm.metadata.name, node);
+                        self.schedule(vm).await?;
+                    }
+                }
+            }
+            Events::NodeDeleted(node) => {
+                for vm in self.storage.list::<Vm>().await? {
+                    if vm.status.node.as_deref() == Some(&node) {
+                        println!(
+                            "rescheduling vm {} off expired node {}",
+                            vm.metadata.name, node
+                        );
+                        unschedule(&self.storage, &self.clock, &vm.key_name(), &node).await?;
+                    }
+                }
+            }
         }
 
         Ok(())
@@ -106,4 +387,413 @@ impl Actor for Scheduler {
 pub enum Events {
     VmEvent(Event<Vm>),
     VpcEvent(Event<Vpc>),
+    /// A node joined, so VMs left waiting for room may fit now.
+    NodeAdded(String),
+    /// A node's lease expired or it was deleted, so the VMs on it need placing again.
+    NodeDeleted(String),
+}
+
+/// Clears `node` from a VM so the scheduler places it again. The VM boots from its base image on
+/// the new node, as its overlay was local to the old one.
+async fn unschedule(
+    storage: &Storage,
+    clock: &SharedClock,
+    name: &str,
+    node: &str,
+) -> Result<(), Error> {
+    let mut attempts = 0;
+    loop {
+        let mut vm = match storage.get::<Vm>(name).await? {
+            Some(vm) if vm.status.node.as_deref() == Some(node) => vm,
+            // Deleted or already moved
+            _ => return Ok(()),
+        };
+        vm.status.node = None;
+        vm.status
+            .transition(VmState::PoweredOff, clock.now().timestamp())?;
+        vm.status.failures = 0;
+        vm.status.last_error = None;
+        vm.status.console_pty = None;
+        vm.status.serial_pty = None;
+        vm.status.cloud_init_status = None;
+        vm.status.message = Some(format!("node {} stopped heartbeating", node));
+        match storage.update(&vm).await {
+            Err(Error::Conflict(_)) if attempts < 3 => attempts += 1,
+            Err(err) => return Err(err),
+            Ok(()) => return Ok(()),
+        }
+    }
+}
+
+/// Replays the VMs and VPCs still waiting on the scheduler whenever this node becomes the
+/// leader, as the events that left them waiting were skipped while another node led. VMs on
+/// nodes that expired in the meantime are rescheduled too.
+pub fn spawn_takeover(
+    storage: Storage,
+    scheduler: Handle<Scheduler>,
+    mut leader: watch::Receiver<bool>,
+) -> JoinHandle<Result<(), anyhow::Error>> {
+    tokio::spawn(async move {
+        while leader.changed().await.is_ok() {
+            if !*leader.borrow() {
+                continue;
+            }
+            let nodes: HashSet<String> = storage
+                .list::<Node>()
+                .await?
+                .into_iter()
+                .map(|node| node.metadata.name)
+                .collect();
+            let mut expired = HashSet::new();
+            for vm in storage.list::<Vm>().await? {
+                match vm.status.node {
+                    None if vm.status.state != VmState::Failed => {
+                        let _ = scheduler.send(Events::VmEvent(Event::New(vm))).await;
+                    }
+                    Some(node) if !nodes.contains(&node) => {
+                        expired.insert(node);
+                    }
+                    _ => {}
+                }
+            }
+            for node in expired {
+                let _ = scheduler.send(Events::NodeDeleted(node)).await;
+            }
+            for vpc in storage.list::<Vpc>().await? {
+                if vpc.spec.multicast_ip.is_none() || vpc.spec.vni.is_none() {
+                    let _ = scheduler.send(Events::VpcEvent(Event::New(vpc))).await;
+                }
+            }
+        }
+        Ok(())
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        config::Config,
+        storage::MemoryBackend,
+        types::{Metadata, VmSpec, VpcSpec},
+    };
+    use arc_swap::ArcSwap;
+    use futures::StreamExt;
+    use std::{sync::Arc, time::Duration};
+
+    fn scheduler(storage: &Storage, config: serde_json::Value) -> Scheduler {
+        let mut base = serde_json::json!({"etcd_addr": "localhost:2379", "jwt_secret": "secret"});
+        base.as_object_mut()
+            .unwrap()
+            .extend(config.as_object().unwrap().clone());
+        let config: Config = serde_json::from_value(base).unwrap();
+        let (_, leader) = watch::channel(true);
+        Scheduler::new(
+            storage.clone(),
+            Arc::new(ArcSwap::from_pointee(config)),
+            leader,
+            crate::clock::system(),
+        )
+    }
+
+    fn node(name: &str, cpus: usize, memory_mib: u64) -> Node {
+        Node {
+            metadata: Metadata {
+                name: name.to_string(),
+                ..Default::default()
+            },
+            cpu_count: cpus,
+            cpu_freq: 0,
+            memory: memory_mib << 10,
+            cordoned: false,
+            last_heartbeat: None,
+        }
+    }
+
+    fn vm(name: &str, cpus: u8, memory: usize) -> Vm {
+        Vm {
+            metadata: Metadata {
+                name: name.to_string(),
+                project: "default".to_string(),
+                ..Default::default()
+            },
+            spec: VmSpec {
+                vpc: "net".to_string(),
+                cpus,
+                memory,
+                ..Default::default()
+            },
+            status: Default::default(),
+        }
+    }
+
+    async fn stored(storage: &Storage, vm: &Vm) -> Vm {
+        storage.get(&vm.key_name()).await.unwrap().unwrap()
+    }
+
+    #[tokio::test]
+    async fn vms_wait_for_a_node_to_join() {
+        let storage = Storage::new(MemoryBackend::new());
+        let mut scheduler = scheduler(&storage, serde_json::json!({}));
+        let vm = vm("a", 1, 512);
+        storage.create(&vm).await.unwrap();
+
+        scheduler
+            .handle(Events::VmEvent(Event::New(vm.clone())))
+            .await
+            .unwrap();
+        let waiting = stored(&storage, &vm).await;
+        assert_eq!(waiting.status.node, None);
+        assert_eq!(waiting.status.state, VmState::Pending);
+        assert_eq!(
+            waiting.status.message.as_deref(),
+            Some("no nodes are registered")
+        );
+
+        storage.create(&node("n1", 4, 4096)).await.unwrap();
+        scheduler
+            .handle(Events::NodeAdded("n1".to_string()))
+            .await
+            .unwrap();
+        let placed = stored(&storage, &vm).await;
+        assert_eq!(placed.status.node.as_deref(), Some("n1"));
+        assert_eq!(placed.status.message, None);
+    }
+
+    #[tokio::test]
+    async fn scorers_spread_or_pack_the_same_cluster() {
+        let storage = Storage::new(MemoryBackend::new());
+        storage.create(&node("busy", 4, 4096)).await.unwrap();
+        storage.create(&node("idle", 4, 4096)).await.unwrap();
+        let mut running = vm("running", 2, 2048);
+        running.status.node = Some("busy".to_string());
+        storage.create(&running).await.unwrap();
+        let new = vm("new", 1, 512);
+
+        let spread = scheduler(&storage, serde_json::json!({}));
+        let decision = spread.place(&new).await.unwrap();
+        assert_eq!(decision.node.as_deref(), Some("idle"));
+
+        let pack = scheduler(
+            &storage,
+            serde_json::json!({"scheduler_scorer": "most_allocated"}),
+        );
+        let decision = pack.place(&new).await.unwrap();
+        assert_eq!(decision.node.as_deref(), Some("busy"));
+        // Both score every node the vm fits on, only the ranking differs
+        assert!(decision
+            .candidates
+            .iter()
+            .all(|candidate| candidate.score.is_some() && candidate.rejected.is_none()));
+
+        let busy = node("busy", 4, 4096);
+        let committed = busy.allocated(&[running]);
+        assert_eq!(
+            LeastAllocated.score(&busy, committed, &new),
+            Some(((1.0 - 0.75) + (1.0 - 0.625)) / 2.0)
+        );
+        assert_eq!(
+            MostAllocated.score(&busy, committed, &new),
+            Some((0.75 + 0.625) / 2.0)
+        );
+        assert_eq!(
+            MostAllocated.score(&busy, committed, &vm("big", 3, 512)),
+            None
+        );
+    }
+
+    #[tokio::test]
+    async fn unset_vpc_values_are_allocated() {
+        let storage = Storage::new(MemoryBackend::new());
+        let mut scheduler = scheduler(
+            &storage,
+            serde_json::json!({"multicast_range": "239.2.0.0/24"}),
+        );
+        let vpc = |name: &str, multicast_ip: Option<Ipv4Addr>| Vpc {
+            metadata: Metadata {
+                name: name.to_string(),
+                project: "default".to_string(),
+                ..Default::default()
+            },
+            spec: VpcSpec {
+                subnet: "10.0.0.0/24".parse().unwrap(),
+                multicast_ip,
+                vni: None,
+                nat_gateway: false,
+            },
+            status: Default::default(),
+        };
+        let supplied = Ipv4Addr::new(239, 9, 9, 9);
+        for vpc in &[vpc("unset", None), vpc("supplied", Some(supplied))] {
+            storage.create(vpc).await.unwrap();
+            scheduler
+                .handle(Events::VpcEvent(Event::New(vpc.clone())))
+                .await
+                .unwrap();
+        }
+
+        let unset: Vpc = storage.get("default/unset").await.unwrap().unwrap();
+        let ip = unset.spec.multicast_ip.unwrap();
+        assert_eq!(ip.octets()[..3], [239, 2, 0]);
+        let kept: Vpc = storage.get("default/supplied").await.unwrap().unwrap();
+        assert_eq!(kept.spec.multicast_ip, Some(supplied));
+        assert!(unset.spec.vni.is_some() && kept.spec.vni.is_some());
+        assert_ne!(unset.spec.vni, kept.spec.vni);
+    }
+
+    #[tokio::test]
+    async fn vms_fill_nodes_up_to_their_capacity() {
+        let storage = Storage::new(MemoryBackend::new());
+        let mut scheduler = scheduler(&storage, serde_json::json!({}));
+        let nodes = [
+            node("small", 2, 2048),
+            node("large", 8, 8192),
+            node("mid", 4, 4096),
+        ];
+        for node in &nodes {
+            storage.create(node).await.unwrap();
+        }
+
+        // 14 cpus of vms for the 14 cpus of the nodes
+        let mut vms = vec![];
+        for i in 0..7 {
+            let vm = vm(&format!("vm{}", i), 2, 1024);
+            storage.create(&vm).await.unwrap();
+            scheduler
+                .handle(Events::VmEvent(Event::New(vm.clone())))
+                .await
+                .unwrap();
+            vms.push(stored(&storage, &vm).await);
+        }
+        // The first goes where the most is left free
+        assert_eq!(vms[0].status.node.as_deref(), Some("large"));
+        assert!(vms.iter().all(|vm| vm.status.node.is_some()));
+        let placed: Vec<Vm> = storage.list().await.unwrap();
+        for node in &nodes {
+            let committed = node.allocated(&placed);
+            assert_eq!(
+                committed.cpus, node.cpu_count,
+                "node {} holds {:?}",
+                node.metadata.name, committed
+            );
+        }
+
+        // Nothing fits once every node is full
+        let extra = vm("extra", 1, 128);
+        storage.create(&extra).await.unwrap();
+        scheduler
+            .handle(Events::VmEvent(Event::New(extra.clone())))
+            .await
+            .unwrap();
+        let waiting = stored(&storage, &extra).await;
+        assert_eq!(waiting.status.node, None);
+        assert_eq!(waiting.status.state, VmState::Pending);
+        assert_eq!(
+            waiting.status.message.as_deref(),
+            Some("insufficient cpus on all nodes")
+        );
+        let decision = waiting.status.scheduling.unwrap();
+        assert_eq!(decision.candidates.len(), 3);
+        assert!(decision
+            .candidates
+            .iter()
+            .all(|candidate| candidate.score.is_none()));
+    }
+
+    #[tokio::test]
+    async fn node_selectors_and_anti_affinity_limit_the_candidates() {
+        let storage = Storage::new(MemoryBackend::new());
+        let scheduler = scheduler(&storage, serde_json::json!({}));
+        storage.create(&node("a", 4, 4096)).await.unwrap();
+        storage.create(&node("b", 4, 4096)).await.unwrap();
+        let mut busy = vm("busy", 2, 1024);
+        busy.status.node = Some("b".to_string());
+        storage.create(&busy).await.unwrap();
+
+        // A selector wins over the score, which prefers the idle node
+        let mut pinned = vm("pinned", 1, 512);
+        pinned.spec.node_selector = Some("b".to_string());
+        let decision = scheduler.place(&pinned).await.unwrap();
+        assert_eq!(decision.node.as_deref(), Some("b"));
+        let skipped = &decision.candidates[0];
+        assert_eq!(skipped.node, "a");
+        assert_eq!(skipped.score, None);
+        assert_eq!(skipped.rejected.as_deref(), Some("node_selector is b"));
+
+        pinned.spec.cpus = 4;
+        let decision = scheduler.place(&pinned).await.unwrap();
+        assert_eq!(decision.node, None);
+        assert_eq!(
+            decision.reason,
+            "selected node b: insufficient cpus: 2 free, 4 requested"
+        );
+        pinned.spec.node_selector = Some("c".to_string());
+        let decision = scheduler.place(&pinned).await.unwrap();
+        assert_eq!(decision.reason, "selected node c isn't registered");
+
+        // Replicas of a group spread over the nodes, and wait once each node has one
+        let mut placed = vec![];
+        for name in &["web1", "web2", "web3"] {
+            let mut replica = vm(name, 1, 512);
+            replica.spec.anti_affinity_group = Some("web".to_string());
+            let decision = scheduler.place(&replica).await.unwrap();
+            replica.status.node = decision.node.clone();
+            storage.create(&replica).await.unwrap();
+            placed.push(decision);
+        }
+        assert_eq!(placed[0].node.as_deref(), Some("a"));
+        assert_eq!(placed[1].node.as_deref(), Some("b"));
+        assert_eq!(
+            placed[1].candidates[0].rejected.as_deref(),
+            Some("runs vm web1 of anti_affinity_group web")
+        );
+        assert_eq!(placed[2].node, None);
+        assert_eq!(placed[2].reason, "no node has room for the vm");
+        // Vms outside the group don't count
+        assert_eq!(
+            scheduler
+                .place(&vm("other", 1, 512))
+                .await
+                .unwrap()
+                .node
+                .as_deref(),
+            Some("a")
+        );
+    }
+
+    #[tokio::test]
+    async fn vms_leave_a_node_whose_key_expires() {
+        let backend = MemoryBackend::new();
+        let storage = Storage::new(backend.clone());
+        let mut expiring = node("n1", 4, 4096);
+        let lease = storage.grant_lease(15).await.unwrap();
+        expiring.metadata.lease = Some(lease);
+        storage.create(&expiring).await.unwrap();
+        let mut stranded = vm("a", 1, 512);
+        stranded.status.node = Some("n1".to_string());
+        stranded.status.transition(VmState::PoweredOff, 0).unwrap();
+        stranded.status.transition(VmState::PoweredOn, 0).unwrap();
+        storage.create(&stranded).await.unwrap();
+
+        let mut events = storage.watch_one::<Vm>("default/a").await.unwrap();
+        let (handle, _) = scheduler(&storage, serde_json::json!({})).spawn();
+        let _watcher = crate::actors::NodeWatcher::new(storage.clone(), handle).spawn();
+        while backend.watches() < 2 {
+            tokio::task::yield_now().await;
+        }
+
+        backend.expire_lease(lease);
+        let moved = match tokio::time::timeout(Duration::from_secs(5), events.next()).await {
+            Ok(Some(Event::Update { new, .. })) => new,
+            event => panic!("expected the vm to be unscheduled, got {:?}", event),
+        };
+        assert_eq!(moved.status.node, None);
+        assert_eq!(moved.status.state, VmState::PoweredOff);
+        assert!(moved.status.last_transition_time > Some(0));
+        assert_eq!(
+            moved.status.message.as_deref(),
+            Some("node n1 stopped heartbeating")
+        );
+        assert!(storage.get::<Node>("n1").await.unwrap().is_none());
+    }
 }
@@ -1,149 +1,1324 @@
 use super::HandleExt;
 use crate::vmm::{
-    CmdlineConfig, ConsoleConfig, ConsoleOutputMode, CpusConfig, DiskConfig, KernelConfig,
-    MemoryConfig, NetConfig, RngConfig, VmConfig,
+    CmdlineConfig, ConsoleConfig, ConsoleOutputMode, CpusConfig, DiskConfig, FsConfig,
+    InitramfsConfig, KernelConfig, MacAddr, MemoryConfig, NetConfig, RngConfig, VmConfig,
 };
 use crate::{
+    allocator::Allocator,
+    clock::SharedClock,
+    config::{Config, SharedConfig},
+    console_log::{self, RotatingLog},
     storage::{Event, Storage},
-    types::{Error, Vm, VmState},
+    types::{CloudInitStatus, ConsoleMode, DiskSpec, Error, Object, Vm, VmState, Vpc},
 };
 use hyper::Body;
 use hyperlocal::{UnixClientExt, Uri};
+use parking_lot::{Mutex, RwLock};
 use rand::{distributions::Alphanumeric, Rng};
 use rtnetlink::Handle as NetLinkHandle;
-use std::{collections::HashMap, ffi::OsStr, path::PathBuf, process::Stdio, time::Duration};
-use tokio::{io::AsyncWriteExt, process::Command};
+use std::{
+    collections::HashMap,
+    ffi::OsStr,
+    os::unix::{fs::FileTypeExt, process::ExitStatusExt},
+    path::{Path, PathBuf},
+    process::{ExitStatus, Stdio},
+    sync::Arc,
+    time::Duration,
+};
+use tokio::{io::AsyncWriteExt, process::Command, sync::watch, task::JoinHandle};
+
+use super::{Actor, Handle, Pending};
+
+/// How often the supervisor checks for hypervisors that have exited.
+const HEALTH_CHECK_INTERVAL: Duration = Duration::from_secs(5);
+
+/// The API sockets of the cloud-hypervisor processes running on this node, by VM key name,
+/// shared with the API so it can query running VMs.
+#[derive(Clone, Default)]
+pub struct HypervisorSockets(Arc<RwLock<HashMap<String, String>>>);
+
+impl HypervisorSockets {
+    pub fn get(&self, vm: &str) -> Option<String> {
+        self.0.read().get(vm).cloned()
+    }
+
+    fn insert(&self, vm: String, socket_path: String) {
+        self.0.write().insert(vm, socket_path);
+    }
+
+    fn remove(&self, vm: &str) {
+        self.0.write().remove(vm);
+    }
+}
+
+/// How long a new hypervisor has to start serving its API.
+const HYPERVISOR_START_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// How many times a GET to cloud-hypervisor's API is retried after failing or timing out.
+/// Other requests change the VM, so they aren't retried.
+const HYPERVISOR_GET_RETRIES: u32 = 2;
+
+/// Fetches cloud-hypervisor's view of a running VM: its live config, state and devices.
+pub async fn vm_info(socket_path: &str, timeout: Duration) -> Result<serde_json::Value, Error> {
+    let (status, body) = hypervisor_request(
+        socket_path,
+        hyper::Method::GET,
+        "/api/v1/vm.info",
+        String::new(),
+        timeout,
+    )
+    .await?;
+    check_response("/api/v1/vm.info", status, &body)?;
+    Ok(serde_json::from_slice(&body)?)
+}
+
+/// Snapshots a running VM into `destination`, a directory that must exist. cloud-hypervisor
+/// only snapshots paused VMs, so the VM is paused for the duration and resumed after, whether
+/// or not the snapshot succeeded.
+pub async fn vm_snapshot(
+    socket_path: &str,
+    destination: &Path,
+    timeout: Duration,
+) -> Result<(), Error> {
+    hypervisor_put(socket_path, "/api/v1/vm.pause", String::new(), timeout).await?;
+    let body = serde_json::json!({
+        "destination_url": format!("file://{}", destination.display()),
+    });
+    let snapshot = hypervisor_put(
+        socket_path,
+        "/api/v1/vm.snapshot",
+        body.to_string(),
+        timeout,
+    )
+    .await;
+    hypervisor_put(socket_path, "/api/v1/vm.resume", String::new(), timeout).await?;
+    snapshot
+}
+
+/// Makes a request to cloud-hypervisor's API, turning error responses into errors.
+async fn hypervisor_put(
+    socket_path: &str,
+    path: &str,
+    body: String,
+    timeout: Duration,
+) -> Result<(), Error> {
+    let (status, body) =
+        hypervisor_request(socket_path, hyper::Method::PUT, path, body, timeout).await?;
+    check_response(path, status, &body)
+}
+
+/// Turns a non-2xx response into [`Error::Hypervisor`], carrying cloud-hypervisor's message.
+fn check_response(path: &str, status: hyper::StatusCode, body: &[u8]) -> Result<(), Error> {
+    if status.is_success() {
+        return Ok(());
+    }
+    Err(Error::Hypervisor(format!(
+        "{} failed with {}: {}",
+        path,
+        status,
+        String::from_utf8_lossy(body)
+    )))
+}
 
-use super::Actor;
+/// Makes a request to cloud-hypervisor's API, failing with [`Error::Timeout`] if the response
+/// doesn't arrive within `timeout`, so a wedged hypervisor can't hold up its caller for good.
+async fn hypervisor_request(
+    socket_path: &str,
+    method: hyper::Method,
+    path: &str,
+    body: String,
+    timeout: Duration,
+) -> Result<(hyper::StatusCode, hyper::body::Bytes), Error> {
+    let retries = if method == hyper::Method::GET {
+        HYPERVISOR_GET_RETRIES
+    } else {
+        0
+    };
+    let client = hyper::Client::unix();
+    let mut attempt = 0;
+    loop {
+        let request = hyper::Request::builder()
+            .method(method.clone())
+            .uri(Uri::new(socket_path, path))
+            .body(Body::from(body.clone()))?;
+        let result = tokio::time::timeout(timeout, async {
+            let resp = client.request(request).await?;
+            let status = resp.status();
+            let body = hyper::body::to_bytes(resp.into_body()).await?;
+            Ok::<_, Error>((status, body))
+        })
+        .await
+        .unwrap_or_else(|_| {
+            Err(Error::Timeout(format!(
+                "{} {} after {:?}",
+                method, path, timeout
+            )))
+        });
+        match result {
+            Err(err) if attempt < retries => {
+                attempt += 1;
+                println!("retrying {} {}: {}", method, path, err);
+            }
+            result => return result,
+        }
+    }
+}
 
+/// Whether the hypervisor serving `socket_path` is alive and running `vm`'s guest, booted with
+/// the cpus and memory it's specified with now.
+async fn reconnectable(vm: &Vm, socket_path: &str, timeout: Duration) -> bool {
+    // hyperlocal has been seen to panic on sockets nothing listens on
+    if tokio::net::UnixStream::connect(socket_path).await.is_err() {
+        return false;
+    }
+    let ping = hypervisor_request(
+        socket_path,
+        hyper::Method::GET,
+        "/api/v1/vmm.ping",
+        String::new(),
+        timeout,
+    )
+    .await;
+    if !matches!(ping, Ok((status, _)) if status.is_success()) {
+        return false;
+    }
+    let info = match vm_info(socket_path, timeout).await {
+        Ok(info) => info,
+        Err(_) => return false,
+    };
+    matches!(info["state"].as_str(), Some("Running") | Some("Paused"))
+        && info["config"]["cpus"]["boot_vcpus"].as_u64() == Some(vm.spec.cpus as u64)
+        && info["config"]["memory"]["size"].as_u64() == Some(vm.spec.memory_bytes())
+}
+
+/// Waits for a freshly spawned hypervisor to accept connections on its API socket, backing off
+/// between attempts. Requests sent before then fail, and `hyperlocal` has been seen to panic on
+/// a socket that doesn't exist yet.
+async fn wait_for_api(child: &mut tokio::process::Child, socket_path: &str) -> Result<(), Error> {
+    let deadline = tokio::time::Instant::now() + HYPERVISOR_START_TIMEOUT;
+    let mut delay = Duration::from_millis(10);
+    loop {
+        if tokio::net::UnixStream::connect(socket_path).await.is_ok() {
+            return Ok(());
+        }
+        if let Some(status) = child.try_wait()? {
+            return Err(Error::IO(std::io::Error::new(
+                std::io::ErrorKind::Other,
+                format!("hypervisor exited before serving its api: {}", status),
+            )));
+        }
+        if tokio::time::Instant::now() >= deadline {
+            return Err(Error::IO(std::io::Error::new(
+                std::io::ErrorKind::TimedOut,
+                format!(
+                    "hypervisor api at {} not ready after {:?}",
+                    socket_path, HYPERVISOR_START_TIMEOUT
+                ),
+            )));
+        }
+        tokio::time::sleep(delay).await;
+        delay = (delay * 2).min(Duration::from_millis(500));
+    }
+}
+
+/// A cloud-hypervisor or virtiofsd process running for a VM without the supervisor tracking
+/// it, as when the node restarted without stopping its guests.
+struct Orphan {
+    pid: u32,
+    /// The API socket of a hypervisor, `None` for virtiofsd.
+    socket_path: Option<String>,
+}
+
+/// Kills any cloud-hypervisor or virtiofsd process left running for the VM with host name `vm`
+/// without the supervisor tracking it, returning their pids.
+pub async fn kill_orphans(vm: &str) -> Result<Vec<u32>, Error> {
+    let mut killed = vec![];
+    for orphan in find_orphans(vm).await? {
+        println!("killing orphaned process {} of vm {}", orphan.pid, vm);
+        if kill(orphan.pid).await? {
+            killed.push(orphan.pid);
+        }
+    }
+    Ok(killed)
+}
+
+/// Sends SIGKILL to `pid`, returning whether it was delivered.
+async fn kill(pid: u32) -> Result<bool, Error> {
+    let status = Command::new("kill")
+        .args(&["-9", &pid.to_string()])
+        .status()
+        .await?;
+    Ok(status.success())
+}
+
+/// Finds the cloud-hypervisor and virtiofsd processes running for the VM with host name `vm`, by
+/// the socket paths the supervisor gives each process.
+async fn find_orphans(vm: &str) -> Result<Vec<Orphan>, Error> {
+    let hypervisor_prefix = format!("path=/tmp/{}-", vm);
+    let virtiofsd_socket = format!("--socket-path=/tmp/searu-virtiofsd-{}.sock", vm);
+    let mut orphans = vec![];
+    let mut entries = tokio::fs::read_dir("/proc").await?;
+    while let Some(entry) = entries.next_entry().await? {
+        let pid: u32 = match entry.file_name().to_str().and_then(|pid| pid.parse().ok()) {
+            Some(pid) => pid,
+            None => continue,
+        };
+        // The process may have exited since the directory was read
+        let cmdline = match tokio::fs::read(entry.path().join("cmdline")).await {
+            Ok(cmdline) => cmdline,
+            Err(_) => continue,
+        };
+        let orphan = cmdline
+            .split(|b| *b == 0)
+            .filter_map(|arg| std::str::from_utf8(arg).ok())
+            .find_map(|arg| {
+                if arg == virtiofsd_socket {
+                    return Some(Orphan {
+                        pid,
+                        socket_path: None,
+                    });
+                }
+                let socket_path = arg.strip_prefix("path=")?;
+                let random = arg
+                    .strip_prefix(&hypervisor_prefix)?
+                    .strip_suffix(".sock")?;
+                (random.len() == 30 && random.chars().all(|c| c.is_ascii_alphanumeric())).then(
+                    || Orphan {
+                        pid,
+                        socket_path: Some(socket_path.to_string()),
+                    },
+                )
+            });
+        orphans.extend(orphan);
+    }
+    Ok(orphans)
+}
+
+/// A VM's instance on this node, locked while the VM is worked on, so VMs are handled side by
+/// side while the work on any one of them stays in order. `None` while the VM isn't running here.
+type VmSlot = Arc<tokio::sync::Mutex<Option<VmInstance>>>;
+
+/// Runs the VMs scheduled onto this node. Each event is handled by a task of its own, so a slow
+/// boot holds up neither the mailbox nor the other VMs.
+#[derive(Clone)]
 pub struct VmSupervisor {
     storage: Storage,
     node_name: String,
-    vms: HashMap<String, VmInstance>,
+    /// Claims on this node's block devices, held by the VMs they're attached to.
+    block_devices: Arc<Allocator>,
+    /// The VMs on this node, by key name.
+    vms: Arc<Mutex<HashMap<String, VmSlot>>>,
+    sockets: HypervisorSockets,
     netlink_handle: NetLinkHandle,
+    config: SharedConfig,
+    clock: SharedClock,
 }
 
 impl VmSupervisor {
-    pub fn new(storage: Storage, handle: NetLinkHandle) -> Result<Self, Error> {
+    pub fn new(
+        storage: Storage,
+        handle: NetLinkHandle,
+        config: SharedConfig,
+        sockets: HypervisorSockets,
+        clock: SharedClock,
+    ) -> Result<Self, Error> {
+        let node_name = sys_info::hostname()?;
         Ok(Self {
+            block_devices: Arc::new(Allocator::new(
+                storage.clone(),
+                &format!("block_device/{}", node_name),
+            )),
             storage,
-            node_name: sys_info::hostname()?,
-            vms: HashMap::default(),
+            node_name,
+            vms: Arc::default(),
+            sockets,
             netlink_handle: handle,
+            config,
+            clock,
         })
     }
 }
 
+#[derive(Debug)]
+pub enum VmMessage {
+    Event(Event<Vm>),
+    /// Looks for hypervisors that exited on their own and applies the VM's restart policy, and
+    /// stores any new cloud-init reports from the guests.
+    HealthCheck,
+}
+
+/// Sends the supervisor a [`VmMessage::HealthCheck`] every [`HEALTH_CHECK_INTERVAL`]. A check is
+/// skipped while the supervisor's mailbox is full, rather than piling up behind the backlog.
+pub fn spawn_health_check(
+    supervisor: Handle<VmSupervisor>,
+) -> JoinHandle<Result<(), anyhow::Error>> {
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(HEALTH_CHECK_INTERVAL);
+        loop {
+            interval.tick().await;
+            match supervisor.try_send(VmMessage::HealthCheck).await {
+                Ok(_) => {}
+                Err(Error::ActorBusy) => println!("vm supervisor is busy, skipping health check"),
+                Err(err) => println!("error: {:?}", err),
+            }
+        }
+    })
+}
+
 #[async_trait::async_trait]
 impl Actor for VmSupervisor {
-    type Message = Event<Vm>;
+    type Message = VmMessage;
+
+    /// The task handling an event, which the watcher's queue waits for before sending the
+    /// VM's next event.
+    type Response = Pending;
 
-    type Response = ();
+    /// Events are handed off at once, so the mailbox only backs up behind adopting the node's
+    /// VMs on start. Keep the queue behind that short and have senders wait instead.
+    const MAILBOX_CAPACITY: usize = 16;
 
     async fn handle(
         &mut self,
         message: Self::Message,
     ) -> Result<Self::Response, crate::types::Error> {
-        println!("{:?}", message);
         match message {
-            Event::New(mut vm) => {
-                if Some(&self.node_name) == vm.status.node.as_ref()
-                    && !self.vms.contains_key(&vm.metadata.name)
-                {
-                    let name = vm.metadata.name.clone();
-                    let inst = VmInstance::new(&vm).await?;
-                    self.vms.insert(name, inst);
-                    let inst = self.vms.get_mut(&vm.metadata.name).unwrap();
-                    vm.status.state = VmState::PoweredOff;
-                    self.storage.store(&vm).await?;
-                    inst.boot().await?;
-                    vm.status.state = VmState::PoweredOn;
-                    self.storage.store(&vm).await?;
-                    let tap = self
-                        .netlink_handle
-                        .get_link_by_name(format!("ich{}", vm.metadata.name))
-                        .await?;
-                    let vpc = self
-                        .netlink_handle
-                        .get_link_by_name(format!("b{}", vm.spec.vpc))
-                        .await?;
-                    self.netlink_handle
-                        .link()
-                        .set(tap.header.index)
-                        .master(vpc.header.index)
-                        .execute()
-                        .await?;
+            VmMessage::Event(event) => {
+                let supervisor = self.clone();
+                Ok(Pending::spawn(async move {
+                    supervisor.handle_event(event).await
+                }))
+            }
+            VmMessage::HealthCheck => {
+                self.check_health().await?;
+                Ok(Pending::done())
+            }
+        }
+    }
+
+    /// Powers off every guest on the node at once, rather than leaving them to be killed with
+    /// the node. Their overlays are kept and their stored state is left as is, so they boot
+    /// again when the node comes back. VMs being worked on are waited for first.
+    async fn shutdown(&mut self) -> Result<(), Error> {
+        let timeout = self.stop_timeout();
+        let sockets = &self.sockets;
+        let slots: Vec<(String, VmSlot)> = self.vms.lock().drain().collect();
+        let stops = slots.into_iter().map(|(name, slot)| async move {
+            let mut inst = match slot.lock().await.take() {
+                Some(inst) => inst,
+                None => return Ok(()),
+            };
+            sockets.remove(&name);
+            println!("shutting down vm {}", name);
+            let result = inst.terminate(timeout).await;
+            inst.stop_virtiofsd().await;
+            if let Err(ref err) = result {
+                println!("error shutting down vm {}: {}", name, err);
+            }
+            result
+        });
+        let results = futures::future::join_all(stops).await;
+        Error::collect(results.into_iter().filter_map(Result::err).collect())
+    }
+
+    /// Adopts the VMs scheduled onto this node. Each VM is started on its own, so one that
+    /// fails is recorded in its status and left to the watcher's retries without holding up
+    /// the rest.
+    async fn init(&mut self) -> Result<(), Error> {
+        // Dying here would take every VM on the node down with the supervisor, so wait out
+        // etcd instead
+        let mut failures = 0;
+        let vms: Vec<Vm> = loop {
+            match self.storage.list().await {
+                Ok(vms) => break vms,
+                Err(err) => {
+                    failures += 1;
+                    let delay = super::backoff(failures);
+                    println!("error listing vms: {:?}, retrying in {:?}", err, delay);
+                    tokio::time::sleep(delay).await;
+                }
+            }
+        };
+        let (mut adopted, mut reconnected, mut failed) = (0, 0, 0);
+        for vm in vms {
+            if vm.status.node.as_ref() != Some(&self.node_name) {
+                continue;
+            }
+            let name = vm.key_name();
+            if vm.status.state != VmState::Failed {
+                let slot = self.slot(&name);
+                let reconnect = self.reconnect(&vm, &mut *slot.lock().await).await;
+                match reconnect {
+                    Ok(true) => {
+                        adopted += 1;
+                        reconnected += 1;
+                        continue;
+                    }
+                    Ok(false) => {}
+                    Err(err) => println!("error reconnecting to vm {}: {:?}", name, err),
+                }
+            }
+            match self.handle_event(Event::New(vm)).await {
+                Ok(()) => adopted += 1,
+                Err(err) => {
+                    failed += 1;
+                    println!("error adopting vm {}: {:?}", name, err);
+                }
+            }
+        }
+        println!(
+            "adopted {} vms, {} still running, {} failed",
+            adopted, reconnected, failed
+        );
+        Ok(())
+    }
+}
+
+impl VmSupervisor {
+    /// The slot of the VM `name`, empty if it isn't running here.
+    fn slot(&self, name: &str) -> VmSlot {
+        self.vms.lock().entry(name.to_string()).or_default().clone()
+    }
+
+    /// Handles an event with the VM's slot locked, and drops the slot if the VM isn't left
+    /// running here.
+    async fn handle_event(&self, event: Event<Vm>) -> Result<(), Error> {
+        let name = event.key_name();
+        let slot = self.slot(&name);
+        let mut inst = slot.lock().await;
+        let result = self.apply_event(event, &mut inst).await;
+        if inst.is_none() {
+            let mut vms = self.vms.lock();
+            if vms
+                .get(&name)
+                .map_or(false, |other| Arc::ptr_eq(other, &slot))
+            {
+                vms.remove(&name);
+            }
+        }
+        result
+    }
+
+    async fn apply_event(
+        &self,
+        event: Event<Vm>,
+        inst: &mut Option<VmInstance>,
+    ) -> Result<(), Error> {
+        println!("{:?}", event);
+        match event {
+            Event::Update { new: vm, old } if needs_restart(&vm, &old) => {
+                if let Some(mut running) = inst.take() {
+                    self.sockets.remove(&vm.key_name());
+                    running.terminate(self.stop_timeout()).await?;
+                    running.stop_virtiofsd().await;
                 }
+                self.reconcile(vm, inst).await?;
             }
+            Event::Update { new: vm, old }
+                if vm.spec.powered_on != old.spec.powered_on && inst.is_some() =>
+            {
+                if let Some(running) = inst.as_ref() {
+                    self.set_power(vm, running).await?;
+                }
+            }
+            Event::New(vm) | Event::Update { new: vm, .. } => self.reconcile(vm, inst).await?,
             Event::Delete(vm) => {
                 println!("deleting vm: {:?}", vm);
-                let inst = self
-                    .vms
-                    .remove(&vm)
+                // A VM that never started may still hold block devices
+                self.block_devices.release(&vm).await?;
+                let stopped = self
+                    .stop(&vm, inst)
+                    .await?
                     .ok_or_else(|| Error::NotFound(format!("vm: {}", vm)))?;
-                println!("shutting down vm");
+                let config = self.config.load_full();
+                if config.purge_console_logs {
+                    console_log::purge(&stopped.console_log, config.console_log_files).await?;
+                    match tokio::fs::remove_dir_all(&stopped.console_files).await {
+                        Err(err) if err.kind() != std::io::ErrorKind::NotFound => {
+                            return Err(err.into())
+                        }
+                        _ => {}
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Starts a VM scheduled onto this node that isn't running here, and stops one that has
+    /// moved off it.
+    async fn reconcile(&self, vm: Vm, inst: &mut Option<VmInstance>) -> Result<(), Error> {
+        let here = Some(&self.node_name) == vm.status.node.as_ref();
+        let name = vm.key_name();
+        if !here && inst.is_some() {
+            // Moved off this node, e.g. by an evacuation
+            println!("vm {} moved off this node", name);
+            self.block_devices.release(&name).await?;
+            self.stop(&name, inst).await?;
+        } else if here
+            && inst.is_none()
+            // A failed VM stays down until it's recreated
+            && vm.status.state != VmState::Failed
+        {
+            self.start_recorded(vm, inst).await?;
+        }
+        Ok(())
+    }
+
+    /// Shuts a VM's hypervisor down and removes its overlay, returning the instance if the VM
+    /// was running here.
+    async fn stop(
+        &self,
+        name: &str,
+        inst: &mut Option<VmInstance>,
+    ) -> Result<Option<VmInstance>, Error> {
+        let mut stopped = match inst.take() {
+            Some(inst) => inst,
+            None => return Ok(None),
+        };
+        self.sockets.remove(name);
+        println!("shutting down vm");
+        stopped.terminate(self.stop_timeout()).await?;
+        stopped.stop_virtiofsd().await;
+        stopped.remove_overlay().await?;
+        Ok(Some(stopped))
+    }
+
+    fn stop_timeout(&self) -> Duration {
+        Duration::from_secs(self.config.load().vm_stop_timeout)
+    }
+
+    /// Boots or shuts down a running VM's guest to match `spec.powered_on`, leaving the
+    /// hypervisor up so it can be booted again. VMs already in the wanted state are left alone.
+    async fn set_power(&self, mut vm: Vm, inst: &VmInstance) -> Result<(), Error> {
+        let now = self.clock.now().timestamp();
+        match (vm.spec.powered_on, &vm.status.state) {
+            (true, VmState::PoweredOff) => {
+                inst.boot().await?;
+                vm.status.transition(VmState::PoweredOn, now)?;
+                // The ptys are only allocated once the VM boots
+                match vm_info(&inst.socket_path, inst.timeout).await {
+                    Ok(info) => {
+                        vm.status.console_pty = allocated_pty(&info, "console");
+                        vm.status.serial_pty = allocated_pty(&info, "serial");
+                    }
+                    Err(err) => {
+                        println!("failed to read ptys of vm {}: {}", vm.key_name(), err)
+                    }
+                }
+                self.storage.store(&vm).await?;
+                // And so is the tap
+                self.attach_tap(&vm).await
+            }
+            (false, VmState::PoweredOn) | (false, VmState::Paused) => {
                 inst.shutdown().await?;
+                vm.status.transition(VmState::PoweredOff, now)?;
+                vm.status.console_pty = None;
+                vm.status.serial_pty = None;
+                self.storage.store(&vm).await
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Takes over a VM whose hypervisor outlived the node's last run, instead of booting it a
+    /// second time on the same overlay. Only a hypervisor that answers on its socket with the
+    /// guest booted in the VM's current shape is kept; any other process left for the VM is
+    /// killed, so a fresh one can start. Returns whether the VM was taken over.
+    ///
+    /// The console of a VM taken over isn't logged, as it was piped to the node's last run, so
+    /// cloud-init reports stop until the VM restarts.
+    async fn reconnect(&self, vm: &Vm, inst: &mut Option<VmInstance>) -> Result<bool, Error> {
+        let name = vm.key_name();
+        let host_name = vm.metadata.host_name();
+        let orphans = find_orphans(&host_name).await?;
+        if orphans.is_empty() {
+            return Ok(false);
+        }
+        let config = self.config.load_full();
+        let timeout = Duration::from_secs(config.hypervisor_timeout);
+        let (mut hypervisor, mut virtiofsd, mut stale) = (None, None, vec![]);
+        for orphan in orphans {
+            match orphan.socket_path {
+                None if virtiofsd.is_none() => virtiofsd = Some(orphan.pid),
+                Some(socket_path)
+                    if hypervisor.is_none() && reconnectable(vm, &socket_path, timeout).await =>
+                {
+                    hypervisor = Some((orphan.pid, socket_path))
+                }
+                _ => stale.push(orphan.pid),
+            }
+        }
+        if hypervisor.is_none() {
+            stale.extend(virtiofsd.take());
+        }
+        for pid in stale {
+            println!("killing stale process {} of vm {}", pid, name);
+            kill(pid).await?;
+        }
+        let (pid, socket_path) = match hypervisor {
+            Some(hypervisor) => hypervisor,
+            None => return Ok(false),
+        };
+        println!("reconnected to hypervisor {} of vm {}", pid, name);
+        let (_, cloud_init) = watch::channel(None);
+        self.sockets.insert(name, socket_path.clone());
+        *inst = Some(VmInstance {
+            child: Process::Adopted(pid),
+            exit: None,
+            cloud_init,
+            reported_cloud_init: vm.status.cloud_init_status,
+            virtiofsd: virtiofsd.map(Process::Adopted),
+            _seed: None,
+            timeout,
+            socket_path,
+            overlay: config.overlay_path(&host_name),
+            console_log: config.console_log_path(&host_name),
+            console_files: config.console_file_dir(&host_name),
+        });
+        Ok(true)
+    }
+
+    /// Starts a VM and records the outcome in its status.
+    async fn start_recorded(&self, vm: Vm, inst: &mut Option<VmInstance>) -> Result<(), Error> {
+        let name = vm.key_name();
+        if let Err(err) = self.start(vm, inst).await {
+            // Dropping the instance kills its hypervisor, so the retry starts clean
+            *inst = None;
+            self.sockets.remove(&name);
+            self.record_start(&name, Some(&err)).await?;
+            return Err(err);
+        }
+        self.record_start(&name, None).await
+    }
+
+    /// Checks every VM that isn't being worked on; the others are checked next time. Exits are
+    /// handled by tasks of their own, as a restart boots the VM again.
+    async fn check_health(&self) -> Result<(), Error> {
+        let slots: Vec<(String, VmSlot)> = self
+            .vms
+            .lock()
+            .iter()
+            .map(|(name, slot)| (name.clone(), slot.clone()))
+            .collect();
+        for (name, slot) in slots {
+            let mut inst = match slot.try_lock_owned() {
+                Ok(inst) => inst,
+                Err(_) => continue,
+            };
+            let running = match inst.as_mut() {
+                Some(running) => running,
+                None => continue,
+            };
+            if running.exit.is_none() {
+                if let Some(status) = running.child.try_wait()? {
+                    running.exit = Some(status);
+                    let supervisor = self.clone();
+                    tokio::spawn(async move {
+                        if let Err(err) = supervisor.handle_exit(&name, status, &mut inst).await {
+                            println!("error handling exit of vm {}: {:?}", name, err);
+                        }
+                    });
+                    continue;
+                }
+            }
+            let status = *running.cloud_init.borrow();
+            if let Some(status) =
+                status.filter(|status| Some(*status) != running.reported_cloud_init)
+            {
+                if let Err(err) = self.record_cloud_init(&name, status, running).await {
+                    println!("error recording cloud-init of vm {}: {:?}", name, err);
+                }
             }
-            Event::Update { .. } => {}
         }
         Ok(())
     }
 
-    async fn init(&mut self) -> Result<(), Error> {
-        let vms: Vec<Vm> = self.storage.list().await?;
-        for vm in vms {
-            self.handle(Event::New(vm)).await?;
+    /// Stores the guest's cloud-init report, retried on the next check if it fails.
+    async fn record_cloud_init(
+        &self,
+        name: &str,
+        status: CloudInitStatus,
+        inst: &mut VmInstance,
+    ) -> Result<(), Error> {
+        if let Some(mut vm) = self.storage.get::<Vm>(name).await? {
+            vm.status.cloud_init_status = Some(status);
+            self.storage.store(&vm).await?;
         }
+        inst.reported_cloud_init = Some(status);
         Ok(())
     }
+
+    /// Restarts a VM whose hypervisor exited if its restart policy says to, and marks it
+    /// `Failed` otherwise. A failed VM's instance stays around, so deleting the VM still cleans
+    /// up its overlay.
+    async fn handle_exit(
+        &self,
+        name: &str,
+        status: ExitStatus,
+        inst: &mut Option<VmInstance>,
+    ) -> Result<(), Error> {
+        println!("hypervisor of vm {} exited: {}", name, status);
+        self.sockets.remove(name);
+        if let Some(running) = inst.as_mut() {
+            running.stop_virtiofsd().await;
+        }
+        let mut vm: Vm = match self.storage.get(name).await? {
+            Some(vm) => vm,
+            None => return Ok(()),
+        };
+        let now = self.clock.now().timestamp();
+        if vm.spec.restart_policy.restarts(status.success()) {
+            *inst = None;
+            vm.status.restarts += 1;
+            vm.status.last_restart_time = Some(now);
+            // Starting stores the VM, counters included
+            self.start_recorded(vm, inst).await
+        } else {
+            vm.status.transition(VmState::Failed, now)?;
+            vm.status.message = Some(format!("hypervisor exited: {}", status));
+            vm.status.console_pty = None;
+            vm.status.serial_pty = None;
+            self.storage.store(&vm).await
+        }
+    }
+
+    /// Creates the VM's hypervisor and records it `PoweredOff`, then boots the guest if
+    /// `spec.powered_on` is set.
+    async fn start(&self, mut vm: Vm, inst: &mut Option<VmInstance>) -> Result<(), Error> {
+        let name = vm.key_name();
+        self.bridge(&vm).await?;
+        self.claim_block_devices(&vm).await?;
+        let config = self.config.load_full();
+        let started = VmInstance::new(&vm, &config).await?;
+        self.sockets
+            .insert(name.clone(), started.socket_path.clone());
+        *inst = Some(started);
+        let inst = inst.as_mut().unwrap();
+        vm.status
+            .transition(VmState::PoweredOff, self.clock.now().timestamp())?;
+        self.storage.store(&vm).await?;
+        if !vm.spec.powered_on {
+            return Ok(());
+        }
+        // Read back what was just stored, so the next store's version check passes
+        let vm: Vm = self
+            .storage
+            .get(&name)
+            .await?
+            .ok_or_else(|| Error::NotFound(format!("vm: {}", name)))?;
+        self.set_power(vm, inst).await
+    }
+
+    /// The bridge of the VM's VPC, failing with [`Error::NotReady`] until the VPC supervisor has
+    /// created it.
+    async fn bridge(&self, vm: &Vm) -> Result<String, Error> {
+        let bridge = match self.storage.get::<Vpc>(&vm.vpc_key_name()).await? {
+            Some(vpc) => format!("b{}", vpc.metadata.host_name()),
+            None => return Err(Error::NotReady(format!("waiting for vpc {}", vm.spec.vpc))),
+        };
+        match self.netlink_handle.get_link_by_name(bridge.clone()).await {
+            Ok(_) => Ok(bridge),
+            Err(Error::NotFound(_)) => {
+                Err(Error::NotReady(format!("waiting for vpc {}", vm.spec.vpc)))
+            }
+            Err(err) => Err(err),
+        }
+    }
+
+    /// Attaches a booted VM's tap to its VPC's bridge.
+    async fn attach_tap(&self, vm: &Vm) -> Result<(), Error> {
+        let bridge = self.bridge(vm).await?;
+        let tap = self
+            .netlink_handle
+            .get_link_by_name(format!("ich{}", vm.metadata.host_name()))
+            .await?;
+        let vpc = self.netlink_handle.get_link_by_name(bridge).await?;
+        self.netlink_handle
+            .link()
+            .set(tap.header.index)
+            .master(vpc.header.index)
+            .execute()
+            .await?;
+        Ok(())
+    }
+
+    /// Claims the block devices a VM attaches, failing if one isn't in the node's
+    /// `block_devices`, isn't a block device or is attached to another VM.
+    async fn claim_block_devices(&self, vm: &Vm) -> Result<(), Error> {
+        let allowed = self.config.load().block_devices.clone();
+        for disk in &vm.spec.disks {
+            let path = block_device_path(disk.path(), &allowed)?;
+            let metadata = tokio::fs::metadata(&path).await?;
+            if !metadata.file_type().is_block_device() {
+                return Err(Error::Invalid(format!(
+                    "{} isn't a block device",
+                    path.display()
+                )));
+            }
+            if !self
+                .block_devices
+                .claim(&vm.key_name(), &path.display())
+                .await?
+            {
+                return Err(Error::Conflict(format!(
+                    "block device {} is attached to another vm",
+                    path.display()
+                )));
+            }
+        }
+        Ok(())
+    }
+
+    /// Records the outcome of starting a VM in its status, so VMs stuck failing or waiting on
+    /// their VPC are visible. The write also triggers the next attempt, which the watcher's
+    /// queue delays by [`super::backoff`].
+    async fn record_start(&self, name: &str, err: Option<&Error>) -> Result<(), Error> {
+        let mut vm: Vm = match self.storage.get(name).await? {
+            Some(vm) => vm,
+            None => return Ok(()),
+        };
+        match err {
+            // Waiting isn't a failure, so it only shows up in the message
+            Some(Error::NotReady(reason)) => vm.status.message = Some(reason.clone()),
+            Some(err) => {
+                vm.status.failures += 1;
+                vm.status.last_error = Some(err.to_string());
+            }
+            None if vm.status.failures == 0 && vm.status.message.is_none() => return Ok(()),
+            None => {
+                vm.status.failures = 0;
+                vm.status.last_error = None;
+                vm.status.message = None;
+            }
+        }
+        self.storage.store(&vm).await
+    }
+}
+
+/// Whether an update to a VM changes its shape. Nothing is booted with room to hotplug, so a
+/// new shape means a restart on the same disk.
+fn needs_restart(new: &Vm, old: &Vm) -> bool {
+    (new.spec.cpus, new.spec.memory) != (old.spec.cpus, old.spec.memory)
+}
+
+/// A process run for a VM: either spawned by the supervisor, or adopted from an earlier run of
+/// the node. Adopted processes aren't the node's children, so they can only be watched for by
+/// pid, and how they exited is unknown.
+enum Process {
+    Child(tokio::process::Child),
+    Adopted(u32),
+}
+
+/// How often an adopted process is checked while waiting for it to exit.
+const ADOPTED_POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+impl Process {
+    fn try_wait(&mut self) -> Result<Option<ExitStatus>, Error> {
+        match self {
+            Process::Child(child) => Ok(child.try_wait()?),
+            Process::Adopted(pid) => Ok((!Path::new(&format!("/proc/{}", pid)).exists())
+                // Reported as a failure, so restart policies treat it like a crash
+                .then(|| ExitStatus::from_raw(1 << 8))),
+        }
+    }
+
+    async fn wait(&mut self) -> Result<ExitStatus, Error> {
+        if let Process::Child(child) = self {
+            return Ok(child.wait().await?);
+        }
+        loop {
+            if let Some(status) = self.try_wait()? {
+                return Ok(status);
+            }
+            tokio::time::sleep(ADOPTED_POLL_INTERVAL).await;
+        }
+    }
+
+    async fn kill(&mut self) -> Result<(), Error> {
+        match self {
+            Process::Child(child) => Ok(child.kill().await?),
+            Process::Adopted(pid) => {
+                kill(*pid).await?;
+                Ok(())
+            }
+        }
+    }
 }
 
 struct VmInstance {
-    _child: tokio::process::Child,
-    client: hyper::Client<hyperlocal::UnixConnector, Body>,
+    child: Process,
+    /// How the hypervisor exited, once the supervisor has noticed.
+    exit: Option<ExitStatus>,
+    /// The guest's latest cloud-init report on its serial console.
+    cloud_init: watch::Receiver<Option<CloudInitStatus>>,
+    /// The cloud-init status last stored in the VM's status.
+    reported_cloud_init: Option<CloudInitStatus>,
+    virtiofsd: Option<Process>,
+    /// The cloud-init seed image, removed when the instance is dropped.
+    _seed: Option<tempfile::TempPath>,
+    /// How long requests to the hypervisor's API may take.
+    timeout: Duration,
     socket_path: String,
+    overlay: PathBuf,
+    console_log: PathBuf,
+    /// Where console and serial files are written, see [`Config::console_file_dir`].
+    console_files: PathBuf,
+}
+
+/// Creates a qcow2 overlay backed by `base` unless one already exists for this VM, so the base
+/// image is never written to.
+async fn create_overlay(base: &Path, overlay: &Path) -> Result<(), Error> {
+    if overlay.exists() {
+        return Ok(());
+    }
+    if !base.exists() {
+        return Err(Error::NotFound(format!("base image: {}", base.display())));
+    }
+    if let Some(dir) = overlay.parent() {
+        tokio::fs::create_dir_all(dir).await?;
+    }
+    let base = tokio::fs::canonicalize(base).await?;
+    let status = Command::new("qemu-img")
+        .kill_on_drop(true)
+        .args(vec![
+            OsStr::new("create"),
+            OsStr::new("-f"),
+            OsStr::new("qcow2"),
+            OsStr::new("-F"),
+            OsStr::new("raw"),
+            OsStr::new("-b"),
+            base.as_os_str(),
+            overlay.as_os_str(),
+        ])
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .stdin(Stdio::null())
+        .status()
+        .await?;
+    if !status.success() {
+        return Err(Error::IO(std::io::Error::new(
+            std::io::ErrorKind::Other,
+            format!("qemu-img failed to create overlay: {}", status),
+        )));
+    }
+    Ok(())
+}
+
+/// Picks the kernel to boot: the one from the spec if set, otherwise the firmware.
+fn boot_config(vm: &Vm) -> Result<(KernelConfig, Option<InitramfsConfig>, CmdlineConfig), Error> {
+    let extra = vm.spec.extra_cmdline.clone().unwrap_or_default();
+    let kernel = match vm.spec.kernel {
+        Some(ref kernel) => kernel,
+        None => {
+            return Ok((
+                KernelConfig {
+                    path: PathBuf::from("./blobs/hypervisor-fw"),
+                },
+                None,
+                CmdlineConfig { args: extra },
+            ))
+        }
+    };
+    if !kernel.is_file() {
+        return Err(Error::NotFound(format!("kernel: {}", kernel.display())));
+    }
+    let initramfs = match vm.spec.initramfs {
+        Some(ref initramfs) if !initramfs.is_file() => {
+            return Err(Error::NotFound(format!(
+                "initramfs: {}",
+                initramfs.display()
+            )))
+        }
+        Some(ref initramfs) => Some(InitramfsConfig {
+            path: initramfs.clone(),
+        }),
+        None => None,
+    };
+    Ok((
+        KernelConfig {
+            path: kernel.clone(),
+        },
+        initramfs,
+        CmdlineConfig {
+            args: match vm.spec.cmdline {
+                Some(ref cmdline) if !extra.is_empty() => format!("{} {}", cmdline, extra),
+                Some(ref cmdline) => cmdline.clone(),
+                None => extra,
+            },
+        },
+    ))
+}
+
+/// Uses the RNG source from the spec if set, checking that the node can read it.
+fn rng_config(vm: &Vm) -> Result<RngConfig, Error> {
+    let rng = match vm.spec.rng {
+        Some(ref rng) => rng,
+        None => {
+            return Ok(RngConfig {
+                iommu: vm.spec.iommu,
+                ..Default::default()
+            })
+        }
+    };
+    // The spec only allows known entropy devices, but the node may not have them
+    if let Err(err) = std::fs::File::open(&rng.src) {
+        return Err(match err.kind() {
+            std::io::ErrorKind::NotFound => {
+                Error::NotFound(format!("rng source: {}", rng.src.display()))
+            }
+            _ => err.into(),
+        });
+    }
+    Ok(RngConfig {
+        src: rng.src.clone(),
+        iommu: rng.iommu || vm.spec.iommu,
+    })
+}
+
+/// Maps a console mode from the spec onto cloud-hypervisor's, using `default` when unset. A
+/// file is placed in `dir`, the VM's [`Config::console_file_dir`].
+fn console_config(
+    mode: Option<&ConsoleMode>,
+    default: ConsoleOutputMode,
+    iommu: bool,
+    dir: &Path,
+) -> ConsoleConfig {
+    let (mode, file) = match mode {
+        None => (default, None),
+        Some(ConsoleMode::Off) => (ConsoleOutputMode::Off, None),
+        Some(ConsoleMode::Null) => (ConsoleOutputMode::Null, None),
+        Some(ConsoleMode::Pty) => (ConsoleOutputMode::Pty, None),
+        Some(ConsoleMode::Tty) => (ConsoleOutputMode::Tty, None),
+        Some(ConsoleMode::File(name)) => (ConsoleOutputMode::File, Some(dir.join(name))),
+    };
+    ConsoleConfig { file, mode, iommu }
+}
+
+/// The pty cloud-hypervisor allocated for `device`, `console` or `serial`, from its vm.info.
+fn allocated_pty(info: &serde_json::Value, device: &str) -> Option<String> {
+    let config = &info["config"][device];
+    if config["mode"] != "Pty" {
+        return None;
+    }
+    config["file"].as_str().map(str::to_string)
+}
+
+/// Resolves a disk's path, failing unless it's one of the `allowed` devices. Both sides are
+/// resolved, so a device is claimed under one name however a VM refers to it.
+fn block_device_path(path: &Path, allowed: &[PathBuf]) -> Result<PathBuf, Error> {
+    let resolved = match std::fs::canonicalize(path) {
+        Ok(resolved) => resolved,
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => {
+            return Err(Error::NotFound(format!("block device: {}", path.display())))
+        }
+        Err(err) => return Err(err.into()),
+    };
+    let is_allowed = allowed
+        .iter()
+        .filter_map(|device| std::fs::canonicalize(device).ok())
+        .any(|device| device == resolved);
+    if !is_allowed {
+        return Err(Error::Forbidden(format!(
+            "block device {} isn't one of the node's block_devices",
+            path.display()
+        )));
+    }
+    Ok(resolved)
+}
+
+/// The NIC of a VM: a tap named after the VM, which [`VmSupervisor::attach_tap`] adds to its
+/// VPC's bridge once it boots, with a random locally administered MAC.
+fn net_config(vm: &Vm) -> NetConfig {
+    NetConfig {
+        tap: Some(format!("ich{}", vm.metadata.host_name())),
+        mac: MacAddr::local_random(),
+        iommu: vm.spec.iommu,
+        ..Default::default()
+    }
+}
+
+/// The disks of a VM: its root overlay, then its cloud-init seed if it has one, then the disks
+/// in its spec in order.
+fn disk_configs(
+    vm: &Vm,
+    config: &Config,
+    overlay: &Path,
+    seed: Option<&Path>,
+) -> Result<Vec<DiskConfig>, Error> {
+    let mut disks = vec![DiskConfig {
+        path: Some(overlay.to_path_buf()),
+        iommu: vm.spec.iommu,
+        ..Default::default()
+    }];
+    if let Some(seed) = seed {
+        disks.push(DiskConfig {
+            path: Some(seed.to_path_buf()),
+            iommu: vm.spec.iommu,
+            ..Default::default()
+        });
+    }
+    for disk in &vm.spec.disks {
+        match disk {
+            DiskSpec::BlockDevice { path, readonly } => disks.push(DiskConfig {
+                path: Some(block_device_path(path, &config.block_devices)?),
+                readonly: *readonly,
+                direct: true,
+                iommu: vm.spec.iommu,
+                ..Default::default()
+            }),
+        }
+    }
+    Ok(disks)
+}
+
+/// Resolves a VM's shared directory, failing unless it's a directory under one of `roots`.
+/// Symlinks and `..` are resolved first, so neither can lead out of a root.
+fn shared_dir_path(path: &Path, roots: &[PathBuf]) -> Result<PathBuf, Error> {
+    let resolved = match std::fs::canonicalize(path) {
+        Ok(resolved) if resolved.is_dir() => resolved,
+        Ok(_) => {
+            return Err(Error::Invalid(format!(
+                "{} isn't a directory",
+                path.display()
+            )))
+        }
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => {
+            return Err(Error::NotFound(format!("shared dir: {}", path.display())))
+        }
+        Err(err) => return Err(err.into()),
+    };
+    let allowed = roots
+        .iter()
+        .filter_map(|root| std::fs::canonicalize(root).ok())
+        .any(|root| resolved.starts_with(root));
+    if !allowed {
+        return Err(Error::Forbidden(format!(
+            "shared dir {} isn't under any of the node's shared_dir_roots",
+            path.display()
+        )));
+    }
+    Ok(resolved)
+}
+
+/// Starts virtiofsd serving the VM's shared directory, returning it along with the matching
+/// device config.
+fn spawn_virtiofsd(
+    vm: &Vm,
+    config: &Config,
+) -> Result<Option<(tokio::process::Child, FsConfig)>, Error> {
+    let shared_dir = match vm.spec.shared_dir {
+        Some(ref shared_dir) => shared_dir,
+        None => return Ok(None),
+    };
+    let path = shared_dir_path(&shared_dir.path, &config.shared_dir_roots)?;
+    let socket = PathBuf::from(format!(
+        "/tmp/searu-virtiofsd-{}.sock",
+        vm.metadata.host_name()
+    ));
+    let mut args = vec![
+        format!("--socket-path={}", socket.display()),
+        format!("--shared-dir={}", path.display()),
+        "--cache=never".to_string(),
+    ];
+    if shared_dir.readonly {
+        args.push("--readonly".to_string());
+    }
+    let child = Command::new("./blobs/virtiofsd")
+        .kill_on_drop(true)
+        .args(args)
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .stdin(Stdio::null())
+        .spawn()?;
+    Ok(Some((
+        child,
+        FsConfig {
+            tag: shared_dir.tag.clone(),
+            socket,
+            ..Default::default()
+        },
+    )))
 }
 
 impl VmInstance {
-    async fn new(vm: &Vm) -> Result<Self, Error> {
+    async fn new(vm: &Vm, config: &Config) -> Result<Self, Error> {
+        vm.spec.validate()?;
+        let (kernel, initramfs, cmdline) = boot_config(vm)?;
+        let rng = rng_config(vm)?;
+        let (virtiofsd, fs) = match spawn_virtiofsd(vm, config)? {
+            Some((virtiofsd, fs)) => (Some(virtiofsd), Some(vec![fs])),
+            None => (None, None),
+        };
+        let host_name = vm.metadata.host_name();
+        let overlay = config.overlay_path(&host_name);
+        create_overlay(&config.base_image, &overlay).await?;
         let socket: String = rand::thread_rng()
             .sample_iter(&Alphanumeric)
             .take(30)
             .map(char::from)
             .collect();
-        let socket_path = format!("/tmp/{}-{}.sock", vm.metadata.name, socket);
-        let child = Command::new("./blobs/cloud-hypervisor")
+        let socket_path = format!("/tmp/{}-{}.sock", host_name, socket);
+        let console_log = config.console_log_path(&host_name);
+        let console_files = config.console_file_dir(&host_name);
+        let to_file = |mode: &Option<ConsoleMode>| matches!(mode, Some(ConsoleMode::File(_)));
+        if to_file(&vm.spec.console_mode) || to_file(&vm.spec.serial_mode) {
+            tokio::fs::create_dir_all(&console_files).await?;
+        }
+        let log = RotatingLog::open(
+            console_log.clone(),
+            config.console_log_max_size,
+            config.console_log_files,
+        )
+        .await?;
+        // The serial console goes to cloud-hypervisor's stdout, which is copied into the log
+        let mut child = Command::new("./blobs/cloud-hypervisor")
             .kill_on_drop(true)
             .args(vec!["--api-socket", &format!("path={}", socket_path)])
-            .stdout(Stdio::null())
+            .stdout(Stdio::piped())
             .stderr(Stdio::null())
             .stdin(Stdio::null())
             .spawn()?;
-        let mut disks = vec![DiskConfig {
-            path: Some(PathBuf::from("./blobs/focal-server-cloudimg-amd64.raw")),
-            ..Default::default()
-        }];
-        if let Some(ref cloud_init) = vm.spec.cloud_init {
+        let (cloud_init_tx, cloud_init) = watch::channel(None);
+        if let Some(stdout) = child.stdout.take() {
+            log.spawn_copy(stdout, move |line| {
+                if let Some(status) = CloudInitStatus::parse_report(line) {
+                    let _ = cloud_init_tx.send(Some(status));
+                }
+            });
+        }
+        let mut seed = None;
+        if vm.spec.cloud_init.is_some() || !vm.spec.cloud_init_meta_data.is_empty() {
             println!("creating cloud-init");
-            let user_data = tempfile::NamedTempFile::new()?;
-            let (_, user_data) = user_data.keep()?;
+            let cloud_init = vm.spec.cloud_init.as_deref().unwrap_or_default();
+            let user_data = tempfile::NamedTempFile::new()?.into_temp_path();
+            let meta_data = tempfile::NamedTempFile::new()?;
+            serde_json::to_writer(meta_data.as_file(), &vm.meta_data())?;
             let mut convert = Command::new("cloud-localds")
                 .kill_on_drop(true)
-                .args(vec![user_data.as_os_str(), OsStr::new("-")])
+                .args(vec![
+                    user_data.as_os_str(),
+                    OsStr::new("-"),
+                    meta_data.path().as_os_str(),
+                ])
                 .stdout(Stdio::null())
                 .stderr(Stdio::null())
                 .stdin(Stdio::piped())
                 .spawn()?;
             let stdin = convert.stdin.as_mut().unwrap();
             stdin.write_all(cloud_init.as_bytes()).await?;
-            let _ = convert.wait().await?;
-            disks.push(DiskConfig {
-                path: Some(user_data.to_path_buf()),
-                ..Default::default()
-            });
-            println!("{:?}", user_data);
+            let status = convert.wait().await?;
+            if !status.success() {
+                return Err(Error::IO(std::io::Error::new(
+                    std::io::ErrorKind::Other,
+                    format!("cloud-localds failed to create seed: {}", status),
+                )));
+            }
+            seed = Some(user_data);
         }
-        let client = hyper::Client::unix();
+        let disks = disk_configs(vm, config, &overlay, seed.as_deref())?;
+        // cloud-hypervisor has no setting for the guest clock: the CMOS RTC starts at the host's
+        // time when the VM boots and kvm-clock keeps the guest in step while it runs. Guests
+        // that need tighter sync should run their own NTP or ptp_kvm client.
         let vm_config = VmConfig {
             cpus: CpusConfig {
                 boot_vcpus: vm.spec.cpus,
@@ -153,79 +1328,631 @@ impl VmInstance {
                 max_phys_bits: None,
             },
             memory: MemoryConfig {
-                size: 1024 << 20,
+                size: vm.spec.memory_bytes(),
+                // vhost-user devices like virtio-fs need to map guest memory
+                shared: vm.spec.shared_memory || vm.spec.shared_dir.is_some(),
+                hugepages: vm.spec.hugepages,
+                hugepage_size: vm.spec.hugepage_size,
                 ..Default::default()
             },
-            kernel: Some(KernelConfig {
-                path: PathBuf::from("./blobs/hypervisor-fw"),
-            }),
-            serial: ConsoleConfig::default_serial(),
-            console: ConsoleConfig {
-                file: None,
-                mode: ConsoleOutputMode::Pty,
-                iommu: false,
-            },
-            initramfs: None,
-            cmdline: CmdlineConfig::default(),
+            kernel: Some(kernel),
+            serial: console_config(
+                vm.spec.serial_mode.as_ref(),
+                ConsoleOutputMode::Tty,
+                false,
+                &console_files,
+            ),
+            console: console_config(
+                vm.spec.console_mode.as_ref(),
+                ConsoleOutputMode::Pty,
+                vm.spec.iommu,
+                &console_files,
+            ),
+            initramfs,
+            cmdline,
             disks: Some(disks),
-            net: Some(vec![NetConfig {
-                tap: Some(format!("ich{}", vm.metadata.name)),
-                ..Default::default()
-            }]),
-            rng: RngConfig::default(),
+            net: Some(vec![net_config(vm)]),
+            rng,
             balloon: None,
-            fs: None,
+            fs,
             pmem: None,
             devices: None,
             vsock: None,
-            iommu: false,
+            iommu: vm.spec.iommu,
             sgx_epc: None,
             watchdog: false,
             numa: None,
         };
-        tokio::time::sleep(Duration::from_millis(500)).await; //TODO: We should have a better way of detecting when the hypervisor is ready
-                                                              // but `hyperlocal` appears to panic when it can't access a url
+        vm_config
+            .validate()
+            .map_err(|err| Error::Invalid(err.to_string()))?;
+        wait_for_api(&mut child, &socket_path).await?;
         let body = serde_json::to_string(&vm_config)?;
-        let _ = client
-            .request(
-                hyper::Request::builder()
-                    .method(hyper::Method::PUT)
-                    .uri(Uri::new(&socket_path, "/api/v1/vm.create"))
-                    .body(Body::from(body))?,
-            )
-            .await?;
+        let timeout = Duration::from_secs(config.hypervisor_timeout);
+        hypervisor_put(&socket_path, "/api/v1/vm.create", body, timeout).await?;
         Ok(Self {
-            _child: child,
-            client,
+            child: Process::Child(child),
+            exit: None,
+            cloud_init,
+            reported_cloud_init: vm.status.cloud_init_status,
+            virtiofsd: virtiofsd.map(Process::Child),
+            _seed: seed,
+            timeout,
             socket_path,
+            overlay,
+            console_log,
+            console_files,
         })
     }
 
+    async fn stop_virtiofsd(&mut self) {
+        if let Some(mut virtiofsd) = self.virtiofsd.take() {
+            let _ = virtiofsd.kill().await;
+        }
+    }
+
+    async fn remove_overlay(&self) -> Result<(), Error> {
+        match tokio::fs::remove_file(&self.overlay).await {
+            Err(err) if err.kind() != std::io::ErrorKind::NotFound => Err(err.into()),
+            _ => Ok(()),
+        }
+    }
+
     async fn boot(&self) -> Result<(), Error> {
         println!("booting vm");
-        let _ = self
-            .client
-            .request(
-                hyper::Request::builder()
-                    .method(hyper::Method::PUT)
-                    .uri(Uri::new(&self.socket_path, "/api/v1/vm.boot"))
-                    .body(Body::from(""))?,
-            )
-            .await?;
+        hypervisor_put(
+            &self.socket_path,
+            "/api/v1/vm.boot",
+            String::new(),
+            self.timeout,
+        )
+        .await?;
         println!("booted vm");
         Ok(())
     }
 
-    async fn shutdown(&self) -> Result<(), Error> {
-        let _ = self
-            .client
-            .request(
-                hyper::Request::builder()
-                    .method(hyper::Method::PUT)
-                    .uri(Uri::new(&self.socket_path, "/api/v1/vm.shutdown"))
-                    .body(Body::from(""))?,
-            )
-            .await?;
+    /// Presses the guest's ACPI power button and waits up to `timeout` for the hypervisor to
+    /// exit, which it does once the guest powers off, then kills it if it hasn't. Guests that
+    /// ignore ACPI or were never booted are killed, so only a failed kill is an error.
+    async fn terminate(&mut self, timeout: Duration) -> Result<(), Error> {
+        if self.exit.is_some() {
+            return Ok(());
+        }
+        match hypervisor_put(
+            &self.socket_path,
+            "/api/v1/vm.power-button",
+            String::new(),
+            self.timeout,
+        )
+        .await
+        {
+            Ok(()) => match tokio::time::timeout(timeout, self.child.wait()).await {
+                Ok(Ok(status)) => {
+                    self.exit = Some(status);
+                    return Ok(());
+                }
+                Ok(Err(err)) => println!("error waiting for hypervisor: {}", err),
+                Err(_) => println!("guest didn't power off within {:?}", timeout),
+            },
+            Err(err) => println!("error pressing power button: {}", err),
+        }
+        println!("killing hypervisor");
+        self.child.kill().await?;
+        self.exit = Some(self.child.wait().await?);
         Ok(())
     }
+
+    async fn shutdown(&self) -> Result<(), Error> {
+        if self.exit.is_some() {
+            return Ok(());
+        }
+        hypervisor_put(
+            &self.socket_path,
+            "/api/v1/vm.shutdown",
+            String::new(),
+            self.timeout,
+        )
+        .await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        config::Config,
+        storage::MemoryBackend,
+        types::{Metadata, RestartPolicy, VpcSpec},
+    };
+    use arc_swap::ArcSwap;
+    use futures::StreamExt;
+
+    fn supervisor(storage: &Storage) -> VmSupervisor {
+        let config: Config = serde_json::from_value(serde_json::json!({
+            "etcd_addr": "localhost:2379",
+            "jwt_secret": "secret",
+        }))
+        .unwrap();
+        let (conn, handle, _) = rtnetlink::new_connection().unwrap();
+        tokio::spawn(conn);
+        VmSupervisor::new(
+            storage.clone(),
+            handle,
+            Arc::new(ArcSwap::from_pointee(config)),
+            HypervisorSockets::default(),
+            crate::clock::system(),
+        )
+        .unwrap()
+    }
+
+    /// Serves cloud-hypervisor's API on `socket`, answering each request with what `respond`
+    /// returns for its request line. The request lines are sent on the returned channel.
+    fn mock_hypervisor(
+        socket: &Path,
+        respond: fn(&str) -> (u16, &'static str),
+    ) -> tokio::sync::mpsc::UnboundedReceiver<String> {
+        use tokio::io::{AsyncBufReadExt, AsyncReadExt, BufReader};
+
+        let listener = tokio::net::UnixListener::bind(socket).unwrap();
+        let (requests, received) = tokio::sync::mpsc::unbounded_channel();
+        tokio::spawn(async move {
+            while let Ok((stream, _)) = listener.accept().await {
+                let requests = requests.clone();
+                tokio::spawn(async move {
+                    let mut stream = BufReader::new(stream);
+                    let mut request = String::new();
+                    stream.read_line(&mut request).await.unwrap();
+                    let mut length = 0;
+                    loop {
+                        let mut line = String::new();
+                        stream.read_line(&mut line).await.unwrap();
+                        if line == "\r\n" {
+                            break;
+                        }
+                        let line = line.to_ascii_lowercase();
+                        if let Some(value) = line.strip_prefix("content-length:") {
+                            length = value.trim().parse().unwrap();
+                        }
+                    }
+                    let mut body = vec![0; length];
+                    stream.read_exact(&mut body).await.unwrap();
+                    let request = request.trim_end().to_string();
+                    let (status, body) = respond(&request);
+                    let _ = requests.send(request);
+                    let resp = format!(
+                        "HTTP/1.1 {} Mock\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                        status,
+                        body.len(),
+                        body
+                    );
+                    stream.get_mut().write_all(resp.as_bytes()).await.unwrap();
+                });
+            }
+        });
+        received
+    }
+
+    #[test]
+    fn shared_dirs_must_stay_under_a_root() {
+        let root = tempfile::tempdir().unwrap();
+        let outside = tempfile::tempdir().unwrap();
+        let inside = root.path().join("data");
+        std::fs::create_dir(&inside).unwrap();
+        let escape = root.path().join("escape");
+        std::os::unix::fs::symlink(outside.path(), &escape).unwrap();
+        let roots = vec![root.path().to_path_buf()];
+
+        let resolved = shared_dir_path(&inside, &roots).unwrap();
+        assert_eq!(resolved, std::fs::canonicalize(&inside).unwrap());
+        for path in &[outside.path().to_path_buf(), escape, inside.join("../..")] {
+            match shared_dir_path(path, &roots) {
+                Err(Error::Forbidden(_)) => {}
+                result => panic!(
+                    "expected {} to be refused, got {:?}",
+                    path.display(),
+                    result
+                ),
+            }
+        }
+        assert!(matches!(
+            shared_dir_path(&root.path().join("missing"), &roots),
+            Err(Error::NotFound(_))
+        ));
+        assert!(matches!(
+            shared_dir_path(&inside, &[]),
+            Err(Error::Forbidden(_))
+        ));
+    }
+
+    #[test]
+    fn only_allowed_block_devices_resolve() {
+        let dir = tempfile::tempdir().unwrap();
+        let device = dir.path().join("sdb");
+        let other = dir.path().join("sdc");
+        std::fs::write(&device, b"").unwrap();
+        std::fs::write(&other, b"").unwrap();
+        let by_id = dir.path().join("by-id");
+        std::os::unix::fs::symlink(&device, &by_id).unwrap();
+        let allowed = vec![device.clone()];
+
+        let resolved = std::fs::canonicalize(&device).unwrap();
+        assert_eq!(block_device_path(&device, &allowed).unwrap(), resolved);
+        assert_eq!(block_device_path(&by_id, &allowed).unwrap(), resolved);
+        assert!(matches!(
+            block_device_path(&other, &allowed),
+            Err(Error::Forbidden(_))
+        ));
+        assert!(matches!(
+            block_device_path(&device, &[]),
+            Err(Error::Forbidden(_))
+        ));
+        assert!(matches!(
+            block_device_path(&dir.path().join("sdd"), &allowed),
+            Err(Error::NotFound(_))
+        ));
+    }
+
+    #[test]
+    fn vms_get_a_tap_with_a_local_mac() {
+        let mut vm = Vm {
+            metadata: Metadata {
+                name: "a".to_string(),
+                project: "default".to_string(),
+                ..Default::default()
+            },
+            spec: Default::default(),
+            status: Default::default(),
+        };
+        vm.spec.iommu = true;
+        let net = net_config(&vm);
+        assert_eq!(net.tap, Some(format!("ich{}", vm.metadata.host_name())));
+        assert!(net.iommu);
+        assert_eq!(net.mac.get_bytes()[0], 0x2e);
+    }
+
+    #[tokio::test]
+    async fn vms_wait_for_their_vpcs_bridge() {
+        let storage = Storage::new(MemoryBackend::new());
+        let supervisor = supervisor(&storage);
+        let mut vm = Vm {
+            metadata: Metadata {
+                name: "a".to_string(),
+                project: "default".to_string(),
+                ..Default::default()
+            },
+            spec: Default::default(),
+            status: Default::default(),
+        };
+        vm.spec.vpc = "net".to_string();
+        assert!(matches!(
+            supervisor.bridge(&vm).await,
+            Err(Error::NotReady(msg)) if msg == "waiting for vpc net"
+        ));
+
+        // The vpc exists, but no supervisor has created its bridge on this node
+        storage
+            .create(&Vpc {
+                metadata: Metadata {
+                    name: "net".to_string(),
+                    project: "default".to_string(),
+                    ..Default::default()
+                },
+                spec: VpcSpec {
+                    subnet: "10.0.0.0/24".parse().unwrap(),
+                    multicast_ip: None,
+                    vni: None,
+                    nat_gateway: false,
+                },
+                status: Default::default(),
+            })
+            .await
+            .unwrap();
+        assert!(matches!(
+            supervisor.bridge(&vm).await,
+            Err(Error::NotReady(msg)) if msg == "waiting for vpc net"
+        ));
+    }
+
+    #[test]
+    fn vms_get_their_memory_and_disks() {
+        let dir = tempfile::tempdir().unwrap();
+        let sdb = dir.path().join("sdb");
+        let sdc = dir.path().join("sdc");
+        std::fs::write(&sdb, b"").unwrap();
+        std::fs::write(&sdc, b"").unwrap();
+        let mut config: Config = serde_json::from_value(serde_json::json!({
+            "etcd_addr": "localhost:2379",
+            "jwt_secret": "secret",
+        }))
+        .unwrap();
+        config.block_devices = vec![sdb.clone(), sdc.clone()];
+        let vm = Vm {
+            metadata: Metadata {
+                name: "a".to_string(),
+                project: "default".to_string(),
+                ..Default::default()
+            },
+            spec: serde_json::from_value(serde_json::json!({
+                "memory": 2048,
+                "disks": [sdb, {"block_device": {"path": sdc, "readonly": true}}],
+            }))
+            .unwrap(),
+            status: Default::default(),
+        };
+        assert_eq!(vm.spec.memory_bytes(), 2048 << 20);
+
+        let overlay = dir.path().join("a.qcow2");
+        let seed = dir.path().join("a.seed");
+        let disks = disk_configs(&vm, &config, &overlay, Some(&seed)).unwrap();
+        let paths: Vec<_> = disks.iter().map(|d| d.path.clone().unwrap()).collect();
+        assert_eq!(
+            paths,
+            vec![
+                overlay,
+                seed,
+                std::fs::canonicalize(&sdb).unwrap(),
+                std::fs::canonicalize(&sdc).unwrap(),
+            ]
+        );
+        let flags: Vec<_> = disks.iter().map(|d| (d.readonly, d.direct)).collect();
+        assert_eq!(
+            flags,
+            vec![(false, false), (false, false), (false, true), (true, true)]
+        );
+    }
+
+    #[tokio::test]
+    async fn only_a_new_shape_calls_for_a_restart() {
+        let storage = Storage::new(MemoryBackend::new());
+        let mut events = storage.watch::<Vm>().await.unwrap();
+        let mut vm = Vm {
+            metadata: Metadata {
+                name: "a".to_string(),
+                project: "default".to_string(),
+                ..Default::default()
+            },
+            spec: Default::default(),
+            status: Default::default(),
+        };
+        vm.spec.cpus = 1;
+        vm.spec.memory = 512;
+        storage.create(&vm).await.unwrap();
+        vm.metadata
+            .labels
+            .insert("tier".to_string(), "web".to_string());
+        storage.store(&vm).await.unwrap();
+        vm.spec.memory = 1024;
+        storage.store(&vm).await.unwrap();
+
+        assert!(matches!(events.next().await, Some(Event::New(_))));
+        match events.next().await {
+            Some(Event::Update { new, old }) => assert!(!needs_restart(&new, &old)),
+            event => panic!("expected an update, got {:?}", event),
+        }
+        match events.next().await {
+            Some(Event::Update { new, old }) => assert!(needs_restart(&new, &old)),
+            event => panic!("expected an update, got {:?}", event),
+        }
+    }
+
+    #[tokio::test]
+    async fn exits_follow_the_restart_policy() {
+        let storage = Storage::new(MemoryBackend::new());
+        let supervisor = supervisor(&storage);
+        let clean = ExitStatus::from_raw(0);
+        let crashed = ExitStatus::from_raw(1 << 8);
+        let cases = [
+            (RestartPolicy::Never, clean, false),
+            (RestartPolicy::Never, crashed, false),
+            (RestartPolicy::OnFailure, clean, false),
+            (RestartPolicy::OnFailure, crashed, true),
+            (RestartPolicy::Always, clean, true),
+            (RestartPolicy::Always, crashed, true),
+        ];
+        for (i, (policy, status, restarts)) in cases.iter().enumerate() {
+            let mut vm = Vm {
+                metadata: Metadata {
+                    name: format!("vm{}", i),
+                    project: "default".to_string(),
+                    ..Default::default()
+                },
+                spec: Default::default(),
+                status: Default::default(),
+            };
+            vm.spec.vpc = "net".to_string();
+            vm.spec.restart_policy = *policy;
+            vm.status.node = Some(supervisor.node_name.clone());
+            vm.status.transition(VmState::PoweredOff, 0).unwrap();
+            vm.status.transition(VmState::PoweredOn, 0).unwrap();
+            storage.create(&vm).await.unwrap();
+
+            let name = vm.key_name();
+            let result = supervisor.handle_exit(&name, *status, &mut None).await;
+            let exited: Vm = storage.get(&name).await.unwrap().unwrap();
+            if *restarts {
+                // Starting again gets as far as waiting for the vpc, which doesn't exist here
+                assert!(matches!(result, Err(Error::NotReady(_))), "{:?}", policy);
+                assert_ne!(exited.status.state, VmState::Failed, "{:?}", policy);
+                assert_eq!(
+                    exited.status.message.as_deref(),
+                    Some("waiting for vpc net")
+                );
+            } else {
+                result.unwrap();
+                assert_eq!(exited.status.state, VmState::Failed, "{:?}", policy);
+                assert_eq!(
+                    exited.status.message,
+                    Some(format!("hypervisor exited: {}", status))
+                );
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn a_silent_hypervisor_times_out() {
+        let dir = tempfile::tempdir().unwrap();
+        let socket = dir.path().join("api.sock");
+        let listener = tokio::net::UnixListener::bind(&socket).unwrap();
+        let (accepted, mut connections) = tokio::sync::mpsc::unbounded_channel();
+        tokio::spawn(async move {
+            // Holds every connection open without ever answering
+            while let Ok((stream, _)) = listener.accept().await {
+                let _ = accepted.send(stream);
+            }
+        });
+        let socket = socket.to_str().unwrap();
+        let timeout = Duration::from_millis(50);
+
+        match vm_info(socket, timeout).await {
+            Err(Error::Timeout(msg)) => assert_eq!(msg, "GET /api/v1/vm.info after 50ms"),
+            result => panic!("expected a timeout, got {:?}", result),
+        }
+        let mut open = vec![];
+        while let Ok(stream) = connections.try_recv() {
+            open.push(stream);
+        }
+        // GETs are retried
+        assert_eq!(open.len() as u32, 1 + HYPERVISOR_GET_RETRIES);
+
+        match hypervisor_put(socket, "/api/v1/vm.pause", String::new(), timeout).await {
+            Err(Error::Timeout(msg)) => assert_eq!(msg, "PUT /api/v1/vm.pause after 50ms"),
+            result => panic!("expected a timeout, got {:?}", result),
+        }
+        let mut puts = 0;
+        while connections.try_recv().is_ok() {
+            puts += 1;
+        }
+        assert_eq!(puts, 1);
+    }
+
+    #[tokio::test]
+    async fn hypervisor_errors_carry_its_message() {
+        let dir = tempfile::tempdir().unwrap();
+        let socket = dir.path().join("api.sock");
+        let mut requests = mock_hypervisor(&socket, |request| {
+            if request.contains("vm.info") || request.contains("vm.snapshot") {
+                (500, r#"{"error":"boom"}"#)
+            } else {
+                (204, "")
+            }
+        });
+        let socket = socket.to_str().unwrap();
+        let timeout = Duration::from_secs(5);
+
+        match vm_info(socket, timeout).await {
+            Err(err @ Error::Hypervisor(_)) => {
+                assert_eq!(
+                    err.to_string(),
+                    r#"hypervisor: /api/v1/vm.info failed with 500 Internal Server Error: {"error":"boom"}"#
+                );
+                assert_eq!(err.status(), rocket::http::Status::BadGateway);
+            }
+            result => panic!("expected a hypervisor error, got {:?}", result),
+        }
+        // An error response is an answer, so even a GET isn't retried
+        assert_eq!(
+            requests.recv().await.unwrap(),
+            "GET /api/v1/vm.info HTTP/1.1"
+        );
+        assert!(requests.try_recv().is_err());
+
+        // A failed snapshot still resumes the VM
+        assert!(matches!(
+            vm_snapshot(socket, dir.path(), timeout).await,
+            Err(Error::Hypervisor(msg)) if msg.starts_with("/api/v1/vm.snapshot failed with 500")
+        ));
+        let mut sent = vec![];
+        while let Ok(request) = requests.try_recv() {
+            sent.push(request);
+        }
+        assert_eq!(
+            sent,
+            vec![
+                "PUT /api/v1/vm.pause HTTP/1.1",
+                "PUT /api/v1/vm.snapshot HTTP/1.1",
+                "PUT /api/v1/vm.resume HTTP/1.1",
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn init_reconnects_to_hypervisors_still_running_the_vm() {
+        const RUNNING: &str = r#"{
+            "state": "Running",
+            "config": {"cpus": {"boot_vcpus": 1}, "memory": {"size": 536870912}}
+        }"#;
+        let storage = Storage::new(MemoryBackend::new());
+        let mut supervisor = supervisor(&storage);
+        let mut fakes = vec![];
+        for (name, cpus) in &[("kept", 1), ("reshaped", 2)] {
+            let mut vm = Vm {
+                metadata: Metadata {
+                    name: format!("reconnect-{}-{}", name, std::process::id()),
+                    project: "default".to_string(),
+                    ..Default::default()
+                },
+                spec: Default::default(),
+                status: Default::default(),
+            };
+            vm.spec.vpc = "net".to_string();
+            vm.spec.cpus = *cpus;
+            vm.spec.memory = 512;
+            vm.status.node = Some(supervisor.node_name.clone());
+            vm.status.transition(VmState::PoweredOff, 0).unwrap();
+            vm.status.transition(VmState::PoweredOn, 0).unwrap();
+            storage.create(&vm).await.unwrap();
+
+            // Stands in for the hypervisor the node's last run left behind, booted with 1 cpu
+            // and 512 MiB
+            let socket = format!("/tmp/{}-{}.sock", vm.metadata.host_name(), "x".repeat(30));
+            let _ = std::fs::remove_file(&socket);
+            let requests = mock_hypervisor(Path::new(&socket), |request| {
+                if request.contains("vm.info") {
+                    (200, RUNNING)
+                } else {
+                    (200, "")
+                }
+            });
+            let process = Command::new("sh")
+                .args(&["-c", "sleep 30; true", &format!("path={}", socket)])
+                .kill_on_drop(true)
+                .spawn()
+                .unwrap();
+            fakes.push((vm.key_name(), socket, requests, process));
+        }
+
+        supervisor.init().await.unwrap();
+
+        let (name, socket, mut requests, process) = fakes.remove(0);
+        assert_eq!(
+            supervisor.sockets.get(&name).as_deref(),
+            Some(socket.as_str())
+        );
+        let slot = supervisor.slot(&name);
+        let inst = slot.lock().await;
+        let pid = process.id().unwrap();
+        assert!(matches!(
+            inst.as_ref().map(|inst| &inst.child),
+            Some(Process::Adopted(adopted)) if *adopted == pid
+        ));
+        assert_eq!(
+            requests.recv().await.unwrap(),
+            "GET /api/v1/vmm.ping HTTP/1.1"
+        );
+        assert_eq!(
+            requests.recv().await.unwrap(),
+            "GET /api/v1/vm.info HTTP/1.1"
+        );
+        let _ = std::fs::remove_file(&socket);
+
+        // A hypervisor running another shape is killed rather than taken over
+        let (name, socket, _, mut process) = fakes.remove(0);
+        assert_eq!(supervisor.sockets.get(&name), None);
+        let status = tokio::time::timeout(Duration::from_secs(5), process.wait())
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(status.signal(), Some(9));
+        let _ = std::fs::remove_file(&socket);
+    }
 }
@@ -1,22 +1,56 @@
-use std::net::IpAddr;
+use std::{
+    collections::HashMap,
+    net::{IpAddr, Ipv4Addr},
+    time::Duration,
+};
 
-use super::Actor;
+use super::{Actor, DHCPActor, DhcpMessage, Handle as ActorHandle};
 use crate::{
+    config::SharedConfig,
+    nat,
     storage::{Event, Storage},
-    types::{Error, Vpc},
+    types::{host_name, Error, Object, Vpc},
 };
 use futures::stream::TryStreamExt;
-use netlink_packet_route::rtnl::link::LinkMessage;
+use ipnet::Ipv4Net;
+use netlink_packet_route::{
+    rtnl::{address::nlas::Nla as AddressNla, link::LinkMessage},
+    AF_INET, IFF_UP,
+};
 use rtnetlink::Handle;
 
+/// How long the supervisor waits for room in the DHCP actor's mailbox before failing the VPC,
+/// so a stuck dnsmasq restart can't stall every other VPC on the node.
+const DHCP_SEND_TIMEOUT: Duration = Duration::from_secs(30);
+
 pub struct VpcSupervisor {
-    _storage: Storage,
+    storage: Storage,
     handle: Handle,
+    dhcp: ActorHandle<DHCPActor>,
+    config: SharedConfig,
+    /// The subnets of the VPCs this node NATs for, by host name, to remove their rules once
+    /// that stops.
+    nat: HashMap<String, Ipv4Net>,
+    /// The host names of the VPCs set up on this node, by key name, as deletes only carry the
+    /// latter.
+    host_names: HashMap<String, String>,
 }
 
 impl VpcSupervisor {
-    pub fn new(_storage: Storage, handle: Handle) -> Self {
-        Self { _storage, handle }
+    pub fn new(
+        storage: Storage,
+        handle: Handle,
+        dhcp: ActorHandle<DHCPActor>,
+        config: SharedConfig,
+    ) -> Self {
+        Self {
+            storage,
+            handle,
+            dhcp,
+            config,
+            nat: HashMap::default(),
+            host_names: HashMap::default(),
+        }
     }
 }
 
@@ -31,7 +65,14 @@ impl Actor for VpcSupervisor {
         message: Self::Message,
     ) -> Result<Self::Response, crate::types::Error> {
         match message {
+            // Status updates come from the nodes and leave the links as they are
+            Event::Update { old, new } if old.spec == new.spec => {
+                self.host_names
+                    .insert(new.key_name(), new.metadata.host_name());
+            }
             Event::New(vpc) | Event::Update { new: vpc, .. } => {
+                let host_name = vpc.metadata.host_name();
+                self.host_names.insert(vpc.key_name(), host_name.clone());
                 if let Some(multicast_ip) = vpc.spec.multicast_ip {
                     if let Some(vni) = vpc.spec.vni {
                         // let mut links = self
@@ -41,19 +82,27 @@ impl Actor for VpcSupervisor {
                         //     .set_name_filter("")
                         //     .execute();
                         //if let Some(link) = links.try_next().await? {
-                        self.handle
+                        let vxlan = self
+                            .handle
                             .link()
                             .add()
-                            .vxlan(format!("vx{}", vpc.metadata.name), vni as u32) //TODO: Add VNI scheduling
-                            .link(4) //TODO: Use name filterings
-                            .group(multicast_ip)
-                            .port(0)
-                            .up()
-                            .execute()
-                            .await?;
-                        let bridge_name = format!("b{}", vpc.metadata.name);
-                        // let veth_name = format!("veth{}", vpc.metadata.name);
-                        // let veth_p_name = format!("veth{}p", vpc.metadata.name);
+                            .vxlan(format!("vx{}", host_name), vni as u32); //TODO: Add VNI scheduling
+                        let overlay_interface = self.config.load().overlay_interface.clone();
+                        let vxlan = match overlay_interface {
+                            Some(name) => {
+                                let (index, local) = self.overlay_link(name).await?;
+                                let vxlan = vxlan.link(index);
+                                match local {
+                                    Some(local) => vxlan.local(local),
+                                    None => vxlan,
+                                }
+                            }
+                            None => vxlan.link(4), //TODO: Use name filterings
+                        };
+                        vxlan.group(multicast_ip).port(0).up().execute().await?;
+                        let bridge_name = format!("b{}", host_name);
+                        // let veth_name = format!("veth{}", host_name);
+                        // let veth_p_name = format!("veth{}p", host_name);
                         self.handle
                             .link()
                             .add()
@@ -95,16 +144,17 @@ impl Actor for VpcSupervisor {
                             .execute()
                             .await?;
 
-                        // TODO: Remoe this in favour of a DHCP solution
                         let host_ip = vpc
                             .spec
-                            .subnet
-                            .hosts()
-                            .next()
+                            .host_ip()
                             .ok_or_else(|| Error::NotFound("host ip".to_string()))?;
                         self.handle
                             .address()
-                            .add(bridge.header.index, IpAddr::V4(host_ip), 24)
+                            .add(
+                                bridge.header.index,
+                                IpAddr::V4(host_ip),
+                                vpc.spec.subnet.prefix_len(),
+                            )
                             .execute()
                             .await?;
                         self.handle
@@ -113,20 +163,174 @@ impl Actor for VpcSupervisor {
                             .up()
                             .execute()
                             .await?;
+                        let ready = self
+                            .wait_for_bridge(format!("b{}", host_name), host_ip)
+                            .await;
+                        let message = ready.as_ref().err().map(ToString::to_string);
+                        self.report(&vpc.key_name(), message).await?;
+                        ready?;
+                        self.set_nat(&vpc).await?;
+                        self.dhcp
+                            .send_timeout(DhcpMessage::Start(vpc), DHCP_SEND_TIMEOUT)
+                            .await?;
                     }
                 }
             }
-            Event::Delete(vpc) => {
-                let vx = self.handle.get_link_by_name(format!("vx{}", vpc)).await?;
-                self.handle.link().del(vx.header.index).execute().await?;
-                let b = self.handle.get_link_by_name(format!("b{}", vpc)).await?;
-                self.handle.link().del(b.header.index).execute().await?;
-                let veth = self.handle.get_link_by_name(format!("veth{}", vpc)).await?;
-                self.handle.link().del(veth.header.index).execute().await?;
+            Event::Delete(name) => {
+                // Only a VPC created and deleted between the listing in `init` and its first
+                // event can be missing, and new VPCs are named by the hash
+                let vpc = self.host_names.remove(&name).unwrap_or_else(|| {
+                    let mut segments = name.splitn(2, '/');
+                    let project = segments.next().unwrap_or_default();
+                    host_name(project, segments.next().unwrap_or_default())
+                });
+                // Every step runs even if an earlier one fails, so one stuck link doesn't leak
+                // the rest
+                let nat = match self.nat.remove(&vpc) {
+                    Some(subnet) => nat::disable(&vpc, subnet).await,
+                    None => Ok(()),
+                };
+                let steps = vec![
+                    nat,
+                    self.dhcp
+                        .send_timeout(DhcpMessage::Stop(vpc.clone()), DHCP_SEND_TIMEOUT)
+                        .await,
+                    self.delete_link(format!("vx{}", vpc)).await,
+                    self.delete_link(format!("b{}", vpc)).await,
+                    self.delete_link(format!("veth{}", vpc)).await,
+                ];
+                let errors: Vec<Error> = steps.into_iter().filter_map(Result::err).collect();
+                for err in &errors {
+                    println!("error tearing down vpc {}: {}", vpc, err);
+                }
+                Error::collect(errors)?;
             }
         }
         Ok(())
     }
+
+    /// Learns the host names of the VPCs that already exist, so they can be torn down if they're
+    /// deleted before their next update.
+    async fn init(&mut self) -> Result<(), Error> {
+        for vpc in self.storage.list::<Vpc>().await? {
+            self.host_names
+                .insert(vpc.key_name(), vpc.metadata.host_name());
+        }
+        Ok(())
+    }
+}
+
+/// How many times the bridge is checked for its address before giving up.
+const BRIDGE_READY_ATTEMPTS: usize = 10;
+const BRIDGE_READY_INTERVAL: Duration = Duration::from_millis(200);
+
+impl VpcSupervisor {
+    /// Waits for a VPC's bridge to be up with its host address, so dnsmasq is never started on a
+    /// bridge it would hand out unusable leases from.
+    async fn wait_for_bridge(&self, name: String, host_ip: Ipv4Addr) -> Result<(), Error> {
+        for _ in 0..BRIDGE_READY_ATTEMPTS {
+            let bridge = self.handle.get_link_by_name(name.clone()).await?;
+            let has_address = self
+                .handle
+                .address()
+                .get()
+                .set_link_index_filter(bridge.header.index)
+                .set_address_filter(IpAddr::V4(host_ip))
+                .execute()
+                .try_next()
+                .await?
+                .is_some();
+            if bridge.header.flags & IFF_UP != 0 && has_address {
+                return Ok(());
+            }
+            tokio::time::sleep(BRIDGE_READY_INTERVAL).await;
+        }
+        Err(Error::NotReady(format!(
+            "bridge {} isn't up with address {}, not starting dhcp",
+            name, host_ip
+        )))
+    }
+
+    /// Sets this node's message in a VPC's status, or clears it with `None`, storing the VPC only
+    /// if that changes it.
+    async fn report(&self, name: &str, message: Option<String>) -> Result<(), Error> {
+        let node = sys_info::hostname()?;
+        let mut vpc: Vpc = match self.storage.get(name).await? {
+            Some(vpc) => vpc,
+            None => return Ok(()),
+        };
+        if vpc.status.node_messages.get(&node) == message.as_ref() {
+            return Ok(());
+        }
+        match message {
+            Some(message) => vpc.status.node_messages.insert(node, message),
+            None => vpc.status.node_messages.remove(&node),
+        };
+        self.storage.update(&vpc).await
+    }
+
+    /// The index of the configured `overlay_interface` and its first IPv4 address, which VXLAN
+    /// links send their multicast traffic from. Fails if the interface is missing or down.
+    async fn overlay_link(&self, name: String) -> Result<(u32, Option<Ipv4Addr>), Error> {
+        let link = self
+            .handle
+            .get_link_by_name(name.clone())
+            .await
+            .map_err(|_| Error::NotFound(format!("overlay_interface {} doesn't exist", name)))?;
+        if link.header.flags & IFF_UP == 0 {
+            return Err(Error::NotReady(format!(
+                "overlay_interface {} is down",
+                name
+            )));
+        }
+        let mut addresses = self
+            .handle
+            .address()
+            .get()
+            .set_link_index_filter(link.header.index)
+            .execute();
+        while let Some(address) = addresses.try_next().await? {
+            if address.header.family as u16 != AF_INET {
+                continue;
+            }
+            let local = address.nlas.iter().find_map(|nla| match nla {
+                AddressNla::Local(bytes) | AddressNla::Address(bytes) if bytes.len() == 4 => {
+                    Some(Ipv4Addr::new(bytes[0], bytes[1], bytes[2], bytes[3]))
+                }
+                _ => None,
+            });
+            if local.is_some() {
+                return Ok((link.header.index, local));
+            }
+        }
+        Ok((link.header.index, None))
+    }
+
+    /// Adds or removes the VPC's NAT rules to match `nat_gateway`.
+    async fn set_nat(&mut self, vpc: &Vpc) -> Result<(), Error> {
+        let name = &vpc.metadata.host_name();
+        if let Some(subnet) = self.nat.remove(name) {
+            if !vpc.spec.nat_gateway || subnet != vpc.spec.subnet {
+                nat::disable(name, subnet).await?;
+            }
+        }
+        if vpc.spec.nat_gateway {
+            nat::enable(name, vpc.spec.subnet).await?;
+            self.nat.insert(name.clone(), vpc.spec.subnet);
+        }
+        Ok(())
+    }
+
+    /// Deletes the link called `name`, treating a link that's already gone as deleted.
+    async fn delete_link(&self, name: String) -> Result<(), Error> {
+        let link = match self.handle.get_link_by_name(name).await {
+            Ok(link) => link,
+            Err(Error::NotFound(_)) => return Ok(()),
+            Err(err) => return Err(err),
+        };
+        self.handle.link().del(link.header.index).execute().await?;
+        Ok(())
+    }
 }
 
 #[async_trait::async_trait]
@@ -1,7 +1,10 @@
-use super::{Events, Handle, Scheduler, VmSupervisor, VpcSupervisor};
+use super::{
+    DHCPActor, DhcpMessage, Events, Handle, KeyedQueue, Scheduler, VmMessage, VmSupervisor,
+    VpcSupervisor,
+};
 use crate::{
-    storage::Storage,
-    types::{Vm, Vpc},
+    storage::{Event, Storage},
+    types::{DhcpReservation, Node, Vm, Vpc},
 };
 use futures::StreamExt;
 use tokio::task::JoinHandle;
@@ -28,10 +31,17 @@ impl VmWatcher {
     pub fn spawn(self) -> JoinHandle<Result<(), anyhow::Error>> {
         tokio::spawn(async move {
             let mut stream = self.storage.watch::<Vm>().await?;
+            let mut queue = KeyedQueue::new(self.supervisor.clone());
+            // Queued too, so a slow placement doesn't hold up the watch stream
+            let mut scheduler = KeyedQueue::new(self.scheduler.clone());
             while let Some(event) = stream.next().await {
-                let _ = self.scheduler.send(Events::VmEvent(event.clone())).await;
-                if let Err(err) = self.supervisor.send(event).await {
-                    println!("error: {:?}", err);
+                let name = event.key_name();
+                let delete = matches!(event, Event::Delete(_));
+                scheduler.send(&name, Events::VmEvent(event.clone()));
+                queue.send(&name, VmMessage::Event(event));
+                if delete {
+                    scheduler.retire(&name);
+                    queue.retire(&name);
                 }
             }
             Ok(())
@@ -61,10 +71,73 @@ impl VpcWatcher {
     pub fn spawn(self) -> JoinHandle<Result<(), anyhow::Error>> {
         tokio::spawn(async move {
             let mut stream = self.storage.watch::<Vpc>().await?;
+            let mut queue = KeyedQueue::new(self.supervisor.clone());
+            let mut scheduler = KeyedQueue::new(self.scheduler.clone());
+            while let Some(event) = stream.next().await {
+                let name = event.key_name();
+                let delete = matches!(event, Event::Delete(_));
+                scheduler.send(&name, Events::VpcEvent(event.clone()));
+                queue.send(&name, event);
+                if delete {
+                    scheduler.retire(&name);
+                    queue.retire(&name);
+                }
+            }
+            Ok(())
+        })
+    }
+}
+
+/// Tells the scheduler about nodes that join, so VMs waiting for room are placed again, and
+/// nodes that go away, so their VMs are placed elsewhere.
+pub struct NodeWatcher {
+    storage: Storage,
+    scheduler: Handle<Scheduler>,
+}
+
+impl NodeWatcher {
+    pub fn new(storage: Storage, scheduler: Handle<Scheduler>) -> Self {
+        Self { storage, scheduler }
+    }
+
+    pub fn spawn(self) -> JoinHandle<Result<(), anyhow::Error>> {
+        tokio::spawn(async move {
+            let mut stream = self.storage.watch::<Node>().await?;
+            let mut queue = KeyedQueue::new(self.scheduler.clone());
+            // Every heartbeat rewrites its node, so only joins and deletes are passed on
+            while let Some(event) = stream.next().await {
+                match event {
+                    Event::New(node) => {
+                        let name = node.metadata.name;
+                        queue.send(&name, Events::NodeAdded(name.clone()));
+                    }
+                    Event::Delete(name) => {
+                        queue.send(&name, Events::NodeDeleted(name.clone()));
+                        queue.retire(&name);
+                    }
+                    Event::Update { .. } => {}
+                }
+            }
+            Ok(())
+        })
+    }
+}
+
+pub struct DhcpReservationWatcher {
+    storage: Storage,
+    dhcp: Handle<DHCPActor>,
+}
+
+impl DhcpReservationWatcher {
+    pub fn new(storage: Storage, dhcp: Handle<DHCPActor>) -> Self {
+        Self { storage, dhcp }
+    }
+
+    pub fn spawn(self) -> JoinHandle<Result<(), anyhow::Error>> {
+        tokio::spawn(async move {
+            let mut stream = self.storage.watch::<DhcpReservation>().await?;
             while let Some(event) = stream.next().await {
-                let _ = self.scheduler.send(Events::VpcEvent(event.clone())).await;
-                println!("sending");
-                if let Err(err) = self.supervisor.send(event).await {
+                if let Err(err) = self.dhcp.send(DhcpMessage::Reservation(event)).await {
                     println!("error: {:?}", err);
                 }
             }
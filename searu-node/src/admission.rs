@@ -0,0 +1,417 @@
+use crate::{
+    config::Config,
+    types::{validate_name, DhcpReservation, Error, JwtClaim, Metadata, Object, Vm, Vpc},
+};
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Operation {
+    Create,
+    Update,
+}
+
+/// What an admission hook knows about the request besides the object itself.
+pub struct AdmissionRequest<'a> {
+    pub claim: &'a JwtClaim,
+    pub operation: Operation,
+}
+
+/// A policy hook run by the create and update routes before an object is stored. Hooks can
+/// reject the object by returning an error or mutate it by returning a changed copy.
+pub trait Admission<O>: Send + Sync {
+    fn admit(&self, request: &AdmissionRequest<'_>, object: O) -> Result<O, Error>;
+}
+
+/// Objects that live in a project and carry their own [`Metadata`].
+pub trait Scoped: Object {
+    fn metadata_mut(&mut self) -> &mut Metadata;
+}
+
+impl Scoped for Vm {
+    fn metadata_mut(&mut self) -> &mut Metadata {
+        &mut self.metadata
+    }
+}
+
+impl Scoped for Vpc {
+    fn metadata_mut(&mut self) -> &mut Metadata {
+        &mut self.metadata
+    }
+}
+
+impl Scoped for DhcpReservation {
+    fn metadata_mut(&mut self) -> &mut Metadata {
+        &mut self.metadata
+    }
+}
+
+/// Runs hooks in the order they were registered, feeding each the previous hook's output.
+pub struct AdmissionChain<O> {
+    hooks: Vec<Box<dyn Admission<O>>>,
+}
+
+impl<O> AdmissionChain<O> {
+    pub fn new() -> Self {
+        Self { hooks: vec![] }
+    }
+
+    pub fn register(mut self, hook: impl Admission<O> + 'static) -> Self {
+        self.hooks.push(Box::new(hook));
+        self
+    }
+
+    pub fn admit(&self, request: &AdmissionRequest<'_>, object: O) -> Result<O, Error> {
+        self.hooks
+            .iter()
+            .try_fold(object, |object, hook| hook.admit(request, object))
+    }
+}
+
+impl<O> Default for AdmissionChain<O> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// The admission chains for every object type the API can create.
+pub struct Admissions {
+    pub vms: AdmissionChain<Vm>,
+    pub vpcs: AdmissionChain<Vpc>,
+    pub reservations: AdmissionChain<DhcpReservation>,
+}
+
+impl Admissions {
+    pub fn new(config: &Config) -> Self {
+        let mut vms = AdmissionChain::new()
+            .register(ValidName)
+            .register(ProjectScope)
+            .register(CreatedBy)
+            .register(HostName)
+            .register(VmDefaults {
+                cpus: config.default_vm_cpus,
+                memory: config.default_vm_memory,
+                vpc: config.default_vm_vpc.clone(),
+            })
+            .register(ValidVmSpec);
+        if config.max_vm_cpus.is_some() || config.max_vm_memory.is_some() {
+            vms = vms.register(MaxVmSize {
+                cpus: config.max_vm_cpus,
+                memory: config.max_vm_memory,
+            });
+        }
+        Self {
+            vms,
+            vpcs: AdmissionChain::new()
+                .register(ValidName)
+                .register(ProjectScope)
+                .register(CreatedBy)
+                .register(HostName)
+                .register(ValidVpcSpec),
+            reservations: AdmissionChain::new()
+                .register(ValidName)
+                .register(ProjectScope)
+                .register(CreatedBy),
+        }
+    }
+}
+
+/// Rejects names that can't be safely used in etcd keys.
+pub struct ValidName;
+
+impl<O: Scoped> Admission<O> for ValidName {
+    fn admit(&self, _request: &AdmissionRequest<'_>, object: O) -> Result<O, Error> {
+        validate_name(&object.metadata().name)?;
+        Ok(object)
+    }
+}
+
+/// Defaults `metadata.project` from the claim and rejects projects the claim can't access.
+pub struct ProjectScope;
+
+impl<O: Scoped> Admission<O> for ProjectScope {
+    fn admit(&self, request: &AdmissionRequest<'_>, mut object: O) -> Result<O, Error> {
+        request
+            .claim
+            .authorize_project(&mut object.metadata_mut().project)?;
+        Ok(object)
+    }
+}
+
+/// Records the user that created an object.
+pub struct CreatedBy;
+
+impl<O: Scoped> Admission<O> for CreatedBy {
+    fn admit(&self, request: &AdmissionRequest<'_>, mut object: O) -> Result<O, Error> {
+        if request.operation == Operation::Create {
+            object.metadata_mut().created_by = Some(request.claim.username().to_string());
+        }
+        Ok(object)
+    }
+}
+
+/// Clears `metadata.host_name` on create. Only objects from before keys were scoped by project
+/// carry one, and a new object given another's could take over its interfaces and files.
+pub struct HostName;
+
+impl<O: Scoped> Admission<O> for HostName {
+    fn admit(&self, request: &AdmissionRequest<'_>, mut object: O) -> Result<O, Error> {
+        if request.operation == Operation::Create {
+            object.metadata_mut().host_name = None;
+        }
+        Ok(object)
+    }
+}
+
+pub struct ValidVmSpec;
+
+impl Admission<Vm> for ValidVmSpec {
+    fn admit(&self, _request: &AdmissionRequest<'_>, vm: Vm) -> Result<Vm, Error> {
+        vm.spec.validate()?;
+        Ok(vm)
+    }
+}
+
+pub struct ValidVpcSpec;
+
+impl Admission<Vpc> for ValidVpcSpec {
+    fn admit(&self, _request: &AdmissionRequest<'_>, vpc: Vpc) -> Result<Vpc, Error> {
+        vpc.spec.validate()?;
+        Ok(vpc)
+    }
+}
+
+/// Fills in the cpus, memory and vpc a VM was created without from the node's config. Values
+/// in the request always win, and updates are left alone so they can't silently reshape a VM.
+pub struct VmDefaults {
+    pub cpus: Option<u8>,
+    pub memory: Option<usize>,
+    pub vpc: Option<String>,
+}
+
+impl Admission<Vm> for VmDefaults {
+    fn admit(&self, request: &AdmissionRequest<'_>, mut vm: Vm) -> Result<Vm, Error> {
+        if request.operation != Operation::Create {
+            return Ok(vm);
+        }
+        if vm.spec.cpus == 0 {
+            vm.spec.cpus = self.cpus.unwrap_or_default();
+        }
+        if vm.spec.memory == 0 {
+            vm.spec.memory = self.memory.unwrap_or_default();
+        }
+        if vm.spec.vpc.is_empty() {
+            vm.spec.vpc = self.vpc.clone().unwrap_or_default();
+        }
+        Ok(vm)
+    }
+}
+
+/// Rejects VMs larger than the configured maximum shape.
+pub struct MaxVmSize {
+    pub cpus: Option<u8>,
+    pub memory: Option<usize>,
+}
+
+impl Admission<Vm> for MaxVmSize {
+    fn admit(&self, _request: &AdmissionRequest<'_>, vm: Vm) -> Result<Vm, Error> {
+        if let Some(cpus) = self.cpus {
+            if vm.spec.cpus > cpus {
+                return Err(Error::Forbidden(format!(
+                    "vm has {} cpus, the maximum is {}",
+                    vm.spec.cpus, cpus
+                )));
+            }
+        }
+        if let Some(memory) = self.memory {
+            if vm.spec.memory > memory {
+                return Err(Error::Forbidden(format!(
+                    "vm has {} MiB of memory, the maximum is {} MiB",
+                    vm.spec.memory, memory
+                )));
+            }
+        }
+        Ok(vm)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::types::{InnerJwtClaim, Role};
+
+    fn claim() -> JwtClaim {
+        JwtClaim {
+            inner: InnerJwtClaim::User("alice".to_string()),
+            exp: 0,
+            role: Role::User,
+            projects: vec!["default".to_string()],
+        }
+    }
+
+    fn vm(cpus: u8, memory: usize, vpc: &str) -> Vm {
+        let mut vm = Vm {
+            metadata: Metadata {
+                name: "a".to_string(),
+                ..Default::default()
+            },
+            spec: Default::default(),
+            status: Default::default(),
+        };
+        vm.spec.cpus = cpus;
+        vm.spec.memory = memory;
+        vm.spec.vpc = vpc.to_string();
+        vm
+    }
+
+    #[test]
+    fn vm_defaults_only_fill_what_a_create_left_out() {
+        let defaults = VmDefaults {
+            cpus: Some(2),
+            memory: Some(1024),
+            vpc: Some("net".to_string()),
+        };
+        let claim = claim();
+        let create = AdmissionRequest {
+            claim: &claim,
+            operation: Operation::Create,
+        };
+        let update = AdmissionRequest {
+            claim: &claim,
+            operation: Operation::Update,
+        };
+
+        let filled = defaults.admit(&create, vm(0, 0, "")).unwrap();
+        assert_eq!(
+            (
+                filled.spec.cpus,
+                filled.spec.memory,
+                filled.spec.vpc.as_str()
+            ),
+            (2, 1024, "net")
+        );
+        let given = defaults.admit(&create, vm(4, 512, "other")).unwrap();
+        assert_eq!(
+            (given.spec.cpus, given.spec.memory, given.spec.vpc.as_str()),
+            (4, 512, "other")
+        );
+        let mixed = defaults.admit(&create, vm(4, 0, "")).unwrap();
+        assert_eq!(
+            (mixed.spec.cpus, mixed.spec.memory, mixed.spec.vpc.as_str()),
+            (4, 1024, "net")
+        );
+        let updated = defaults.admit(&update, vm(0, 0, "")).unwrap();
+        assert_eq!(
+            (
+                updated.spec.cpus,
+                updated.spec.memory,
+                updated.spec.vpc.as_str()
+            ),
+            (0, 0, "")
+        );
+    }
+
+    /// Appends its tag to the VM's `order` label, or rejects the VM when `reject` is set.
+    struct Tag {
+        tag: &'static str,
+        reject: bool,
+    }
+
+    impl Admission<Vm> for Tag {
+        fn admit(&self, _request: &AdmissionRequest<'_>, mut vm: Vm) -> Result<Vm, Error> {
+            if self.reject {
+                return Err(Error::Forbidden(self.tag.to_string()));
+            }
+            vm.metadata
+                .labels
+                .entry("order".to_string())
+                .or_default()
+                .push_str(self.tag);
+            Ok(vm)
+        }
+    }
+
+    #[test]
+    fn hooks_run_in_order_until_one_rejects() {
+        let claim = claim();
+        let request = AdmissionRequest {
+            claim: &claim,
+            operation: Operation::Create,
+        };
+        let tag = |tag, reject| Tag { tag, reject };
+
+        let chain = AdmissionChain::new()
+            .register(tag("a", false))
+            .register(tag("b", false))
+            .register(tag("c", false));
+        let admitted = chain.admit(&request, vm(1, 512, "net")).unwrap();
+        assert_eq!(admitted.metadata.labels["order"], "abc");
+
+        let chain = AdmissionChain::new()
+            .register(tag("a", false))
+            .register(tag("b", true))
+            .register(tag("c", true));
+        match chain.admit(&request, vm(1, 512, "net")) {
+            Err(Error::Forbidden(tag)) => assert_eq!(tag, "b"),
+            result => panic!(
+                "expected b to reject, got {:?}",
+                result.map(|vm| vm.metadata)
+            ),
+        }
+    }
+
+    #[test]
+    fn vm_chain_defaults_then_rejects_oversized_vms() {
+        let config: Config = serde_json::from_value(serde_json::json!({
+            "etcd_addr": "localhost:2379",
+            "jwt_secret": "secret",
+            "default_vm_cpus": 2,
+            "default_vm_memory": 1024,
+            "default_vm_vpc": "net",
+            "max_vm_cpus": 4,
+            "max_vm_memory": 2048,
+        }))
+        .unwrap();
+        let admissions = Admissions::new(&config);
+        let claim = claim();
+        let create = AdmissionRequest {
+            claim: &claim,
+            operation: Operation::Create,
+        };
+
+        let mut given = vm(0, 0, "");
+        given.metadata.host_name = Some("other".to_string());
+        let admitted = admissions.vms.admit(&create, given).unwrap();
+        assert_eq!(admitted.metadata.project, "default");
+        assert_eq!(admitted.metadata.created_by.as_deref(), Some("alice"));
+        assert_eq!(admitted.metadata.host_name, None);
+        assert_eq!(
+            (
+                admitted.spec.cpus,
+                admitted.spec.memory,
+                admitted.spec.vpc.as_str()
+            ),
+            (2, 1024, "net")
+        );
+        admissions.vms.admit(&create, vm(4, 2048, "net")).unwrap();
+
+        for oversized in [vm(5, 512, "net"), vm(1, 4096, "net")] {
+            assert!(matches!(
+                admissions.vms.admit(&create, oversized),
+                Err(Error::Forbidden(_))
+            ));
+        }
+        // A bad name is caught before anything else looks at the VM
+        let mut bad_name = vm(5, 512, "net");
+        bad_name.metadata.name = "a/b".to_string();
+        assert!(matches!(
+            admissions.vms.admit(&create, bad_name),
+            Err(Error::Invalid(_))
+        ));
+        // So is a project the caller can't access
+        let mut other_project = vm(5, 512, "net");
+        other_project.metadata.project = "other".to_string();
+        assert!(matches!(
+            admissions.vms.admit(&create, other_project),
+            Err(Error::Forbidden(msg)) if !msg.contains("maximum")
+        ));
+    }
+}
@@ -0,0 +1,164 @@
+use std::{fmt::Display, str::FromStr};
+
+use crate::{storage::Storage, types::Error};
+
+/// Hands out values from a pool (VNIs, multicast groups, ...) by claiming a key per value in
+/// etcd, so values are returned to the pool as soon as their owner releases them and concurrent
+/// allocations from different nodes can't hand out the same value twice.
+pub struct Allocator {
+    storage: Storage,
+    prefix: String,
+}
+
+/// The prefix of the keys claiming values from `pool`.
+pub fn pool_prefix(pool: &str) -> String {
+    format!("alloc/{}/", pool)
+}
+
+impl Allocator {
+    pub fn new(storage: Storage, pool: &str) -> Self {
+        Self {
+            storage,
+            prefix: pool_prefix(pool),
+        }
+    }
+
+    fn key(&self, value: &impl Display) -> String {
+        format!("{}{}", self.prefix, value)
+    }
+
+    /// Claims the first free candidate for `owner`, or returns the value it already holds.
+    pub async fn allocate<T, I>(&self, owner: &str, candidates: I) -> Result<Option<T>, Error>
+    where
+        T: Display + FromStr,
+        I: IntoIterator<Item = T>,
+    {
+        let claims = self.storage.claims(&self.prefix).await?;
+        if let Some(value) = claims
+            .iter()
+            .find(|(_, claim_owner)| claim_owner.as_str() == owner)
+            .and_then(|(value, _)| value.parse().ok())
+        {
+            return Ok(Some(value));
+        }
+        for candidate in candidates {
+            if claims.contains_key(&candidate.to_string()) {
+                continue;
+            }
+            if self.storage.claim(&self.key(&candidate), owner).await? {
+                return Ok(Some(candidate));
+            }
+        }
+        Ok(None)
+    }
+
+    /// Claims a specific value for `owner`, returning false if someone else holds it.
+    pub async fn claim(&self, owner: &str, value: &impl Display) -> Result<bool, Error> {
+        let key = self.key(value);
+        if self.storage.claim(&key, owner).await? {
+            return Ok(true);
+        }
+        let claims = self.storage.claims(&self.prefix).await?;
+        Ok(claims.get(&value.to_string()).map(String::as_str) == Some(owner))
+    }
+
+    /// Returns every value held by `owner` to the pool.
+    pub async fn release(&self, owner: &str) -> Result<(), Error> {
+        let claims = self.storage.claims(&self.prefix).await?;
+        for (value, claim_owner) in claims {
+            if claim_owner == owner {
+                self.storage.release(&self.key(&value), owner).await?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Orders the offsets `0..size` of a pool starting from one picked by hashing `owner`, so
+/// owners spread out over the whole pool instead of packing its start. The order depends only
+/// on `owner`, so an owner always tries the same values first.
+pub fn spread(owner: &str, size: u64) -> impl Iterator<Item = u64> {
+    let start = if size == 0 { 0 } else { fnv1a(owner) % size };
+    (0..size).map(move |i| (start + i) % size)
+}
+
+/// FNV-1a, used over `DefaultHasher` because its output is stable across Rust releases.
+fn fnv1a(s: &str) -> u64 {
+    s.bytes().fold(0xcbf2_9ce4_8422_2325, |hash, byte| {
+        (hash ^ byte as u64).wrapping_mul(0x0100_0000_01b3)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::MemoryBackend;
+
+    fn allocator() -> Allocator {
+        Allocator::new(Storage::new(MemoryBackend::new()), "vni")
+    }
+
+    #[tokio::test]
+    async fn released_values_are_handed_out_again() {
+        let allocator = allocator();
+        assert_eq!(allocator.allocate("a", 1..4u16).await.unwrap(), Some(1));
+        assert_eq!(allocator.allocate("b", 1..4u16).await.unwrap(), Some(2));
+        // An owner keeps the value it already holds
+        assert_eq!(allocator.allocate("a", 1..4u16).await.unwrap(), Some(1));
+
+        allocator.release("a").await.unwrap();
+        assert_eq!(allocator.allocate("c", 1..4u16).await.unwrap(), Some(1));
+        // Releasing what an owner doesn't hold leaves the pool alone
+        allocator.release("a").await.unwrap();
+        assert_eq!(allocator.allocate("a", 1..4u16).await.unwrap(), Some(3));
+    }
+
+    #[tokio::test]
+    async fn an_exhausted_pool_allocates_nothing() {
+        let allocator = allocator();
+        for owner in &["a", "b", "c"] {
+            assert!(allocator.allocate(owner, 1..4u16).await.unwrap().is_some());
+        }
+        assert_eq!(allocator.allocate("d", 1..4u16).await.unwrap(), None);
+        allocator.release("b").await.unwrap();
+        assert_eq!(allocator.allocate("d", 1..4u16).await.unwrap(), Some(2));
+    }
+
+    #[tokio::test]
+    async fn concurrent_claims_never_share_a_value() {
+        let storage = Storage::new(MemoryBackend::new());
+        let first = Allocator::new(storage.clone(), "vni");
+        let second = Allocator::new(storage, "vni");
+
+        let (a, b) = futures::join!(first.allocate("a", 1..3u16), second.allocate("b", 1..3u16));
+        let (a, b) = (a.unwrap().unwrap(), b.unwrap().unwrap());
+        assert_ne!(a, b);
+
+        assert!(first.claim("c", &7).await.unwrap());
+        assert!(!second.claim("d", &7).await.unwrap());
+        // Claiming a value again is fine for its owner
+        assert!(second.claim("c", &7).await.unwrap());
+        let (c, d) = futures::join!(first.claim("e", &8), second.claim("f", &8));
+        assert!(c.unwrap() ^ d.unwrap());
+    }
+
+    #[test]
+    fn spread_covers_the_pool_from_a_stable_start() {
+        let order: Vec<u64> = spread("default/net", 16).collect();
+        let mut sorted = order.clone();
+        sorted.sort_unstable();
+        assert_eq!(sorted, (0..16).collect::<Vec<_>>());
+        assert_eq!(spread("default/net", 16).collect::<Vec<_>>(), order);
+        assert_eq!(spread("default/net", 0).count(), 0);
+
+        // 1024 vpcs over a /24 of multicast groups land roughly evenly rather than at its start
+        let mut groups = [0usize; 256];
+        for i in 0..1024 {
+            let first = spread(&format!("default/vpc-{}", i), 256).next().unwrap();
+            groups[first as usize] += 1;
+        }
+        let used = groups.iter().filter(|count| **count > 0).count();
+        assert!(used > 200, "only {} of 256 groups used", used);
+        assert!(groups.iter().all(|count| *count <= 16), "{:?}", groups);
+    }
+}
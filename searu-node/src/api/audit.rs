@@ -0,0 +1,96 @@
+use std::sync::Arc;
+
+use crate::{
+    auth::Auth,
+    storage::Storage,
+    types::{encode_name, AdminClaim, AuditEntry, Error, ListResponse, Object},
+};
+
+use super::{
+    base::ExternalBase,
+    page::{DEFAULT_LIMIT, MAX_LIMIT},
+};
+use rocket::*;
+use rocket_contrib::json::Json;
+
+/// Reads the audit log oldest first. `since` is in seconds since the epoch, and `page_token`
+/// is the `next_page` of a previous response. The log isn't scoped to a project, so its tokens
+/// are issued for the empty project.
+#[get("/audit?<limit>&<since>&<user>&<object_type>&<page_token>")]
+#[allow(clippy::too_many_arguments)]
+pub async fn list(
+    storage: State<'_, Storage>,
+    auth: State<'_, Arc<Auth>>,
+    base: ExternalBase,
+    _claim: AdminClaim,
+    limit: Option<usize>,
+    since: Option<i64>,
+    user: Option<String>,
+    object_type: Option<String>,
+    page_token: Option<String>,
+) -> Result<Json<ListResponse<AuditEntry>>, Error> {
+    let limit = limit.unwrap_or(DEFAULT_LIMIT).min(MAX_LIMIT).max(1);
+    let mut start = match (page_token, since) {
+        // A range start is inclusive, so step past the last entry already returned
+        (Some(token), _) => format!("{}\0", auth.page_key(&token, AuditEntry::OBJECT_TYPE, "")?),
+        (None, Some(since)) => AuditEntry::id_prefix(since.saturating_mul(1_000_000_000)),
+        (None, None) => String::new(),
+    };
+    let mut objects = vec![];
+    let mut next_page = String::new();
+    let mut revision = None;
+    // Filters are applied after reading, so keep reading pages until enough entries match
+    loop {
+        let (entries, rev) = storage
+            .list_range::<AuditEntry>(&start, None, limit as i64, None)
+            .await?;
+        let exhausted = entries.len() < limit;
+        revision.get_or_insert(rev);
+        for entry in entries {
+            start = format!("{}\0", entry.id);
+            let matches = user
+                .as_ref()
+                .map_or(true, |u| entry.user.as_ref() == Some(u))
+                && object_type
+                    .as_ref()
+                    .map_or(true, |t| entry.object_type.as_ref() == Some(t));
+            if matches {
+                objects.push(entry);
+                if objects.len() == limit {
+                    break;
+                }
+            }
+        }
+        if objects.len() == limit {
+            next_page = objects
+                .last()
+                .map(|e| auth.page_token(AuditEntry::OBJECT_TYPE, "", &e.id))
+                .unwrap_or_default();
+            break;
+        }
+        if exhausted {
+            break;
+        }
+    }
+    let next_link = (!next_page.is_empty()).then(|| {
+        let mut query = format!("limit={}&page_token={}", limit, encode_name(&next_page));
+        if let Some(user) = user {
+            query.push_str(&format!("&user={}", encode_name(&user)));
+        }
+        if let Some(object_type) = object_type {
+            query.push_str(&format!("&object_type={}", encode_name(&object_type)));
+        }
+        base.link(&format!("/audit?{}", query))
+    });
+    Ok(ListResponse {
+        objects,
+        next_page,
+        next_link,
+        revision: revision.unwrap_or_default(),
+    }
+    .into())
+}
+
+pub fn routes() -> Vec<Route> {
+    routes![list]
+}
@@ -0,0 +1,38 @@
+use crate::{config::SharedConfig, types::Error};
+use rocket::{http::Status, outcome::Outcome, request, Request, State};
+
+/// The path the API is reachable at from outside, for building links in responses. A reverse
+/// proxy serving the API under a prefix reports it in `X-Forwarded-Prefix`, which takes
+/// precedence over `external_base_path` from the config. No other forwarding headers are read.
+pub struct ExternalBase(pub String);
+
+impl ExternalBase {
+    /// The external link to `path`, which is relative to the API root and starts with `/`.
+    pub fn link(&self, path: &str) -> String {
+        format!("{}{}", self.0, path)
+    }
+}
+
+#[rocket::async_trait]
+impl<'r> request::FromRequest<'r> for ExternalBase {
+    type Error = Error;
+
+    async fn from_request(request: &'r Request<'_>) -> request::Outcome<Self, Self::Error> {
+        let prefix = match request.headers().get_one("X-Forwarded-Prefix") {
+            Some(prefix) => prefix.to_string(),
+            None => match request.guard::<State<SharedConfig>>().await.succeeded() {
+                Some(config) => config.load().external_base_path.clone(),
+                None => {
+                    return Outcome::Failure((
+                        Status::InternalServerError,
+                        Error::NotFound("config".to_string()),
+                    ))
+                }
+            },
+        };
+        Outcome::Success(ExternalBase(format!(
+            "{}/api",
+            prefix.trim_end_matches('/')
+        )))
+    }
+}
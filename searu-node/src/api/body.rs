@@ -0,0 +1,104 @@
+use crate::{config::SharedConfig, types::Error};
+use rocket::{
+    data::{self, FromData, ToByteUnit},
+    http::Status,
+    outcome::Outcome,
+    Data, Request, State,
+};
+use serde::de::DeserializeOwned;
+use tokio::io::AsyncReadExt;
+
+/// Why a request body was rejected, cached on the request for [`super::default_catcher`], which
+/// otherwise only learns the status.
+pub struct BodyError(pub Option<String>);
+
+fn fail<T>(request: &Request<'_>, status: Status, err: Error) -> data::Outcome<T, Error> {
+    let msg = err.to_string();
+    request.local_cache(|| BodyError(Some(msg)));
+    Outcome::Failure((status, err))
+}
+
+/// A JSON request body that is checked against `max_body_size` and `max_json_depth` from the
+/// config before it's deserialized, failing with 413 or 400 respectively.
+pub struct LimitedJson<T>(pub T);
+
+impl<T> LimitedJson<T> {
+    pub fn into_inner(self) -> T {
+        self.0
+    }
+}
+
+#[rocket::async_trait]
+impl<'r, T: DeserializeOwned + Send> FromData<'r> for LimitedJson<T> {
+    type Error = Error;
+
+    async fn from_data(request: &'r Request<'_>, data: Data) -> data::Outcome<Self, Self::Error> {
+        let (max_size, max_depth) = match request.guard::<State<SharedConfig>>().await.succeeded() {
+            Some(config) => {
+                let config = config.load();
+                (config.max_body_size, config.max_json_depth)
+            }
+            None => {
+                return fail(
+                    request,
+                    Status::InternalServerError,
+                    Error::NotFound("config".to_string()),
+                )
+            }
+        };
+        // Read one byte past the limit to tell a body that fits exactly from one that doesn't
+        let mut body = vec![];
+        if let Err(err) = data
+            .open((max_size + 1).bytes())
+            .read_to_end(&mut body)
+            .await
+        {
+            return fail(request, Status::BadRequest, err.into());
+        }
+        if body.len() as u64 > max_size {
+            return fail(
+                request,
+                Status::PayloadTooLarge,
+                Error::PayloadTooLarge(format!("body is larger than {} bytes", max_size)),
+            );
+        }
+        if json_depth(&body) > max_depth {
+            return fail(
+                request,
+                Status::BadRequest,
+                Error::Invalid(format!("json nests deeper than {}", max_depth)),
+            );
+        }
+        match serde_json::from_slice(&body) {
+            Ok(value) => Outcome::Success(LimitedJson(value)),
+            Err(err) => fail(request, Status::BadRequest, err.into()),
+        }
+    }
+}
+
+/// The deepest nesting of arrays and objects in `json`, without parsing it.
+fn json_depth(json: &[u8]) -> usize {
+    let (mut depth, mut max) = (0usize, 0);
+    let (mut in_string, mut escaped) = (false, false);
+    for &byte in json {
+        if in_string {
+            match byte {
+                _ if escaped => escaped = false,
+                b'\\' => escaped = true,
+                b'"' => in_string = false,
+                _ => {}
+            }
+            continue;
+        }
+        match byte {
+            b'"' => in_string = true,
+            b'[' | b'{' => {
+                depth += 1;
+                max = max.max(depth);
+            }
+            b']' | b'}' => depth = depth.saturating_sub(1),
+            _ => {}
+        }
+    }
+    max
+}
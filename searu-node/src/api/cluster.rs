@@ -0,0 +1,91 @@
+use std::collections::{BTreeMap, BTreeSet};
+
+use crate::{
+    storage::Storage,
+    types::{AdminClaim, Capacity, ClusterConfig, ClusterSummary, Error, JwtClaim, Node, Vm, Vpc},
+};
+use rocket::*;
+use rocket_contrib::json::Json;
+
+async fn get_config(storage: &Storage) -> Result<ClusterConfig, Error> {
+    Ok(storage.get(ClusterConfig::NAME).await?.unwrap_or_default())
+}
+
+#[get("/cluster")]
+pub async fn get(
+    storage: State<'_, Storage>,
+    _claim: JwtClaim,
+) -> Result<Json<ClusterConfig>, Error> {
+    Ok(get_config(&storage).await?.into())
+}
+
+#[post("/cluster/pause")]
+pub async fn pause(
+    storage: State<'_, Storage>,
+    _claim: AdminClaim,
+) -> Result<Json<ClusterConfig>, Error> {
+    let mut config = get_config(&storage).await?;
+    config.scheduling_paused = true;
+    storage.store(&config).await?;
+    Ok(config.into())
+}
+
+#[post("/cluster/resume")]
+pub async fn resume(
+    storage: State<'_, Storage>,
+    _claim: AdminClaim,
+) -> Result<Json<ClusterConfig>, Error> {
+    let mut config = get_config(&storage).await?;
+    config.scheduling_paused = false;
+    storage.store(&config).await?;
+    // Touch the VMs left pending while paused so the scheduler sees them again.
+    let vms: Vec<Vm> = storage.list().await?;
+    for mut vm in vms {
+        if vm.status.node.is_none() && vm.status.message.is_some() {
+            vm.status.message = None;
+            storage.store(&vm).await?;
+        }
+    }
+    Ok(config.into())
+}
+
+/// Node, capacity, VM and VPC totals across the cluster, all read at one revision.
+#[get("/cluster/summary")]
+pub async fn summary(
+    storage: State<'_, Storage>,
+    _claim: AdminClaim,
+) -> Result<Json<ClusterSummary>, Error> {
+    let (nodes, revision) = storage.list_with_revision::<Node>(None).await?;
+    let (vms, _) = storage.list_with_revision::<Vm>(Some(revision)).await?;
+    let (vpcs, _) = storage.list_with_revision::<Vpc>(Some(revision)).await?;
+    let mut summary = ClusterSummary {
+        ready_nodes: nodes.len(),
+        not_ready_nodes: 0,
+        capacity: Capacity::default(),
+        allocated: Capacity::default(),
+        free: Capacity::default(),
+        vms: BTreeMap::new(),
+        vpcs: vpcs.len(),
+    };
+    for node in &nodes {
+        let allocated = node.allocated(&vms);
+        summary.capacity = summary.capacity + node.capacity();
+        summary.allocated = summary.allocated + allocated;
+        summary.free = summary.free + node.capacity().saturating_sub(allocated);
+    }
+    let mut lost = BTreeSet::new();
+    for vm in &vms {
+        *summary.vms.entry(vm.status.state.clone()).or_default() += 1;
+        if let Some(ref node) = vm.status.node {
+            if !nodes.iter().any(|n| &n.metadata.name == node) {
+                lost.insert(node);
+            }
+        }
+    }
+    summary.not_ready_nodes = lost.len();
+    Ok(summary.into())
+}
+
+pub fn routes() -> Vec<Route> {
+    routes![get, pause, resume, summary]
+}
@@ -0,0 +1,319 @@
+use crate::{
+    config::SharedConfig,
+    storage::Storage,
+    types::{AdminClaim, ClusterConfig, DhcpReservation, Error, Object, Project, User, Vm, Vpc},
+};
+use async_compression::tokio::{bufread::GzipDecoder, write::GzipEncoder};
+use rocket::{
+    data::ToByteUnit,
+    http::ContentType,
+    request::{FromRequest, Outcome},
+    response::{self, Responder},
+    Data, Request, Response, *,
+};
+use rocket_contrib::json::Json;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use tokio::io::{AsyncBufReadExt, AsyncRead, AsyncWrite, AsyncWriteExt, BufReader, DuplexStream};
+
+/// Which sides of the request are gzip encoded.
+pub struct Encoding {
+    accept_gzip: bool,
+    content_gzip: bool,
+}
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for Encoding {
+    type Error = Error;
+
+    async fn from_request(request: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+        let has_gzip = |header: &str| {
+            request
+                .headers()
+                .get(header)
+                .any(|value| value.split(',').any(|enc| enc.trim() == "gzip"))
+        };
+        Outcome::Success(Encoding {
+            accept_gzip: has_gzip("Accept-Encoding"),
+            content_gzip: has_gzip("Content-Encoding"),
+        })
+    }
+}
+
+/// The type of the record an export ends with when it fails part way, whose object is the error
+/// message.
+const ERROR_RECORD: &str = "error";
+
+#[derive(Serialize)]
+struct ExportRecord<'a, O> {
+    #[serde(rename = "type")]
+    object_type: &'a str,
+    object: &'a O,
+}
+
+#[derive(Deserialize)]
+struct ImportRecord {
+    #[serde(rename = "type")]
+    object_type: String,
+    object: Value,
+}
+
+#[derive(Serialize)]
+pub struct ImportSummary {
+    pub imported: usize,
+}
+
+/// A newline-delimited JSON body streamed as it is produced.
+pub struct NdjsonStream {
+    reader: DuplexStream,
+    gzip: bool,
+}
+
+impl<'r> Responder<'r, 'static> for NdjsonStream {
+    fn respond_to(self, _request: &'r Request<'_>) -> response::Result<'static> {
+        let mut resp = Response::build();
+        resp.header(ContentType::new("application", "x-ndjson"));
+        if self.gzip {
+            resp.raw_header("Content-Encoding", "gzip");
+        }
+        resp.streamed_body(self.reader).ok()
+    }
+}
+
+async fn write_objects<O: Object, W: AsyncWrite + Unpin>(
+    storage: &Storage,
+    writer: &mut W,
+) -> Result<(), Error> {
+    let objects: Vec<O> = storage.list().await?;
+    for object in &objects {
+        let mut line = serde_json::to_vec(&ExportRecord {
+            object_type: O::OBJECT_TYPE,
+            object,
+        })?;
+        line.push(b'\n');
+        writer.write_all(&line).await?;
+    }
+    Ok(())
+}
+
+async fn write_all_objects<W: AsyncWrite + Unpin>(
+    storage: &Storage,
+    writer: &mut W,
+) -> Result<(), Error> {
+    write_objects::<ClusterConfig, _>(storage, writer).await?;
+    write_objects::<Project, _>(storage, writer).await?;
+    write_objects::<User, _>(storage, writer).await?;
+    write_objects::<Vpc, _>(storage, writer).await?;
+    write_objects::<DhcpReservation, _>(storage, writer).await?;
+    write_objects::<Vm, _>(storage, writer).await?;
+    Ok(())
+}
+
+/// Writes an error record, so a client can tell an export that failed after the response started
+/// from one that finished.
+async fn write_error<W: AsyncWrite + Unpin>(writer: &mut W, err: &Error) -> Result<(), Error> {
+    let mut line = serde_json::to_vec(&ExportRecord {
+        object_type: ERROR_RECORD,
+        object: &err.to_string(),
+    })?;
+    line.push(b'\n');
+    writer.write_all(&line).await?;
+    Ok(())
+}
+
+async fn write_export<W: AsyncWrite + Unpin>(storage: Storage, mut writer: W) -> Result<(), Error> {
+    let res = write_all_objects(&storage, &mut writer).await;
+    if let Err(ref err) = res {
+        write_error(&mut writer, err).await?;
+    }
+    writer.shutdown().await?;
+    res
+}
+
+/// Streams every stored object as NDJSON, one `{"type", "object"}` record per line, gzipped when
+/// the client accepts it. The status is sent before the objects are read, so a failure shows up as
+/// a last record of type `error` instead.
+#[get("/export")]
+pub async fn export(
+    storage: State<'_, Storage>,
+    _claim: AdminClaim,
+    encoding: Encoding,
+) -> NdjsonStream {
+    let (reader, writer) = tokio::io::duplex(64 * 1024);
+    let storage = storage.inner().clone();
+    let gzip = encoding.accept_gzip;
+    tokio::spawn(async move {
+        let res = if gzip {
+            write_export(storage, GzipEncoder::new(writer)).await
+        } else {
+            write_export(storage, writer).await
+        };
+        if let Err(err) = res {
+            println!("export failed: {:?}", err);
+        }
+    });
+    NdjsonStream { reader, gzip }
+}
+
+async fn import_as<O: Object>(storage: &Storage, mut object: Value) -> Result<(), Error> {
+    // Versions and leases belong to the exporting cluster, and versions would fail the store's
+    // version check here
+    if let Some(metadata) = object.get_mut("metadata").and_then(Value::as_object_mut) {
+        metadata.remove("version");
+        metadata.remove("mod_revision");
+        metadata.remove("lease");
+    }
+    let object: O = serde_json::from_value(object)?;
+    storage.store(&object).await
+}
+
+async fn import_record(storage: &Storage, record: ImportRecord) -> Result<(), Error> {
+    let object_type = record.object_type.as_str();
+    if object_type == ClusterConfig::OBJECT_TYPE {
+        import_as::<ClusterConfig>(storage, record.object).await
+    } else if object_type == Project::OBJECT_TYPE {
+        import_as::<Project>(storage, record.object).await
+    } else if object_type == User::OBJECT_TYPE {
+        import_as::<User>(storage, record.object).await
+    } else if object_type == Vpc::OBJECT_TYPE {
+        import_as::<Vpc>(storage, record.object).await
+    } else if object_type == DhcpReservation::OBJECT_TYPE {
+        import_as::<DhcpReservation>(storage, record.object).await
+    } else if object_type == Vm::OBJECT_TYPE {
+        import_as::<Vm>(storage, record.object).await
+    } else if object_type == ERROR_RECORD {
+        Err(Error::Invalid(format!(
+            "the export failed: {}",
+            record.object.as_str().unwrap_or_default()
+        )))
+    } else {
+        Err(Error::Invalid(format!(
+            "unknown object type: {}",
+            object_type
+        )))
+    }
+}
+
+/// Imports an NDJSON stream in the format produced by [`export`], one record at a time, reading at
+/// most `max_import_size` bytes.
+#[post("/import", data = "<data>")]
+pub async fn import(
+    storage: State<'_, Storage>,
+    config: State<'_, SharedConfig>,
+    _claim: AdminClaim,
+    encoding: Encoding,
+    data: Data,
+) -> Result<Json<ImportSummary>, Error> {
+    let stream = data.open(config.load().max_import_size.bytes());
+    let reader: Box<dyn AsyncRead + Unpin + Send> = if encoding.content_gzip {
+        Box::new(GzipDecoder::new(BufReader::new(stream)))
+    } else {
+        Box::new(stream)
+    };
+    let mut lines = BufReader::new(reader).lines();
+    let mut imported = 0;
+    while let Some(line) = lines.next_line().await? {
+        if line.trim().is_empty() {
+            continue;
+        }
+        let record: ImportRecord = serde_json::from_str(&line)?;
+        import_record(&storage, record).await?;
+        imported += 1;
+    }
+    Ok(ImportSummary { imported }.into())
+}
+
+pub fn routes() -> Vec<Route> {
+    routes![export, import]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::{Backend, Compare, KeyValue, MemoryBackend, Op, WatchEvent};
+    use futures::stream::BoxStream;
+
+    /// Fails to read VMs, as etcd would if it went away part way through an export.
+    struct NoVms(MemoryBackend);
+
+    #[async_trait::async_trait]
+    impl Backend for NoVms {
+        async fn range(
+            &self,
+            start: &str,
+            end: Option<&str>,
+            limit: Option<i64>,
+            revision: Option<i64>,
+        ) -> Result<(Vec<KeyValue>, i64), Error> {
+            if start.starts_with(Vm::OBJECT_TYPE) {
+                return Err(Error::NotReady("etcd".to_string()));
+            }
+            self.0.range(start, end, limit, revision).await
+        }
+
+        async fn txn(&self, compares: Vec<Compare>, ops: Vec<Op>) -> Result<bool, Error> {
+            self.0.txn(compares, ops).await
+        }
+
+        async fn delete_range(&self, start: &str, end: Option<&str>) -> Result<i64, Error> {
+            self.0.delete_range(start, end).await
+        }
+
+        async fn grant_lease(&self, ttl: i64) -> Result<i64, Error> {
+            self.0.grant_lease(ttl).await
+        }
+
+        async fn keep_alive(&self, lease: i64) -> Result<Option<i64>, Error> {
+            self.0.keep_alive(lease).await
+        }
+
+        async fn revoke_lease(&self, lease: i64) -> Result<(), Error> {
+            self.0.revoke_lease(lease).await
+        }
+
+        async fn watch(
+            &self,
+            start: &str,
+            end: Option<&str>,
+        ) -> Result<BoxStream<'static, WatchEvent>, Error> {
+            self.0.watch(start, end).await
+        }
+    }
+
+    #[tokio::test]
+    async fn a_failed_export_ends_with_an_error_record() {
+        let storage = Storage::new(NoVms(MemoryBackend::new()));
+        let project = Project {
+            name: "default".to_string(),
+        };
+        storage.store(&project).await.unwrap();
+
+        let mut out = vec![];
+        assert!(write_export(storage, &mut out).await.is_err());
+        let records: Vec<ImportRecord> = String::from_utf8(out)
+            .unwrap()
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        let types: Vec<&str> = records.iter().map(|r| r.object_type.as_str()).collect();
+        assert_eq!(types, vec![Project::OBJECT_TYPE, ERROR_RECORD]);
+
+        // Importing it stops at the error rather than passing for a complete export
+        let target = Storage::new(MemoryBackend::new());
+        let mut imported = 0;
+        let mut failed = None;
+        for record in records {
+            match import_record(&target, record).await {
+                Ok(()) => imported += 1,
+                Err(err) => {
+                    failed = Some(err);
+                    break;
+                }
+            }
+        }
+        assert_eq!(imported, 1);
+        assert!(
+            matches!(failed, Some(Error::Invalid(msg)) if msg.starts_with("the export failed"))
+        );
+    }
+}
@@ -1,22 +1,77 @@
-use rocket::*;
+use crate::{
+    metrics::Metrics,
+    types::{BuildInfo, ErrorResponse},
+};
+use chrono::{TimeZone, Utc};
+use rocket::{http::Status, *};
+use rocket_contrib::json::Json;
 
+mod audit;
+mod base;
+mod body;
+mod cluster;
+mod export;
 mod nodes;
+mod operations;
+mod page;
 mod projects;
+mod reservations;
+mod snapshots;
 mod users;
 mod vms;
 mod vpcs;
 
+/// The legacy version probe, which has always answered `v0.0.1`. Clients should use
+/// `/version` instead.
 #[get("/")]
 pub fn index() -> &'static str {
     "v0.0.1"
 }
 
+/// Prometheus metrics. Left unauthenticated so scrapers don't need a token.
+#[get("/metrics")]
+pub fn metrics(metrics: State<'_, Metrics>) -> String {
+    metrics.render()
+}
+
+#[get("/version")]
+pub fn version() -> Json<BuildInfo> {
+    let timestamp = env!("SEARU_BUILD_TIMESTAMP").parse().unwrap_or(0);
+    BuildInfo {
+        version: env!("CARGO_PKG_VERSION").to_string(),
+        git_commit: env!("SEARU_GIT_COMMIT").to_string(),
+        build_timestamp: Utc.timestamp(timestamp, 0).to_rfc3339(),
+    }
+    .into()
+}
+
+/// Answers requests that fail before reaching a route, like a body that doesn't parse or a
+/// missing token, with the same JSON as errors from routes instead of Rocket's HTML page.
+#[catch(default)]
+pub fn default_catcher(status: Status, request: &Request<'_>) -> (Status, Json<ErrorResponse>) {
+    let msg = match request.local_cache(|| body::BodyError(None)) {
+        body::BodyError(Some(msg)) => msg.clone(),
+        body::BodyError(None) => status.to_string(),
+    };
+    (status, Json(ErrorResponse { msg }))
+}
+
+pub fn catchers() -> Vec<Catcher> {
+    catchers![default_catcher]
+}
+
 pub fn routes() -> Vec<Route> {
-    let mut routes = routes![index];
+    let mut routes = routes![index, version, metrics];
     routes.append(&mut users::routes());
     routes.append(&mut projects::routes());
     routes.append(&mut nodes::routes());
+    routes.append(&mut operations::routes());
     routes.append(&mut vms::routes());
+    routes.append(&mut snapshots::routes());
     routes.append(&mut vpcs::routes());
+    routes.append(&mut reservations::routes());
+    routes.append(&mut cluster::routes());
+    routes.append(&mut export::routes());
+    routes.append(&mut audit::routes());
     routes
 }
@@ -1,21 +1,80 @@
+use std::{collections::BTreeMap, sync::Arc};
+
 use crate::{
+    auth::Auth,
+    clock::SharedClock,
     storage::Storage,
-    types::{Error, JwtClaim, ListResponse, Node},
+    types::{
+        validate_labels, AdminClaim, Error, JwtClaim, LabelSelector, ListResponse, Metadata, Node,
+        Operation, OperationKind, OperationState, Vm,
+    },
 };
 use rocket::*;
 use rocket_contrib::json::Json;
 
-#[get("/nodes")]
+use super::{
+    base::ExternalBase,
+    body::LimitedJson,
+    page::{list_page, PageQuery},
+};
+
+/// Lists nodes, optionally only those whose labels match `selector` and that have at least
+/// `min_cpus` cpus and `min_memory` MiB left after the VMs already scheduled onto them.
+#[get("/nodes?<revision>&<selector>&<min_cpus>&<min_memory>&<limit>&<page_token>")]
+#[allow(clippy::too_many_arguments)]
 pub async fn list(
     storage: State<'_, Storage>,
+    auth: State<'_, Arc<Auth>>,
+    base: ExternalBase,
     _claim: JwtClaim,
+    revision: Option<i64>,
+    selector: Option<String>,
+    min_cpus: Option<usize>,
+    min_memory: Option<u64>,
+    limit: Option<usize>,
+    page_token: Option<String>,
 ) -> Result<Json<ListResponse<Node>>, Error> {
-    let objects = storage.list().await?;
-    Ok(ListResponse {
-        objects,
-        next_page: "".to_string(),
-    }
-    .into())
+    let mut filters = vec![];
+    let selector = match selector {
+        Some(selector) => {
+            let parsed: LabelSelector = selector.parse()?;
+            filters.push(("selector", selector));
+            Some(parsed)
+        }
+        None => None,
+    };
+    let sized = min_cpus.is_some() || min_memory.is_some();
+    // Free capacity is computed from the VMs at the revision the nodes are read at
+    let (vms, revision) = if sized {
+        let (vms, revision) = storage.list_with_revision::<Vm>(revision).await?;
+        (vms, Some(revision))
+    } else {
+        (vec![], revision)
+    };
+    filters.extend(min_cpus.map(|cpus| ("min_cpus", cpus.to_string())));
+    filters.extend(min_memory.map(|memory| ("min_memory", memory.to_string())));
+    let query = PageQuery {
+        path: "/nodes",
+        project: "",
+        limit,
+        page_token,
+        revision,
+        filters,
+    };
+    let list = list_page(&storage, &auth, &base, query, |node: &Node| {
+        if let Some(ref selector) = selector {
+            if !selector.matches(&node.metadata.labels) {
+                return false;
+            }
+        }
+        if !sized {
+            return true;
+        }
+        let free = node.free(&vms);
+        free.cpus >= min_cpus.unwrap_or(0) && free.memory >= min_memory.unwrap_or(0)
+    })
+    .await?;
+    Ok(list.into())
 }
 
 #[get("/nodes/<id>")]
@@ -31,6 +90,111 @@ pub async fn get(
     Ok(node.into())
 }
 
+/// Merges labels into a node's, removing those set to `null`. Heartbeats keep labels, so they
+/// last until changed here.
+#[post("/nodes/<id>/labels", data = "<labels>", format = "json")]
+pub async fn labels(
+    storage: State<'_, Storage>,
+    _claim: AdminClaim,
+    id: String,
+    labels: LimitedJson<BTreeMap<String, Option<String>>>,
+) -> Result<Json<Node>, Error> {
+    let labels = labels.into_inner();
+    validate_labels(
+        labels
+            .iter()
+            .filter_map(|(key, value)| Some((key, value.as_ref()?))),
+    )?;
+    // The node's own heartbeat may land in between, so retry on a conflict
+    let mut attempts = 0;
+    loop {
+        let mut node: Node = storage
+            .get(&id)
+            .await?
+            .ok_or_else(|| Error::NotFound(format!("node: {}", id)))?;
+        for (key, value) in &labels {
+            match value {
+                Some(value) => node.metadata.labels.insert(key.clone(), value.clone()),
+                None => node.metadata.labels.remove(key),
+            };
+        }
+        match storage.update(&node).await {
+            Err(Error::Conflict(_)) if attempts < 3 => attempts += 1,
+            Err(err) => return Err(err),
+            Ok(()) => return Ok(node.into()),
+        }
+    }
+}
+
+/// Stops new VMs from being placed on a node. VMs already on it keep running; use
+/// `/nodes/<id>/evacuate` to move them off too.
+#[post("/nodes/<id>/cordon")]
+pub async fn cordon(
+    storage: State<'_, Storage>,
+    _claim: AdminClaim,
+    id: String,
+) -> Result<Json<Node>, Error> {
+    Ok(set_cordoned(&storage, &id, true).await?.into())
+}
+
+/// Lets VMs be placed on a node again.
+#[post("/nodes/<id>/uncordon")]
+pub async fn uncordon(
+    storage: State<'_, Storage>,
+    _claim: AdminClaim,
+    id: String,
+) -> Result<Json<Node>, Error> {
+    Ok(set_cordoned(&storage, &id, false).await?.into())
+}
+
+/// Sets whether a node is cordoned, retrying if its heartbeat lands in between.
+async fn set_cordoned(storage: &Storage, id: &str, cordoned: bool) -> Result<Node, Error> {
+    let mut attempts = 0;
+    loop {
+        let mut node: Node = storage
+            .get(id)
+            .await?
+            .ok_or_else(|| Error::NotFound(format!("node: {}", id)))?;
+        if node.cordoned == cordoned {
+            return Ok(node);
+        }
+        node.cordoned = cordoned;
+        match storage.update(&node).await {
+            Err(Error::Conflict(_)) if attempts < 3 => attempts += 1,
+            Err(err) => return Err(err),
+            Ok(()) => return Ok(node),
+        }
+    }
+}
+
+/// Cordons a node and starts moving its VMs elsewhere, returning the operation to poll at
+/// `/operations/<id>`. The operation is run by this node's API.
+#[post("/nodes/<id>/evacuate")]
+pub async fn evacuate(
+    storage: State<'_, Storage>,
+    clock: State<'_, SharedClock>,
+    claim: AdminClaim,
+    id: String,
+) -> Result<Json<Operation>, Error> {
+    set_cordoned(&storage, &id, true).await?;
+    let op = Operation {
+        metadata: Metadata {
+            name: Operation::id(),
+            created_by: Some(claim.0.username().to_string()),
+            ..Default::default()
+        },
+        kind: OperationKind::Evacuate { node: id },
+        runner: sys_info::hostname()?,
+        state: OperationState::Running,
+        started_at: clock.now().timestamp(),
+        finished_at: None,
+        total: 0,
+        results: vec![],
+    };
+    storage.create(&op).await?;
+    Ok(op.into())
+}
+
 pub fn routes() -> Vec<Route> {
-    routes![list, get]
+    routes![list, get, labels, cordon, uncordon, evacuate]
 }
@@ -0,0 +1,24 @@
+use crate::{
+    storage::Storage,
+    types::{AdminClaim, Error, Operation},
+};
+use rocket::*;
+use rocket_contrib::json::Json;
+
+/// Reports an operation's progress, with the outcome for each object handled so far.
+#[get("/operations/<id>")]
+pub async fn get(
+    storage: State<'_, Storage>,
+    _claim: AdminClaim,
+    id: String,
+) -> Result<Json<Operation>, Error> {
+    let op: Operation = storage
+        .get(&id)
+        .await?
+        .ok_or_else(|| Error::NotFound(format!("operation: {}", id)))?;
+    Ok(op.into())
+}
+
+pub fn routes() -> Vec<Route> {
+    routes![get]
+}
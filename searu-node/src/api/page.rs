@@ -0,0 +1,109 @@
+use crate::{
+    auth::Auth,
+    storage::Storage,
+    types::{encode_name, Error, ListResponse, Object},
+};
+
+use super::base::ExternalBase;
+
+pub(super) const DEFAULT_LIMIT: usize = 100;
+pub(super) const MAX_LIMIT: usize = 1000;
+
+/// The paging parameters of a list request, and what's needed to link to its next page.
+pub(super) struct PageQuery<'a> {
+    /// The route's path, like `/vms`, for `next_link`.
+    pub path: &'a str,
+    /// The project the listing is limited to, `""` if it isn't. Tokens only continue listings
+    /// of the same type and project.
+    pub project: &'a str,
+    pub limit: Option<usize>,
+    pub page_token: Option<String>,
+    pub revision: Option<i64>,
+    /// The route's other query parameters, repeated in `next_link`.
+    pub filters: Vec<(&'static str, String)>,
+}
+
+/// Lists the objects of type `O` that `keep` accepts, in key order. Without a `limit` or
+/// `page_token` everything comes back at once. Otherwise up to `limit` objects are read after
+/// where `page_token` left off, and `next_page` is set unless the last object was reached.
+/// Every page is read at the revision of the first, carried in `next_link`, so a listing that
+/// is followed through sees one snapshot until etcd compacts it.
+pub(super) async fn list_page<O: Object>(
+    storage: &Storage,
+    auth: &Auth,
+    base: &ExternalBase,
+    query: PageQuery<'_>,
+    mut keep: impl FnMut(&O) -> bool,
+) -> Result<ListResponse<O>, Error> {
+    if query.limit.is_none() && query.page_token.is_none() {
+        let (mut objects, revision) = storage.list_with_revision::<O>(query.revision).await?;
+        objects.retain(|object| keep(object));
+        return Ok(ListResponse {
+            objects,
+            next_page: String::new(),
+            next_link: None,
+            revision,
+        });
+    }
+    let limit = query.limit.unwrap_or(DEFAULT_LIMIT).min(MAX_LIMIT).max(1);
+    // The objects of a project scoped type in one project are a range of their own
+    let (first, end) = if O::PROJECT_SCOPED && !query.project.is_empty() {
+        let project = encode_name(query.project);
+        (format!("{}/", project), Some(format!("{}0", project)))
+    } else {
+        (String::new(), None)
+    };
+    let mut start = match query.page_token {
+        // A range start is inclusive, so step past the last object already returned
+        Some(ref token) => format!("{}\0", auth.page_key(token, O::OBJECT_TYPE, query.project)?),
+        None => first,
+    };
+    let mut objects = vec![];
+    let mut next_page = String::new();
+    let mut at = query.revision;
+    // Filters are applied after reading, so keep reading until enough objects match
+    loop {
+        let (batch, rev) = storage
+            .list_range::<O>(&start, end.as_deref(), limit as i64, at)
+            .await?;
+        let revision = *at.get_or_insert(rev);
+        let (count, exhausted) = (batch.len(), batch.len() < limit);
+        let mut read = 0;
+        let mut last = String::new();
+        for object in batch {
+            read += 1;
+            last = object.key()[O::OBJECT_TYPE.len() + 1..].to_string();
+            start = format!("{}\0", last);
+            if keep(&object) {
+                objects.push(object);
+                if objects.len() == limit {
+                    break;
+                }
+            }
+        }
+        if objects.len() == limit && (read < count || !exhausted) {
+            next_page = auth.page_token(O::OBJECT_TYPE, query.project, &last);
+        }
+        if objects.len() == limit || exhausted {
+            let next_link = (!next_page.is_empty()).then(|| {
+                let mut link = format!(
+                    "{}?limit={}&page_token={}&revision={}",
+                    query.path,
+                    limit,
+                    encode_name(&next_page),
+                    revision
+                );
+                for (name, value) in &query.filters {
+                    link.push_str(&format!("&{}={}", name, encode_name(value)));
+                }
+                base.link(&link)
+            });
+            return Ok(ListResponse {
+                objects,
+                next_page,
+                next_link,
+                revision,
+            });
+        }
+    }
+}
@@ -1,34 +1,248 @@
+use std::sync::Arc;
+
 use crate::{
+    auth::Auth,
     storage::Storage,
-    types::{Error, JwtClaim, ListResponse, Project},
+    types::{
+        scoped_name, validate_name, AdminClaim, Error, JwtClaim, ListResponse, Metadata, Project,
+        ProjectUsage, Quota, Vm, Vpc,
+    },
 };
 use rocket::*;
 use rocket_contrib::json::Json;
 
+use super::{
+    base::ExternalBase,
+    body::LimitedJson,
+    page::{list_page, PageQuery},
+};
+
 #[post("/projects", data = "<project>", format = "json")]
 pub async fn create(
     storage: State<'_, Storage>,
     _claim: JwtClaim,
-    project: Json<Project>,
+    project: LimitedJson<Project>,
 ) -> Result<Json<Project>, Error> {
     let project = project.into_inner();
-    storage.store(&project).await?;
+    validate_name(&project.name)?;
+    storage.create(&project).await?;
     Ok(project.into())
 }
 
-#[get("/projects")]
+#[get("/projects?<revision>&<limit>&<page_token>")]
 pub async fn list(
     storage: State<'_, Storage>,
+    auth: State<'_, Arc<Auth>>,
+    base: ExternalBase,
     _claim: JwtClaim,
+    revision: Option<i64>,
+    limit: Option<usize>,
+    page_token: Option<String>,
 ) -> Result<Json<ListResponse<Project>>, Error> {
-    let objects = storage.list().await?;
-    Ok(ListResponse {
-        objects,
-        next_page: "".to_string(),
+    let query = PageQuery {
+        path: "/projects",
+        project: "",
+        limit,
+        page_token,
+        revision,
+        filters: vec![],
+    };
+    Ok(list_page(&storage, &auth, &base, query, |_: &Project| true)
+        .await?
+        .into())
+}
+
+/// Sets a project's quota, replacing any it had. Applies to creates from then on; what the
+/// project already has is left running even if it's over.
+#[put("/projects/<name>/quota", data = "<quota>", format = "json")]
+pub async fn set_quota(
+    storage: State<'_, Storage>,
+    _claim: AdminClaim,
+    name: &str,
+    quota: LimitedJson<Quota>,
+) -> Result<Json<Quota>, Error> {
+    storage
+        .get::<Project>(name)
+        .await?
+        .ok_or_else(|| Error::NotFound(format!("project: {}", name)))?;
+    let mut quota = quota.into_inner();
+    quota.project = name.to_string();
+    storage.store(&quota).await?;
+    Ok(quota.into())
+}
+
+#[get("/projects/<name>/quota")]
+pub async fn get_quota(
+    storage: State<'_, Storage>,
+    claim: JwtClaim,
+    name: &str,
+) -> Result<Json<Quota>, Error> {
+    if !claim.can_access(name) {
+        return Err(Error::Forbidden(format!("project: {}", name)));
+    }
+    let quota = storage.get::<Quota>(name).await?.unwrap_or_else(|| Quota {
+        project: name.to_string(),
+        ..Default::default()
+    });
+    Ok(quota.into())
+}
+
+/// Which objects a listing shows: those in `project`, which the claim must have access to, or
+/// those in any project it can access when `project` is `None`.
+pub(super) fn visible<'a>(
+    claim: &'a JwtClaim,
+    project: Option<&'a str>,
+) -> Result<impl Fn(&Metadata) -> bool + 'a, Error> {
+    if let Some(project) = project {
+        if !claim.can_access(project) {
+            return Err(Error::Forbidden(format!("project: {}", project)));
+        }
     }
-    .into())
+    Ok(move |metadata: &Metadata| match project {
+        Some(project) => metadata.project == project,
+        None => claim.can_access(&metadata.project),
+    })
+}
+
+/// The key name of the object called `name` in `project`, defaulting to the claim's first
+/// project, which the claim must have access to.
+pub(super) fn scoped_key(
+    claim: &JwtClaim,
+    project: Option<&str>,
+    name: &str,
+) -> Result<String, Error> {
+    let mut project = project.unwrap_or_default().to_string();
+    claim.authorize_project(&mut project)?;
+    Ok(scoped_name(&project, name))
+}
+
+/// Checks that adding `adding` to what `project` already uses stays within its quota, if it
+/// has one. `replacing` names a VM of the project being changed, which is left out of the
+/// existing usage since `adding` counts it in its new shape. Only the kinds of object being
+/// added are counted, so a project over a lowered VPC quota can still create VMs.
+pub(super) async fn check_quota(
+    storage: &Storage,
+    project: &str,
+    adding: ProjectUsage,
+    replacing: Option<&str>,
+) -> Result<(), Error> {
+    let quota: Quota = match storage.get(project).await? {
+        Some(quota) => quota,
+        None => return Ok(()),
+    };
+    let mut usage = adding;
+    if adding.vms > 0 {
+        let vms: Vec<Vm> = storage.list_in_project(project).await?;
+        for vm in vms
+            .iter()
+            .filter(|vm| Some(vm.metadata.name.as_str()) != replacing)
+        {
+            usage.cpus += vm.spec.cpus as usize;
+            usage.memory += vm.spec.memory as u64;
+            usage.vms += 1;
+        }
+    }
+    if adding.vpcs > 0 {
+        usage.vpcs += storage.list_in_project::<Vpc>(project).await?.len();
+    }
+    quota.check(&usage)
 }
 
 pub fn routes() -> Vec<Route> {
-    routes![create, list]
+    routes![create, list, set_quota, get_quota]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        storage::MemoryBackend,
+        types::{Metadata, VmSpec, VpcSpec},
+    };
+
+    fn vm(project: &str, name: &str, cpus: u8) -> Vm {
+        Vm {
+            metadata: Metadata {
+                name: name.to_string(),
+                project: project.to_string(),
+                ..Default::default()
+            },
+            spec: VmSpec {
+                vpc: "net".to_string(),
+                cpus,
+                memory: 512,
+                ..Default::default()
+            },
+            status: Default::default(),
+        }
+    }
+
+    fn spec(cpus: u8) -> VmSpec {
+        vm("", "", cpus).spec
+    }
+
+    #[tokio::test]
+    async fn creates_stop_at_the_quota() {
+        let storage = Storage::new(MemoryBackend::new());
+        check_quota(&storage, "team", ProjectUsage::vm(&spec(64)), None)
+            .await
+            .unwrap();
+        storage
+            .store(&Quota {
+                project: "team".to_string(),
+                max_cpus: Some(4),
+                max_memory: None,
+                max_vms: Some(2),
+                max_vpcs: Some(1),
+            })
+            .await
+            .unwrap();
+
+        // Within the quota, other projects not counting
+        storage.create(&vm("team", "a", 2)).await.unwrap();
+        storage.create(&vm("other", "b", 8)).await.unwrap();
+        check_quota(&storage, "team", ProjectUsage::vm(&spec(2)), None)
+            .await
+            .unwrap();
+        storage.create(&vm("team", "b", 2)).await.unwrap();
+
+        // Hitting it
+        match check_quota(&storage, "team", ProjectUsage::vm(&spec(0)), None).await {
+            Err(Error::Forbidden(msg)) => {
+                assert_eq!(msg, "project team would use 3 vms, over its quota of 2")
+            }
+            result => panic!("expected the quota to be hit, got {:?}", result),
+        }
+        // Resizing counts the vm in its new shape only
+        check_quota(&storage, "team", ProjectUsage::vm(&spec(2)), Some("a"))
+            .await
+            .unwrap();
+        assert!(matches!(
+            check_quota(&storage, "team", ProjectUsage::vm(&spec(3)), Some("a")).await,
+            Err(Error::Forbidden(msg)) if msg.contains("5 cpus")
+        ));
+
+        let vpc = Vpc {
+            metadata: Metadata {
+                name: "net".to_string(),
+                project: "team".to_string(),
+                ..Default::default()
+            },
+            spec: VpcSpec {
+                subnet: "10.0.0.0/24".parse().unwrap(),
+                multicast_ip: None,
+                vni: None,
+                nat_gateway: false,
+            },
+            status: Default::default(),
+        };
+        check_quota(&storage, "team", ProjectUsage::vpc(), None)
+            .await
+            .unwrap();
+        storage.create(&vpc).await.unwrap();
+        assert!(matches!(
+            check_quota(&storage, "team", ProjectUsage::vpc(), None).await,
+            Err(Error::Forbidden(msg)) if msg.contains("2 vpcs")
+        ));
+    }
 }
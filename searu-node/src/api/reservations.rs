@@ -0,0 +1,183 @@
+use crate::{
+    admission::{AdmissionRequest, Admissions, Operation},
+    storage::Storage,
+    types::{DhcpReservation, Error, JwtClaim, ListResponse, Vpc},
+};
+use rocket::*;
+use rocket_contrib::json::Json;
+
+use super::body::LimitedJson;
+
+/// The VPC a reservation is in, if the claim may access its project. A reservation hands out
+/// addresses on the VPC, so it's the VPC's project and not only the reservation's that counts.
+async fn get_vpc(
+    storage: &Storage,
+    reservation: &DhcpReservation,
+    claim: &JwtClaim,
+) -> Result<Vpc, Error> {
+    let key = reservation.vpc_key_name();
+    let vpc: Vpc = storage
+        .get(&key)
+        .await?
+        .ok_or_else(|| Error::NotFound(format!("vpc: {}", key)))?;
+    if !claim.can_access(&vpc.metadata.project) {
+        return Err(Error::Forbidden(format!(
+            "project: {}",
+            vpc.metadata.project
+        )));
+    }
+    Ok(vpc)
+}
+
+/// Fails with [`Error::Conflict`] if another reservation in the same VPC holds the reservation's
+/// IP or MAC, as dnsmasq would only honour one of them.
+async fn check_unique(storage: &Storage, reservation: &DhcpReservation) -> Result<(), Error> {
+    let vpc = reservation.vpc_key_name();
+    for other in storage.list::<DhcpReservation>().await? {
+        if other.vpc_key_name() != vpc {
+            continue;
+        }
+        if other.spec.ip == reservation.spec.ip {
+            return Err(Error::Conflict(format!(
+                "ip {} is reserved by {}",
+                other.spec.ip, other.metadata.name
+            )));
+        }
+        if other.spec.mac == reservation.spec.mac {
+            return Err(Error::Conflict(format!(
+                "mac {} is reserved by {}",
+                other.spec.mac, other.metadata.name
+            )));
+        }
+    }
+    Ok(())
+}
+
+#[post("/reservations", data = "<reservation>", format = "json")]
+pub async fn create(
+    storage: State<'_, Storage>,
+    admissions: State<'_, Admissions>,
+    claim: JwtClaim,
+    reservation: LimitedJson<DhcpReservation>,
+) -> Result<Json<DhcpReservation>, Error> {
+    let request = AdmissionRequest {
+        claim: &claim,
+        operation: Operation::Create,
+    };
+    let reservation = admissions
+        .reservations
+        .admit(&request, reservation.into_inner())?;
+    let vpc = get_vpc(&storage, &reservation, &claim).await?;
+    reservation.spec.validate(&vpc.spec)?;
+    check_unique(&storage, &reservation).await?;
+    storage.create(&reservation).await?;
+    Ok(reservation.into())
+}
+
+#[get("/reservations?<revision>")]
+pub async fn list(
+    storage: State<'_, Storage>,
+    claim: JwtClaim,
+    revision: Option<i64>,
+) -> Result<Json<ListResponse<DhcpReservation>>, Error> {
+    let (mut objects, revision) = storage
+        .list_with_revision::<DhcpReservation>(revision)
+        .await?;
+    objects.retain(|reservation| claim.can_access(&reservation.metadata.project));
+    Ok(ListResponse {
+        objects,
+        next_page: "".to_string(),
+        next_link: None,
+        revision,
+    }
+    .into())
+}
+
+/// Deletes a reservation, which takes access to both its own project and its VPC's. A
+/// reservation whose VPC is gone only needs the former.
+#[delete("/reservations/<name>")]
+pub async fn delete(storage: State<'_, Storage>, name: &str, claim: JwtClaim) -> Result<(), Error> {
+    let reservation: DhcpReservation = storage
+        .get(name)
+        .await?
+        .ok_or_else(|| Error::NotFound(format!("reservation: {}", name)))?;
+    if !claim.can_access(&reservation.metadata.project) {
+        return Err(Error::Forbidden(format!(
+            "project: {}",
+            reservation.metadata.project
+        )));
+    }
+    match get_vpc(&storage, &reservation, &claim).await {
+        Ok(_) | Err(Error::NotFound(_)) => {}
+        Err(err) => return Err(err),
+    }
+    storage.delete::<DhcpReservation>(name).await?;
+    Ok(())
+}
+
+pub fn routes() -> Vec<Route> {
+    routes![list, create, delete]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        storage::MemoryBackend,
+        types::{DhcpReservationSpec, Metadata},
+        vmm::MacAddr,
+    };
+
+    fn reservation(name: &str, vpc: &str, mac: &str, ip: &str) -> DhcpReservation {
+        DhcpReservation {
+            metadata: Metadata {
+                name: name.to_string(),
+                project: "default".to_string(),
+                ..Default::default()
+            },
+            spec: DhcpReservationSpec {
+                vpc: vpc.to_string(),
+                mac: MacAddr::parse_str(mac).unwrap(),
+                ip: ip.parse().unwrap(),
+            },
+        }
+    }
+
+    #[tokio::test]
+    async fn ips_and_macs_are_unique_within_a_vpc() {
+        let storage = Storage::new(MemoryBackend::new());
+        storage
+            .create(&reservation("a", "net", "52:54:00:00:00:01", "10.0.0.5"))
+            .await
+            .unwrap();
+
+        match check_unique(
+            &storage,
+            &reservation("b", "net", "52:54:00:00:00:02", "10.0.0.5"),
+        )
+        .await
+        {
+            Err(Error::Conflict(msg)) => assert_eq!(msg, "ip 10.0.0.5 is reserved by a"),
+            result => panic!("expected a conflict, got {:?}", result),
+        }
+        match check_unique(
+            &storage,
+            &reservation("b", "net", "52:54:00:00:00:01", "10.0.0.6"),
+        )
+        .await
+        {
+            Err(Error::Conflict(msg)) => {
+                assert_eq!(msg, "mac 52:54:00:00:00:01 is reserved by a")
+            }
+            result => panic!("expected a conflict, got {:?}", result),
+        }
+
+        // The same values are free in another VPC
+        check_unique(
+            &storage,
+            &reservation("b", "other", "52:54:00:00:00:01", "10.0.0.5"),
+        )
+        .await
+        .unwrap();
+    }
+}
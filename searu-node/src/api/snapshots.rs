@@ -0,0 +1,139 @@
+use std::time::Duration;
+
+use crate::{
+    actors::{vm_snapshot, HypervisorSockets},
+    clock::SharedClock,
+    config::SharedConfig,
+    storage::Storage,
+    types::{scoped_name, Error, JwtClaim, ListResponse, Metadata, Snapshot},
+};
+use rocket::*;
+use rocket_contrib::json::Json;
+
+use super::{projects::scoped_key, vms::get_vm};
+
+/// Snapshots a VM running on this node. Like `/vms/<name>/info`, it has to be asked of the API
+/// on the VM's node, and the VM is looked up in `project`.
+#[post("/vms/<name>/snapshots?<project>")]
+#[allow(clippy::too_many_arguments)]
+pub async fn create(
+    storage: State<'_, Storage>,
+    sockets: State<'_, HypervisorSockets>,
+    config: State<'_, SharedConfig>,
+    clock: State<'_, SharedClock>,
+    name: &str,
+    project: Option<&str>,
+    claim: JwtClaim,
+) -> Result<Json<Snapshot>, Error> {
+    let key = scoped_key(&claim, project, name)?;
+    let vm = get_vm(&storage, &key, &claim).await?;
+    let hostname = sys_info::hostname()?;
+    match vm.status.node {
+        Some(ref node) if *node == hostname => {}
+        Some(node) => {
+            return Err(Error::NotFound(format!(
+                "vm {} runs on node {}, ask its api",
+                name, node
+            )))
+        }
+        None => return Err(Error::NotFound(format!("vm {} isn't scheduled", name))),
+    }
+    let socket_path = sockets
+        .get(&key)
+        .ok_or_else(|| Error::NotFound(format!("vm {} isn't running", name)))?;
+    let id = Snapshot::id(name);
+    let config = config.load_full();
+    let path = config.snapshot_dir.join(&id);
+    tokio::fs::create_dir_all(&path).await?;
+    let timeout = Duration::from_secs(config.hypervisor_timeout);
+    if let Err(err) = vm_snapshot(&socket_path, &path, timeout).await {
+        let _ = tokio::fs::remove_dir_all(&path).await;
+        return Err(err);
+    }
+    let snapshot = Snapshot {
+        metadata: Metadata {
+            name: id,
+            project: vm.metadata.project.clone(),
+            created_by: Some(claim.username().to_string()),
+            ..Default::default()
+        },
+        vm: name.to_string(),
+        node: hostname,
+        path,
+        created_at: clock.now().timestamp(),
+        restoring: false,
+    };
+    storage.create(&snapshot).await?;
+    Ok(snapshot.into())
+}
+
+#[get("/vms/<name>/snapshots?<project>")]
+pub async fn list(
+    storage: State<'_, Storage>,
+    name: &str,
+    project: Option<&str>,
+    claim: JwtClaim,
+) -> Result<Json<ListResponse<Snapshot>>, Error> {
+    let key = scoped_key(&claim, project, name)?;
+    let vm = get_vm(&storage, &key, &claim).await?;
+    let (snapshots, revision) = storage.list_with_revision::<Snapshot>(None).await?;
+    let mut objects: Vec<Snapshot> = snapshots
+        .into_iter()
+        .filter(|snapshot| snapshot.vm == name && snapshot.metadata.project == vm.metadata.project)
+        .collect();
+    objects.sort_by_key(|snapshot| snapshot.created_at);
+    Ok(ListResponse {
+        objects,
+        next_page: "".to_string(),
+        next_link: None,
+        revision,
+    }
+    .into())
+}
+
+/// Deletes a snapshot and its files, which has to be asked of the API on the node holding them.
+/// The VM needn't exist anymore, but a snapshot being restored from can't be deleted.
+#[delete("/vms/<name>/snapshots/<id>?<project>")]
+pub async fn delete(
+    storage: State<'_, Storage>,
+    name: &str,
+    id: &str,
+    project: Option<&str>,
+    claim: JwtClaim,
+) -> Result<(), Error> {
+    let key = scoped_key(&claim, project, name)?;
+    let snapshot: Snapshot = storage
+        .get(id)
+        .await?
+        .filter(|snapshot: &Snapshot| scoped_name(&snapshot.metadata.project, &snapshot.vm) == key)
+        .ok_or_else(|| Error::NotFound(format!("snapshot: {}", id)))?;
+    if !claim.can_access(&snapshot.metadata.project) {
+        return Err(Error::Forbidden(format!(
+            "project: {}",
+            snapshot.metadata.project
+        )));
+    }
+    if snapshot.restoring {
+        return Err(Error::Conflict(format!(
+            "snapshot {} is being restored from",
+            id
+        )));
+    }
+    if snapshot.node != sys_info::hostname()? {
+        return Err(Error::NotFound(format!(
+            "snapshot {} is on node {}, ask its api",
+            id, snapshot.node
+        )));
+    }
+    // Deleting the object only if it's unchanged keeps a restore that started since it was read
+    // from losing its files
+    storage.delete_if_unchanged(&snapshot).await?;
+    match tokio::fs::remove_dir_all(&snapshot.path).await {
+        Err(err) if err.kind() != std::io::ErrorKind::NotFound => Err(err.into()),
+        _ => Ok(()),
+    }
+}
+
+pub fn routes() -> Vec<Route> {
+    routes![create, list, delete]
+}
@@ -1,28 +1,33 @@
+use std::sync::Arc;
+
 use crate::{
     auth::Auth,
     storage::Storage,
-    types::{Error, JwtClaim, JwtResponse, User, UserSpec},
+    types::{validate_name, AdminClaim, Error, JwtResponse, User, UserSpec},
 };
 use rocket::*;
 use rocket_contrib::json::Json;
 
+use super::body::LimitedJson;
+
 #[post("/users", data = "<user>", format = "json")]
 pub async fn create(
     storage: State<'_, Storage>,
-    _claim: JwtClaim,
-    user: Json<UserSpec>,
+    _claim: AdminClaim,
+    user: LimitedJson<UserSpec>,
 ) -> Result<Json<User>, Error> {
     let user_spec = user.into_inner();
+    validate_name(&user_spec.username)?;
     let user = user_spec.encrypt()?;
-    storage.store(&user).await?;
+    storage.create(&user).await?;
     Ok(user.into())
 }
 
 #[post("/users/login", data = "<user>", format = "json")]
 pub async fn login(
     storage: State<'_, Storage>,
-    auth: State<'_, Auth>,
-    user: Json<UserSpec>,
+    auth: State<'_, Arc<Auth>>,
+    user: LimitedJson<UserSpec>,
 ) -> Result<Json<JwtResponse>, Error> {
     let user_spec = user.into_inner();
     let user: User = storage
@@ -34,7 +39,7 @@ pub async fn login(
     {
         return Err(Error::Unauthorized);
     }
-    let token = auth.create_jwt(user_spec.username)?;
+    let token = auth.create_jwt(user_spec.username, user.role, user.projects)?;
     Ok(JwtResponse { token }.into())
 }
 
@@ -1,44 +1,511 @@
+use std::{sync::Arc, time::Duration};
+
 use crate::{
+    actors::{kill_orphans, vm_info, HypervisorSockets},
+    admission::{AdmissionRequest, Admissions, Operation},
+    auth::Auth,
+    clock::SharedClock,
+    config::SharedConfig,
     storage::Storage,
-    types::{Error, JwtClaim, ListResponse, Vm},
+    types::{
+        host_name, BatchResult, Error, JwtClaim, LabelSelector, ListResponse, Node, Object,
+        ProjectUsage, RenewResponse, Role, Scale, Vm, VmSpecPatch, VmState,
+    },
 };
 use rocket::*;
 use rocket_contrib::json::Json;
 
+use super::{
+    base::ExternalBase,
+    body::LimitedJson,
+    page::{list_page, PageQuery},
+    projects::{check_quota, scoped_key, visible},
+};
+
+/// The VM whose key name is `key`, if the claim may access its project.
+pub(super) async fn get_vm(storage: &Storage, key: &str, claim: &JwtClaim) -> Result<Vm, Error> {
+    let vm: Vm = storage
+        .get(key)
+        .await?
+        .ok_or_else(|| Error::NotFound(format!("vm: {}", key)))?;
+    if !claim.can_access(&vm.metadata.project) {
+        return Err(Error::Forbidden(format!(
+            "project: {}",
+            vm.metadata.project
+        )));
+    }
+    Ok(vm)
+}
+
 #[post("/vms", data = "<vm>", format = "json")]
 pub async fn create(
     storage: State<'_, Storage>,
-    _claim: JwtClaim,
-    vm: Json<Vm>,
+    admissions: State<'_, Admissions>,
+    claim: JwtClaim,
+    vm: LimitedJson<Vm>,
+) -> Result<Json<Vm>, Error> {
+    let request = AdmissionRequest {
+        claim: &claim,
+        operation: Operation::Create,
+    };
+    let mut vm = admissions.vms.admit(&request, vm.into_inner())?;
+    check_quota(
+        &storage,
+        &vm.metadata.project,
+        ProjectUsage::vm(&vm.spec),
+        None,
+    )
+    .await?;
+    vm.metadata.lease = match vm.spec.ttl_seconds {
+        Some(ttl) => Some(storage.grant_lease(ttl).await?),
+        None => None,
+    };
+    storage.create(&vm).await?;
+    Ok(vm.into())
+}
+
+/// Replaces a VM's spec. `metadata.version` must match the stored VM, so concurrent updates
+/// fail with a conflict rather than overwrite each other. A VM can't be moved to another
+/// project.
+///
+/// Like every route naming a single VM, the VM is looked up in `project`, which defaults to the
+/// caller's first project.
+#[put("/vms/<name>?<project>", data = "<vm>", format = "json")]
+pub async fn update(
+    storage: State<'_, Storage>,
+    admissions: State<'_, Admissions>,
+    name: &str,
+    project: Option<&str>,
+    claim: JwtClaim,
+    vm: LimitedJson<Vm>,
+) -> Result<Json<Vm>, Error> {
+    let key = scoped_key(&claim, project, name)?;
+    let current = get_vm(&storage, &key, &claim).await?;
+    let mut vm = vm.into_inner();
+    if vm.metadata.name != name {
+        return Err(Error::Invalid(format!(
+            "metadata.name {} doesn't match {}",
+            vm.metadata.name, name
+        )));
+    }
+    if vm.metadata.project.is_empty() {
+        vm.metadata.project = current.metadata.project.clone();
+    } else if vm.metadata.project != current.metadata.project {
+        return Err(Error::Invalid(format!(
+            "vm {} is in project {} and can't be moved to {}",
+            name, current.metadata.project, vm.metadata.project
+        )));
+    }
+    let request = AdmissionRequest {
+        claim: &claim,
+        operation: Operation::Update,
+    };
+    vm = admissions.vms.admit(&request, vm)?;
+    if vm.spec.powered_on != current.spec.powered_on {
+        current.status.check_power(vm.spec.powered_on)?;
+    }
+    // The status belongs to the node running the VM, and the lease to the create request
+    vm.status = current.status;
+    vm.metadata.lease = current.metadata.lease;
+    vm.metadata.created_by = current.metadata.created_by;
+    vm.metadata.host_name = current.metadata.host_name;
+    check_quota(
+        &storage,
+        &vm.metadata.project,
+        ProjectUsage::vm(&vm.spec),
+        Some(name),
+    )
+    .await?;
+    storage.update(&vm).await?;
+    Ok(vm.into())
+}
+
+/// Changes only the spec fields given in `patch`. The VM is read and written back with a
+/// version check, so a concurrent update fails with a conflict instead of being lost.
+#[patch("/vms/<name>?<project>", data = "<patch>", format = "json")]
+pub async fn patch(
+    storage: State<'_, Storage>,
+    admissions: State<'_, Admissions>,
+    name: &str,
+    project: Option<&str>,
+    claim: JwtClaim,
+    patch: LimitedJson<VmSpecPatch>,
 ) -> Result<Json<Vm>, Error> {
-    let vm = vm.into_inner();
-    storage.store(&vm).await?;
+    let key = scoped_key(&claim, project, name)?;
+    let mut vm = get_vm(&storage, &key, &claim).await?;
+    let powered_on = vm.spec.powered_on;
+    patch.into_inner().apply(&mut vm.spec);
+    if vm.spec.powered_on != powered_on {
+        vm.status.check_power(vm.spec.powered_on)?;
+    }
+    let request = AdmissionRequest {
+        claim: &claim,
+        operation: Operation::Update,
+    };
+    let vm = admissions.vms.admit(&request, vm)?;
+    check_quota(
+        &storage,
+        &vm.metadata.project,
+        ProjectUsage::vm(&vm.spec),
+        Some(name),
+    )
+    .await?;
+    storage.update(&vm).await?;
     Ok(vm.into())
 }
 
-#[get("/vms")]
+/// Sets a VM's cpus and memory without touching the rest of its spec. VMs boot without room to
+/// hotplug either, so the node applies the new shape by restarting the VM on the same disk.
+#[post("/vms/<name>/scale?<project>", data = "<scale>", format = "json")]
+pub async fn scale(
+    storage: State<'_, Storage>,
+    admissions: State<'_, Admissions>,
+    name: &str,
+    project: Option<&str>,
+    claim: JwtClaim,
+    scale: LimitedJson<Scale>,
+) -> Result<Json<Scale>, Error> {
+    let scale = scale.into_inner();
+    let key = scoped_key(&claim, project, name)?;
+    let mut vm = get_vm(&storage, &key, &claim).await?;
+    if matches!(
+        vm.status.state,
+        VmState::Failed | VmState::Uncreated | VmState::Pending
+    ) {
+        return Err(Error::Invalid(format!(
+            "vm {} is {:?} and can't be scaled",
+            name, vm.status.state
+        )));
+    }
+    if let Some(ref node_name) = vm.status.node {
+        let node: Node = storage
+            .get(node_name)
+            .await?
+            .ok_or_else(|| Error::NotFound(format!("node: {}", node_name)))?;
+        let vms: Vec<Vm> = storage.list().await?;
+        let free = node.free(vms.iter().filter(|other| other.key_name() != key));
+        if scale.cpus as usize > free.cpus || scale.memory as u64 > free.memory {
+            return Err(Error::Invalid(format!(
+                "node {} has room for {} cpus and {} MiB",
+                node_name, free.cpus, free.memory
+            )));
+        }
+    }
+    vm.spec.cpus = scale.cpus;
+    vm.spec.memory = scale.memory;
+    vm.spec.validate()?;
+    let request = AdmissionRequest {
+        claim: &claim,
+        operation: Operation::Update,
+    };
+    let vm = admissions.vms.admit(&request, vm)?;
+    check_quota(
+        &storage,
+        &vm.metadata.project,
+        ProjectUsage::vm(&vm.spec),
+        Some(name),
+    )
+    .await?;
+    storage.update(&vm).await?;
+    Ok(Scale {
+        cpus: vm.spec.cpus,
+        memory: vm.spec.memory,
+    }
+    .into())
+}
+
+/// Pushes back the expiry of a VM created with `ttl_seconds` by another full ttl.
+#[post("/vms/<name>/renew?<project>")]
+pub async fn renew(
+    storage: State<'_, Storage>,
+    name: &str,
+    project: Option<&str>,
+    claim: JwtClaim,
+) -> Result<Json<RenewResponse>, Error> {
+    let key = scoped_key(&claim, project, name)?;
+    let vm = get_vm(&storage, &key, &claim).await?;
+    let lease = vm
+        .metadata
+        .lease
+        .ok_or_else(|| Error::Invalid(format!("vm {} has no ttl", name)))?;
+    let ttl_seconds = storage
+        .keep_alive(lease)
+        .await?
+        .ok_or_else(|| Error::NotFound(format!("lease for vm: {}", name)))?;
+    Ok(RenewResponse { ttl_seconds }.into())
+}
+
+/// The VMs in the caller's projects, or only in `project`.
+#[get("/vms?<revision>&<project>&<limit>&<page_token>")]
+#[allow(clippy::too_many_arguments)]
 pub async fn list(
     storage: State<'_, Storage>,
-    _claim: JwtClaim,
+    clock: State<'_, SharedClock>,
+    auth: State<'_, Arc<Auth>>,
+    base: ExternalBase,
+    claim: JwtClaim,
+    revision: Option<i64>,
+    project: Option<&str>,
+    limit: Option<usize>,
+    page_token: Option<String>,
 ) -> Result<Json<ListResponse<Vm>>, Error> {
-    let objects = storage.list().await?;
-    Ok(ListResponse {
-        objects,
-        next_page: "".to_string(),
+    let shown = visible(&claim, project)?;
+    let query = PageQuery {
+        path: "/vms",
+        project: project.unwrap_or_default(),
+        limit,
+        page_token,
+        revision,
+        filters: project
+            .map(|project| ("project", project.to_string()))
+            .into_iter()
+            .collect(),
+    };
+    let mut list = list_page(&storage, &auth, &base, query, |vm: &Vm| shown(&vm.metadata)).await?;
+    set_node_ready(&storage, &mut list.objects, list.revision).await?;
+    let now = clock.now().timestamp();
+    for vm in &mut list.objects {
+        vm.status.set_uptime(now);
     }
-    .into())
+    Ok(list.into())
+}
+
+/// A single VM, with the same computed status fields as the list.
+#[get("/vms/<name>?<project>")]
+pub async fn get(
+    storage: State<'_, Storage>,
+    clock: State<'_, SharedClock>,
+    name: &str,
+    project: Option<&str>,
+    claim: JwtClaim,
+) -> Result<Json<Vm>, Error> {
+    let key = scoped_key(&claim, project, name)?;
+    let (vm, revision) = storage.get_with_revision::<Vm>(&key, None).await?;
+    let mut vm = vm.ok_or_else(|| Error::NotFound(format!("vm: {}", key)))?;
+    if !claim.can_access(&vm.metadata.project) {
+        return Err(Error::Forbidden(format!(
+            "project: {}",
+            vm.metadata.project
+        )));
+    }
+    set_node_ready(&storage, std::slice::from_mut(&mut vm), revision).await?;
+    vm.status.set_uptime(clock.now().timestamp());
+    Ok(vm.into())
 }
 
-#[delete("/vms/<name>")]
+/// Deletes a VM. With `force`, first kills any processes this node left running for the VM
+/// without tracking them, as after a crash, and deletes the VM even if it can't be read. Only
+/// admins can force delete a VM that can't be read or is already gone.
+#[delete("/vms/<name>?<project>&<force>")]
 pub async fn delete(
     storage: State<'_, Storage>,
+    sockets: State<'_, HypervisorSockets>,
     name: &str,
-    _claim: JwtClaim,
+    project: Option<&str>,
+    claim: JwtClaim,
+    force: Option<bool>,
 ) -> Result<(), Error> {
-    storage.delete::<Vm>(name).await?;
+    let force = force.unwrap_or(false);
+    let key = scoped_key(&claim, project, name)?;
+    let vm = match storage.get::<Vm>(&key).await {
+        Ok(vm) => vm,
+        Err(err) if force => {
+            println!("force deleting vm {} that can't be read: {}", key, err);
+            None
+        }
+        Err(err) => return Err(err),
+    };
+    match vm {
+        Some(ref vm) if !claim.can_access(&vm.metadata.project) => {
+            return Err(Error::Forbidden(format!(
+                "project: {}",
+                vm.metadata.project
+            )))
+        }
+        None if force && claim.role != Role::Admin => {
+            return Err(Error::Forbidden(format!(
+                "only admins can force delete vm {}, its project can't be checked",
+                key
+            )))
+        }
+        _ => {}
+    }
+    if force {
+        // A VM that can't be read may predate project scoped keys, with processes named after
+        // its name, or not, with processes named after the hash
+        let (node, host_names) = match vm {
+            Some(vm) => (vm.status.node.clone(), vec![vm.metadata.host_name()]),
+            None => {
+                let (project, _) = key.split_at(key.len() - name.len() - 1);
+                (None, vec![name.to_string(), host_name(project, name)])
+            }
+        };
+        let hostname = sys_info::hostname()?;
+        match node {
+            Some(ref node) if *node != hostname => println!(
+                "force deleting vm {} without checking node {} for its processes",
+                key, node
+            ),
+            // A VM the supervisor tracks is torn down by it as usual
+            _ if sockets.get(&key).is_some() => {}
+            _ => {
+                for host_name in host_names {
+                    let killed = kill_orphans(&host_name).await?;
+                    println!("force deleting vm {}: killed {:?}", key, killed);
+                }
+            }
+        }
+    }
+    storage.delete::<Vm>(&key).await?;
     Ok(())
 }
 
+/// Deletes every VM in the caller's projects whose labels match `selector`. An empty selector
+/// matches every VM, so it additionally requires `all=true`.
+#[delete("/vms?<selector>&<all>")]
+pub async fn delete_matching(
+    storage: State<'_, Storage>,
+    claim: JwtClaim,
+    selector: Option<String>,
+    all: Option<bool>,
+) -> Result<Json<Vec<BatchResult>>, Error> {
+    let selector: LabelSelector = selector.as_deref().unwrap_or_default().parse()?;
+    if selector.is_empty() && !all.unwrap_or(false) {
+        return Err(Error::Invalid(
+            "an empty selector deletes every vm, pass all=true to confirm".to_string(),
+        ));
+    }
+    let vms: Vec<Vm> = storage.list().await?;
+    let mut results = vec![];
+    for vm in vms {
+        if !claim.can_access(&vm.metadata.project) || !selector.matches(&vm.metadata.labels) {
+            continue;
+        }
+        let error = storage
+            .delete::<Vm>(&vm.key_name())
+            .await
+            .err()
+            .map(|err| err.to_string());
+        results.push(BatchResult {
+            name: vm.key_name(),
+            error,
+        });
+    }
+    Ok(results.into())
+}
+
+/// Fills in `status.node_ready` from the nodes registered as of `revision`.
+async fn set_node_ready(storage: &Storage, vms: &mut [Vm], revision: i64) -> Result<(), Error> {
+    let (nodes, _) = storage.list_with_revision::<Node>(Some(revision)).await?;
+    for vm in vms {
+        vm.status.node_ready = vm
+            .status
+            .node
+            .as_ref()
+            .map(|name| nodes.iter().any(|node| &node.metadata.name == name));
+    }
+    Ok(())
+}
+
+/// cloud-hypervisor's view of a VM running on this node, to compare against its stored spec.
+#[get("/vms/<name>/info?<project>")]
+pub async fn info(
+    storage: State<'_, Storage>,
+    sockets: State<'_, HypervisorSockets>,
+    config: State<'_, SharedConfig>,
+    name: &str,
+    project: Option<&str>,
+    claim: JwtClaim,
+) -> Result<Json<serde_json::Value>, Error> {
+    let key = scoped_key(&claim, project, name)?;
+    let vm = get_vm(&storage, &key, &claim).await?;
+    let node = vm
+        .status
+        .node
+        .ok_or_else(|| Error::NotFound(format!("vm {} isn't scheduled", name)))?;
+    if node != sys_info::hostname()? {
+        return Err(Error::NotFound(format!(
+            "vm {} runs on node {}, ask its api",
+            name, node
+        )));
+    }
+    let socket_path = sockets
+        .get(&key)
+        .ok_or_else(|| Error::NotFound(format!("vm {} isn't running", name)))?;
+    let timeout = Duration::from_secs(config.load().hypervisor_timeout);
+    Ok(vm_info(&socket_path, timeout).await?.into())
+}
+
 pub fn routes() -> Vec<Route> {
-    routes![list, create, delete]
+    routes![
+        list,
+        get,
+        create,
+        update,
+        patch,
+        delete,
+        delete_matching,
+        renew,
+        info,
+        scale
+    ]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{config::Config, storage::MemoryBackend};
+    use arc_swap::ArcSwap;
+    use rocket::{
+        http::{ContentType, Header, Status},
+        local::asynchronous::Client,
+    };
+
+    #[tokio::test]
+    async fn creating_a_vm_with_two_ttys_is_a_bad_request() {
+        let config: Config = serde_json::from_value(serde_json::json!({
+            "etcd_addr": "localhost:2379",
+            "jwt_secret": "c2VjcmV0",
+        }))
+        .unwrap();
+        let clock = crate::clock::system();
+        let auth = Arc::new(Auth::new(&config.jwt_secret, None, clock).unwrap());
+        let token = auth
+            .create_jwt("alice".to_string(), Role::User, vec!["default".to_string()])
+            .unwrap();
+        let storage = Storage::new(MemoryBackend::new());
+        let rocket = rocket::custom(rocket::Config::default())
+            .manage(storage.clone())
+            .manage(Admissions::new(&config))
+            .manage(Arc::new(ArcSwap::from_pointee(config)))
+            .manage(auth)
+            .mount("/api", routes![create]);
+        let client = Client::tracked(rocket).await.unwrap();
+
+        let vm = serde_json::json!({
+            "metadata": {"name": "a"},
+            "spec": {
+                "vpc": "net",
+                "cpus": 1,
+                "memory": 512,
+                "console_mode": "tty",
+                "serial_mode": "tty",
+            },
+        });
+        let resp = client
+            .post("/api/vms")
+            .header(ContentType::JSON)
+            .header(Header::new("Authorization", format!("Bearer {}", token)))
+            .body(vm.to_string())
+            .dispatch()
+            .await;
+        assert_eq!(resp.status(), Status::BadRequest);
+        let body: serde_json::Value =
+            serde_json::from_str(&resp.into_string().await.unwrap()).unwrap();
+        assert_eq!(
+            body["msg"],
+            "invalid: serial and console cannot both use tty"
+        );
+        assert!(storage.list::<Vm>().await.unwrap().is_empty());
+    }
 }
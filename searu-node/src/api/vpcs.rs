@@ -1,44 +1,272 @@
+use std::sync::Arc;
+
 use crate::{
+    actors::{read_leases, DhcpdConfig},
+    admission::{AdmissionRequest, Admissions, Operation},
+    allocator::Allocator,
+    auth::Auth,
+    config::SharedConfig,
     storage::Storage,
-    types::{Error, JwtClaim, ListResponse, Vpc},
+    types::{
+        AdminClaim, DhcpLease, Error, JwtClaim, LabelSelector, ListResponse, Object, ProjectUsage,
+        Vm, Vpc,
+    },
 };
 use rocket::*;
 use rocket_contrib::json::Json;
 
+use super::{
+    base::ExternalBase,
+    body::LimitedJson,
+    page::{list_page, PageQuery},
+    projects::{check_quota, scoped_key, visible},
+};
+
+/// Claims the multicast IP and VNI supplied with a new VPC for it, failing with
+/// [`Error::Conflict`] if another VPC holds either. Nothing stays claimed when it fails.
+async fn claim_supplied(storage: &Storage, vpc: &Vpc) -> Result<(), Error> {
+    let owner = &vpc.key_name();
+    let multicast_ips = Allocator::new(storage.clone(), "multicast_ip");
+    let vnis = Allocator::new(storage.clone(), "vni");
+    if let Some(ip) = vpc.spec.multicast_ip {
+        if !multicast_ips.claim(owner, &ip).await? {
+            return Err(Error::Conflict(format!("multicast_ip {} is in use", ip)));
+        }
+    }
+    if let Some(vni) = vpc.spec.vni {
+        if !vnis.claim(owner, &vni).await? {
+            multicast_ips.release(owner).await?;
+            return Err(Error::Conflict(format!("vni {} is in use", vni)));
+        }
+    }
+    Ok(())
+}
+
+/// Returns the values `owner` holds to their pools.
+async fn release_claims(storage: &Storage, owner: &str) -> Result<(), Error> {
+    Allocator::new(storage.clone(), "multicast_ip")
+        .release(owner)
+        .await?;
+    Allocator::new(storage.clone(), "vni").release(owner).await
+}
+
 #[post("/vpcs", data = "<vpc>", format = "json")]
 pub async fn create(
     storage: State<'_, Storage>,
-    _claim: JwtClaim,
-    vpc: Json<Vpc>,
+    admissions: State<'_, Admissions>,
+    claim: JwtClaim,
+    vpc: LimitedJson<Vpc>,
 ) -> Result<Json<Vpc>, Error> {
-    let vpc = vpc.into_inner();
-    storage.store(&vpc).await?;
+    let request = AdmissionRequest {
+        claim: &claim,
+        operation: Operation::Create,
+    };
+    let mut vpc = admissions.vpcs.admit(&request, vpc.into_inner())?;
+    // The status is reported by the nodes
+    vpc.status = Default::default();
+    check_quota(&storage, &vpc.metadata.project, ProjectUsage::vpc(), None).await?;
+    // Claim supplied values before the VPC exists, so a taken value fails the request instead
+    // of being silently shared. The scheduler only allocates the ones left unset.
+    let owner = &vpc.key_name();
+    // Claims are held by key name, so don't touch those of an existing VPC with this name
+    if storage.get::<Vpc>(owner).await?.is_some() {
+        return Err(Error::AlreadyExists(Vpc::key_for(owner)));
+    }
+    claim_supplied(&storage, &vpc).await?;
+    match storage.create(&vpc).await {
+        Ok(()) => {}
+        // Created since the check above, so the claims are that VPC's now
+        Err(err @ Error::AlreadyExists(_)) => return Err(err),
+        Err(err) => {
+            release_claims(&storage, owner).await?;
+            return Err(err);
+        }
+    }
     Ok(vpc.into())
 }
 
-#[get("/vpcs")]
+/// The VPCs in the caller's projects, or only in `project`.
+#[get("/vpcs?<revision>&<project>&<limit>&<page_token>")]
+#[allow(clippy::too_many_arguments)]
 pub async fn list(
     storage: State<'_, Storage>,
-    _claim: JwtClaim,
+    auth: State<'_, Arc<Auth>>,
+    base: ExternalBase,
+    claim: JwtClaim,
+    revision: Option<i64>,
+    project: Option<&str>,
+    limit: Option<usize>,
+    page_token: Option<String>,
 ) -> Result<Json<ListResponse<Vpc>>, Error> {
-    let objects = storage.list().await?;
+    let shown = visible(&claim, project)?;
+    let query = PageQuery {
+        path: "/vpcs",
+        project: project.unwrap_or_default(),
+        limit,
+        page_token,
+        revision,
+        filters: project
+            .map(|project| ("project", project.to_string()))
+            .into_iter()
+            .collect(),
+    };
+    let list = list_page(&storage, &auth, &base, query, |vpc: &Vpc| {
+        shown(&vpc.metadata)
+    })
+    .await?;
+    Ok(list.into())
+}
+
+/// The VPC whose key name is `key`.
+async fn get_vpc(storage: &Storage, key: &str) -> Result<Vpc, Error> {
+    storage
+        .get(key)
+        .await?
+        .ok_or_else(|| Error::NotFound(format!("vpc: {}", key)))
+}
+
+/// Deletes a VPC. Like every route naming a single VPC, the VPC is looked up in `project`,
+/// which defaults to the caller's first project.
+#[delete("/vpcs/<name>?<project>")]
+pub async fn delete(
+    storage: State<'_, Storage>,
+    name: &str,
+    project: Option<&str>,
+    claim: JwtClaim,
+) -> Result<(), Error> {
+    let key = scoped_key(&claim, project, name)?;
+    storage.delete::<Vpc>(&key).await?;
+    Ok(())
+}
+
+/// The VMs attached to a VPC, which are in the VPC's project, optionally only those whose labels
+/// match `selector`.
+#[get("/vpcs/<name>/vms?<project>&<selector>")]
+pub async fn vms(
+    storage: State<'_, Storage>,
+    name: &str,
+    project: Option<&str>,
+    claim: JwtClaim,
+    selector: Option<String>,
+) -> Result<Json<ListResponse<Vm>>, Error> {
+    let selector: LabelSelector = selector.as_deref().unwrap_or_default().parse()?;
+    let key = scoped_key(&claim, project, name)?;
+    get_vpc(&storage, &key).await?;
+    let (mut objects, revision) = storage.list_with_revision::<Vm>(None).await?;
+    objects.retain(|vm| vm.vpc_key_name() == key && selector.matches(&vm.metadata.labels));
     Ok(ListResponse {
         objects,
         next_page: "".to_string(),
+        next_link: None,
+        revision,
     }
     .into())
 }
 
-#[delete("/vpcs/<name>")]
-pub async fn delete(
+/// The addresses this node's dnsmasq has handed out in a VPC.
+#[get("/vpcs/<name>/leases?<project>")]
+pub async fn leases(
     storage: State<'_, Storage>,
+    config: State<'_, SharedConfig>,
     name: &str,
-    _claim: JwtClaim,
-) -> Result<(), Error> {
-    storage.delete::<Vpc>(name).await?;
-    Ok(())
+    project: Option<&str>,
+    claim: JwtClaim,
+) -> Result<Json<Vec<DhcpLease>>, Error> {
+    let key = scoped_key(&claim, project, name)?;
+    let vpc = get_vpc(&storage, &key).await?;
+    let lease_file = config.load().dhcp_lease_file(&vpc.metadata.host_name());
+    Ok(read_leases(&lease_file).await?.into())
+}
+
+/// The dnsmasq arguments this node runs, or would run, for a VPC, built the same way as when
+/// dnsmasq starts, so it shows the intended configuration even if dnsmasq failed.
+#[get("/vpcs/<name>/dhcp?<project>")]
+pub async fn dhcp(
+    storage: State<'_, Storage>,
+    config: State<'_, SharedConfig>,
+    name: &str,
+    project: Option<&str>,
+    claim: AdminClaim,
+) -> Result<Json<DhcpdConfig>, Error> {
+    let key = scoped_key(&claim.0, project, name)?;
+    let vpc = get_vpc(&storage, &key).await?;
+    let config = config.load_full();
+    Ok(DhcpdConfig::new(&storage, &config, &vpc).await?.into())
 }
 
 pub fn routes() -> Vec<Route> {
-    routes![list, create, delete]
+    routes![list, create, delete, leases, vms, dhcp]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        allocator::pool_prefix,
+        storage::MemoryBackend,
+        types::{Metadata, VpcSpec},
+    };
+
+    fn vpc(name: &str, multicast_ip: Option<&str>, vni: Option<u16>) -> Vpc {
+        Vpc {
+            metadata: Metadata {
+                name: name.to_string(),
+                project: "default".to_string(),
+                ..Default::default()
+            },
+            spec: VpcSpec {
+                subnet: "10.0.0.0/24".parse().unwrap(),
+                multicast_ip: multicast_ip.map(|ip| ip.parse().unwrap()),
+                vni,
+                nat_gateway: false,
+            },
+            status: Default::default(),
+        }
+    }
+
+    #[tokio::test]
+    async fn supplied_values_are_claimed_only_when_free() {
+        let storage = Storage::new(MemoryBackend::new());
+        let claims = |pool: &'static str| {
+            let storage = storage.clone();
+            async move { storage.claims(&pool_prefix(pool)).await.unwrap() }
+        };
+
+        // Supplied and free
+        claim_supplied(&storage, &vpc("a", Some("239.1.0.1"), Some(10)))
+            .await
+            .unwrap();
+        let held = claims("multicast_ip").await;
+        assert_eq!(held.get("239.1.0.1").map(String::as_str), Some("default/a"));
+        assert_eq!(
+            claims("vni").await.get("10").map(String::as_str),
+            Some("default/a")
+        );
+
+        // Supplied but taken, leaving nothing claimed
+        match claim_supplied(&storage, &vpc("b", Some("239.1.0.2"), Some(10))).await {
+            Err(Error::Conflict(msg)) => assert_eq!(msg, "vni 10 is in use"),
+            result => panic!("expected a conflict, got {:?}", result),
+        }
+        assert!(!claims("multicast_ip").await.contains_key("239.1.0.2"));
+        match claim_supplied(&storage, &vpc("b", Some("239.1.0.1"), None)).await {
+            Err(Error::Conflict(msg)) => assert_eq!(msg, "multicast_ip 239.1.0.1 is in use"),
+            result => panic!("expected a conflict, got {:?}", result),
+        }
+
+        // Unset, left for the scheduler to allocate
+        claim_supplied(&storage, &vpc("c", None, None))
+            .await
+            .unwrap();
+        assert!(!claims("multicast_ip")
+            .await
+            .values()
+            .any(|owner| owner == "default/c"));
+        assert_eq!(claims("vni").await.len(), 1);
+
+        release_claims(&storage, "default/a").await.unwrap();
+        claim_supplied(&storage, &vpc("b", Some("239.1.0.1"), Some(10)))
+            .await
+            .unwrap();
+    }
 }
@@ -0,0 +1,105 @@
+use rocket::{
+    fairing::{Fairing, Info, Kind},
+    http::Method,
+    Request, Response,
+};
+
+use crate::{
+    clock::SharedClock,
+    storage::Storage,
+    types::{
+        AuditEntry, ClusterConfig, DhcpReservation, JwtClaim, Node, Object, Project, Quota,
+        Snapshot, User, Vm, Vpc,
+    },
+};
+
+/// Records every mutating API request, successful or not, as an [`AuditEntry`].
+pub struct AuditLog {
+    storage: Storage,
+    clock: SharedClock,
+}
+
+impl AuditLog {
+    pub fn new(storage: Storage, clock: SharedClock) -> Self {
+        Self { storage, clock }
+    }
+}
+
+/// The [`Object::OBJECT_TYPE`] a request to `/api/<collection>/<name>/<sub-collection>` acts
+/// on, if any.
+fn object_type(collection: &str, sub_collection: Option<&str>) -> Option<&'static str> {
+    Some(match (collection, sub_collection) {
+        ("vms", Some("snapshots")) => Snapshot::OBJECT_TYPE,
+        ("projects", Some("quota")) => Quota::OBJECT_TYPE,
+        ("users", _) => User::OBJECT_TYPE,
+        ("projects", _) => Project::OBJECT_TYPE,
+        ("nodes", _) => Node::OBJECT_TYPE,
+        ("vms", _) => Vm::OBJECT_TYPE,
+        ("vpcs", _) => Vpc::OBJECT_TYPE,
+        ("reservations", _) => DhcpReservation::OBJECT_TYPE,
+        ("cluster", _) => ClusterConfig::OBJECT_TYPE,
+        _ => return None,
+    })
+}
+
+#[rocket::async_trait]
+impl Fairing for AuditLog {
+    fn info(&self) -> Info {
+        Info {
+            name: "Audit log",
+            kind: Kind::Response,
+        }
+    }
+
+    async fn on_response<'r>(&self, req: &'r Request<'_>, res: &mut Response<'r>) {
+        if matches!(req.method(), Method::Get | Method::Head | Method::Options) {
+            return;
+        }
+        let user = req
+            .guard::<JwtClaim>()
+            .await
+            .succeeded()
+            .map(|claim| claim.username().to_string());
+        let path = req.uri().path().to_string();
+        // Paths look like /api/<collection>/<name>/...
+        let mut segments = path.split('/').filter(|s| !s.is_empty()).skip(1);
+        let collection = segments.next();
+        let name = segments.next().map(str::to_string);
+        let object_type = collection
+            .and_then(|collection| object_type(collection, segments.next()))
+            .map(str::to_string);
+        let now = self.clock.now();
+        let entry = AuditEntry {
+            id: AuditEntry::id(now.timestamp_nanos()),
+            timestamp: now.timestamp(),
+            user,
+            method: req.method().to_string(),
+            path,
+            object_type,
+            name,
+            status: res.status().code,
+        };
+        if let Err(err) = self.storage.store(&entry).await {
+            println!("failed to record audit entry: {:?}", err);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn paths_map_to_object_types() {
+        assert_eq!(object_type("vms", None), Some("vm"));
+        assert_eq!(object_type("vms", Some("scale")), Some("vm"));
+        assert_eq!(object_type("vms", Some("snapshots")), Some("snapshot"));
+        assert_eq!(object_type("projects", Some("quota")), Some("quota"));
+        assert_eq!(object_type("reservations", None), Some("dhcp_reservation"));
+        assert_eq!(
+            object_type("cluster", Some("pause")),
+            Some("cluster_config")
+        );
+        assert_eq!(object_type("import", None), None);
+    }
+}
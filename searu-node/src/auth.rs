@@ -1,40 +1,298 @@
-use crate::types::{Error, InnerJwtClaim, JwtClaim};
-use chrono::Utc;
-use jsonwebtoken::{decode, encode, Algorithm, DecodingKey, EncodingKey, Header, Validation};
+use std::{
+    collections::HashMap,
+    time::{Duration, Instant},
+};
+
+use crate::{
+    clock::SharedClock,
+    config::OidcConfig,
+    types::{Error, InnerJwtClaim, JwtClaim, Role},
+};
+use hyper::{body, Client};
+use hyper_rustls::HttpsConnector;
+use jsonwebtoken::{
+    decode, decode_header, encode, Algorithm, DecodingKey, EncodingKey, Header, Validation,
+};
+use ring::hmac;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use tokio::sync::RwLock;
 
 pub struct Auth {
     encoding_key: EncodingKey,
     decoding_key: DecodingKey<'static>,
+    /// Signs pagination tokens, see [`Auth::page_token`].
+    page_key: hmac::Key,
+    oidc: Option<Oidc>,
+    clock: SharedClock,
 }
 
 impl Auth {
-    pub fn new(secret: &str) -> Result<Self, Error> {
+    pub fn new(secret: &str, oidc: Option<OidcConfig>, clock: SharedClock) -> Result<Self, Error> {
         Ok(Self {
             encoding_key: EncodingKey::from_base64_secret(secret)?,
             decoding_key: DecodingKey::from_base64_secret(secret)?.into_static(),
+            page_key: hmac::Key::new(
+                hmac::HMAC_SHA256,
+                &base64::decode(secret)
+                    .map_err(|_| Error::Invalid("jwt_secret isn't base64".to_string()))?,
+            ),
+            oidc: oidc.map(Oidc::new),
+            clock,
         })
     }
 
-    pub fn create_jwt(&self, username: String) -> Result<String, Error> {
+    pub fn create_jwt(
+        &self,
+        username: String,
+        role: Role,
+        projects: Vec<String>,
+    ) -> Result<String, Error> {
         let header = Header::new(Algorithm::HS512);
-        let exp = Utc::now()
+        let exp = self
+            .clock
+            .now()
             .checked_add_signed(chrono::Duration::hours(24))
             .expect("valid timestamp")
             .timestamp();
         let claim = JwtClaim {
             inner: InnerJwtClaim::User(username),
             exp,
+            role,
+            projects,
         };
         Ok(encode(&header, &claim, &self.encoding_key)?)
     }
 
     pub fn parse_jwt(&self, token: &str) -> Result<JwtClaim, Error> {
         println!("parse jwt");
-        let data = decode::<JwtClaim>(
-            token,
-            &self.decoding_key,
-            &Validation::new(Algorithm::HS512),
-        )?;
-        Ok(data.claims)
+        let mut validation = Validation::new(Algorithm::HS512);
+        // Expiry is checked against `clock` instead of the system time
+        validation.validate_exp = false;
+        let data = decode::<JwtClaim>(token, &self.decoding_key, &validation)?;
+        self.check_expiry(data.claims)
+    }
+
+    /// Verifies a bearer token issued by searu, falling back to the OIDC provider if one is
+    /// configured.
+    pub async fn verify(&self, token: &str) -> Result<JwtClaim, Error> {
+        match (self.parse_jwt(token), &self.oidc) {
+            (Ok(claim), _) => Ok(claim),
+            (Err(_), Some(oidc)) => self.check_expiry(oidc.verify(token).await?),
+            (Err(err), None) => Err(err),
+        }
+    }
+
+    /// Signs `key`, where a listing of `object_type` in `project` left off, into an opaque
+    /// `next_page` token. Only [`Auth::page_key`] with the same type and project accepts it, so
+    /// clients can't craft tokens that continue into other projects' objects.
+    pub fn page_token(&self, object_type: &str, project: &str, key: &str) -> String {
+        let page = PageToken {
+            object_type: object_type.to_string(),
+            project: project.to_string(),
+            key: key.to_string(),
+        };
+        let payload = serde_json::to_vec(&page).expect("page tokens serialize");
+        let tag = hmac::sign(&self.page_key, &payload);
+        format!(
+            "{}.{}",
+            base64::encode_config(&payload, base64::URL_SAFE_NO_PAD),
+            base64::encode_config(tag.as_ref(), base64::URL_SAFE_NO_PAD)
+        )
+    }
+
+    /// Checks a token from [`Auth::page_token`] and returns the key it continues from,
+    /// rejecting tokens that were tampered with or issued for another type or project.
+    pub fn page_key(&self, token: &str, object_type: &str, project: &str) -> Result<String, Error> {
+        let invalid = || Error::Invalid("page_token".to_string());
+        let mut parts = token.splitn(2, '.');
+        let payload =
+            base64::decode_config(parts.next().unwrap_or_default(), base64::URL_SAFE_NO_PAD)
+                .map_err(|_| invalid())?;
+        let tag = base64::decode_config(parts.next().ok_or_else(invalid)?, base64::URL_SAFE_NO_PAD)
+            .map_err(|_| invalid())?;
+        hmac::verify(&self.page_key, &payload, &tag).map_err(|_| invalid())?;
+        let page: PageToken = serde_json::from_slice(&payload).map_err(|_| invalid())?;
+        if page.object_type != object_type || page.project != project {
+            return Err(Error::Invalid(format!(
+                "page_token was issued for another {}",
+                if page.object_type != object_type {
+                    "object type"
+                } else {
+                    "project"
+                }
+            )));
+        }
+        Ok(page.key)
+    }
+
+    /// Rejects claims whose `exp` has passed.
+    fn check_expiry(&self, claim: JwtClaim) -> Result<JwtClaim, Error> {
+        if claim.exp <= self.clock.now().timestamp() {
+            return Err(Error::Unauthorized);
+        }
+        Ok(claim)
+    }
+}
+
+/// The signed contents of a pagination token.
+#[derive(Serialize, Deserialize)]
+struct PageToken {
+    object_type: String,
+    project: String,
+    key: String,
+}
+
+/// The least time between JWKS fetches triggered by tokens signed with an unknown key.
+const JWKS_REFRESH_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Verifies tokens issued by an external OIDC provider against its JWKS.
+struct Oidc {
+    config: OidcConfig,
+    client: Client<HttpsConnector<hyper::client::HttpConnector>>,
+    keys: RwLock<JwkSet>,
+}
+
+#[derive(Default)]
+struct JwkSet {
+    keys: HashMap<String, DecodingKey<'static>>,
+    fetched_at: Option<Instant>,
+}
+
+#[derive(Deserialize)]
+struct Jwks {
+    keys: Vec<Jwk>,
+}
+
+#[derive(Deserialize)]
+struct Jwk {
+    #[serde(default)]
+    kid: Option<String>,
+    kty: String,
+    #[serde(default)]
+    n: Option<String>,
+    #[serde(default)]
+    e: Option<String>,
+}
+
+impl Oidc {
+    fn new(config: OidcConfig) -> Self {
+        Self {
+            config,
+            client: Client::builder().build(HttpsConnector::with_native_roots()),
+            keys: RwLock::default(),
+        }
+    }
+
+    async fn verify(&self, token: &str) -> Result<JwtClaim, Error> {
+        let header = decode_header(token)?;
+        // Only the RSA algorithms, so a token can't pick a weaker scheme than the provider's keys
+        if !matches!(
+            header.alg,
+            Algorithm::RS256 | Algorithm::RS384 | Algorithm::RS512
+        ) {
+            return Err(Error::Unauthorized);
+        }
+        let kid = header.kid.unwrap_or_default();
+        let key = match self.key(&kid).await {
+            Some(key) => key,
+            None => {
+                self.refresh().await?;
+                self.key(&kid).await.ok_or(Error::Unauthorized)?
+            }
+        };
+        let mut validation = Validation::new(header.alg);
+        validation.validate_exp = false;
+        validation.iss = Some(self.config.issuer.clone());
+        validation.set_audience(&[&self.config.audience]);
+        let claims = decode::<HashMap<String, Value>>(token, &key, &validation)?.claims;
+        self.map_claims(&claims)
+    }
+
+    async fn key(&self, kid: &str) -> Option<DecodingKey<'static>> {
+        self.keys.read().await.keys.get(kid).cloned()
+    }
+
+    async fn refresh(&self) -> Result<(), Error> {
+        let mut set = self.keys.write().await;
+        if matches!(set.fetched_at, Some(at) if at.elapsed() < JWKS_REFRESH_INTERVAL) {
+            return Ok(());
+        }
+        set.fetched_at = Some(Instant::now());
+        let uri: hyper::Uri = self
+            .config
+            .jwks_url
+            .parse()
+            .map_err(|_| Error::Invalid(format!("jwks_url: {}", self.config.jwks_url)))?;
+        let resp = self.client.get(uri).await?;
+        let jwks: Jwks = serde_json::from_slice(&body::to_bytes(resp.into_body()).await?)?;
+        set.keys = jwks
+            .keys
+            .into_iter()
+            .filter(|jwk| jwk.kty == "RSA")
+            .filter_map(|jwk| {
+                let key = DecodingKey::from_rsa_components(jwk.n.as_ref()?, jwk.e.as_ref()?);
+                Some((jwk.kid.unwrap_or_default(), key.into_static()))
+            })
+            .collect();
+        Ok(())
+    }
+
+    /// Maps the provider's claims onto a searu identity, taking projects from the groups claim
+    /// and the admin role from membership in `admin_group`.
+    fn map_claims(&self, claims: &HashMap<String, Value>) -> Result<JwtClaim, Error> {
+        let username = claims
+            .get(&self.config.username_claim)
+            .and_then(Value::as_str)
+            .ok_or(Error::Unauthorized)?
+            .to_string();
+        let exp = claims
+            .get("exp")
+            .and_then(Value::as_i64)
+            .ok_or(Error::Unauthorized)?;
+        let groups: Vec<String> = claims
+            .get(&self.config.groups_claim)
+            .and_then(Value::as_array)
+            .map(|groups| {
+                groups
+                    .iter()
+                    .filter_map(|group| group.as_str().map(str::to_string))
+                    .collect()
+            })
+            .unwrap_or_default();
+        let role = match self.config.admin_group {
+            Some(ref admin) if groups.contains(admin) => Role::Admin,
+            _ => Role::User,
+        };
+        Ok(JwtClaim {
+            inner: InnerJwtClaim::User(username),
+            exp,
+            role,
+            projects: groups,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::FixedClock;
+    use chrono::{TimeZone, Utc};
+
+    #[test]
+    fn tokens_expire_exactly_at_exp() {
+        let issued = Utc.timestamp(1_600_000_000, 0);
+        let clock = FixedClock::new(issued);
+        let auth = Auth::new("c2VjcmV0", None, clock.clone()).unwrap();
+        let token = auth
+            .create_jwt("alice".to_string(), Role::User, vec![])
+            .unwrap();
+        let exp = auth.parse_jwt(&token).unwrap().exp;
+        assert_eq!(exp, issued.timestamp() + 24 * 60 * 60);
+
+        clock.set(Utc.timestamp(exp - 1, 0));
+        assert!(auth.parse_jwt(&token).is_ok());
+        clock.set(Utc.timestamp(exp, 0));
+        assert!(matches!(auth.parse_jwt(&token), Err(Error::Unauthorized)));
     }
 }
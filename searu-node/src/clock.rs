@@ -0,0 +1,46 @@
+use std::sync::Arc;
+
+use chrono::{DateTime, Utc};
+
+/// Where time-based logic like token expiry and audit retention gets the current time, so it
+/// can be driven by a fixed clock instead of the system's.
+pub trait Clock: Send + Sync {
+    fn now(&self) -> DateTime<Utc>;
+}
+
+pub type SharedClock = Arc<dyn Clock>;
+
+/// The system's wall clock.
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> DateTime<Utc> {
+        Utc::now()
+    }
+}
+
+pub fn system() -> SharedClock {
+    Arc::new(SystemClock)
+}
+
+/// A clock that stays at the time it's set to, for tests of time-based logic.
+#[cfg(test)]
+pub struct FixedClock(std::sync::Mutex<DateTime<Utc>>);
+
+#[cfg(test)]
+impl FixedClock {
+    pub fn new(now: DateTime<Utc>) -> Arc<Self> {
+        Arc::new(Self(std::sync::Mutex::new(now)))
+    }
+
+    pub fn set(&self, now: DateTime<Utc>) {
+        *self.0.lock().unwrap() = now;
+    }
+}
+
+#[cfg(test)]
+impl Clock for FixedClock {
+    fn now(&self) -> DateTime<Utc> {
+        *self.0.lock().unwrap()
+    }
+}
@@ -1,12 +1,247 @@
-use std::path::Path;
+use std::{
+    net::{IpAddr, Ipv4Addr, SocketAddr},
+    path::{Path, PathBuf},
+    sync::Arc,
+};
 
-pub use config::{ConfigError, File};
+use crate::actors::ScorerKind;
+use arc_swap::ArcSwap;
+pub use config::{ConfigError, Environment, File};
+use ipnet::Ipv4Net;
 use serde::Deserialize;
+use tokio::{
+    signal::unix::{signal, SignalKind},
+    sync::watch,
+    task::JoinHandle,
+};
 
-#[derive(Debug, Deserialize)]
+/// The current config, swapped out in place when the node receives SIGHUP.
+pub type SharedConfig = Arc<ArcSwap<Config>>;
+
+#[derive(Clone, Debug, Deserialize)]
 pub struct Config {
     pub etcd_addr: String,
     pub jwt_secret: String,
+    /// Read-only image every VM's root disk overlay is backed by.
+    #[serde(default = "default_base_image")]
+    pub base_image: PathBuf,
+    /// Directory holding the per-VM copy-on-write overlays.
+    #[serde(default = "default_overlay_dir")]
+    pub overlay_dir: PathBuf,
+    /// Directory holding VM snapshots, one subdirectory each.
+    #[serde(default = "default_snapshot_dir")]
+    pub snapshot_dir: PathBuf,
+    /// Seconds between node heartbeats.
+    #[serde(default = "default_heartbeat_interval")]
+    pub heartbeat_interval: u64,
+    /// Seconds a node stays registered without a heartbeat before etcd expires it and its VMs
+    /// are rescheduled. Keep it a few `heartbeat_interval`s, so one late heartbeat doesn't move
+    /// every VM. An expired node loses its labels and cordon when it registers again.
+    #[serde(default = "default_node_ttl")]
+    pub node_ttl: u64,
+    /// DNS servers advertised to guests over DHCP. A reload that changes them restarts the
+    /// dnsmasq of every VPC on the node.
+    #[serde(default)]
+    pub dns_servers: Vec<Ipv4Addr>,
+    /// The most cpus a single VM may request.
+    #[serde(default)]
+    pub max_vm_cpus: Option<u8>,
+    /// The most memory, in MiB, a single VM may request.
+    #[serde(default)]
+    pub max_vm_memory: Option<usize>,
+    /// The cpus given to VMs created without any.
+    #[serde(default)]
+    pub default_vm_cpus: Option<u8>,
+    /// The memory, in MiB, given to VMs created without any.
+    #[serde(default)]
+    pub default_vm_memory: Option<usize>,
+    /// The VPC VMs created without one are attached to.
+    #[serde(default)]
+    pub default_vm_vpc: Option<String>,
+    /// Seconds a request to a VM's hypervisor may take before it's abandoned. It has to cover
+    /// writing out a snapshot of the VM's memory.
+    #[serde(default = "default_hypervisor_timeout")]
+    pub hypervisor_timeout: u64,
+    /// Seconds a VM being stopped has to power off after its ACPI power button is pressed,
+    /// before its hypervisor is killed.
+    #[serde(default = "default_vm_stop_timeout")]
+    pub vm_stop_timeout: u64,
+    /// Directory holding each VM's serial console log, kept across VM restarts.
+    #[serde(default = "default_console_log_dir")]
+    pub console_log_dir: PathBuf,
+    /// Size in bytes at which a console log is rotated.
+    #[serde(default = "default_console_log_max_size")]
+    pub console_log_max_size: u64,
+    /// How many rotated console logs to keep per VM.
+    #[serde(default = "default_console_log_files")]
+    pub console_log_files: usize,
+    /// Deletes a VM's console logs and files along with the VM instead of retaining them.
+    #[serde(default)]
+    pub purge_console_logs: bool,
+    /// Address the API listens on. Use `0.0.0.0` to accept connections from other hosts.
+    #[serde(default = "default_api_bind_addr")]
+    pub api_bind_addr: IpAddr,
+    #[serde(default = "default_api_port")]
+    pub api_port: u16,
+    /// Port the WebSocket endpoint listens on, on `api_bind_addr`. Defaults to the port after
+    /// `api_port`.
+    #[serde(default)]
+    pub ws_port: Option<u16>,
+    /// The largest request body, in bytes, the API will read.
+    #[serde(default = "default_max_body_size")]
+    pub max_body_size: u64,
+    /// How deeply arrays and objects may nest in a JSON request body.
+    #[serde(default = "default_max_json_depth")]
+    pub max_json_depth: usize,
+    /// The largest body, in bytes, `/import` will read. An import holds a whole cluster's objects,
+    /// so it gets a limit of its own rather than `max_body_size`.
+    #[serde(default = "default_max_import_size")]
+    pub max_import_size: u64,
+    /// An external OIDC provider whose tokens are accepted alongside searu's own.
+    #[serde(default)]
+    pub oidc: Option<OidcConfig>,
+    /// How the scheduler ranks the nodes a VM fits on.
+    #[serde(default)]
+    pub scheduler_scorer: ScorerKind,
+    /// Directory holding each VPC's dnsmasq lease file, kept across restarts so guests keep
+    /// their addresses. Only read on start, as the running dnsmasqs keep their files.
+    #[serde(default = "default_dhcp_lease_dir")]
+    pub dhcp_lease_dir: PathBuf,
+    /// The interface VPCs' VXLAN links send and receive their multicast traffic on, for nodes
+    /// with more than one. It must be up when a VPC's link is created.
+    #[serde(default)]
+    pub overlay_interface: Option<String>,
+    /// Multicast groups VPCs' VXLAN traffic is spread across.
+    #[serde(default = "default_multicast_range")]
+    pub multicast_range: Ipv4Net,
+    /// Seconds audit entries are kept before being compacted away.
+    #[serde(default = "default_audit_retention")]
+    pub audit_retention: u64,
+    /// The path the API's root is served under by a reverse proxy, like `/searu`, used for
+    /// links in responses. A request's `X-Forwarded-Prefix` header overrides it.
+    #[serde(default)]
+    pub external_base_path: String,
+    /// Directories on this node VMs may share into the guest with `shared_dir`, along with
+    /// everything under them. No directory can be shared when empty.
+    #[serde(default)]
+    pub shared_dir_roots: Vec<PathBuf>,
+    /// Block devices on this node VMs may attach as disks, like `/dev/sdb`. Symlinks such as
+    /// `/dev/disk/by-id` paths are resolved on both sides. No device can be attached when empty.
+    #[serde(default)]
+    pub block_devices: Vec<PathBuf>,
+    /// Creates an `admin` user on startup when there are no users yet.
+    #[serde(default = "default_seed_admin")]
+    pub seed_admin: bool,
+    /// The seeded admin's password, also settable as `SEARU_ADMIN_PASSWORD`. A random one is
+    /// generated and logged when unset.
+    #[serde(default)]
+    pub admin_password: Option<String>,
+}
+
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+pub struct OidcConfig {
+    /// Must match the `iss` claim of accepted tokens.
+    pub issuer: String,
+    pub jwks_url: String,
+    /// Must be in the `aud` claim of accepted tokens.
+    pub audience: String,
+    /// The claim used as the searu username.
+    #[serde(default = "default_oidc_username_claim")]
+    pub username_claim: String,
+    /// The claim listing the user's groups, which are used as their projects.
+    #[serde(default = "default_oidc_groups_claim")]
+    pub groups_claim: String,
+    /// Members of this group get the admin role.
+    #[serde(default)]
+    pub admin_group: Option<String>,
+}
+
+fn default_heartbeat_interval() -> u64 {
+    60
+}
+
+fn default_node_ttl() -> u64 {
+    180
+}
+
+fn default_base_image() -> PathBuf {
+    PathBuf::from("./blobs/focal-server-cloudimg-amd64.raw")
+}
+
+fn default_overlay_dir() -> PathBuf {
+    PathBuf::from("./overlays")
+}
+
+fn default_snapshot_dir() -> PathBuf {
+    PathBuf::from("./snapshots")
+}
+
+fn default_hypervisor_timeout() -> u64 {
+    30
+}
+
+fn default_vm_stop_timeout() -> u64 {
+    30
+}
+
+fn default_console_log_dir() -> PathBuf {
+    PathBuf::from("./console-logs")
+}
+
+fn default_console_log_max_size() -> u64 {
+    10 << 20
+}
+
+fn default_console_log_files() -> usize {
+    5
+}
+
+fn default_api_bind_addr() -> IpAddr {
+    IpAddr::V4(Ipv4Addr::LOCALHOST)
+}
+
+fn default_api_port() -> u16 {
+    8000
+}
+
+fn default_max_body_size() -> u64 {
+    1 << 20
+}
+
+fn default_max_json_depth() -> usize {
+    32
+}
+
+fn default_max_import_size() -> u64 {
+    1 << 30
+}
+
+fn default_seed_admin() -> bool {
+    true
+}
+
+fn default_audit_retention() -> u64 {
+    30 * 24 * 60 * 60
+}
+
+fn default_multicast_range() -> Ipv4Net {
+    Ipv4Net::new(Ipv4Addr::new(239, 1, 0, 0), 16).expect("valid prefix length")
+}
+
+fn default_dhcp_lease_dir() -> PathBuf {
+    PathBuf::from("./dhcp-leases")
+}
+
+fn default_oidc_username_claim() -> String {
+    "sub".to_string()
+}
+
+fn default_oidc_groups_claim() -> String {
+    "groups".to_string()
+}
+
+fn multicast() -> Ipv4Net {
+    Ipv4Net::new(Ipv4Addr::new(224, 0, 0, 0), 4).expect("valid prefix length")
 }
 
 impl Config {
@@ -24,7 +259,174 @@ impl Config {
                 config.merge(File::with_name(path))?;
             }
         }
+        config.merge(Environment::with_prefix("SEARU"))?;
+
+        let config: Self = config.try_into()?;
+        config.api_addr()?;
+        config.ws_addr()?;
+        if !multicast().contains(&config.multicast_range) {
+            return Err(ConfigError::Message(format!(
+                "multicast_range {} isn't a multicast range",
+                config.multicast_range
+            )));
+        }
+        Ok(config)
+    }
+
+    /// The copy-on-write overlay holding the root disk of the VM with host name `vm`, see
+    /// [`Metadata::host_name`](crate::types::Metadata::host_name).
+    pub fn overlay_path(&self, vm: &str) -> PathBuf {
+        self.overlay_dir.join(format!("{}.qcow2", vm))
+    }
+
+    /// The log the serial console of the VM with host name `vm` is copied into.
+    pub fn console_log_path(&self, vm: &str) -> PathBuf {
+        self.console_log_dir.join(format!("{}.log", vm))
+    }
+
+    /// The directory the console and serial files the VM with host name `vm` asks for with
+    /// `ConsoleMode::File` are written to, apart from the logs so no VM's file can take another's
+    /// log name.
+    pub fn console_file_dir(&self, vm: &str) -> PathBuf {
+        self.console_log_dir.join("files").join(vm)
+    }
+
+    /// The dnsmasq lease file for the VPC with host name `vpc`.
+    pub fn dhcp_lease_file(&self, vpc: &str) -> PathBuf {
+        self.dhcp_lease_dir.join(format!("{}.leases", vpc))
+    }
+
+    /// The address the API binds to.
+    pub fn api_addr(&self) -> Result<SocketAddr, ConfigError> {
+        if self.api_port == 0 {
+            return Err(ConfigError::Message(
+                "api_port must be between 1 and 65535".to_string(),
+            ));
+        }
+        Ok(SocketAddr::new(self.api_bind_addr, self.api_port))
+    }
+
+    /// The address the WebSocket endpoint binds to.
+    pub fn ws_addr(&self) -> Result<SocketAddr, ConfigError> {
+        let port = match self.ws_port {
+            Some(port) => port,
+            None => self.api_port.checked_add(1).ok_or_else(|| {
+                ConfigError::Message("ws_port must be set when api_port is 65535".to_string())
+            })?,
+        };
+        if port == 0 || port == self.api_port {
+            return Err(ConfigError::Message(
+                "ws_port must be between 1 and 65535 and differ from api_port".to_string(),
+            ));
+        }
+        Ok(SocketAddr::new(self.api_bind_addr, port))
+    }
+
+    /// Keeps the settings that can only change on restart from `current`, logging any that were
+    /// changed in the new config.
+    fn keep_restart_only(mut self, current: &Config) -> Self {
+        if self.etcd_addr != current.etcd_addr {
+            println!("etcd_addr changed, requires restart");
+            self.etcd_addr = current.etcd_addr.clone();
+        }
+        if self.jwt_secret != current.jwt_secret {
+            println!("jwt_secret changed, requires restart");
+            self.jwt_secret = current.jwt_secret.clone();
+        }
+        if self.oidc != current.oidc {
+            println!("oidc changed, requires restart");
+            self.oidc = current.oidc.clone();
+        }
+        if self.overlay_dir != current.overlay_dir {
+            println!("overlay_dir changed, requires restart");
+            self.overlay_dir = current.overlay_dir.clone();
+        }
+        if self.snapshot_dir != current.snapshot_dir {
+            println!("snapshot_dir changed, requires restart");
+            self.snapshot_dir = current.snapshot_dir.clone();
+        }
+        if self.api_bind_addr != current.api_bind_addr || self.api_port != current.api_port {
+            println!("api_bind_addr or api_port changed, requires restart");
+            self.api_bind_addr = current.api_bind_addr;
+            self.api_port = current.api_port;
+        }
+        if self.ws_port != current.ws_port {
+            println!("ws_port changed, requires restart");
+            self.ws_port = current.ws_port;
+        }
+        if self.dhcp_lease_dir != current.dhcp_lease_dir {
+            println!("dhcp_lease_dir changed, requires restart");
+            self.dhcp_lease_dir = current.dhcp_lease_dir.clone();
+        }
+        self
+    }
+}
+
+pub fn shared(config: Config) -> SharedConfig {
+    Arc::new(ArcSwap::from_pointee(config))
+}
+
+/// Reloads `config` from disk every time the process receives SIGHUP, and tells `reloaded`
+/// after each reload, for settings that only take effect once applied.
+pub fn spawn_reload(
+    config: SharedConfig,
+    reloaded: watch::Sender<()>,
+) -> JoinHandle<Result<(), anyhow::Error>> {
+    tokio::spawn(async move {
+        let mut hangup = signal(SignalKind::hangup())?;
+        while hangup.recv().await.is_some() {
+            match Config::new() {
+                Ok(new) => {
+                    let new = new.keep_restart_only(&config.load());
+                    config.store(Arc::new(new));
+                    println!("reloaded config");
+                    let _ = reloaded.send(());
+                }
+                Err(err) => println!("failed to reload config: {}", err),
+            }
+        }
+        Ok(())
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config(settings: serde_json::Value) -> Config {
+        let mut base = serde_json::json!({"etcd_addr": "localhost:2379", "jwt_secret": "secret"});
+        base.as_object_mut()
+            .unwrap()
+            .extend(settings.as_object().unwrap().clone());
+        serde_json::from_value(base).unwrap()
+    }
+
+    #[test]
+    fn reloads_keep_restart_only_settings() {
+        let current = config(serde_json::json!({
+            "dns_servers": ["10.0.0.1"],
+            "dhcp_lease_dir": "/var/lib/searu/leases",
+        }));
+        let reloaded = config(serde_json::json!({
+            "etcd_addr": "etcd:2379",
+            "dns_servers": ["10.0.0.2"],
+            "dhcp_lease_dir": "/tmp/leases",
+            "max_vm_cpus": 8,
+            "oidc": {
+                "issuer": "https://idp.example.com",
+                "jwks_url": "https://idp.example.com/jwks",
+                "audience": "searu",
+            },
+        }))
+        .keep_restart_only(&current);
 
-        config.try_into()
+        assert_eq!(reloaded.dns_servers, vec![Ipv4Addr::new(10, 0, 0, 2)]);
+        assert_eq!(reloaded.max_vm_cpus, Some(8));
+        assert_eq!(reloaded.etcd_addr, "localhost:2379");
+        assert_eq!(reloaded.oidc, None);
+        assert_eq!(
+            reloaded.dhcp_lease_dir,
+            PathBuf::from("/var/lib/searu/leases")
+        );
     }
 }
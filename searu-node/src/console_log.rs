@@ -0,0 +1,147 @@
+use std::{
+    ffi::OsString,
+    path::{Path, PathBuf},
+};
+
+use tokio::{
+    fs::{File, OpenOptions},
+    io::{AsyncRead, AsyncReadExt, AsyncWriteExt},
+    task::JoinHandle,
+};
+
+use crate::types::Error;
+
+/// The longest console line handed to [`RotatingLog::spawn_copy`]'s callback.
+pub const MAX_LINE_LEN: usize = 1024;
+
+/// An append-only log that is rotated once it grows past `max_size` bytes, keeping `files`
+/// rotated copies next to it as `<path>.1` (newest) through `<path>.<files>` (oldest).
+pub struct RotatingLog {
+    path: PathBuf,
+    max_size: u64,
+    files: usize,
+    file: File,
+    size: u64,
+}
+
+impl RotatingLog {
+    /// Opens the log at `path`, appending to what an earlier run left there.
+    pub async fn open(path: PathBuf, max_size: u64, files: usize) -> Result<Self, Error> {
+        if let Some(dir) = path.parent() {
+            tokio::fs::create_dir_all(dir).await?;
+        }
+        let file = append(&path).await?;
+        let size = file.metadata().await?.len();
+        Ok(Self {
+            path,
+            max_size,
+            files,
+            file,
+            size,
+        })
+    }
+
+    pub async fn write(&mut self, buf: &[u8]) -> Result<(), Error> {
+        if self.size > 0 && self.size + buf.len() as u64 > self.max_size {
+            self.rotate().await?;
+        }
+        self.file.write_all(buf).await?;
+        self.size += buf.len() as u64;
+        Ok(())
+    }
+
+    async fn rotate(&mut self) -> Result<(), Error> {
+        self.file.flush().await?;
+        if self.files == 0 {
+            remove(&self.path).await?;
+        } else {
+            remove(&rotated(&self.path, self.files)).await?;
+            for n in (1..self.files).rev() {
+                rename(&rotated(&self.path, n), &rotated(&self.path, n + 1)).await?;
+            }
+            rename(&self.path, &rotated(&self.path, 1)).await?;
+        }
+        self.file = append(&self.path).await?;
+        self.size = 0;
+        Ok(())
+    }
+
+    /// Copies everything read from `reader` into the log until it closes, handing each line
+    /// to `on_line` on the way. Lines longer than [`MAX_LINE_LEN`] are only logged.
+    pub fn spawn_copy<R, F>(mut self, mut reader: R, mut on_line: F) -> JoinHandle<()>
+    where
+        R: AsyncRead + Unpin + Send + 'static,
+        F: FnMut(&[u8]) + Send + 'static,
+    {
+        tokio::spawn(async move {
+            let mut buf = vec![0; 8192];
+            let mut line = Vec::with_capacity(MAX_LINE_LEN);
+            let mut overlong = false;
+            loop {
+                let n = match reader.read(&mut buf).await {
+                    Ok(0) => break,
+                    Ok(n) => n,
+                    Err(err) => {
+                        println!("error reading console: {:?}", err);
+                        break;
+                    }
+                };
+                for &byte in &buf[..n] {
+                    if byte == b'\n' {
+                        if !overlong {
+                            on_line(&line);
+                        }
+                        line.clear();
+                        overlong = false;
+                    } else if line.len() < MAX_LINE_LEN {
+                        line.push(byte);
+                    } else {
+                        overlong = true;
+                    }
+                }
+                if let Err(err) = self.write(&buf[..n]).await {
+                    println!("error writing console log {:?}: {:?}", self.path, err);
+                    break;
+                }
+            }
+            let _ = self.file.flush().await;
+        })
+    }
+}
+
+/// Removes the log at `path` along with up to `files` rotated copies.
+pub async fn purge(path: &Path, files: usize) -> Result<(), Error> {
+    remove(path).await?;
+    for n in 1..=files {
+        remove(&rotated(path, n)).await?;
+    }
+    Ok(())
+}
+
+fn rotated(path: &Path, n: usize) -> PathBuf {
+    let mut name = OsString::from(path.as_os_str());
+    name.push(format!(".{}", n));
+    PathBuf::from(name)
+}
+
+async fn append(path: &Path) -> Result<File, Error> {
+    Ok(OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)
+        .await?)
+}
+
+async fn remove(path: &Path) -> Result<(), Error> {
+    match tokio::fs::remove_file(path).await {
+        Err(err) if err.kind() != std::io::ErrorKind::NotFound => Err(err.into()),
+        _ => Ok(()),
+    }
+}
+
+async fn rename(from: &Path, to: &Path) -> Result<(), Error> {
+    match tokio::fs::rename(from, to).await {
+        Err(err) if err.kind() != std::io::ErrorKind::NotFound => Err(err.into()),
+        _ => Ok(()),
+    }
+}
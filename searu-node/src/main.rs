@@ -1,68 +1,413 @@
-use std::time::Duration;
+use std::{sync::Arc, time::Duration};
 
-use actors::{Actor, NodeInfo, Scheduler, VmSupervisor, VmWatcher, VpcSupervisor, VpcWatcher};
-use types::{Project, UserSpec};
+use actors::{
+    Actor, AuditCompactor, DHCPActor, DhcpReservationWatcher, HypervisorSockets, LeaderElection,
+    NodeInfo, NodeWatcher, OperationRunner, OperationWatcher, Scheduler, VmSupervisor, VmWatcher,
+    VpcSupervisor, VpcWatcher,
+};
+use rand::{distributions::Alphanumeric, Rng};
+use tokio::{
+    signal::unix::{signal, SignalKind},
+    sync::watch,
+};
+use types::{Error, Project, Role, User, UserSpec, Vm, VmState, Vpc};
 
 mod actors;
+mod admission;
+mod allocator;
 mod api;
+mod audit;
 mod auth;
+mod clock;
 mod config;
+mod console_log;
+mod metrics;
+mod nat;
+mod preflight;
 mod storage;
 mod types;
 pub mod vmm;
+mod ws;
+
+/// How long shutdown waits beyond `vm_stop_timeout`, for the work actors are in the middle of
+/// to finish before their guests are stopped.
+const SHUTDOWN_GRACE_PERIOD: Duration = Duration::from_secs(30);
 
 #[tokio::main]
 async fn main() -> Result<(), anyhow::Error> {
     let config = config::Config::new()?;
-    let client = etcd_client::Client::connect([&config.etcd_addr], None).await?;
-    let storage = storage::Storage::new(client);
-    let auth = auth::Auth::new(&config.jwt_secret)?;
-    storage
-        .store(&UserSpec::new("admin".to_string(), "admin".to_string()).encrypt()?)
-        .await?;
-    storage
-        .store(&Project {
-            name: "default".to_string(),
-        })
-        .await?;
-    let node_info = NodeInfo::new(storage.clone()).repeat(Duration::from_secs(60));
-    let (scheduler, scheduler_handle) = Scheduler::new(storage.clone()).spawn();
     let (netlink_conn, netlink_handle, _) = rtnetlink::new_connection().unwrap();
     let netlink_conn = tokio::spawn(async {
         netlink_conn.await;
         Ok::<_, anyhow::Error>(())
     });
-    let vm_supervisor = VmSupervisor::new(storage.clone(), netlink_handle.clone())?;
-    let (vm_supervisor, vm_supervisor_handle) = vm_supervisor.spawn();
-    let vm_watcher = VmWatcher::new(storage.clone(), scheduler.clone(), vm_supervisor).spawn();
+    preflight::run(&config, &netlink_handle).await?;
+    let client = etcd_client::Client::connect([&config.etcd_addr], None).await?;
+    let storage = storage::Storage::new(storage::EtcdBackend::new(client));
+    let clock = clock::system();
+    let auth = Arc::new(auth::Auth::new(
+        &config.jwt_secret,
+        config.oidc.clone(),
+        clock.clone(),
+    )?);
+    let admissions = admission::Admissions::new(&config);
+    let api_addr = config.api_addr()?;
+    let ws_addr = config.ws_addr()?;
+    let config = config::shared(config);
+    let (reloaded, config_reloads) = watch::channel(());
+    let config_reload = config::spawn_reload(config.clone(), reloaded);
+    if config.load().seed_admin {
+        seed(&storage, config.load().admin_password.clone()).await?;
+    }
+    migrate(&storage).await?;
+    let heartbeat_config = config.clone();
+    let node_info = NodeInfo::new(storage.clone(), config.clone(), clock.clone())
+        .repeat_with(move || Duration::from_secs(heartbeat_config.load().heartbeat_interval));
+    let audit_compactor = LeaderElection::new(storage.clone(), "audit_compactor", clock.clone())?
+        .run(
+            AuditCompactor::new(storage.clone(), config.clone(), clock.clone()),
+            Duration::from_secs(60 * 60),
+        );
+    let scheduler_leader =
+        LeaderElection::new(storage.clone(), "scheduler", clock.clone())?.campaign();
+    let (scheduler, scheduler_handle) = {
+        let (storage, config, leader, clock) = (
+            storage.clone(),
+            config.clone(),
+            scheduler_leader.clone(),
+            clock.clone(),
+        );
+        Scheduler::spawn_supervised("scheduler", move || {
+            Ok(Scheduler::new(
+                storage.clone(),
+                config.clone(),
+                leader.clone(),
+                clock.clone(),
+            ))
+        })
+    };
+    let scheduler_takeover =
+        actors::spawn_takeover(storage.clone(), scheduler.clone(), scheduler_leader);
+    let sockets = HypervisorSockets::default();
+    let (vm_supervisor, vm_supervisor_handle) = {
+        let (storage, netlink_handle, config, sockets, clock) = (
+            storage.clone(),
+            netlink_handle.clone(),
+            config.clone(),
+            sockets.clone(),
+            clock.clone(),
+        );
+        VmSupervisor::spawn_supervised("vm_supervisor", move || {
+            VmSupervisor::new(
+                storage.clone(),
+                netlink_handle.clone(),
+                config.clone(),
+                sockets.clone(),
+                clock.clone(),
+            )
+        })
+    };
+    let vm_health_check = actors::spawn_health_check(vm_supervisor.clone());
+    let metrics = metrics::Metrics::default()
+        .mailbox("scheduler", scheduler.clone())
+        .mailbox("vm_supervisor", vm_supervisor.clone());
+    let vm_watcher =
+        VmWatcher::new(storage.clone(), scheduler.clone(), vm_supervisor.clone()).spawn();
 
-    let (vpc_supervisor, vpc_supervisor_handle) =
-        VpcSupervisor::new(storage.clone(), netlink_handle).spawn();
-    let vpc_watcher = VpcWatcher::new(storage.clone(), scheduler, vpc_supervisor).spawn();
+    let (dhcp, dhcp_handle) = {
+        let (storage, config) = (storage.clone(), config.clone());
+        DHCPActor::spawn_supervised("dhcp", move || {
+            Ok(DHCPActor::new(storage.clone(), config.clone()))
+        })
+    };
+    let dhcp_watcher = DhcpReservationWatcher::new(storage.clone(), dhcp.clone()).spawn();
+    let dhcp_config_reload = actors::spawn_config_reload(dhcp.clone(), config_reloads);
+    let (vpc_supervisor, vpc_supervisor_handle) = {
+        let (storage, dhcp, config) = (storage.clone(), dhcp.clone(), config.clone());
+        VpcSupervisor::spawn_supervised("vpc_supervisor", move || {
+            Ok(VpcSupervisor::new(
+                storage.clone(),
+                netlink_handle.clone(),
+                dhcp.clone(),
+                config.clone(),
+            ))
+        })
+    };
+    let metrics = metrics
+        .mailbox("dhcp", dhcp.clone())
+        .mailbox("vpc_supervisor", vpc_supervisor.clone());
+    let vpc_watcher =
+        VpcWatcher::new(storage.clone(), scheduler.clone(), vpc_supervisor.clone()).spawn();
+    let node_watcher = NodeWatcher::new(storage.clone(), scheduler.clone()).spawn();
+    let (operation_runner, operation_runner_handle) = {
+        let (storage, clock) = (storage.clone(), clock.clone());
+        OperationRunner::spawn_supervised("operation_runner", move || {
+            OperationRunner::new(storage.clone(), clock.clone())
+        })
+    };
+    let metrics = metrics.mailbox("operation_runner", operation_runner.clone());
+    let operation_watcher =
+        OperationWatcher::new(storage.clone(), operation_runner.clone()).spawn();
+    let shutdown_timeout =
+        Duration::from_secs(config.load().vm_stop_timeout) + SHUTDOWN_GRACE_PERIOD;
+    let figment = rocket::Config::figment()
+        .merge(("address", api_addr.ip()))
+        .merge(("port", api_addr.port()));
+    println!("api listening on {}", api_addr);
+    let websockets = ws::spawn(ws_addr, storage.clone(), auth.clone());
+    let audit_log = audit::AuditLog::new(storage.clone(), clock.clone());
     let rocket = tokio::spawn(async {
-        rocket::build()
+        rocket::custom(figment)
             .manage(storage)
             .manage(config)
             .manage(auth)
+            .manage(admissions)
+            .manage(metrics)
+            .manage(sockets)
+            .manage(clock)
+            .attach(audit_log)
             .mount("/api", api::routes())
+            .register("/api", api::catchers())
             .ignite()
             .await?
             .launch()
             .await?;
         Ok::<_, anyhow::Error>(())
     });
-    let _ = futures::future::select_all(vec![
+    let signals = tokio::spawn(async {
+        let mut terminate = signal(SignalKind::terminate())?;
+        tokio::select! {
+            _ = terminate.recv() => println!("received SIGTERM"),
+            result = tokio::signal::ctrl_c() => {
+                result?;
+                println!("received SIGINT");
+            }
+        }
+        Ok::<_, anyhow::Error>(())
+    });
+    let result = futures::future::select_all(vec![
+        signals,
+        config_reload,
         node_info,
+        audit_compactor,
         rocket,
+        websockets,
         vm_supervisor_handle,
+        vm_health_check,
         vm_watcher,
         vpc_supervisor_handle,
         vpc_watcher,
+        node_watcher,
+        dhcp_handle,
+        dhcp_watcher,
+        dhcp_config_reload,
         scheduler_handle,
+        scheduler_takeover,
+        operation_runner_handle,
+        operation_watcher,
         netlink_conn,
     ])
     .await
-    .0?;
+    .0;
+    println!("shutting down");
+    let shutdown = async {
+        // Guests go first, while their networks are still up
+        if let Err(err) = vm_supervisor.shutdown().await {
+            println!("error shutting down vm_supervisor: {}", err);
+        }
+        let results = futures::future::join4(
+            scheduler.shutdown(),
+            vpc_supervisor.shutdown(),
+            dhcp.shutdown(),
+            operation_runner.shutdown(),
+        )
+        .await;
+        for (actor, result) in [
+            ("scheduler", results.0),
+            ("vpc_supervisor", results.1),
+            ("dhcp", results.2),
+            ("operation_runner", results.3),
+        ] {
+            if let Err(err) = result {
+                println!("error shutting down {}: {}", actor, err);
+            }
+        }
+    };
+    if tokio::time::timeout(shutdown_timeout, shutdown)
+        .await
+        .is_err()
+    {
+        println!("timed out shutting down after {:?}", shutdown_timeout);
+    }
+    let _ = result?;
     println!("exiting");
     Ok(())
 }
+
+/// Moves VMs and VPCs stored before keys were scoped by project under their project, before any
+/// actor watches them. See [`storage::Storage::migrate_project_keys`].
+async fn migrate(storage: &storage::Storage) -> Result<(), Error> {
+    let vpc_pools = [
+        allocator::pool_prefix("multicast_ip"),
+        allocator::pool_prefix("vni"),
+    ];
+    let vpcs = storage.migrate_project_keys::<Vpc>(&vpc_pools).await?;
+    let vm_pools = [allocator::pool_prefix("block_device")];
+    let vms = storage.migrate_project_keys::<Vm>(&vm_pools).await?;
+    if vpcs > 0 || vms > 0 {
+        println!("moved {} vpcs and {} vms under their projects", vpcs, vms);
+    }
+    let powered_on = migrate_powered_on(storage, &sys_info::hostname()?).await?;
+    if powered_on > 0 {
+        println!("set powered_on on {} vms", powered_on);
+    }
+    Ok(())
+}
+
+/// Marks that [`migrate_powered_on`] ran on the cluster.
+const POWERED_ON_MIGRATION: &str = "migration/powered_on";
+
+/// Sets `powered_on` on the VMs stored before nodes honoured it, which were left `false` but
+/// booted all the same, so they keep booting. VMs since powered off through the API are left
+/// off. Runs once per cluster, returning how many VMs it changed.
+async fn migrate_powered_on(storage: &storage::Storage, node: &str) -> Result<usize, Error> {
+    if storage
+        .claims("migration/")
+        .await?
+        .contains_key("powered_on")
+    {
+        return Ok(0);
+    }
+    let mut migrated = 0;
+    for mut vm in storage.list::<Vm>().await? {
+        if vm.spec.powered_on || vm.status.state == VmState::PoweredOff {
+            continue;
+        }
+        vm.spec.powered_on = true;
+        match storage.update(&vm).await {
+            Ok(()) => migrated += 1,
+            // Changed since it was listed, so it's already managed through the API
+            Err(Error::Conflict(_)) => {}
+            Err(err) => return Err(err),
+        }
+    }
+    storage.claim(POWERED_ON_MIGRATION, node).await?;
+    Ok(migrated)
+}
+
+/// Creates the `admin` user and `default` project on a fresh cluster. Nothing is overwritten, so
+/// a changed admin password survives restarts.
+async fn seed(storage: &storage::Storage, password: Option<String>) -> Result<(), Error> {
+    match storage
+        .create(&Project {
+            name: "default".to_string(),
+        })
+        .await
+    {
+        Ok(()) | Err(Error::AlreadyExists(_)) => {}
+        Err(err) => return Err(err),
+    }
+    if !storage.list::<User>().await?.is_empty() {
+        return Ok(());
+    }
+    let password = match password {
+        Some(password) => password,
+        None => {
+            let password: String = rand::thread_rng()
+                .sample_iter(&Alphanumeric)
+                .take(24)
+                .map(char::from)
+                .collect();
+            println!("seeded admin user with password {}", password);
+            password
+        }
+    };
+    let mut admin = UserSpec::new("admin".to_string(), password).encrypt()?;
+    admin.role = Role::Admin;
+    admin.projects = vec!["default".to_string()];
+    match storage.create(&admin).await {
+        Ok(()) | Err(Error::AlreadyExists(_)) => Ok(()),
+        Err(err) => Err(err),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use storage::MemoryBackend;
+
+    #[tokio::test]
+    async fn seeding_keeps_an_existing_admin() {
+        let storage = storage::Storage::new(MemoryBackend::new());
+        let admin = User {
+            username: "admin".to_string(),
+            encrypted_password: "changed".to_string(),
+            role: Role::Admin,
+            projects: vec!["ops".to_string()],
+        };
+        storage.create(&admin).await.unwrap();
+
+        seed(&storage, Some("from-config".to_string()))
+            .await
+            .unwrap();
+        let kept: User = storage.get("admin").await.unwrap().unwrap();
+        assert_eq!(kept.encrypted_password, "changed");
+        assert_eq!(kept.projects, vec!["ops".to_string()]);
+        assert!(storage.get::<Project>("default").await.unwrap().is_some());
+    }
+
+    #[tokio::test]
+    async fn seeding_a_fresh_cluster_creates_the_admin_once() {
+        let storage = storage::Storage::new(MemoryBackend::new());
+        seed(&storage, Some("first".to_string())).await.unwrap();
+        let admin: User = storage.get("admin").await.unwrap().unwrap();
+        assert_eq!(admin.role, Role::Admin);
+        assert_eq!(admin.projects, vec!["default".to_string()]);
+        assert!(bcrypt::verify("first", &admin.encrypted_password).unwrap());
+
+        seed(&storage, Some("second".to_string())).await.unwrap();
+        let reseeded: User = storage.get("admin").await.unwrap().unwrap();
+        assert_eq!(reseeded.encrypted_password, admin.encrypted_password);
+    }
+
+    #[tokio::test]
+    async fn vms_from_before_powered_on_keep_booting() {
+        let storage = storage::Storage::new(MemoryBackend::new());
+        let vm = |name: &str, state: VmState| {
+            let mut vm = Vm {
+                metadata: types::Metadata {
+                    name: name.to_string(),
+                    project: "default".to_string(),
+                    ..Default::default()
+                },
+                spec: Default::default(),
+                status: Default::default(),
+            };
+            vm.status.state = state;
+            vm
+        };
+        storage
+            .create(&vm("running", VmState::PoweredOn))
+            .await
+            .unwrap();
+        storage
+            .create(&vm("off", VmState::PoweredOff))
+            .await
+            .unwrap();
+
+        assert_eq!(migrate_powered_on(&storage, "n1").await.unwrap(), 1);
+        let running: Vm = storage.get("default/running").await.unwrap().unwrap();
+        assert!(running.spec.powered_on);
+        let off: Vm = storage.get("default/off").await.unwrap().unwrap();
+        assert!(!off.spec.powered_on);
+
+        // Only once, so VMs powered off later stay off
+        storage
+            .create(&vm("later", VmState::Pending))
+            .await
+            .unwrap();
+        assert_eq!(migrate_powered_on(&storage, "n1").await.unwrap(), 0);
+        let later: Vm = storage.get("default/later").await.unwrap().unwrap();
+        assert!(!later.spec.powered_on);
+    }
+}
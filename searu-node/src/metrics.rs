@@ -0,0 +1,76 @@
+use std::fmt::Write;
+
+use crate::actors::{Actor, Handle};
+
+struct Gauge {
+    name: &'static str,
+    help: &'static str,
+    labels: String,
+    read: Box<dyn Fn() -> f64 + Send + Sync>,
+}
+
+/// Gauges read on every scrape and rendered in the Prometheus text format.
+#[derive(Default)]
+pub struct Metrics {
+    gauges: Vec<Gauge>,
+}
+
+impl Metrics {
+    pub fn gauge(
+        mut self,
+        name: &'static str,
+        help: &'static str,
+        labels: String,
+        read: impl Fn() -> f64 + Send + Sync + 'static,
+    ) -> Self {
+        self.gauges.push(Gauge {
+            name,
+            help,
+            labels,
+            read: Box::new(read),
+        });
+        self
+    }
+
+    /// Tracks how many messages are queued for `actor`, to make backpressure visible.
+    pub fn mailbox<A>(self, actor: &str, handle: Handle<A>) -> Self
+    where
+        A: Actor + 'static,
+        A::Message: Send,
+        A::Response: Send,
+    {
+        let labels = format!("actor=\"{}\"", actor);
+        let depth = handle.clone();
+        self.gauge(
+            "searu_actor_mailbox_depth",
+            "Messages waiting in an actor's mailbox.",
+            labels.clone(),
+            move || depth.mailbox().0 as f64,
+        )
+        .gauge(
+            "searu_actor_mailbox_capacity",
+            "Messages an actor's mailbox can hold.",
+            labels,
+            move || handle.mailbox().1 as f64,
+        )
+    }
+
+    pub fn render(&self) -> String {
+        let mut out = String::new();
+        let mut names: Vec<(&str, &str)> = vec![];
+        for gauge in &self.gauges {
+            if !names.iter().any(|(name, _)| *name == gauge.name) {
+                names.push((gauge.name, gauge.help));
+            }
+        }
+        // Samples of a metric have to be grouped under a single HELP and TYPE
+        for (name, help) in names {
+            let _ = writeln!(out, "# HELP {} {}", name, help);
+            let _ = writeln!(out, "# TYPE {} gauge", name);
+            for gauge in self.gauges.iter().filter(|gauge| gauge.name == name) {
+                let _ = writeln!(out, "{}{{{}}} {}", name, gauge.labels, (gauge.read)());
+            }
+        }
+        out
+    }
+}
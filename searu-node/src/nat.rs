@@ -0,0 +1,90 @@
+//! Forwarding and masquerading that let a VPC's guests reach networks outside the VPC through
+//! the node they run on.
+
+use ipnet::Ipv4Net;
+use tokio::process::Command;
+
+use crate::types::Error;
+
+/// The iptables rules NATing a VPC's traffic out of the node, as `(table, rule)`. VPCs are
+/// passed by their host name, which their bridge is named after.
+fn rules(vpc: &str, subnet: Ipv4Net) -> Vec<(&'static str, Vec<String>)> {
+    let bridge = format!("b{}", vpc);
+    let subnet = subnet.trunc().to_string();
+    vec![
+        (
+            "nat",
+            vec![
+                "POSTROUTING".to_string(),
+                "-s".to_string(),
+                subnet,
+                "!".to_string(),
+                "-o".to_string(),
+                bridge.clone(),
+                "-j".to_string(),
+                "MASQUERADE".to_string(),
+            ],
+        ),
+        (
+            "filter",
+            vec![
+                "FORWARD".to_string(),
+                "-i".to_string(),
+                bridge.clone(),
+                "-j".to_string(),
+                "ACCEPT".to_string(),
+            ],
+        ),
+        (
+            "filter",
+            vec![
+                "FORWARD".to_string(),
+                "-o".to_string(),
+                bridge,
+                "-m".to_string(),
+                "conntrack".to_string(),
+                "--ctstate".to_string(),
+                "RELATED,ESTABLISHED".to_string(),
+                "-j".to_string(),
+                "ACCEPT".to_string(),
+            ],
+        ),
+    ]
+}
+
+/// Enables forwarding on the node and adds the VPC's NAT rules, skipping rules already present.
+pub async fn enable(vpc: &str, subnet: Ipv4Net) -> Result<(), Error> {
+    tokio::fs::write("/proc/sys/net/ipv4/ip_forward", "1").await?;
+    for (table, rule) in rules(vpc, subnet) {
+        if !iptables(table, "-C", &rule).await? && !iptables(table, "-A", &rule).await? {
+            return Err(Error::IO(std::io::Error::new(
+                std::io::ErrorKind::Other,
+                format!("iptables failed to add {} rule: {}", table, rule.join(" ")),
+            )));
+        }
+    }
+    Ok(())
+}
+
+/// Removes the VPC's NAT rules. Forwarding stays on, as other VPCs may rely on it.
+pub async fn disable(vpc: &str, subnet: Ipv4Net) -> Result<(), Error> {
+    for (table, rule) in rules(vpc, subnet) {
+        while iptables(table, "-C", &rule).await? {
+            iptables(table, "-D", &rule).await?;
+        }
+    }
+    Ok(())
+}
+
+/// Runs an iptables command on `rule`, returning whether it succeeded.
+async fn iptables(table: &str, command: &str, rule: &[String]) -> Result<bool, Error> {
+    let status = Command::new("iptables")
+        .kill_on_drop(true)
+        .args(&["-w", "-t", table, command])
+        .args(rule)
+        .stdout(std::process::Stdio::null())
+        .stderr(std::process::Stdio::null())
+        .status()
+        .await?;
+    Ok(status.success())
+}
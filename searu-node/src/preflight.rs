@@ -0,0 +1,138 @@
+//! Checks run at startup, before any actor spawns, that the node can actually run what it
+//! would be asked to. Missing requirements fail startup; missing optional tools only disable
+//! the features that use them, so they are logged as warnings.
+
+use std::{
+    os::unix::fs::PermissionsExt,
+    path::{Path, PathBuf},
+};
+
+use futures::stream::TryStreamExt;
+use rtnetlink::Handle as NetLinkHandle;
+
+use crate::{config::Config, types::Error};
+
+/// The bit for CAP_NET_ADMIN in the capability sets of /proc/self/status.
+const CAP_NET_ADMIN: u32 = 12;
+
+/// Kernel modules that VPC networking and VM network devices rely on.
+const KERNEL_MODULES: &[&str] = &["bridge", "vxlan", "tun"];
+
+pub async fn run(config: &Config, netlink: &NetLinkHandle) -> Result<(), Error> {
+    let mut errors = vec![];
+    let mut require = |ok: bool, msg: String| {
+        if !ok {
+            errors.push(Error::NotReady(msg));
+        }
+    };
+    require(
+        has_capability(CAP_NET_ADMIN).await?,
+        "missing CAP_NET_ADMIN, vpcs and vm networking need it".to_string(),
+    );
+    require(
+        netlink.link().get().execute().try_next().await.is_ok(),
+        "can't list links over netlink".to_string(),
+    );
+    require(
+        Path::new("/dev/kvm").exists(),
+        "/dev/kvm doesn't exist, vms can't run".to_string(),
+    );
+    require(
+        is_executable(Path::new("./blobs/cloud-hypervisor")),
+        "./blobs/cloud-hypervisor is missing or not executable".to_string(),
+    );
+    require(
+        find_on_path("qemu-img").is_some(),
+        "qemu-img isn't on the path, vm disks can't be created".to_string(),
+    );
+    for module in KERNEL_MODULES {
+        require(
+            has_kernel_module(module).await,
+            format!("kernel module {} isn't available", module),
+        );
+    }
+
+    warn(
+        find_on_path("dnsmasq").is_some(),
+        "dnsmasq isn't on the path, vpcs won't serve dhcp",
+    );
+    warn(
+        find_on_path("iptables").is_some(),
+        "iptables isn't on the path, vpcs with nat_gateway won't start",
+    );
+    warn(
+        find_on_path("cloud-localds").is_some(),
+        "cloud-localds isn't on the path, vms with cloud_init won't start",
+    );
+    warn(
+        Path::new("./blobs/hypervisor-fw").is_file(),
+        "./blobs/hypervisor-fw is missing, vms without a kernel won't boot",
+    );
+    warn(
+        is_executable(Path::new("./blobs/virtiofsd")),
+        "./blobs/virtiofsd is missing, vms with a shared_dir won't start",
+    );
+    warn(
+        config.base_image.is_file(),
+        &format!(
+            "base image {} is missing, vms won't start",
+            config.base_image.display()
+        ),
+    );
+    Error::collect(errors)
+}
+
+fn warn(ok: bool, msg: &str) {
+    if !ok {
+        println!("preflight warning: {}", msg);
+    }
+}
+
+/// Whether the effective capability set of this process includes `cap`.
+async fn has_capability(cap: u32) -> Result<bool, Error> {
+    let status = tokio::fs::read_to_string("/proc/self/status").await?;
+    let caps = status
+        .lines()
+        .find_map(|line| line.strip_prefix("CapEff:"))
+        .and_then(|caps| u64::from_str_radix(caps.trim(), 16).ok())
+        .unwrap_or_default();
+    Ok(caps & (1 << cap) != 0)
+}
+
+/// Whether `module` is loaded, built in, or can be loaded on demand.
+async fn has_kernel_module(module: &str) -> bool {
+    if Path::new("/sys/module").join(module).exists() {
+        return true;
+    }
+    let release = match tokio::fs::read_to_string("/proc/sys/kernel/osrelease").await {
+        Ok(release) => release,
+        Err(_) => return false,
+    };
+    let dir = Path::new("/lib/modules").join(release.trim());
+    for index in &["modules.builtin", "modules.dep"] {
+        if let Ok(contents) = tokio::fs::read_to_string(dir.join(index)).await {
+            // Lines start with the module's path, like `kernel/drivers/net/vxlan.ko.xz: ...`
+            let found = contents.lines().any(|line| {
+                let path = line.split(".ko").next().unwrap_or_default();
+                path.rsplit('/').next() == Some(module)
+            });
+            if found {
+                return true;
+            }
+        }
+    }
+    false
+}
+
+fn find_on_path(bin: &str) -> Option<PathBuf> {
+    let path = std::env::var_os("PATH")?;
+    std::env::split_paths(&path)
+        .map(|dir| dir.join(bin))
+        .find(|path| is_executable(path))
+}
+
+fn is_executable(path: &Path) -> bool {
+    path.metadata()
+        .map(|metadata| metadata.is_file() && metadata.permissions().mode() & 0o111 != 0)
+        .unwrap_or(false)
+}
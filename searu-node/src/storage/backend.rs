@@ -0,0 +1,97 @@
+use futures::stream::BoxStream;
+
+use crate::types::Error;
+
+/// A key and its value, along with the metadata objects are versioned by.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct KeyValue {
+    pub key: String,
+    pub value: Vec<u8>,
+    /// How many times the key has been written since it was created, starting at 1.
+    pub version: i64,
+    /// The store revision of the key's last write.
+    pub mod_revision: i64,
+    /// The lease the key is attached to, 0 for none.
+    pub lease: i64,
+}
+
+/// A condition on a key that a [`Backend::txn`] requires.
+#[derive(Clone, Debug)]
+pub enum Compare {
+    /// The key's version, 0 meaning the key doesn't exist.
+    Version(String, i64),
+    ModRevision(String, i64),
+    Value(String, Vec<u8>),
+}
+
+/// A write applied by a [`Backend::txn`].
+#[derive(Clone, Debug)]
+pub enum Op {
+    Put {
+        key: String,
+        value: Vec<u8>,
+        lease: Option<i64>,
+    },
+    Delete(String),
+}
+
+#[derive(Clone, Debug)]
+pub enum WatchEvent {
+    /// A key was written, with its previous value if the backend reports it.
+    Put {
+        kv: KeyValue,
+        prev: Option<KeyValue>,
+    },
+    Delete(String),
+}
+
+/// A store of versioned keys that [`super::Storage`] keeps objects in. Ranges cover the keys
+/// from `start` up to but excluding `end`, or only `start` when `end` is `None`.
+#[async_trait::async_trait]
+pub trait Backend: Send + Sync {
+    /// Reads up to `limit` keys in a range, optionally as of `revision`, along with the revision
+    /// they were read at.
+    async fn range(
+        &self,
+        start: &str,
+        end: Option<&str>,
+        limit: Option<i64>,
+        revision: Option<i64>,
+    ) -> Result<(Vec<KeyValue>, i64), Error>;
+
+    /// Applies `ops` if every compare holds, returning whether they did.
+    async fn txn(&self, compares: Vec<Compare>, ops: Vec<Op>) -> Result<bool, Error>;
+
+    /// Deletes a range of keys, returning how many were deleted.
+    async fn delete_range(&self, start: &str, end: Option<&str>) -> Result<i64, Error>;
+
+    /// Grants a lease that expires after `ttl` seconds unless kept alive.
+    async fn grant_lease(&self, ttl: i64) -> Result<i64, Error>;
+
+    /// Refreshes a lease once, returning its remaining ttl, or `None` if it already expired.
+    async fn keep_alive(&self, lease: i64) -> Result<Option<i64>, Error>;
+
+    /// Revokes a lease before it expires, deleting the keys attached to it.
+    async fn revoke_lease(&self, lease: i64) -> Result<(), Error>;
+
+    /// Streams the changes to a range of keys. Dropping the stream ends the watch.
+    async fn watch(
+        &self,
+        start: &str,
+        end: Option<&str>,
+    ) -> Result<BoxStream<'static, WatchEvent>, Error>;
+}
+
+/// The end of the range covering every key starting with `prefix`.
+pub fn prefix_end(prefix: &str) -> String {
+    let mut end = prefix.to_string();
+    match end
+        .pop()
+        .and_then(|last| std::char::from_u32(last as u32 + 1))
+    {
+        Some(next) => end.push(next),
+        // Nothing sorts after the prefix, so the range runs to the end of the keys
+        None => return "\0".to_string(),
+    }
+    end
+}
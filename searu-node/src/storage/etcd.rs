@@ -0,0 +1,192 @@
+use std::{
+    pin::Pin,
+    task::{Context, Poll},
+};
+
+use etcd_client::{
+    Client, Compare as EtcdCompare, CompareOp, DeleteOptions, EventType, GetOptions, PutOptions,
+    Txn, TxnOp, WatchOptions, Watcher,
+};
+use futures::{stream::BoxStream, Stream, StreamExt};
+
+use super::backend::{Backend, Compare, KeyValue, Op, WatchEvent};
+use crate::types::Error;
+
+/// Keeps objects in etcd. Clones of the client share one multiplexed connection, so each
+/// operation works on its own clone rather than queueing behind the others on a lock.
+pub struct EtcdBackend {
+    client: Client,
+}
+
+impl EtcdBackend {
+    pub fn new(client: Client) -> Self {
+        Self { client }
+    }
+
+    fn client(&self) -> Client {
+        self.client.clone()
+    }
+}
+
+fn convert(kv: &etcd_client::KeyValue) -> KeyValue {
+    KeyValue {
+        key: String::from_utf8_lossy(kv.key()).into_owned(),
+        value: kv.value().to_vec(),
+        version: kv.version(),
+        mod_revision: kv.mod_revision(),
+        lease: kv.lease(),
+    }
+}
+
+#[async_trait::async_trait]
+impl Backend for EtcdBackend {
+    async fn range(
+        &self,
+        start: &str,
+        end: Option<&str>,
+        limit: Option<i64>,
+        revision: Option<i64>,
+    ) -> Result<(Vec<KeyValue>, i64), Error> {
+        let mut options = GetOptions::default();
+        if let Some(end) = end {
+            options = options.with_range(end);
+        }
+        if let Some(limit) = limit {
+            options = options.with_limit(limit);
+        }
+        if let Some(revision) = revision {
+            options = options.with_revision(revision);
+        }
+        let resp = self.client().get(start, Some(options)).await?;
+        let revision = resp.header().map(|h| h.revision()).unwrap_or_default();
+        Ok((resp.kvs().iter().map(convert).collect(), revision))
+    }
+
+    async fn txn(&self, compares: Vec<Compare>, ops: Vec<Op>) -> Result<bool, Error> {
+        let compares: Vec<EtcdCompare> = compares
+            .into_iter()
+            .map(|compare| match compare {
+                Compare::Version(key, version) => {
+                    EtcdCompare::version(key, CompareOp::Equal, version)
+                }
+                Compare::ModRevision(key, rev) => {
+                    EtcdCompare::mod_revision(key, CompareOp::Equal, rev)
+                }
+                Compare::Value(key, value) => EtcdCompare::value(key, CompareOp::Equal, value),
+            })
+            .collect();
+        let ops: Vec<TxnOp> = ops
+            .into_iter()
+            .map(|op| match op {
+                Op::Put { key, value, lease } => TxnOp::put(
+                    key,
+                    value,
+                    lease.map(|lease| PutOptions::new().with_lease(lease)),
+                ),
+                Op::Delete(key) => TxnOp::delete(key, None),
+            })
+            .collect();
+        let mut txn = Txn::new();
+        if !compares.is_empty() {
+            txn = txn.when(compares);
+        }
+        let resp = self.client().txn(txn.and_then(ops)).await?;
+        Ok(resp.succeeded())
+    }
+
+    async fn delete_range(&self, start: &str, end: Option<&str>) -> Result<i64, Error> {
+        let options = end.map(|end| DeleteOptions::new().with_range(end));
+        let resp = self.client().delete(start, options).await?;
+        Ok(resp.deleted())
+    }
+
+    async fn grant_lease(&self, ttl: i64) -> Result<i64, Error> {
+        let resp = self.client().lease_grant(ttl, None).await?;
+        Ok(resp.id())
+    }
+
+    async fn keep_alive(&self, lease: i64) -> Result<Option<i64>, Error> {
+        let (mut keeper, mut stream) = self.client().lease_keep_alive(lease).await?;
+        keeper.keep_alive().await?;
+        Ok(stream
+            .message()
+            .await?
+            .map(|resp| resp.ttl())
+            .filter(|ttl| *ttl > 0))
+    }
+
+    async fn revoke_lease(&self, lease: i64) -> Result<(), Error> {
+        self.client().lease_revoke(lease).await?;
+        Ok(())
+    }
+
+    async fn watch(
+        &self,
+        start: &str,
+        end: Option<&str>,
+    ) -> Result<BoxStream<'static, WatchEvent>, Error> {
+        // Without the previous value every put would look like a new key, and no update would
+        // ever be reported
+        let mut options = WatchOptions::default().with_prev_key();
+        if let Some(end) = end {
+            options = options.with_range(end);
+        }
+        let (watcher, stream) = self.client().watch(start, Some(options)).await?;
+        let stream = stream
+            .flat_map(|resp| {
+                futures::stream::iter(match resp {
+                    Ok(resp) => resp
+                        .events()
+                        .iter()
+                        .filter_map(|e| {
+                            let kv = e.kv()?;
+                            Some(match e.event_type() {
+                                EventType::Put => WatchEvent::Put {
+                                    kv: convert(kv),
+                                    prev: e.prev_kv().map(convert),
+                                },
+                                EventType::Delete => WatchEvent::Delete(convert(kv).key),
+                            })
+                        })
+                        .collect::<Vec<_>>(),
+                    Err(_) => vec![],
+                })
+            })
+            .boxed();
+        Ok(CancelOnDrop {
+            stream,
+            watcher: Some(watcher),
+        }
+        .boxed())
+    }
+}
+
+/// A watch's events that cancels the watch on etcd when dropped, so watches whose receiver is
+/// gone don't pile up on the server across watcher restarts.
+struct CancelOnDrop {
+    stream: BoxStream<'static, WatchEvent>,
+    watcher: Option<Watcher>,
+}
+
+impl Stream for CancelOnDrop {
+    type Item = WatchEvent;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        self.stream.poll_next_unpin(cx)
+    }
+}
+
+impl Drop for CancelOnDrop {
+    fn drop(&mut self) {
+        if let Some(mut watcher) = self.watcher.take() {
+            // Outside a runtime we're shutting down, and the connection goes with it
+            if let Ok(runtime) = tokio::runtime::Handle::try_current() {
+                runtime.spawn(async move {
+                    if let Err(err) = watcher.cancel().await {
+                        println!("failed to cancel watch {}: {}", watcher.watch_id(), err);
+                    }
+                });
+            }
+        }
+    }
+}
@@ -0,0 +1,439 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    sync::Arc,
+};
+
+use futures::{
+    channel::mpsc::{self, UnboundedSender},
+    stream::BoxStream,
+    StreamExt,
+};
+use parking_lot::Mutex;
+
+use super::backend::{Backend, Compare, KeyValue, Op, WatchEvent};
+use crate::types::Error;
+
+/// Keeps objects in memory, for tests that need a [`super::Storage`] without an etcd. Versions,
+/// revisions, transactions and watches behave like etcd's, and watches always report the
+/// previous value of a key. Leases never expire on their own; [`MemoryBackend::expire_lease`]
+/// stands in for a lease running out. Clones share the same keys.
+#[derive(Clone)]
+pub struct MemoryBackend {
+    state: Arc<Mutex<State>>,
+}
+
+#[derive(Default)]
+struct State {
+    revision: i64,
+    kvs: BTreeMap<String, KeyValue>,
+    /// Every write in order, with the key's value after it or `None` once deleted, so ranges
+    /// can be read as of an earlier revision.
+    history: Vec<(i64, String, Option<KeyValue>)>,
+    /// The ttl of every live lease.
+    leases: HashMap<i64, i64>,
+    next_lease: i64,
+    watches: Vec<Watch>,
+}
+
+struct Watch {
+    start: String,
+    end: Option<String>,
+    tx: UnboundedSender<WatchEvent>,
+}
+
+fn in_range(key: &str, start: &str, end: Option<&str>) -> bool {
+    match end {
+        None => key == start,
+        // Like etcd, an end of "\0" runs to the last key
+        Some("\0") => key >= start,
+        Some(end) => key >= start && key < end,
+    }
+}
+
+impl State {
+    fn holds(&self, compare: &Compare) -> bool {
+        match compare {
+            Compare::Version(key, version) => {
+                self.kvs.get(key).map_or(0, |kv| kv.version) == *version
+            }
+            Compare::ModRevision(key, rev) => {
+                self.kvs.get(key).map_or(0, |kv| kv.mod_revision) == *rev
+            }
+            Compare::Value(key, value) => self.kvs.get(key).map_or(false, |kv| kv.value == *value),
+        }
+    }
+
+    /// The keys as they were at `revision`.
+    fn at(&self, revision: i64) -> BTreeMap<String, KeyValue> {
+        if revision == self.revision {
+            return self.kvs.clone();
+        }
+        let mut kvs = BTreeMap::new();
+        for (_, key, kv) in self.history.iter().take_while(|(rev, ..)| *rev <= revision) {
+            match kv {
+                Some(kv) => kvs.insert(key.clone(), kv.clone()),
+                None => kvs.remove(key),
+            };
+        }
+        kvs
+    }
+
+    fn put(&mut self, key: String, value: Vec<u8>, lease: Option<i64>) {
+        let kv = KeyValue {
+            key: key.clone(),
+            value,
+            version: self.kvs.get(&key).map_or(0, |kv| kv.version) + 1,
+            mod_revision: self.revision,
+            lease: lease.unwrap_or_default(),
+        };
+        let prev = self.kvs.insert(key.clone(), kv.clone());
+        self.history.push((self.revision, key, Some(kv.clone())));
+        self.notify(WatchEvent::Put { kv, prev });
+    }
+
+    fn delete(&mut self, key: &str) -> bool {
+        if self.kvs.remove(key).is_none() {
+            return false;
+        }
+        self.history.push((self.revision, key.to_string(), None));
+        self.notify(WatchEvent::Delete(key.to_string()));
+        true
+    }
+
+    fn notify(&mut self, event: WatchEvent) {
+        let key = match event {
+            WatchEvent::Put { ref kv, .. } => kv.key.clone(),
+            WatchEvent::Delete(ref key) => key.clone(),
+        };
+        self.watches.retain(|watch| !watch.tx.is_closed());
+        for watch in &self.watches {
+            if in_range(&key, &watch.start, watch.end.as_deref()) {
+                let _ = watch.tx.unbounded_send(event.clone());
+            }
+        }
+    }
+}
+
+impl Default for MemoryBackend {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl MemoryBackend {
+    pub fn new() -> Self {
+        let state = State {
+            // etcd starts out at revision 1
+            revision: 1,
+            ..Default::default()
+        };
+        Self {
+            state: Arc::new(Mutex::new(state)),
+        }
+    }
+
+    /// Revokes `lease` as though it ran out, deleting the keys attached to it.
+    pub fn expire_lease(&self, lease: i64) {
+        let mut state = self.state.lock();
+        if state.leases.remove(&lease).is_none() {
+            return;
+        }
+        let keys: Vec<String> = state
+            .kvs
+            .values()
+            .filter(|kv| kv.lease == lease)
+            .map(|kv| kv.key.clone())
+            .collect();
+        if keys.is_empty() {
+            return;
+        }
+        state.revision += 1;
+        for key in keys {
+            state.delete(&key);
+        }
+    }
+
+    /// How many leases are live.
+    pub fn leases(&self) -> usize {
+        self.state.lock().leases.len()
+    }
+
+    /// How many watches are still open, dropped streams not counting.
+    pub fn watches(&self) -> usize {
+        let mut state = self.state.lock();
+        state.watches.retain(|watch| !watch.tx.is_closed());
+        state.watches.len()
+    }
+}
+
+#[async_trait::async_trait]
+impl Backend for MemoryBackend {
+    async fn range(
+        &self,
+        start: &str,
+        end: Option<&str>,
+        limit: Option<i64>,
+        revision: Option<i64>,
+    ) -> Result<(Vec<KeyValue>, i64), Error> {
+        let state = self.state.lock();
+        let at = revision.unwrap_or(state.revision);
+        if at > state.revision {
+            return Err(Error::Invalid(format!("revision {} is in the future", at)));
+        }
+        let limit = limit.filter(|limit| *limit > 0).unwrap_or(i64::MAX) as usize;
+        let kvs = state
+            .at(at)
+            .into_iter()
+            .map(|(_, kv)| kv)
+            .filter(|kv| in_range(&kv.key, start, end))
+            .take(limit)
+            .collect();
+        Ok((kvs, state.revision))
+    }
+
+    async fn txn(&self, compares: Vec<Compare>, ops: Vec<Op>) -> Result<bool, Error> {
+        let mut state = self.state.lock();
+        if !compares.iter().all(|compare| state.holds(compare)) {
+            return Ok(false);
+        }
+        for op in &ops {
+            if let Op::Put {
+                lease: Some(lease), ..
+            } = op
+            {
+                if *lease != 0 && !state.leases.contains_key(lease) {
+                    return Err(Error::NotFound(format!("lease: {}", lease)));
+                }
+            }
+        }
+        if ops.is_empty() {
+            return Ok(true);
+        }
+        state.revision += 1;
+        for op in ops {
+            match op {
+                Op::Put { key, value, lease } => state.put(key, value, lease),
+                Op::Delete(key) => {
+                    state.delete(&key);
+                }
+            }
+        }
+        Ok(true)
+    }
+
+    async fn delete_range(&self, start: &str, end: Option<&str>) -> Result<i64, Error> {
+        let mut state = self.state.lock();
+        let keys: Vec<String> = state
+            .kvs
+            .keys()
+            .filter(|key| in_range(key, start, end))
+            .cloned()
+            .collect();
+        if !keys.is_empty() {
+            state.revision += 1;
+        }
+        for key in &keys {
+            state.delete(key);
+        }
+        Ok(keys.len() as i64)
+    }
+
+    async fn grant_lease(&self, ttl: i64) -> Result<i64, Error> {
+        let mut state = self.state.lock();
+        state.next_lease += 1;
+        let lease = state.next_lease;
+        state.leases.insert(lease, ttl);
+        Ok(lease)
+    }
+
+    async fn keep_alive(&self, lease: i64) -> Result<Option<i64>, Error> {
+        Ok(self.state.lock().leases.get(&lease).copied())
+    }
+
+    async fn revoke_lease(&self, lease: i64) -> Result<(), Error> {
+        self.expire_lease(lease);
+        Ok(())
+    }
+
+    async fn watch(
+        &self,
+        start: &str,
+        end: Option<&str>,
+    ) -> Result<BoxStream<'static, WatchEvent>, Error> {
+        let (tx, rx) = mpsc::unbounded();
+        self.state.lock().watches.push(Watch {
+            start: start.to_string(),
+            end: end.map(str::to_string),
+            tx,
+        });
+        Ok(rx.boxed())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::prefix_end;
+
+    fn put(key: &str, value: &str) -> Op {
+        Op::Put {
+            key: key.to_string(),
+            value: value.as_bytes().to_vec(),
+            lease: None,
+        }
+    }
+
+    #[tokio::test]
+    async fn put_get_and_delete() {
+        let backend = MemoryBackend::new();
+        assert!(backend.txn(vec![], vec![put("vm/a", "1")]).await.unwrap());
+        assert!(backend.txn(vec![], vec![put("vm/a", "2")]).await.unwrap());
+        let (kvs, revision) = backend.range("vm/a", None, None, None).await.unwrap();
+        assert_eq!(kvs.len(), 1);
+        assert_eq!(kvs[0].value, b"2");
+        assert_eq!(kvs[0].version, 2);
+        assert_eq!(kvs[0].mod_revision, revision);
+
+        assert_eq!(backend.delete_range("vm/a", None).await.unwrap(), 1);
+        assert!(backend
+            .range("vm/a", None, None, None)
+            .await
+            .unwrap()
+            .0
+            .is_empty());
+        assert_eq!(backend.delete_range("vm/a", None).await.unwrap(), 0);
+    }
+
+    #[tokio::test]
+    async fn lists_ranges_in_key_order() {
+        let backend = MemoryBackend::new();
+        let ops = vec![
+            put("vm/b", ""),
+            put("vpc/a", ""),
+            put("vm/a", ""),
+            put("vm/c", ""),
+        ];
+        backend.txn(vec![], ops).await.unwrap();
+        let end = prefix_end("vm/");
+        let (kvs, _) = backend.range("vm/", Some(&end), None, None).await.unwrap();
+        let keys: Vec<_> = kvs.iter().map(|kv| kv.key.as_str()).collect();
+        assert_eq!(keys, ["vm/a", "vm/b", "vm/c"]);
+
+        let (kvs, _) = backend
+            .range("vm/", Some(&end), Some(2), None)
+            .await
+            .unwrap();
+        assert_eq!(kvs.len(), 2);
+        let (kvs, _) = backend.range("vm/", Some("\0"), None, None).await.unwrap();
+        assert_eq!(kvs.len(), 4);
+    }
+
+    #[tokio::test]
+    async fn reads_as_of_an_earlier_revision() {
+        let backend = MemoryBackend::new();
+        backend.txn(vec![], vec![put("vm/a", "1")]).await.unwrap();
+        let (_, before) = backend.range("vm/a", None, None, None).await.unwrap();
+        backend.txn(vec![], vec![put("vm/a", "2")]).await.unwrap();
+        backend.delete_range("vm/a", None).await.unwrap();
+
+        let (kvs, revision) = backend
+            .range("vm/a", None, None, Some(before))
+            .await
+            .unwrap();
+        assert_eq!(kvs[0].value, b"1");
+        assert_eq!(revision, before + 2);
+        assert!(backend
+            .range("vm/a", None, None, Some(revision + 1))
+            .await
+            .is_err());
+    }
+
+    #[tokio::test]
+    async fn txn_applies_only_when_compares_hold() {
+        let backend = MemoryBackend::new();
+        let absent = || vec![Compare::Version("vm/a".to_string(), 0)];
+        assert!(backend.txn(absent(), vec![put("vm/a", "1")]).await.unwrap());
+        assert!(!backend.txn(absent(), vec![put("vm/a", "2")]).await.unwrap());
+
+        let owner = Compare::Value("vm/a".to_string(), b"1".to_vec());
+        assert!(backend
+            .txn(vec![owner.clone()], vec![put("vm/a", "3")])
+            .await
+            .unwrap());
+        assert!(!backend
+            .txn(vec![owner], vec![put("vm/a", "4")])
+            .await
+            .unwrap());
+        let (kvs, _) = backend.range("vm/a", None, None, None).await.unwrap();
+        assert_eq!(kvs[0].value, b"3");
+        assert_eq!(kvs[0].version, 2);
+    }
+
+    #[tokio::test]
+    async fn watch_reports_previous_values() {
+        let backend = MemoryBackend::new();
+        let mut events = backend
+            .watch("vm/", Some(&prefix_end("vm/")))
+            .await
+            .unwrap();
+        backend.txn(vec![], vec![put("vm/a", "1")]).await.unwrap();
+        backend.txn(vec![], vec![put("vpc/a", "1")]).await.unwrap();
+        backend.txn(vec![], vec![put("vm/a", "2")]).await.unwrap();
+        backend.delete_range("vm/a", None).await.unwrap();
+
+        match events.next().await.unwrap() {
+            WatchEvent::Put { kv, prev } => {
+                assert_eq!(kv.value, b"1");
+                assert!(prev.is_none());
+            }
+            event => panic!("expected a put, got {:?}", event),
+        }
+        match events.next().await.unwrap() {
+            WatchEvent::Put { kv, prev } => {
+                assert_eq!(kv.value, b"2");
+                assert_eq!(prev.unwrap().value, b"1");
+            }
+            event => panic!("expected a put, got {:?}", event),
+        }
+        match events.next().await.unwrap() {
+            WatchEvent::Delete(key) => assert_eq!(key, "vm/a"),
+            event => panic!("expected a delete, got {:?}", event),
+        }
+    }
+
+    #[tokio::test]
+    async fn expiring_a_lease_deletes_its_keys() {
+        let backend = MemoryBackend::new();
+        let lease = backend.grant_lease(30).await.unwrap();
+        let ops = vec![
+            Op::Put {
+                key: "node/a".to_string(),
+                value: vec![],
+                lease: Some(lease),
+            },
+            put("node/b", ""),
+        ];
+        backend.txn(vec![], ops).await.unwrap();
+        assert_eq!(backend.keep_alive(lease).await.unwrap(), Some(30));
+
+        let mut events = backend.watch("node/a", None).await.unwrap();
+        backend.expire_lease(lease);
+        assert_eq!(backend.keep_alive(lease).await.unwrap(), None);
+        let (kvs, _) = backend
+            .range("node/", Some("node0"), None, None)
+            .await
+            .unwrap();
+        assert_eq!(kvs.len(), 1);
+        assert_eq!(kvs[0].key, "node/b");
+        assert!(matches!(events.next().await, Some(WatchEvent::Delete(key)) if key == "node/a"));
+    }
+
+    #[tokio::test]
+    async fn dropped_watches_are_closed() {
+        let backend = MemoryBackend::new();
+        let events = backend.watch("vm/a", None).await.unwrap();
+        assert_eq!(backend.watches(), 1);
+        drop(events);
+        assert_eq!(backend.watches(), 0);
+    }
+}
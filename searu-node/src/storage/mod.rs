@@ -0,0 +1,754 @@
+use std::{collections::HashMap, sync::Arc};
+
+use futures::{Stream, StreamExt};
+use serde::Serialize;
+
+use crate::types::{decode_name, encode_name, scoped_name, Error, Object};
+
+mod backend;
+mod etcd;
+#[cfg(test)]
+mod memory;
+pub use backend::*;
+pub use etcd::*;
+#[cfg(test)]
+pub use memory::*;
+
+/// Typed access to the objects kept in a [`Backend`].
+#[derive(Clone)]
+pub struct Storage {
+    backend: Arc<dyn Backend>,
+}
+
+impl Storage {
+    pub fn new(backend: impl Backend + 'static) -> Self {
+        Self {
+            backend: Arc::new(backend),
+        }
+    }
+
+    /// Stores an object, failing with [`Error::Conflict`] if it carries a `version` or
+    /// `mod_revision` and the stored object has moved on since.
+    pub async fn store(&self, object: &impl Object) -> Result<(), Error> {
+        let key = object.key();
+        let metadata = object.metadata();
+        let mut compares = vec![];
+        if let Some(version) = metadata.version {
+            compares.push(Compare::Version(key.clone(), version));
+        }
+        if let Some(mod_revision) = metadata.mod_revision {
+            compares.push(Compare::ModRevision(key.clone(), mod_revision));
+        }
+        if self.backend.txn(compares, vec![put(object)?]).await? {
+            Ok(())
+        } else {
+            Err(Error::Conflict(key))
+        }
+    }
+
+    /// Stores a new object, failing with [`Error::AlreadyExists`] instead of overwriting an
+    /// existing object with the same key. Names of [`Object::PROJECT_SCOPED`] types only have
+    /// to be unique within their project.
+    pub async fn create(&self, object: &impl Object) -> Result<(), Error> {
+        let key = object.key();
+        let compares = vec![Compare::Version(key.clone(), 0)];
+        if self.backend.txn(compares, vec![put(object)?]).await? {
+            Ok(())
+        } else {
+            Err(Error::AlreadyExists(key))
+        }
+    }
+
+    /// Stores an object only if it hasn't changed since it was read at `metadata.version`,
+    /// failing with [`Error::Conflict`] otherwise.
+    pub async fn update(&self, object: &impl Object) -> Result<(), Error> {
+        let key = object.key();
+        let version = object
+            .metadata()
+            .version
+            .ok_or_else(|| Error::Invalid("metadata.version is required".to_string()))?;
+        let compares = vec![Compare::Version(key.clone(), version)];
+        if self.backend.txn(compares, vec![put(object)?]).await? {
+            Ok(())
+        } else {
+            Err(Error::Conflict(key))
+        }
+    }
+
+    /// Reads the object whose [`Object::key_name`] is `key`.
+    pub async fn get<O: Object>(&self, key: &str) -> Result<Option<O>, Error> {
+        Ok(self.get_with_revision(key, None).await?.0)
+    }
+
+    /// Gets an object, optionally as of `revision`, along with the revision it was read at.
+    pub async fn get_with_revision<O: Object>(
+        &self,
+        key: &str,
+        revision: Option<i64>,
+    ) -> Result<(Option<O>, i64), Error> {
+        let (kvs, revision) = self
+            .backend
+            .range(&O::key_for(key), None, None, revision)
+            .await?;
+        if let Some(kv) = kvs.first() {
+            Ok((Some(O::parse(kv)?), revision))
+        } else {
+            Ok((None, revision))
+        }
+    }
+
+    pub async fn delete<O: Object>(&self, key: &str) -> Result<(), Error> {
+        self.backend.delete_range(&O::key_for(key), None).await?;
+        Ok(())
+    }
+
+    /// Deletes an object only if it hasn't changed since it was read at `metadata.version`,
+    /// failing with [`Error::Conflict`] otherwise.
+    pub async fn delete_if_unchanged(&self, object: &impl Object) -> Result<(), Error> {
+        let key = object.key();
+        let version = object
+            .metadata()
+            .version
+            .ok_or_else(|| Error::Invalid("metadata.version is required".to_string()))?;
+        let compares = vec![Compare::Version(key.clone(), version)];
+        if self
+            .backend
+            .txn(compares, vec![Op::Delete(key.clone())])
+            .await?
+        {
+            Ok(())
+        } else {
+            Err(Error::Conflict(key))
+        }
+    }
+
+    pub async fn list<O: Object>(&self) -> Result<Vec<O>, Error> {
+        Ok(self.list_with_revision(None).await?.0)
+    }
+
+    /// Lists the objects of a [`Object::PROJECT_SCOPED`] type in `project`, reading only that
+    /// project's keys.
+    pub async fn list_in_project<O: Object>(&self, project: &str) -> Result<Vec<O>, Error> {
+        let prefix = format!("{}/{}/", O::OBJECT_TYPE, encode_name(project));
+        let (kvs, _) = self
+            .backend
+            .range(&prefix, Some(&prefix_end(&prefix)), None, None)
+            .await?;
+        Ok(kvs.iter().filter_map(|kv| O::parse(kv).ok()).collect())
+    }
+
+    /// Lists objects, optionally as of `revision`, along with the revision they were read at.
+    pub async fn list_with_revision<O: Object>(
+        &self,
+        revision: Option<i64>,
+    ) -> Result<(Vec<O>, i64), Error> {
+        let prefix = format!("{}/", O::OBJECT_TYPE);
+        let (kvs, revision) = self
+            .backend
+            .range(&prefix, Some(&prefix_end(&prefix)), None, revision)
+            .await?;
+        Ok((
+            kvs.iter().filter_map(|kv| O::parse(kv).ok()).collect(),
+            revision,
+        ))
+    }
+
+    /// Lists up to `limit` objects with names from `start` up to but excluding `end`, or to the
+    /// last object when `end` is `None`, in name order, optionally as of `revision`. The bounds
+    /// are compared against the encoded key names as stored in etcd, `<project>/<name>` for
+    /// project scoped types.
+    pub async fn list_range<O: Object>(
+        &self,
+        start: &str,
+        end: Option<&str>,
+        limit: i64,
+        revision: Option<i64>,
+    ) -> Result<(Vec<O>, i64), Error> {
+        let (kvs, revision) = self
+            .backend
+            .range(
+                &format!("{}/{}", O::OBJECT_TYPE, start),
+                Some(&range_end::<O>(end)),
+                Some(limit),
+                revision,
+            )
+            .await?;
+        Ok((
+            kvs.iter().filter_map(|kv| O::parse(kv).ok()).collect(),
+            revision,
+        ))
+    }
+
+    /// Deletes the objects with names from `start` up to but excluding `end`, returning how many
+    /// were deleted.
+    pub async fn delete_range<O: Object>(&self, start: &str, end: &str) -> Result<i64, Error> {
+        self.backend
+            .delete_range(
+                &format!("{}/{}", O::OBJECT_TYPE, start),
+                Some(&range_end::<O>(Some(end))),
+            )
+            .await
+    }
+
+    /// Moves the objects of a [`Object::PROJECT_SCOPED`] type that releases before project
+    /// scoped keys stored at `<type>/<name>` to `<type>/<project>/<name>`, returning how many
+    /// were moved. Objects without a project are moved into `default`. A moved object keeps its
+    /// lease and gets its name as `host_name`, so the interfaces and files the nodes made for it
+    /// stay its own. The values it holds in the allocator pools under `pools`, see
+    /// [`crate::allocator::pool_prefix`], are handed over to its key name along with it.
+    ///
+    /// Nodes run this on start, but older nodes can't see moved objects, so every node of a
+    /// cluster has to be stopped before the first one is upgraded.
+    pub async fn migrate_project_keys<O: Object>(&self, pools: &[String]) -> Result<usize, Error> {
+        let prefix = format!("{}/", O::OBJECT_TYPE);
+        let mut moved = 0;
+        loop {
+            let (kvs, _) = self
+                .backend
+                .range(&prefix, Some(&prefix_end(&prefix)), None, None)
+                .await?;
+            let legacy: Vec<KeyValue> = kvs
+                .into_iter()
+                .filter(|kv| !kv.key[prefix.len()..].contains('/'))
+                .collect();
+            if legacy.is_empty() {
+                return Ok(moved);
+            }
+            let mut claims = HashMap::new();
+            for pool in pools {
+                claims.insert(pool.as_str(), self.claims(pool).await?);
+            }
+            let mut progress = false;
+            for kv in legacy {
+                match self.migrate_key::<O>(&kv, &claims).await {
+                    Ok(true) => {
+                        moved += 1;
+                        progress = true;
+                    }
+                    Ok(false) => {}
+                    Err(err) => println!("error migrating {}: {:?}", kv.key, err),
+                }
+            }
+            // Whatever is left can't be moved, or is being moved by another node
+            if !progress {
+                return Ok(moved);
+            }
+        }
+    }
+
+    /// Moves one object for [`Storage::migrate_project_keys`], returning false if it or one of
+    /// its claims changed since they were read, or its new key is taken.
+    async fn migrate_key<O: Object>(
+        &self,
+        kv: &KeyValue,
+        claims: &HashMap<&str, HashMap<String, String>>,
+    ) -> Result<bool, Error> {
+        let mut value: serde_json::Value = serde_json::from_slice(&kv.value)?;
+        let metadata = value
+            .get_mut("metadata")
+            .and_then(serde_json::Value::as_object_mut)
+            .ok_or_else(|| Error::Invalid(format!("{} has no metadata", kv.key)))?;
+        let name = metadata
+            .get("name")
+            .and_then(serde_json::Value::as_str)
+            .unwrap_or_default()
+            .to_string();
+        let project = match metadata.get("project").and_then(serde_json::Value::as_str) {
+            Some(project) if !project.is_empty() => project.to_string(),
+            _ => "default".to_string(),
+        };
+        metadata.insert("project".to_string(), project.clone().into());
+        metadata.insert("host_name".to_string(), name.clone().into());
+        let key_name = scoped_name(&project, &name);
+        let new_key = O::key_for(&key_name);
+        let mut compares = vec![
+            Compare::Version(kv.key.clone(), kv.version),
+            Compare::Version(new_key.clone(), 0),
+        ];
+        let mut ops = vec![
+            Op::Delete(kv.key.clone()),
+            Op::Put {
+                key: new_key,
+                value: serde_json::to_vec(&value)?,
+                lease: Some(kv.lease).filter(|lease| *lease != 0),
+            },
+        ];
+        for (pool, claims) in claims {
+            for (value, owner) in claims.iter().filter(|(_, owner)| **owner == name) {
+                let key = format!("{}{}", pool, value);
+                compares.push(Compare::Value(key.clone(), owner.as_bytes().to_vec()));
+                ops.push(Op::Put {
+                    key,
+                    value: key_name.as_bytes().to_vec(),
+                    lease: None,
+                });
+            }
+        }
+        self.backend.txn(compares, ops).await
+    }
+
+    /// Grants a lease that expires after `ttl` seconds unless kept alive.
+    pub async fn grant_lease(&self, ttl: i64) -> Result<i64, Error> {
+        self.backend.grant_lease(ttl).await
+    }
+
+    /// Refreshes a lease once, returning its remaining ttl, or `None` if it already expired.
+    pub async fn keep_alive(&self, lease: i64) -> Result<Option<i64>, Error> {
+        self.backend.keep_alive(lease).await
+    }
+
+    /// Revokes a lease before it expires, deleting the keys attached to it.
+    pub async fn revoke_lease(&self, lease: i64) -> Result<(), Error> {
+        self.backend.revoke_lease(lease).await
+    }
+
+    /// Puts `owner` at `key` only if the key doesn't exist, returning whether it was claimed.
+    pub async fn claim(&self, key: &str, owner: &str) -> Result<bool, Error> {
+        self.backend
+            .txn(
+                vec![Compare::Version(key.to_string(), 0)],
+                vec![Op::Put {
+                    key: key.to_string(),
+                    value: owner.as_bytes().to_vec(),
+                    lease: None,
+                }],
+            )
+            .await
+    }
+
+    /// Like [`Storage::claim`], but attaches the key to `lease`, so the claim lapses unless the
+    /// lease is kept alive.
+    pub async fn campaign(&self, key: &str, owner: &str, lease: i64) -> Result<bool, Error> {
+        self.backend
+            .txn(
+                vec![Compare::Version(key.to_string(), 0)],
+                vec![Op::Put {
+                    key: key.to_string(),
+                    value: owner.as_bytes().to_vec(),
+                    lease: Some(lease),
+                }],
+            )
+            .await
+    }
+
+    /// Deletes `key` only if it is still held by `owner`.
+    pub async fn release(&self, key: &str, owner: &str) -> Result<(), Error> {
+        self.backend
+            .txn(
+                vec![Compare::Value(key.to_string(), owner.as_bytes().to_vec())],
+                vec![Op::Delete(key.to_string())],
+            )
+            .await?;
+        Ok(())
+    }
+
+    /// Lists the claims under `prefix`, keyed by the remainder of the key after the prefix.
+    pub async fn claims(&self, prefix: &str) -> Result<HashMap<String, String>, Error> {
+        let (kvs, _) = self
+            .backend
+            .range(prefix, Some(&prefix_end(prefix)), None, None)
+            .await?;
+        Ok(kvs
+            .into_iter()
+            .filter_map(|kv| {
+                let key = kv.key.strip_prefix(prefix)?.to_string();
+                let owner = String::from_utf8(kv.value).ok()?;
+                Some((key, owner))
+            })
+            .collect())
+    }
+
+    /// Streams the changes to objects of type `O`. The watch lasts as long as the stream, so
+    /// watchers hold it for as long as they run.
+    pub async fn watch<O: Object + 'static>(&self) -> Result<impl Stream<Item = Event<O>>, Error> {
+        let prefix = format!("{}/", O::OBJECT_TYPE);
+        self.watch_range(&prefix, Some(&prefix_end(&prefix))).await
+    }
+
+    /// Watches only the object whose key name is `name`, so watchers of single objects aren't
+    /// sent every event of its type.
+    pub async fn watch_one<O: Object + 'static>(
+        &self,
+        name: &str,
+    ) -> Result<impl Stream<Item = Event<O>>, Error> {
+        self.watch_range(&O::key_for(name), None).await
+    }
+
+    async fn watch_range<O: Object + 'static>(
+        &self,
+        start: &str,
+        end: Option<&str>,
+    ) -> Result<impl Stream<Item = Event<O>>, Error> {
+        let stream = self.backend.watch(start, end).await?;
+        Ok(stream.filter_map(|event| {
+            futures::future::ready(match event {
+                WatchEvent::Put { kv, prev } => O::parse(&kv).ok().and_then(|new| match prev {
+                    Some(prev) => {
+                        let old = O::parse(&prev).ok()?;
+                        Some(Event::Update { new, old })
+                    }
+                    None => Some(Event::New(new)),
+                }),
+                WatchEvent::Delete(key) => key
+                    .strip_prefix(O::OBJECT_TYPE)
+                    .and_then(|key| key.strip_prefix('/'))
+                    .filter(|name| !name.is_empty())
+                    .and_then(decode_key_name)
+                    .map(Event::Delete),
+            })
+        }))
+    }
+}
+
+#[derive(Clone, Debug, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Event<O> {
+    New(O),
+    /// Carries the deleted object's [`Object::key_name`].
+    Delete(String),
+    Update {
+        new: O,
+        old: O,
+    },
+}
+
+impl<O: Object> Event<O> {
+    /// The [`Object::key_name`] of the object this event refers to.
+    pub fn key_name(&self) -> String {
+        match self {
+            Event::New(o) | Event::Update { new: o, .. } => o.key_name(),
+            Event::Delete(name) => name.clone(),
+        }
+    }
+}
+
+/// Decodes the part of a key after the object type back into a key name, segment by segment.
+fn decode_key_name(encoded: &str) -> Option<String> {
+    let segments: Option<Vec<String>> = encoded.split('/').map(decode_name).collect();
+    Some(segments?.join("/"))
+}
+
+/// The key a range of `O` ends before, `'0'` being the character after `'/'`.
+fn range_end<O: Object>(end: Option<&str>) -> String {
+    match end {
+        Some(end) => format!("{}/{}", O::OBJECT_TYPE, end),
+        None => format!("{}0", O::OBJECT_TYPE),
+    }
+}
+
+fn put(object: &impl Object) -> Result<Op, Error> {
+    // Puts without a lease detach the key from its lease, so carry it over
+    Ok(Op::Put {
+        key: object.key(),
+        value: serde_json::to_vec(object)?,
+        lease: object.metadata().lease,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::types::{host_name, Metadata, Vm, Vpc, VpcSpec};
+
+    fn vm(name: &str) -> Vm {
+        Vm {
+            metadata: Metadata {
+                name: name.to_string(),
+                project: "default".to_string(),
+                ..Default::default()
+            },
+            spec: Default::default(),
+            status: Default::default(),
+        }
+    }
+
+    #[tokio::test]
+    async fn storing_a_stale_object_is_a_conflict() {
+        let storage = Storage::new(MemoryBackend::new());
+        storage.create(&vm("a")).await.unwrap();
+        let read: Vm = storage.get("default/a").await.unwrap().unwrap();
+        storage.store(&read).await.unwrap();
+
+        // `read` still carries the version the first store replaced
+        match storage.store(&read).await {
+            Err(Error::Conflict(key)) => assert_eq!(key, "vm/default/a"),
+            result => panic!("expected a conflict, got {:?}", result),
+        }
+        // Objects without a version are stored as is
+        storage.store(&vm("a")).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn watch_reports_puts_on_existing_keys_as_updates() {
+        let storage = Storage::new(MemoryBackend::new());
+        let mut events = storage.watch::<Vm>().await.unwrap();
+        let mut vm = vm("a");
+        storage.create(&vm).await.unwrap();
+        vm.spec.powered_on = true;
+        storage.store(&vm).await.unwrap();
+        storage.delete::<Vm>("default/a").await.unwrap();
+
+        match events.next().await {
+            Some(Event::New(new)) => assert!(!new.spec.powered_on),
+            event => panic!("expected a new vm, got {:?}", event),
+        }
+        match events.next().await {
+            Some(Event::Update { new, old }) => {
+                assert!(new.spec.powered_on);
+                assert!(!old.spec.powered_on);
+                assert_eq!(new.metadata.version, Some(2));
+            }
+            event => panic!("expected an update, got {:?}", event),
+        }
+        match events.next().await {
+            Some(Event::Delete(name)) => assert_eq!(name, "default/a"),
+            event => panic!("expected a delete, got {:?}", event),
+        }
+    }
+
+    #[tokio::test]
+    async fn dropping_a_watch_stream_ends_the_watch() {
+        let backend = MemoryBackend::new();
+        let storage = Storage::new(backend.clone());
+        let all = storage.watch::<Vm>().await.unwrap();
+        let one = storage.watch_one::<Vm>("default/a").await.unwrap();
+        assert_eq!(backend.watches(), 2);
+        drop(all);
+        assert_eq!(backend.watches(), 1);
+        drop(one);
+        assert_eq!(backend.watches(), 0);
+
+        // A watcher task holds its stream until the task ends
+        let mut events = storage.watch::<Vm>().await.unwrap();
+        let task = tokio::spawn(async move { while events.next().await.is_some() {} });
+        storage.create(&vm("a")).await.unwrap();
+        tokio::task::yield_now().await;
+        assert_eq!(backend.watches(), 1);
+        task.abort();
+        assert!(task.await.unwrap_err().is_cancelled());
+        assert_eq!(backend.watches(), 0);
+    }
+
+    #[tokio::test]
+    async fn creating_the_same_vm_twice_fails_without_overwriting_it() {
+        let storage = Storage::new(MemoryBackend::new());
+        storage.create(&vm("a")).await.unwrap();
+        let mut again = vm("a");
+        again.spec.powered_on = true;
+
+        match storage.create(&again).await {
+            Err(Error::AlreadyExists(key)) => assert_eq!(key, "vm/default/a"),
+            result => panic!("expected the name to be taken, got {:?}", result),
+        }
+        let stored: Vm = storage.get("default/a").await.unwrap().unwrap();
+        assert!(!stored.spec.powered_on);
+        assert_eq!(stored.metadata.version, Some(1));
+    }
+
+    #[tokio::test]
+    async fn names_are_unique_within_a_project() {
+        let storage = Storage::new(MemoryBackend::new());
+        let vpc = |project: &str| Vpc {
+            metadata: Metadata {
+                name: "net".to_string(),
+                project: project.to_string(),
+                ..Default::default()
+            },
+            spec: VpcSpec {
+                subnet: "10.0.0.0/24".parse().unwrap(),
+                multicast_ip: None,
+                vni: None,
+                nat_gateway: false,
+            },
+            status: Default::default(),
+        };
+        let mut other = vm("a");
+        other.metadata.project = "other".to_string();
+        storage.create(&vm("a")).await.unwrap();
+        storage.create(&other).await.unwrap();
+        storage.create(&vpc("default")).await.unwrap();
+        storage.create(&vpc("other")).await.unwrap();
+
+        assert!(matches!(
+            storage.create(&vm("a")).await,
+            Err(Error::AlreadyExists(key)) if key == "vm/default/a"
+        ));
+        assert!(matches!(
+            storage.create(&vpc("other")).await,
+            Err(Error::AlreadyExists(key)) if key == "vpc/other/net"
+        ));
+        assert_eq!(storage.list::<Vm>().await.unwrap().len(), 2);
+        assert_eq!(storage.list::<Vpc>().await.unwrap().len(), 2);
+    }
+
+    #[tokio::test]
+    async fn vms_are_listed_by_project() {
+        let storage = Storage::new(MemoryBackend::new());
+        let mut other = vm("a");
+        other.metadata.project = "other".to_string();
+        storage.create(&vm("a")).await.unwrap();
+        storage.create(&vm("b")).await.unwrap();
+        storage.create(&other).await.unwrap();
+
+        assert_eq!(other.key(), "vm/other/a");
+        let names =
+            |vms: Vec<Vm>| -> Vec<String> { vms.into_iter().map(|vm| vm.key_name()).collect() };
+        assert_eq!(
+            names(storage.list_in_project("default").await.unwrap()),
+            vec!["default/a", "default/b"]
+        );
+        assert_eq!(
+            names(storage.list_in_project("other").await.unwrap()),
+            vec!["other/a"]
+        );
+        assert_eq!(storage.list::<Vm>().await.unwrap().len(), 3);
+    }
+
+    #[tokio::test]
+    async fn legacy_keys_move_into_their_project() {
+        let backend = MemoryBackend::new();
+        let storage = Storage::new(backend.clone());
+        let mut legacy = vm("a");
+        legacy.metadata.project = String::new();
+        backend
+            .txn(
+                vec![],
+                vec![
+                    Op::Put {
+                        key: "vm/a".to_string(),
+                        value: serde_json::to_vec(&legacy).unwrap(),
+                        lease: None,
+                    },
+                    Op::Put {
+                        key: "alloc/pool/1".to_string(),
+                        value: b"a".to_vec(),
+                        lease: None,
+                    },
+                ],
+            )
+            .await
+            .unwrap();
+        storage.create(&vm("b")).await.unwrap();
+
+        let pools = vec!["alloc/pool/".to_string()];
+        assert_eq!(storage.migrate_project_keys::<Vm>(&pools).await.unwrap(), 1);
+        assert_eq!(storage.migrate_project_keys::<Vm>(&pools).await.unwrap(), 0);
+
+        let moved: Vm = storage.get("default/a").await.unwrap().unwrap();
+        assert_eq!(moved.metadata.project, "default");
+        assert_eq!(moved.metadata.host_name.as_deref(), Some("a"));
+        assert_eq!(moved.metadata.host_name(), "a");
+        let (kvs, _) = backend.range("vm/a", None, None, None).await.unwrap();
+        assert!(kvs.is_empty());
+        let claims = storage.claims("alloc/pool/").await.unwrap();
+        assert_eq!(claims.get("1").map(String::as_str), Some("default/a"));
+        // Objects created since keep their hashed host name
+        let created: Vm = storage.get("default/b").await.unwrap().unwrap();
+        assert_eq!(created.metadata.host_name, None);
+        assert_eq!(created.metadata.host_name(), host_name("default", "b"));
+    }
+
+    /// Adds an etcd round trip to every read and write, optionally taken one at a time like
+    /// the single locked client storage used to share.
+    struct RoundTrip {
+        inner: MemoryBackend,
+        lock: Option<tokio::sync::Mutex<()>>,
+    }
+
+    impl RoundTrip {
+        async fn wait(&self) {
+            let _guard = match &self.lock {
+                Some(lock) => Some(lock.lock().await),
+                None => None,
+            };
+            tokio::time::sleep(std::time::Duration::from_millis(1)).await;
+        }
+    }
+
+    #[async_trait::async_trait]
+    impl Backend for RoundTrip {
+        async fn range(
+            &self,
+            start: &str,
+            end: Option<&str>,
+            limit: Option<i64>,
+            revision: Option<i64>,
+        ) -> Result<(Vec<KeyValue>, i64), Error> {
+            self.wait().await;
+            self.inner.range(start, end, limit, revision).await
+        }
+
+        async fn txn(&self, compares: Vec<Compare>, ops: Vec<Op>) -> Result<bool, Error> {
+            self.wait().await;
+            self.inner.txn(compares, ops).await
+        }
+
+        async fn delete_range(&self, start: &str, end: Option<&str>) -> Result<i64, Error> {
+            self.wait().await;
+            self.inner.delete_range(start, end).await
+        }
+
+        async fn grant_lease(&self, ttl: i64) -> Result<i64, Error> {
+            self.inner.grant_lease(ttl).await
+        }
+
+        async fn keep_alive(&self, lease: i64) -> Result<Option<i64>, Error> {
+            self.inner.keep_alive(lease).await
+        }
+
+        async fn revoke_lease(&self, lease: i64) -> Result<(), Error> {
+            self.inner.revoke_lease(lease).await
+        }
+
+        async fn watch(
+            &self,
+            start: &str,
+            end: Option<&str>,
+        ) -> Result<futures::stream::BoxStream<'static, WatchEvent>, Error> {
+            self.inner.watch(start, end).await
+        }
+    }
+
+    /// Concurrent get, list and store throughput with and without a lock around the backend.
+    /// Run with `cargo test -- --ignored --nocapture concurrent_throughput`.
+    #[tokio::test(flavor = "multi_thread")]
+    #[ignore]
+    async fn concurrent_throughput() {
+        const TASKS: usize = 32;
+        const ROUNDS: usize = 20;
+        for (name, lock) in [("locked", Some(Default::default())), ("shared", None)] {
+            let storage = Storage::new(RoundTrip {
+                inner: MemoryBackend::new(),
+                lock,
+            });
+            let started = std::time::Instant::now();
+            let tasks = (0..TASKS).map(|task| {
+                let storage = storage.clone();
+                tokio::spawn(async move {
+                    let name = format!("vm{}", task);
+                    storage.store(&vm(&name)).await.unwrap();
+                    for _ in 0..ROUNDS {
+                        let vm: Vm = storage
+                            .get(&scoped_name("default", &name))
+                            .await
+                            .unwrap()
+                            .unwrap();
+                        storage.list::<Vm>().await.unwrap();
+                        storage.store(&vm).await.unwrap();
+                    }
+                })
+            });
+            for task in futures::future::join_all(tasks).await {
+                task.unwrap();
+            }
+            let ops = TASKS * (ROUNDS * 3 + 1);
+            println!(
+                "{}: {} ops in {:?}, {:.0} ops/s",
+                name,
+                ops,
+                started.elapsed(),
+                ops as f64 / started.elapsed().as_secs_f64()
+            );
+        }
+    }
+}
@@ -0,0 +1,51 @@
+use rand::{distributions::Alphanumeric, Rng};
+use serde::{Deserialize, Serialize};
+use std::borrow::Cow;
+
+use super::{Metadata, Object};
+
+/// A record of a mutating API request.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct AuditEntry {
+    /// Orders entries by time, see [`AuditEntry::id`].
+    pub id: String,
+    /// When the request was made, in seconds since the epoch.
+    pub timestamp: i64,
+    pub user: Option<String>,
+    pub method: String,
+    pub path: String,
+    pub object_type: Option<String>,
+    pub name: Option<String>,
+    pub status: u16,
+}
+
+impl AuditEntry {
+    /// An id that sorts entries by time, so they can be range read from etcd. The random suffix
+    /// keeps entries written at the same instant by different nodes apart.
+    pub fn id(timestamp_nanos: i64) -> String {
+        let suffix: String = rand::thread_rng()
+            .sample_iter(&Alphanumeric)
+            .take(8)
+            .map(char::from)
+            .collect();
+        format!("{}-{}", Self::id_prefix(timestamp_nanos), suffix)
+    }
+
+    /// The part of an id entries from `timestamp_nanos` on sort after.
+    pub fn id_prefix(timestamp_nanos: i64) -> String {
+        format!("{:020}", timestamp_nanos.max(0))
+    }
+}
+
+impl Object for AuditEntry {
+    const OBJECT_TYPE: &'static str = "audit";
+
+    fn metadata(&self) -> Cow<'_, Metadata> {
+        Cow::Owned(Metadata {
+            name: self.id.clone(),
+            ..Default::default()
+        })
+    }
+
+    fn set_version(&mut self, _: i64) {}
+}
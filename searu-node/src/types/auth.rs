@@ -8,6 +8,22 @@ use super::{Error, Metadata, Object};
 pub struct User {
     pub username: String,
     pub encrypted_password: String,
+    #[serde(default)]
+    pub role: Role,
+    #[serde(default)]
+    pub projects: Vec<String>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+pub enum Role {
+    Admin,
+    User,
+}
+
+impl Default for Role {
+    fn default() -> Self {
+        Role::User
+    }
 }
 
 impl Object for User {
@@ -17,7 +33,7 @@ impl Object for User {
         Cow::Owned(Metadata {
             name: self.username.clone(),
             project: "".to_string(),
-            version: None,
+            ..Default::default()
         })
     }
 
@@ -28,17 +44,25 @@ impl Object for User {
 pub struct UserSpec {
     pub username: String,
     pub password: String,
+    #[serde(default)]
+    pub projects: Vec<String>,
 }
 
 impl UserSpec {
     pub fn new(username: String, password: String) -> Self {
-        Self { username, password }
+        Self {
+            username,
+            password,
+            projects: vec![],
+        }
     }
 
     pub(crate) fn encrypt(self) -> Result<User, Error> {
         Ok(User {
             username: self.username,
             encrypted_password: bcrypt::hash(self.password, bcrypt::DEFAULT_COST)?,
+            role: Role::default(),
+            projects: self.projects,
         })
     }
 }
@@ -47,6 +71,41 @@ impl UserSpec {
 pub struct JwtClaim {
     pub inner: InnerJwtClaim,
     pub exp: i64,
+    #[serde(default)]
+    pub role: Role,
+    #[serde(default)]
+    pub projects: Vec<String>,
+}
+
+impl JwtClaim {
+    pub fn username(&self) -> &str {
+        match self.inner {
+            InnerJwtClaim::User(ref username) => username,
+        }
+    }
+
+    /// Whether the claim may see and change objects in `project`.
+    pub fn can_access(&self, project: &str) -> bool {
+        self.role == Role::Admin || self.projects.iter().any(|p| p == project)
+    }
+
+    /// Checks that the claim may create objects in `project`, defaulting an empty project to
+    /// the claim's first project.
+    pub fn authorize_project(&self, project: &mut String) -> Result<(), Error> {
+        if project.is_empty() {
+            *project = self
+                .projects
+                .first()
+                .cloned()
+                .ok_or_else(|| Error::Forbidden("no project assigned".to_string()))?;
+            return Ok(());
+        }
+        if self.can_access(project) {
+            Ok(())
+        } else {
+            Err(Error::Forbidden(format!("project: {}", project)))
+        }
+    }
 }
 
 #[derive(Serialize, Deserialize)]
@@ -62,13 +121,13 @@ impl<'r> rocket::request::FromRequest<'r> for JwtClaim {
         request: &'r rocket::Request<'_>,
     ) -> rocket::request::Outcome<Self, Self::Error> {
         if let Some(auth) = request
-            .guard::<State<crate::auth::Auth>>()
+            .guard::<State<std::sync::Arc<crate::auth::Auth>>>()
             .await
             .succeeded()
         {
             if let Some(header) = request.headers().get_one("Authorization") {
                 if let Some(token) = header.splitn(2, "Bearer ").nth(1) {
-                    if let Ok(claim) = auth.parse_jwt(token) {
+                    if let Ok(claim) = auth.verify(token).await {
                         return Outcome::Success(claim);
                     }
                 }
@@ -78,6 +137,30 @@ impl<'r> rocket::request::FromRequest<'r> for JwtClaim {
     }
 }
 
+/// A request guard that only succeeds for claims carrying the admin role.
+pub struct AdminClaim(pub JwtClaim);
+
+#[rocket::async_trait]
+impl<'r> rocket::request::FromRequest<'r> for AdminClaim {
+    type Error = Error;
+
+    async fn from_request(
+        request: &'r rocket::Request<'_>,
+    ) -> rocket::request::Outcome<Self, Self::Error> {
+        match request.guard::<JwtClaim>().await {
+            Outcome::Success(claim) if claim.role == Role::Admin => {
+                Outcome::Success(AdminClaim(claim))
+            }
+            Outcome::Success(_) => Outcome::Failure((
+                rocket::http::Status::Forbidden,
+                Error::Forbidden("admin role required".to_string()),
+            )),
+            Outcome::Failure(failure) => Outcome::Failure(failure),
+            Outcome::Forward(forward) => Outcome::Forward(forward),
+        }
+    }
+}
+
 #[derive(Serialize, Deserialize)]
 pub struct JwtResponse {
     pub token: String,
@@ -1,14 +1,28 @@
 #![allow(clippy::upper_case_acronyms)]
 
-use etcd_client::KeyValue;
 use ipnet::Ipv4Net;
 use serde::{de::DeserializeOwned, Deserialize, Serialize};
-use std::{borrow::Cow, net::Ipv4Addr};
+use std::{
+    borrow::Cow,
+    collections::BTreeMap,
+    net::Ipv4Addr,
+    path::{Component, Path, PathBuf},
+};
 use thiserror::Error;
 
+use crate::{storage::KeyValue, vmm::MacAddr};
+
+mod audit;
 mod auth;
+mod operation;
+mod selector;
+mod snapshot;
 
+pub use audit::*;
 pub use auth::*;
+pub use operation::*;
+pub use selector::*;
+pub use snapshot::*;
 
 #[derive(Serialize, Deserialize)]
 pub struct Project {
@@ -28,9 +42,101 @@ impl Object for Project {
     fn set_version(&mut self, _rev: i64) {}
 }
 
+/// Limits on what a project's VMs and VPCs may use, stored under the project's name. Unset
+/// limits don't apply.
+#[derive(Serialize, Deserialize, Clone, Default, Debug)]
+pub struct Quota {
+    #[serde(default)]
+    pub project: String,
+    #[serde(default)]
+    pub max_cpus: Option<usize>,
+    /// In MiB, like [`VmSpec::memory`].
+    #[serde(default)]
+    pub max_memory: Option<u64>,
+    #[serde(default)]
+    pub max_vms: Option<usize>,
+    #[serde(default)]
+    pub max_vpcs: Option<usize>,
+}
+
+/// What a project's VMs and VPCs use, counted against its [`Quota`].
+#[derive(Serialize, Clone, Copy, Default, Debug)]
+pub struct ProjectUsage {
+    pub cpus: usize,
+    pub memory: u64,
+    pub vms: usize,
+    pub vpcs: usize,
+}
+
+impl ProjectUsage {
+    /// What one VM with `spec` uses.
+    pub fn vm(spec: &VmSpec) -> Self {
+        ProjectUsage {
+            cpus: spec.cpus as usize,
+            memory: spec.memory as u64,
+            vms: 1,
+            vpcs: 0,
+        }
+    }
+
+    /// What one VPC uses.
+    pub fn vpc() -> Self {
+        ProjectUsage {
+            vpcs: 1,
+            ..Default::default()
+        }
+    }
+}
+
+impl Quota {
+    /// Checks that `usage`, including whatever is being created, stays within the quota.
+    pub fn check(&self, usage: &ProjectUsage) -> Result<(), Error> {
+        let limits = [
+            (
+                "cpus",
+                usage.cpus as u64,
+                self.max_cpus.map(|max| max as u64),
+            ),
+            ("MiB of memory", usage.memory, self.max_memory),
+            ("vms", usage.vms as u64, self.max_vms.map(|max| max as u64)),
+            (
+                "vpcs",
+                usage.vpcs as u64,
+                self.max_vpcs.map(|max| max as u64),
+            ),
+        ];
+        for (resource, used, max) in limits.iter() {
+            if let Some(max) = max {
+                if used > max {
+                    return Err(Error::Forbidden(format!(
+                        "project {} would use {} {}, over its quota of {}",
+                        self.project, used, resource, max
+                    )));
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Object for Quota {
+    const OBJECT_TYPE: &'static str = "quota";
+
+    fn metadata(&self) -> Cow<'_, Metadata> {
+        Cow::Owned(Metadata {
+            name: self.project.clone(),
+            project: self.project.clone(),
+            ..Default::default()
+        })
+    }
+
+    fn set_version(&mut self, _: i64) {}
+}
+
 #[derive(Clone, Serialize, Deserialize, Debug)]
 pub struct Vm {
     pub metadata: Metadata,
+    #[serde(default)]
     pub spec: VmSpec,
     #[serde(default)]
     pub status: VmStatus,
@@ -39,6 +145,8 @@ pub struct Vm {
 impl Object for Vm {
     const OBJECT_TYPE: &'static str = "vm";
 
+    const PROJECT_SCOPED: bool = true;
+
     fn metadata(&self) -> Cow<'_, Metadata> {
         Cow::Borrowed(&self.metadata)
     }
@@ -46,28 +154,557 @@ impl Object for Vm {
     fn set_version(&mut self, rev: i64) {
         self.metadata.version = Some(rev)
     }
+
+    fn set_mod_revision(&mut self, rev: i64) {
+        self.metadata.mod_revision = Some(rev)
+    }
+
+    fn set_lease(&mut self, lease: i64) {
+        self.metadata.lease = Some(lease).filter(|lease| *lease != 0)
+    }
 }
 
-#[derive(Clone, Serialize, Deserialize, Debug)]
+impl Vm {
+    /// The key name of the VM's VPC, which is in the VM's project.
+    pub fn vpc_key_name(&self) -> String {
+        scoped_name(&self.metadata.project, &self.spec.vpc)
+    }
+
+    /// The cloud-init meta-data on the VM's seed disk: its name as `instance-id` and
+    /// `local-hostname` and its project, overridden key by key by `spec.cloud_init_meta_data`.
+    /// Written as JSON, which cloud-init reads as YAML.
+    pub fn meta_data(&self) -> serde_json::Value {
+        let mut meta_data = serde_json::Map::new();
+        meta_data.insert("instance-id".to_string(), self.metadata.name.clone().into());
+        meta_data.insert(
+            "local-hostname".to_string(),
+            self.metadata.name.clone().into(),
+        );
+        meta_data.insert("project".to_string(), self.metadata.project.clone().into());
+        for (key, value) in &self.spec.cloud_init_meta_data {
+            meta_data.insert(key.clone(), value.clone());
+        }
+        serde_json::Value::Object(meta_data)
+    }
+}
+
+pub const MIN_VM_MEMORY_MIB: usize = 128;
+pub const MAX_VM_MEMORY_MIB: usize = 1 << 20;
+
+/// A VM's desired shape. `vpc`, `cpus` and `memory` may be left out on create to take the
+/// node's configured defaults.
+#[derive(Clone, Serialize, Deserialize, Default, Debug)]
 pub struct VmSpec {
+    #[serde(default)]
     pub vpc: String,
+    #[serde(default)]
     pub cpus: u8,
+    /// Guest memory in MiB.
+    #[serde(default)]
     pub memory: usize,
+    /// cloud-init user-data, passed to the guest on a NoCloud seed disk.
+    #[serde(default)]
     pub cloud_init: Option<String>,
+    /// cloud-init meta-data merged over the node's, which sets `instance-id` and
+    /// `local-hostname` to the VM's name and `project` to its project. See [`Vm::meta_data`].
+    #[serde(default)]
+    pub cloud_init_meta_data: BTreeMap<String, serde_json::Value>,
+    /// Whether the guest runs. VMs are created powered on unless this is set to `false`.
+    #[serde(default = "default_powered_on")]
     pub powered_on: bool,
+    /// A kernel on the node to boot directly, bypassing the firmware.
+    #[serde(default)]
+    pub kernel: Option<PathBuf>,
+    /// An initramfs to load alongside `kernel`.
+    #[serde(default)]
+    pub initramfs: Option<PathBuf>,
+    /// The kernel command line used with `kernel`.
+    #[serde(default)]
+    pub cmdline: Option<String>,
+    /// Deletes the VM this many seconds after creation unless renewed. Cleanup is best-effort:
+    /// it relies on etcd expiring the lease and a node tearing the VM down on the resulting
+    /// delete, so it is not a hard guarantee on how long the VM runs.
+    #[serde(default)]
+    pub ttl_seconds: Option<i64>,
+    /// The entropy source for the guest's virtio-rng device, `/dev/urandom` when unset.
+    #[serde(default)]
+    pub rng: Option<RngSpec>,
+    /// Backs guest memory with hugepages from the node.
+    #[serde(default)]
+    pub hugepages: bool,
+    /// The hugepage size in bytes, the node's default when unset. Requires `hugepages`.
+    #[serde(default)]
+    pub hugepage_size: Option<u64>,
+    /// Maps guest memory shared, as needed by vhost-user devices.
+    #[serde(default)]
+    pub shared_memory: bool,
+    /// A directory on the node shared into the guest over virtio-fs. Implies `shared_memory`.
+    /// It has to be under one of the node's `shared_dir_roots`.
+    #[serde(default)]
+    pub shared_dir: Option<SharedDir>,
+    /// Places the disks, network, rng and console behind a virtual IOMMU, for guests doing
+    /// nested virtualization or device passthrough. Can't be combined with `shared_dir`, as
+    /// virtio-fs doesn't support the IOMMU.
+    #[serde(default)]
+    pub iommu: bool,
+    /// Arguments appended to the kernel command line, after `cmdline` when booting a kernel.
+    #[serde(default)]
+    pub extra_cmdline: Option<String>,
+    /// Where the virtio console goes, a pty when unset.
+    #[serde(default)]
+    pub console_mode: Option<ConsoleMode>,
+    /// Where the serial port goes, the node's console log when unset.
+    #[serde(default)]
+    pub serial_mode: Option<ConsoleMode>,
+    /// What the node does when the VM's hypervisor exits on its own.
+    #[serde(default)]
+    pub restart_policy: RestartPolicy,
+    /// Disks attached after the root disk, in order.
+    #[serde(default)]
+    pub disks: Vec<DiskSpec>,
+    /// The only node the VM may be placed on. The VM stays pending if that node is cordoned or
+    /// doesn't have room for it.
+    #[serde(default)]
+    pub node_selector: Option<String>,
+    /// VMs in the same group are never placed on the same node. The group is a hard
+    /// constraint: nodes already running a member are ruled out before the rest are scored.
+    #[serde(default)]
+    pub anti_affinity_group: Option<String>,
+}
+
+fn default_powered_on() -> bool {
+    true
+}
+
+/// An extra disk for a VM. A bare path, like `"/dev/sdb"`, is read as a block device.
+#[derive(Clone, Serialize, Debug, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum DiskSpec {
+    /// A raw block device on the node, like `/dev/sdb`, passed through with direct I/O. It has
+    /// to be one of the node's `block_devices`. A device is claimed by the first VM to start
+    /// with it and can't be attached to another until that VM is deleted.
+    BlockDevice {
+        path: PathBuf,
+        #[serde(default)]
+        readonly: bool,
+    },
+}
+
+impl<'de> Deserialize<'de> for DiskSpec {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        #[derive(Deserialize)]
+        #[serde(rename_all = "snake_case")]
+        enum Tagged {
+            BlockDevice {
+                path: PathBuf,
+                #[serde(default)]
+                readonly: bool,
+            },
+        }
+
+        #[derive(Deserialize)]
+        #[serde(untagged)]
+        enum Given {
+            Path(PathBuf),
+            Tagged(Tagged),
+        }
+
+        Ok(match Given::deserialize(deserializer)? {
+            Given::Path(path) => DiskSpec::BlockDevice {
+                path,
+                readonly: false,
+            },
+            Given::Tagged(Tagged::BlockDevice { path, readonly }) => {
+                DiskSpec::BlockDevice { path, readonly }
+            }
+        })
+    }
+}
+
+impl DiskSpec {
+    /// The disk's path on the node.
+    pub fn path(&self) -> &Path {
+        match self {
+            DiskSpec::BlockDevice { path, .. } => path,
+        }
+    }
+}
+
+/// Whether a VM is started again after its hypervisor exits. A VM that isn't is left `Failed`.
+#[derive(Clone, Copy, Serialize, Deserialize, Debug, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum RestartPolicy {
+    Never,
+    /// Only when the hypervisor exits with an error, so a guest powering itself off stays off.
+    OnFailure,
+    Always,
+}
+
+impl Default for RestartPolicy {
+    fn default() -> Self {
+        RestartPolicy::Never
+    }
+}
+
+impl RestartPolicy {
+    /// Whether a VM whose hypervisor exited, cleanly or not, should be started again.
+    pub fn restarts(&self, clean: bool) -> bool {
+        match self {
+            RestartPolicy::Never => false,
+            RestartPolicy::OnFailure => !clean,
+            RestartPolicy::Always => true,
+        }
+    }
+}
+
+/// Where a VM's console or serial port is connected.
+#[derive(Clone, Serialize, Deserialize, Debug, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum ConsoleMode {
+    Off,
+    Null,
+    /// A pty on the node, whose path is reported in the VM's status for attaching.
+    Pty,
+    /// cloud-hypervisor's stdout, which is copied into the VM's console log.
+    Tty,
+    /// A file of this name in the VM's directory under the node's `console_log_dir`.
+    File(PathBuf),
+}
+
+/// The longest kernel command line x86 kernels accept.
+pub const MAX_CMDLINE_LEN: usize = 2048;
+
+/// The entropy sources a VM's virtio-rng device may read from.
+pub const RNG_SOURCES: &[&str] = &["/dev/urandom", "/dev/hwrng"];
+
+/// The longest tag virtio-fs accepts.
+pub const MAX_FS_TAG_LEN: usize = 36;
+
+#[derive(Clone, Serialize, Deserialize, Debug)]
+pub struct SharedDir {
+    pub path: PathBuf,
+    /// The tag the guest mounts the directory by, e.g. `mount -t virtiofs <tag> /mnt`.
+    pub tag: String,
+    #[serde(default)]
+    pub readonly: bool,
+}
+
+#[derive(Clone, Serialize, Deserialize, Debug)]
+pub struct RngSpec {
+    /// One of [`RNG_SOURCES`] on the node, e.g. `/dev/hwrng`.
+    pub src: PathBuf,
+    #[serde(default)]
+    pub iommu: bool,
+}
+
+impl VmSpec {
+    /// Guest memory in bytes, as expected by cloud-hypervisor.
+    pub fn memory_bytes(&self) -> u64 {
+        (self.memory as u64) << 20
+    }
+
+    pub fn validate(&self) -> Result<(), Error> {
+        if self.vpc.is_empty() {
+            return Err(Error::Invalid("vpc is required".to_string()));
+        }
+        if self.cpus == 0 {
+            return Err(Error::Invalid("cpus must be at least 1".to_string()));
+        }
+        if self.memory < MIN_VM_MEMORY_MIB || self.memory > MAX_VM_MEMORY_MIB {
+            return Err(Error::Invalid(format!(
+                "memory must be between {} and {} MiB, got {}",
+                MIN_VM_MEMORY_MIB, MAX_VM_MEMORY_MIB, self.memory
+            )));
+        }
+        if let Some(ttl) = self.ttl_seconds {
+            if ttl <= 0 {
+                return Err(Error::Invalid("ttl_seconds must be positive".to_string()));
+            }
+        }
+        if let Some(size) = self.hugepage_size {
+            if !self.hugepages {
+                return Err(Error::Invalid(
+                    "hugepage_size requires hugepages".to_string(),
+                ));
+            }
+            if !size.is_power_of_two() {
+                return Err(Error::Invalid(format!(
+                    "hugepage_size must be a power of two, got {}",
+                    size
+                )));
+            }
+            if self.memory_bytes() % size != 0 {
+                return Err(Error::Invalid(format!(
+                    "memory must be a multiple of hugepage_size {}",
+                    size
+                )));
+            }
+        }
+        if let Some(ref rng) = self.rng {
+            if !RNG_SOURCES.iter().any(|src| rng.src == Path::new(src)) {
+                return Err(Error::Invalid(format!(
+                    "rng source must be one of {}, got {}",
+                    RNG_SOURCES.join(", "),
+                    rng.src.display()
+                )));
+            }
+        }
+        if let Some(ref shared_dir) = self.shared_dir {
+            if shared_dir.tag.is_empty() || shared_dir.tag.len() > MAX_FS_TAG_LEN {
+                return Err(Error::Invalid(format!(
+                    "shared_dir tag must be 1 to {} bytes",
+                    MAX_FS_TAG_LEN
+                )));
+            }
+        }
+        if self.console_mode == Some(ConsoleMode::Tty)
+            && matches!(self.serial_mode, None | Some(ConsoleMode::Tty))
+        {
+            return Err(Error::Invalid(
+                "serial and console cannot both use tty".to_string(),
+            ));
+        }
+        for mode in self.console_mode.iter().chain(self.serial_mode.iter()) {
+            if let ConsoleMode::File(path) = mode {
+                let mut components = path.components();
+                if !matches!(
+                    (components.next(), components.next()),
+                    (Some(Component::Normal(_)), None)
+                ) {
+                    return Err(Error::Invalid(format!(
+                        "console file {} must be a file name without a directory",
+                        path.display()
+                    )));
+                }
+            }
+        }
+        if let Some(ref extra) = self.extra_cmdline {
+            if extra.len() > MAX_CMDLINE_LEN || extra.chars().any(char::is_control) {
+                return Err(Error::Invalid(format!(
+                    "extra_cmdline must be at most {} bytes without control characters",
+                    MAX_CMDLINE_LEN
+                )));
+            }
+        }
+        if self.iommu && self.shared_dir.is_some() {
+            return Err(Error::Invalid(
+                "iommu can't be used with shared_dir".to_string(),
+            ));
+        }
+        if self.kernel.is_none() && (self.initramfs.is_some() || self.cmdline.is_some()) {
+            return Err(Error::Invalid(
+                "initramfs and cmdline require a kernel".to_string(),
+            ));
+        }
+        for (i, disk) in self.disks.iter().enumerate() {
+            let path = disk.path();
+            if !path.starts_with("/dev") {
+                return Err(Error::Invalid(format!(
+                    "block device {} isn't under /dev",
+                    path.display()
+                )));
+            }
+            if self.disks[..i].iter().any(|other| other.path() == path) {
+                return Err(Error::Invalid(format!(
+                    "block device {} is listed twice",
+                    path.display()
+                )));
+            }
+        }
+        Ok(())
+    }
 }
 
 #[derive(Clone, Serialize, Deserialize, Default, Debug)]
 pub struct VmStatus {
     pub node: Option<String>,
     pub state: VmState,
+    #[serde(default)]
+    pub message: Option<String>,
+    /// How many times in a row the node has failed to start the VM. Retries back off
+    /// exponentially with this count and it resets once the VM starts.
+    #[serde(default)]
+    pub failures: u32,
+    /// The error from the most recent failed start.
+    #[serde(default)]
+    pub last_error: Option<String>,
+    /// How many times the node has started the VM again under its `restart_policy`.
+    #[serde(default)]
+    pub restarts: u32,
+    /// When the VM was last started again under its `restart_policy`, in seconds since the unix
+    /// epoch.
+    #[serde(default)]
+    pub last_restart_time: Option<i64>,
+    /// Whether `node` is still registered, filled in by the API when it returns the VM rather
+    /// than stored.
+    #[serde(default, skip_deserializing, skip_serializing_if = "Option::is_none")]
+    pub node_ready: Option<bool>,
+    /// When `state` last changed, in seconds since the unix epoch.
+    #[serde(default)]
+    pub last_transition_time: Option<i64>,
+    /// Seconds since the VM last powered on, filled in by the API while it's powered on rather
+    /// than stored. Any restart, including one applying a new scale, starts it over.
+    #[serde(default, skip_deserializing, skip_serializing_if = "Option::is_none")]
+    pub uptime_seconds: Option<i64>,
+    /// The pty the virtio console is attached to, when `console_mode` is `pty`.
+    #[serde(default)]
+    pub console_pty: Option<String>,
+    /// The pty the serial port is attached to, when `serial_mode` is `pty`.
+    #[serde(default)]
+    pub serial_pty: Option<String>,
+    /// The scheduler's most recent attempt to place the VM.
+    #[serde(default)]
+    pub scheduling: Option<SchedulingDecision>,
+    /// What the guest last reported of cloud-init's progress, see [`CloudInitStatus`].
+    #[serde(default)]
+    pub cloud_init_status: Option<CloudInitStatus>,
+}
+
+/// cloud-init's progress as reported by the guest. There's no agent in the guest yet, so the
+/// guest reports by writing a line containing [`CLOUD_INIT_MARKER`] followed by `running`,
+/// `done` or `error` to its serial console, `/dev/ttyS0`, e.g. from `bootcmd` and
+/// `final_message`. Only guests whose `serial_mode` is left at the default are read, and the
+/// last report wins.
+#[derive(Clone, Copy, Serialize, Deserialize, Debug, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum CloudInitStatus {
+    Running,
+    Done,
+    Error,
 }
 
+/// The prefix of a cloud-init status report on a guest's serial console.
+pub const CLOUD_INIT_MARKER: &str = "searu-cloud-init: ";
+
+impl CloudInitStatus {
+    /// Finds a status report in a line of console output.
+    pub fn parse_report(line: &[u8]) -> Option<Self> {
+        let line = String::from_utf8_lossy(line);
+        let start = line.find(CLOUD_INIT_MARKER)? + CLOUD_INIT_MARKER.len();
+        match line[start..].split_whitespace().next()? {
+            "running" => Some(CloudInitStatus::Running),
+            "done" => Some(CloudInitStatus::Done),
+            "error" => Some(CloudInitStatus::Error),
+            _ => None,
+        }
+    }
+}
+
+/// Cluster-wide totals, for an overview without listing every object.
+#[derive(Serialize, Debug)]
+pub struct ClusterSummary {
+    /// Nodes with a live heartbeat.
+    pub ready_nodes: usize,
+    /// Nodes VMs are still scheduled onto whose heartbeat has lapsed.
+    pub not_ready_nodes: usize,
+    /// The cpus and memory of the ready nodes.
+    pub capacity: Capacity,
+    /// What the VMs scheduled onto ready nodes request.
+    pub allocated: Capacity,
+    /// What's left on the ready nodes, counted per node so one overcommitted node doesn't eat
+    /// into the others.
+    pub free: Capacity,
+    pub vms: BTreeMap<VmState, usize>,
+    pub vpcs: usize,
+}
+
+/// Why the scheduler placed a VM where it did, or couldn't place it.
+#[derive(Clone, Serialize, Deserialize, Debug, PartialEq)]
+pub struct SchedulingDecision {
+    /// The chosen node, if the VM fit anywhere.
+    pub node: Option<String>,
+    pub reason: String,
+    pub candidates: Vec<NodeCandidate>,
+}
+
+/// A node the scheduler considered: its score if the VM fit, why not otherwise.
 #[derive(Clone, Serialize, Deserialize, Debug, PartialEq)]
+pub struct NodeCandidate {
+    pub node: String,
+    #[serde(default)]
+    pub score: Option<f64>,
+    #[serde(default)]
+    pub rejected: Option<String>,
+}
+
+impl VmStatus {
+    /// Moves to `next` at `now`, in seconds since the unix epoch, rejecting moves the VM state
+    /// machine doesn't allow.
+    pub fn transition(&mut self, next: VmState, now: i64) -> Result<(), Error> {
+        if !self.state.can_transition_to(&next) {
+            return Err(Error::Invalid(format!(
+                "vm state cannot go from {:?} to {:?}",
+                self.state, next
+            )));
+        }
+        if self.state != next {
+            self.last_transition_time = Some(now);
+        }
+        self.state = next;
+        Ok(())
+    }
+
+    /// Checks that a VM in this state can follow `spec.powered_on` changing to `powered_on`, so
+    /// e.g. a failed VM can't be powered on. VMs that haven't been started yet are left to boot
+    /// in whichever state they're given.
+    pub fn check_power(&self, powered_on: bool) -> Result<(), Error> {
+        let next = if powered_on {
+            VmState::PoweredOn
+        } else {
+            VmState::PoweredOff
+        };
+        match self.state {
+            VmState::Uncreated | VmState::Pending => Ok(()),
+            ref state if state.can_transition_to(&next) => Ok(()),
+            ref state => Err(Error::Invalid(format!(
+                "vm state cannot go from {:?} to {:?}",
+                state, next
+            ))),
+        }
+    }
+
+    /// Fills in `uptime_seconds` as of `now` if the VM is powered on.
+    pub fn set_uptime(&mut self, now: i64) {
+        self.uptime_seconds = match (&self.state, self.last_transition_time) {
+            (VmState::PoweredOn, Some(since)) => Some((now - since).max(0)),
+            _ => None,
+        };
+    }
+}
+
+#[derive(Clone, Serialize, Deserialize, Debug, PartialEq, Eq, PartialOrd, Ord)]
 pub enum VmState {
     Uncreated,
+    /// Waiting for a node with room for it, or for its node to start it.
+    Pending,
     PoweredOff,
     PoweredOn,
+    Paused,
+    Failed,
+}
+
+impl VmState {
+    /// Whether a VM may move from this state to `next`. Staying in the same state and moving to
+    /// `Failed` are always allowed. A failed VM only leaves `Failed` to start over, either
+    /// recreated as `Uncreated` or unscheduled from its node as `PoweredOff`.
+    pub fn can_transition_to(&self, next: &VmState) -> bool {
+        self == next
+            || *next == VmState::Failed
+            || matches!(
+                (self, next),
+                (VmState::Uncreated, VmState::PoweredOff)
+                    | (VmState::Uncreated, VmState::Pending)
+                    | (VmState::PoweredOff, VmState::Pending)
+                    | (VmState::Pending, VmState::PoweredOff)
+                    | (VmState::PoweredOff, VmState::PoweredOn)
+                    | (VmState::PoweredOn, VmState::PoweredOff)
+                    | (VmState::PoweredOn, VmState::Paused)
+                    | (VmState::Paused, VmState::PoweredOn)
+                    | (VmState::Paused, VmState::PoweredOff)
+                    | (VmState::Failed, VmState::Uncreated)
+                    | (VmState::Failed, VmState::PoweredOff)
+            )
+    }
 }
 
 impl Default for VmState {
@@ -80,18 +717,90 @@ impl Default for VmState {
 pub struct Vpc {
     pub metadata: Metadata,
     pub spec: VpcSpec,
+    #[serde(default)]
+    pub status: VpcStatus,
 }
 
-#[derive(Clone, Serialize, Deserialize)]
+#[derive(Clone, Serialize, Deserialize, PartialEq)]
 pub struct VpcSpec {
     pub subnet: Ipv4Net,
     pub multicast_ip: Option<Ipv4Addr>,
     pub vni: Option<u16>,
+    /// Masquerades guest traffic leaving the VPC through each node and advertises the bridge
+    /// as the guests' default route. Without it guests get no default route, so traffic
+    /// outside the VPC fails instead of disappearing into a bridge that can't forward it.
+    #[serde(default)]
+    pub nat_gateway: bool,
+}
+
+/// What the nodes report about a VPC.
+#[derive(Clone, Serialize, Deserialize, Default, Debug)]
+pub struct VpcStatus {
+    /// Why the VPC isn't working on a node, by node name. A node clears its message once the
+    /// VPC works there.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub node_messages: BTreeMap<String, String>,
+}
+
+impl VpcSpec {
+    /// The address assigned to the VPC's bridge on every node, also used as the DHCP server address.
+    pub fn host_ip(&self) -> Option<Ipv4Addr> {
+        self.subnet.hosts().next()
+    }
+
+    /// The dynamic range handed out by dnsmasq. It skips the host and veth addresses at the
+    /// start of the subnet and the last host address.
+    pub fn dhcp_range(&self) -> Result<DhcpRange, Error> {
+        // A /29 leaves .3 to .5 after skipping the network, host, veth, last and broadcast
+        if self.subnet.prefix_len() > 29 {
+            return Err(Error::Invalid(format!(
+                "subnet {} is too small for a dhcp range, use a /29 or larger",
+                self.subnet
+            )));
+        }
+        let start = u32::from(self.subnet.network()) + 3;
+        let end = u32::from(self.subnet.broadcast()) - 2;
+        Ok(DhcpRange {
+            start: Ipv4Addr::from(start),
+            end: Ipv4Addr::from(end),
+        })
+    }
+
+    pub fn validate(&self) -> Result<(), Error> {
+        self.dhcp_range()?;
+        if let Some(ip) = self.multicast_ip {
+            if !ip.is_multicast() {
+                return Err(Error::Invalid(format!(
+                    "multicast_ip {} isn't a multicast address",
+                    ip
+                )));
+            }
+        }
+        if self.vni == Some(0) {
+            return Err(Error::Invalid("vni must be at least 1".to_string()));
+        }
+        Ok(())
+    }
+}
+
+/// An inclusive range of addresses handed out by DHCP.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize)]
+pub struct DhcpRange {
+    pub start: Ipv4Addr,
+    pub end: Ipv4Addr,
+}
+
+impl DhcpRange {
+    pub fn contains(&self, ip: &Ipv4Addr) -> bool {
+        self.start <= *ip && *ip <= self.end
+    }
 }
 
 impl Object for Vpc {
     const OBJECT_TYPE: &'static str = "vpc";
 
+    const PROJECT_SCOPED: bool = true;
+
     fn metadata(&self) -> Cow<'_, Metadata> {
         Cow::Borrowed(&self.metadata)
     }
@@ -99,42 +808,387 @@ impl Object for Vpc {
     fn set_version(&mut self, rev: i64) {
         self.metadata.version = Some(rev)
     }
+
+    fn set_mod_revision(&mut self, rev: i64) {
+        self.metadata.mod_revision = Some(rev)
+    }
+
+    fn set_lease(&mut self, lease: i64) {
+        self.metadata.lease = Some(lease).filter(|lease| *lease != 0)
+    }
+}
+
+#[derive(Clone, Serialize, Deserialize, Debug)]
+pub struct DhcpReservation {
+    pub metadata: Metadata,
+    pub spec: DhcpReservationSpec,
+}
+
+#[derive(Clone, Serialize, Deserialize, Debug)]
+pub struct DhcpReservationSpec {
+    pub vpc: String,
+    pub mac: MacAddr,
+    pub ip: Ipv4Addr,
+}
+
+/// An address dnsmasq has handed out, as recorded in its lease file.
+#[derive(Clone, Serialize, Debug)]
+pub struct DhcpLease {
+    /// When the lease expires, in seconds since the epoch.
+    pub expires: i64,
+    pub mac: MacAddr,
+    pub ip: Ipv4Addr,
+    pub hostname: Option<String>,
+}
+
+impl DhcpLease {
+    /// Parses a line of a dnsmasq lease file, `<expires> <mac> <ip> <hostname> <client id>`,
+    /// where unknown fields are `*`.
+    pub fn parse(line: &str) -> Option<Self> {
+        let mut fields = line.split_whitespace();
+        let expires = fields.next()?.parse().ok()?;
+        let mac = MacAddr::parse_str(fields.next()?).ok()?;
+        let ip = fields.next()?.parse().ok()?;
+        let hostname = fields
+            .next()
+            .filter(|hostname| *hostname != "*")
+            .map(str::to_string);
+        Some(Self {
+            expires,
+            mac,
+            ip,
+            hostname,
+        })
+    }
+}
+
+impl DhcpReservation {
+    /// The key name of the reservation's VPC, which is in the reservation's project.
+    pub fn vpc_key_name(&self) -> String {
+        scoped_name(&self.metadata.project, &self.spec.vpc)
+    }
+}
+
+impl DhcpReservationSpec {
+    /// Checks that the reserved address is usable in the VPC and can't be handed out dynamically.
+    pub fn validate(&self, vpc: &VpcSpec) -> Result<(), Error> {
+        if !vpc.subnet.contains(&self.ip) {
+            return Err(Error::Invalid(format!(
+                "reservation ip {} is not in subnet {}",
+                self.ip, vpc.subnet
+            )));
+        }
+        if self.ip == vpc.subnet.network() || self.ip == vpc.subnet.broadcast() {
+            return Err(Error::Invalid(format!(
+                "reservation ip {} is not a host address",
+                self.ip
+            )));
+        }
+        if Some(self.ip) == vpc.host_ip() {
+            return Err(Error::Invalid(format!(
+                "reservation ip {} is the vpc host address",
+                self.ip
+            )));
+        }
+        if let Ok(range) = vpc.dhcp_range() {
+            if range.contains(&self.ip) {
+                return Err(Error::Invalid(format!(
+                    "reservation ip {} is within the dynamic range {}-{}",
+                    self.ip, range.start, range.end
+                )));
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Object for DhcpReservation {
+    const OBJECT_TYPE: &'static str = "dhcp_reservation";
+
+    fn metadata(&self) -> Cow<'_, Metadata> {
+        Cow::Borrowed(&self.metadata)
+    }
+
+    fn set_version(&mut self, rev: i64) {
+        self.metadata.version = Some(rev)
+    }
+
+    fn set_mod_revision(&mut self, rev: i64) {
+        self.metadata.mod_revision = Some(rev)
+    }
+
+    fn set_lease(&mut self, lease: i64) {
+        self.metadata.lease = Some(lease).filter(|lease| *lease != 0)
+    }
 }
 
 #[derive(Serialize, Deserialize, Clone, Default, Debug)]
 pub struct Metadata {
     pub name: String,
+    /// Defaults to the caller's first project on create.
+    #[serde(default)]
     pub project: String,
     pub version: Option<i64>,
+    /// The etcd revision the object was last modified at.
+    #[serde(default)]
+    pub mod_revision: Option<i64>,
+    /// The user that created the object.
+    #[serde(default)]
+    pub created_by: Option<String>,
+    /// The etcd lease the object's key is attached to, if any. The object is deleted when the
+    /// lease expires.
+    #[serde(default)]
+    pub lease: Option<i64>,
+    #[serde(default)]
+    pub labels: BTreeMap<String, String>,
+    /// Set on VMs and VPCs created before keys were scoped by project, which keep naming their
+    /// resources on the nodes after their name. See [`Metadata::host_name`].
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub host_name: Option<String>,
+}
+
+impl Metadata {
+    /// The name of the object's resources on the nodes, like interfaces, sockets and files.
+    pub fn host_name(&self) -> String {
+        match self.host_name {
+            Some(ref host_name) => host_name.clone(),
+            None => host_name(&self.project, &self.name),
+        }
+    }
+}
+
+/// The longest object name accepted.
+pub const MAX_NAME_LEN: usize = 253;
+
+/// Checks that `name` can name an object: it must be non-empty, at most [`MAX_NAME_LEN`] bytes
+/// and free of slashes and control characters.
+pub fn validate_name(name: &str) -> Result<(), Error> {
+    if name.is_empty() || name.len() > MAX_NAME_LEN {
+        return Err(Error::Invalid(format!(
+            "name must be 1 to {} bytes",
+            MAX_NAME_LEN
+        )));
+    }
+    if name.chars().any(|c| c == '/' || c.is_control()) {
+        return Err(Error::Invalid(format!(
+            "name {:?} contains a slash or control character",
+            name
+        )));
+    }
+    Ok(())
+}
+
+/// Percent-encodes every byte of `name` outside `[A-Za-z0-9._~-]`, so no name can reach
+/// outside its object type's key space even if it got past [`validate_name`]. Names made only
+/// of those characters are left unchanged.
+pub fn encode_name(name: &str) -> String {
+    let mut encoded = String::with_capacity(name.len());
+    for byte in name.bytes() {
+        match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'.' | b'_' | b'~' | b'-' => {
+                encoded.push(byte as char)
+            }
+            _ => encoded.push_str(&format!("%{:02X}", byte)),
+        }
+    }
+    encoded
+}
+
+/// Reverses [`encode_name`], returning `None` for malformed input.
+pub fn decode_name(encoded: &str) -> Option<String> {
+    let mut bytes = Vec::with_capacity(encoded.len());
+    let mut iter = encoded.bytes();
+    while let Some(byte) = iter.next() {
+        if byte == b'%' {
+            let hex = [iter.next()?, iter.next()?];
+            bytes.push(u8::from_str_radix(std::str::from_utf8(&hex).ok()?, 16).ok()?);
+        } else {
+            bytes.push(byte);
+        }
+    }
+    String::from_utf8(bytes).ok()
+}
+
+/// The key name of the object called `name` in `project`, for types that are
+/// [`Object::PROJECT_SCOPED`].
+pub fn scoped_name(project: &str, name: &str) -> String {
+    format!("{}/{}", project, name)
+}
+
+/// How many hex digits of a hash [`Metadata::host_name`] uses, few enough that `veth` and the
+/// name fit in an interface name.
+const HOST_NAME_LEN: usize = 11;
+
+/// The name the resources on the nodes of the object called `name` in `project` are given, a
+/// hash of both, as the name alone is only unique within the project and may not fit in an
+/// interface name.
+pub fn host_name(project: &str, name: &str) -> String {
+    let digest = ring::digest::digest(&ring::digest::SHA256, scoped_name(project, name).as_bytes());
+    let hex: String = digest
+        .as_ref()
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect();
+    hex[..HOST_NAME_LEN].to_string()
 }
 
 pub trait Object: Serialize + DeserializeOwned {
     const OBJECT_TYPE: &'static str;
 
+    /// Whether names are only unique within a project, so that keys read
+    /// `<type>/<project>/<name>`. Other objects are keyed `<type>/<name>`.
+    const PROJECT_SCOPED: bool = false;
+
     fn metadata(&self) -> Cow<'_, Metadata>;
 
+    /// What the object is stored, read and watched under: `<project>/<name>` for
+    /// [`Object::PROJECT_SCOPED`] types and the name otherwise.
+    fn key_name(&self) -> String {
+        let metadata = self.metadata();
+        if Self::PROJECT_SCOPED {
+            scoped_name(&metadata.project, &metadata.name)
+        } else {
+            metadata.name.clone()
+        }
+    }
+
     fn key(&self) -> String {
-        format!("{}/{}", Self::OBJECT_TYPE, self.metadata().name)
+        Self::key_for(&self.key_name())
+    }
+
+    /// The key of the object of this type whose [`Object::key_name`] is `name`. The project and
+    /// name of a scoped type are encoded separately, keeping the slash between them.
+    fn key_for(name: &str) -> String {
+        let mut segments = name.splitn(2, '/');
+        let encoded = match (segments.next(), segments.next()) {
+            (Some(project), Some(name)) if Self::PROJECT_SCOPED => {
+                format!("{}/{}", encode_name(project), encode_name(name))
+            }
+            _ => encode_name(name),
+        };
+        format!("{}/{}", Self::OBJECT_TYPE, encoded)
     }
 
     fn set_version(&mut self, rev: i64);
 
+    fn set_mod_revision(&mut self, _rev: i64) {}
+
+    fn set_lease(&mut self, _lease: i64) {}
+
     fn parse(kv: &KeyValue) -> Result<Self, Error>
     where
         Self: Sized,
     {
-        let mut obj: Self = serde_json::from_slice(kv.value())?;
-        obj.set_version(kv.version());
+        let mut obj: Self = serde_json::from_slice(&kv.value)?;
+        obj.set_version(kv.version);
+        obj.set_mod_revision(kv.mod_revision);
+        obj.set_lease(kv.lease);
         Ok(obj)
     }
 }
 
+/// Cluster-wide settings, stored as a single object named [`ClusterConfig::NAME`].
+#[derive(Serialize, Deserialize, Clone, Default, Debug)]
+pub struct ClusterConfig {
+    #[serde(default)]
+    pub scheduling_paused: bool,
+    #[serde(skip)]
+    pub version: Option<i64>,
+}
+
+impl ClusterConfig {
+    pub const NAME: &'static str = "cluster";
+}
+
+impl Object for ClusterConfig {
+    const OBJECT_TYPE: &'static str = "cluster_config";
+
+    fn metadata(&self) -> Cow<'_, Metadata> {
+        Cow::Owned(Metadata {
+            name: Self::NAME.to_string(),
+            version: self.version,
+            ..Default::default()
+        })
+    }
+
+    fn set_version(&mut self, rev: i64) {
+        self.version = Some(rev);
+    }
+}
+
 #[derive(Serialize, Deserialize)]
 pub struct Node {
     pub metadata: Metadata,
     pub cpu_count: usize,
     pub cpu_freq: u64,
+    /// Total memory in KiB.
     pub memory: u64,
+    /// Keeps the scheduler from placing VMs on the node. Set through the API and kept by
+    /// heartbeats.
+    #[serde(default)]
+    pub cordoned: bool,
+    /// When the node last heartbeated, in seconds since the epoch.
+    #[serde(default)]
+    pub last_heartbeat: Option<i64>,
+}
+
+/// An amount of cpus and memory, in MiB to match [`VmSpec::memory`].
+#[derive(Serialize, Clone, Copy, Default, Debug)]
+pub struct Capacity {
+    pub cpus: usize,
+    pub memory: u64,
+}
+
+impl std::ops::Add for Capacity {
+    type Output = Capacity;
+
+    fn add(self, other: Capacity) -> Capacity {
+        Capacity {
+            cpus: self.cpus + other.cpus,
+            memory: self.memory + other.memory,
+        }
+    }
+}
+
+impl Capacity {
+    /// What's left of `self` after `other`, stopping at zero.
+    pub fn saturating_sub(self, other: Capacity) -> Capacity {
+        Capacity {
+            cpus: self.cpus.saturating_sub(other.cpus),
+            memory: self.memory.saturating_sub(other.memory),
+        }
+    }
+}
+
+impl From<&VmSpec> for Capacity {
+    fn from(spec: &VmSpec) -> Self {
+        Capacity {
+            cpus: spec.cpus as usize,
+            memory: spec.memory as u64,
+        }
+    }
+}
+
+impl Node {
+    pub fn capacity(&self) -> Capacity {
+        Capacity {
+            cpus: self.cpu_count,
+            memory: self.memory >> 10,
+        }
+    }
+
+    /// The resources requested by the VMs in `vms` that are scheduled onto this node.
+    pub fn allocated<'a>(&self, vms: impl IntoIterator<Item = &'a Vm>) -> Capacity {
+        vms.into_iter()
+            .filter(|vm| vm.status.node.as_deref() == Some(self.metadata.name.as_str()))
+            .fold(Capacity::default(), |acc, vm| {
+                acc + Capacity::from(&vm.spec)
+            })
+    }
+
+    pub fn free<'a>(&self, vms: impl IntoIterator<Item = &'a Vm>) -> Capacity {
+        self.capacity().saturating_sub(self.allocated(vms))
+    }
 }
 
 impl Object for Node {
@@ -147,6 +1201,14 @@ impl Object for Node {
     fn set_version(&mut self, rev: i64) {
         self.metadata.version = Some(rev);
     }
+
+    fn set_mod_revision(&mut self, rev: i64) {
+        self.metadata.mod_revision = Some(rev);
+    }
+
+    fn set_lease(&mut self, lease: i64) {
+        self.metadata.lease = Some(lease).filter(|lease| *lease != 0);
+    }
 }
 
 #[derive(Error, Debug)]
@@ -165,6 +1227,8 @@ pub enum Error {
     Oneshot(#[from] tokio::sync::oneshot::error::RecvError),
     #[error("actor failed to send")]
     ActorSend,
+    #[error("actor is busy: its mailbox is full")]
+    ActorBusy,
     #[error("sysinfo: {0}")]
     SysInfo(#[from] sys_info::Error),
     #[error("io: {0}")]
@@ -181,11 +1245,68 @@ pub enum Error {
     Persist(#[from] tempfile::PersistError),
     #[error("rtnetlink: {0}")]
     RtNetlink(#[from] rtnetlink::Error),
+    #[error("invalid: {0}")]
+    Invalid(String),
+    #[error("forbidden: {0}")]
+    Forbidden(String),
+    #[error("already exists: {0}")]
+    AlreadyExists(String),
+    #[error("conflict: {0}")]
+    Conflict(String),
+    #[error("not ready: {0}")]
+    NotReady(String),
+    #[error("timed out: {0}")]
+    Timeout(String),
+    #[error("hypervisor: {0}")]
+    Hypervisor(String),
+    #[error("payload too large: {0}")]
+    PayloadTooLarge(String),
+    #[error("{}", display_all(.0))]
+    Multiple(Vec<Error>),
 }
 
+fn display_all(errors: &[Error]) -> String {
+    errors
+        .iter()
+        .map(ToString::to_string)
+        .collect::<Vec<_>>()
+        .join("; ")
+}
+
+impl Error {
+    /// Combines the errors from steps that were all attempted, or `Ok` if none failed.
+    pub fn collect(mut errors: Vec<Error>) -> Result<(), Error> {
+        match errors.len() {
+            0 => Ok(()),
+            1 => Err(errors.remove(0)),
+            _ => Err(Error::Multiple(errors)),
+        }
+    }
+}
+
+/// The body of every error response.
 #[derive(Serialize)]
-struct ErrorResponse {
-    msg: String,
+pub struct ErrorResponse {
+    pub msg: String,
+}
+
+impl Error {
+    pub fn status(&self) -> rocket::http::Status {
+        use rocket::http::Status;
+
+        match self {
+            Error::Unauthorized => Status::Unauthorized,
+            Error::Forbidden(_) => Status::Forbidden,
+            Error::NotFound(_) => Status::NotFound,
+            Error::Invalid(_) => Status::BadRequest,
+            Error::AlreadyExists(_) | Error::Conflict(_) => Status::Conflict,
+            Error::NotReady(_) | Error::ActorBusy => Status::ServiceUnavailable,
+            Error::Timeout(_) => Status::GatewayTimeout,
+            Error::Hypervisor(_) => Status::BadGateway,
+            Error::PayloadTooLarge(_) => Status::PayloadTooLarge,
+            _ => Status::InternalServerError,
+        }
+    }
 }
 
 impl<'r> rocket::response::Responder<'r, 'static> for Error {
@@ -196,10 +1317,12 @@ impl<'r> rocket::response::Responder<'r, 'static> for Error {
         };
         use std::io::Cursor;
 
+        let status = self.status();
         let msg = self.to_string();
         let resp = ErrorResponse { msg };
         let resp = serde_json::to_string(&resp).map_err(|_| Status::InternalServerError)?;
         Response::build()
+            .status(status)
             .header(ContentType::new("application", "json"))
             .sized_body(resp.len(), Cursor::new(resp))
             .ok()
@@ -210,4 +1333,298 @@ impl<'r> rocket::response::Responder<'r, 'static> for Error {
 pub struct ListResponse<T> {
     pub objects: Vec<T>,
     pub next_page: String,
+    /// A link to the next page as seen from outside any reverse proxy, when there is one.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub next_link: Option<String>,
+    /// The etcd revision the list was read at.
+    pub revision: i64,
+}
+
+#[derive(Serialize)]
+pub struct BuildInfo {
+    pub version: String,
+    pub git_commit: String,
+    pub build_timestamp: String,
+}
+
+/// The resources of a VM, as set through its scale subresource.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Scale {
+    pub cpus: u8,
+    /// Guest memory in MiB.
+    pub memory: usize,
+}
+
+/// A partial [`VmSpec`], applied by `PATCH /vms/<name>`. Fields left out keep their current
+/// value. Optional fields can be set but not cleared, which takes a full update.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+#[serde(deny_unknown_fields)]
+pub struct VmSpecPatch {
+    pub cpus: Option<u8>,
+    /// Guest memory in MiB.
+    pub memory: Option<usize>,
+    pub powered_on: Option<bool>,
+    pub cloud_init: Option<String>,
+    pub cloud_init_meta_data: Option<BTreeMap<String, serde_json::Value>>,
+    pub kernel: Option<PathBuf>,
+    pub initramfs: Option<PathBuf>,
+    pub cmdline: Option<String>,
+    pub rng: Option<RngSpec>,
+    pub hugepages: Option<bool>,
+    pub hugepage_size: Option<u64>,
+    pub shared_memory: Option<bool>,
+    pub shared_dir: Option<SharedDir>,
+    pub iommu: Option<bool>,
+    pub extra_cmdline: Option<String>,
+    pub console_mode: Option<ConsoleMode>,
+    pub serial_mode: Option<ConsoleMode>,
+    pub restart_policy: Option<RestartPolicy>,
+    pub disks: Option<Vec<DiskSpec>>,
+    pub node_selector: Option<String>,
+    pub anti_affinity_group: Option<String>,
+}
+
+impl VmSpecPatch {
+    pub fn apply(self, spec: &mut VmSpec) {
+        fn set<T>(field: &mut T, value: Option<T>) {
+            if let Some(value) = value {
+                *field = value;
+            }
+        }
+        fn set_some<T>(field: &mut Option<T>, value: Option<T>) {
+            if value.is_some() {
+                *field = value;
+            }
+        }
+        set(&mut spec.cpus, self.cpus);
+        set(&mut spec.memory, self.memory);
+        set(&mut spec.powered_on, self.powered_on);
+        set_some(&mut spec.cloud_init, self.cloud_init);
+        set(&mut spec.cloud_init_meta_data, self.cloud_init_meta_data);
+        set_some(&mut spec.kernel, self.kernel);
+        set_some(&mut spec.initramfs, self.initramfs);
+        set_some(&mut spec.cmdline, self.cmdline);
+        set_some(&mut spec.rng, self.rng);
+        set(&mut spec.hugepages, self.hugepages);
+        set_some(&mut spec.hugepage_size, self.hugepage_size);
+        set(&mut spec.shared_memory, self.shared_memory);
+        set_some(&mut spec.shared_dir, self.shared_dir);
+        set(&mut spec.iommu, self.iommu);
+        set_some(&mut spec.extra_cmdline, self.extra_cmdline);
+        set_some(&mut spec.console_mode, self.console_mode);
+        set_some(&mut spec.serial_mode, self.serial_mode);
+        set(&mut spec.restart_policy, self.restart_policy);
+        set(&mut spec.disks, self.disks);
+        set_some(&mut spec.node_selector, self.node_selector);
+        set_some(&mut spec.anti_affinity_group, self.anti_affinity_group);
+    }
+}
+
+/// The outcome for one object of a request acting on several.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct BatchResult {
+    pub name: String,
+    /// Why the object wasn't acted on, `None` if it was.
+    pub error: Option<String>,
+}
+
+#[derive(Serialize)]
+pub struct RenewResponse {
+    /// Seconds left before the object's lease expires.
+    pub ttl_seconds: i64,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn spec() -> VmSpec {
+        VmSpec {
+            vpc: "default".to_string(),
+            cpus: 1,
+            memory: 512,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn disks_may_be_given_as_paths() {
+        let spec: VmSpec = serde_json::from_value(serde_json::json!({
+            "disks": [
+                "/dev/sdb",
+                {"block_device": {"path": "/dev/sdc", "readonly": true}},
+            ],
+        }))
+        .unwrap();
+        assert_eq!(
+            spec.disks,
+            vec![
+                DiskSpec::BlockDevice {
+                    path: PathBuf::from("/dev/sdb"),
+                    readonly: false,
+                },
+                DiskSpec::BlockDevice {
+                    path: PathBuf::from("/dev/sdc"),
+                    readonly: true,
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn rng_sources_are_limited_to_entropy_devices() {
+        let mut spec = spec();
+        for src in RNG_SOURCES {
+            spec.rng = Some(RngSpec {
+                src: PathBuf::from(src),
+                iommu: false,
+            });
+            spec.validate().unwrap();
+        }
+        for src in &["/etc/shadow", "/dev/sda", "/dev/../etc/shadow"] {
+            spec.rng = Some(RngSpec {
+                src: PathBuf::from(src),
+                iommu: false,
+            });
+            assert!(
+                matches!(spec.validate(), Err(Error::Invalid(_))),
+                "{} was accepted",
+                src
+            );
+        }
+    }
+
+    #[test]
+    fn console_files_are_bare_file_names() {
+        let mut spec = spec();
+        spec.console_mode = Some(ConsoleMode::File(PathBuf::from("console.out")));
+        spec.validate().unwrap();
+        for path in &["", "/etc/passwd", "../console.out", "logs/console.out", "."] {
+            spec.serial_mode = Some(ConsoleMode::File(PathBuf::from(path)));
+            assert!(
+                matches!(spec.validate(), Err(Error::Invalid(_))),
+                "{:?} was accepted",
+                path
+            );
+        }
+    }
+
+    #[test]
+    fn memory_is_given_in_mib() {
+        let mut spec = spec();
+        assert_eq!(spec.memory_bytes(), 512 << 20);
+        assert_eq!(spec.memory_bytes(), 536_870_912);
+        spec.memory = 64 << 10;
+        assert_eq!(spec.memory_bytes(), 64 << 30);
+    }
+
+    #[test]
+    fn vm_state_transition_matrix() {
+        use VmState::*;
+        let states = [Uncreated, Pending, PoweredOff, PoweredOn, Paused, Failed];
+        let allowed = [
+            (Uncreated, Pending),
+            (Uncreated, PoweredOff),
+            (Pending, PoweredOff),
+            (PoweredOff, Pending),
+            (PoweredOff, PoweredOn),
+            (PoweredOn, PoweredOff),
+            (PoweredOn, Paused),
+            (Paused, PoweredOn),
+            (Paused, PoweredOff),
+            (Failed, Uncreated),
+            (Failed, PoweredOff),
+        ];
+        for from in &states {
+            for to in &states {
+                let expected =
+                    from == to || *to == Failed || allowed.contains(&(from.clone(), to.clone()));
+                assert_eq!(
+                    from.can_transition_to(to),
+                    expected,
+                    "{:?} -> {:?}",
+                    from,
+                    to
+                );
+            }
+        }
+
+        let mut status = VmStatus::default();
+        assert!(matches!(
+            status.transition(PoweredOn, 10),
+            Err(Error::Invalid(_))
+        ));
+        status.transition(PoweredOff, 10).unwrap();
+        status.transition(PoweredOn, 20).unwrap();
+        assert_eq!(
+            (status.state.clone(), status.last_transition_time),
+            (PoweredOn, Some(20))
+        );
+        status.transition(PoweredOn, 30).unwrap();
+        assert_eq!(status.last_transition_time, Some(20));
+    }
+
+    #[test]
+    fn power_changes_follow_the_state_machine() {
+        let status = |state| VmStatus {
+            state,
+            ..Default::default()
+        };
+        for state in [
+            VmState::Uncreated,
+            VmState::Pending,
+            VmState::PoweredOff,
+            VmState::Paused,
+        ] {
+            status(state.clone()).check_power(true).unwrap();
+        }
+        assert!(matches!(
+            status(VmState::Failed).check_power(true),
+            Err(Error::Invalid(_))
+        ));
+        status(VmState::Failed).check_power(false).unwrap();
+        status(VmState::PoweredOn).check_power(false).unwrap();
+    }
+
+    #[test]
+    fn adversarial_names_stay_in_their_key_space() {
+        let too_long = "a".repeat(MAX_NAME_LEN + 1);
+        for name in &["", "a/b", "../vm", "/", "a\nb", "\u{0}", too_long.as_str()] {
+            assert!(validate_name(name).is_err(), "{:?} was accepted", name);
+        }
+        let longest = "a".repeat(MAX_NAME_LEN);
+        let accepted = [
+            "..",
+            ".",
+            "%2F",
+            "%",
+            "a b",
+            "caf\u{e9}",
+            "\u{1f980}",
+            "vm\\x",
+            longest.as_str(),
+        ];
+        for name in &accepted {
+            validate_name(name).unwrap();
+            let encoded = encode_name(name);
+            assert!(
+                !encoded.contains('/'),
+                "{:?} encoded to {:?}",
+                name,
+                encoded
+            );
+            assert_eq!(decode_name(&encoded).as_deref(), Some(*name));
+        }
+        // Encoding never merges two names into the same key
+        assert_ne!(encode_name("%2F"), encode_name("/"));
+        assert_eq!(encode_name("%2F"), "%252F");
+        assert_eq!(Vm::key_for(&scoped_name("..", "%2F")), "vm/../%252F");
+        // Even names that skipped validation can't add a key segment
+        assert_eq!(Vm::key_for(&scoped_name("a", "b/c")), "vm/a/b%2Fc");
+        assert_eq!(Project::key_for("a/b"), "project/a%2Fb");
+
+        assert_eq!(decode_name("%2"), None);
+        assert_eq!(decode_name("%zz"), None);
+        assert_eq!(decode_name("%FF"), None);
+    }
 }
@@ -0,0 +1,69 @@
+use rand::{distributions::Alphanumeric, Rng};
+use serde::{Deserialize, Serialize};
+use std::borrow::Cow;
+
+use super::{BatchResult, Metadata, Object};
+
+/// A long-running request carried out in the background by the node that accepted it, polled
+/// through `GET /operations/<id>`.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Operation {
+    pub metadata: Metadata,
+    pub kind: OperationKind,
+    /// The node carrying the operation out.
+    pub runner: String,
+    pub state: OperationState,
+    /// When the operation was accepted, in seconds since the epoch.
+    pub started_at: i64,
+    #[serde(default)]
+    pub finished_at: Option<i64>,
+    /// How many objects the operation acts on.
+    pub total: usize,
+    /// The outcome for each object handled so far.
+    #[serde(default)]
+    pub results: Vec<BatchResult>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum OperationKind {
+    /// Moves every VM off `node`, which is cordoned first so none are placed back on it.
+    Evacuate { node: String },
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum OperationState {
+    Running,
+    /// Finished, with every object handled successfully.
+    Succeeded,
+    /// Finished, with at least one object that failed, see `results`.
+    Failed,
+}
+
+impl Operation {
+    pub fn id() -> String {
+        let suffix: String = rand::thread_rng()
+            .sample_iter(&Alphanumeric)
+            .take(12)
+            .map(char::from)
+            .collect();
+        format!("op-{}", suffix.to_lowercase())
+    }
+}
+
+impl Object for Operation {
+    const OBJECT_TYPE: &'static str = "operation";
+
+    fn metadata(&self) -> Cow<'_, Metadata> {
+        Cow::Borrowed(&self.metadata)
+    }
+
+    fn set_version(&mut self, rev: i64) {
+        self.metadata.version = Some(rev)
+    }
+
+    fn set_mod_revision(&mut self, rev: i64) {
+        self.metadata.mod_revision = Some(rev)
+    }
+}
@@ -0,0 +1,54 @@
+use std::{collections::BTreeMap, str::FromStr};
+
+use super::Error;
+
+/// A comma separated list of `key=value` pairs, all of which an object's labels must contain
+/// for it to match.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct LabelSelector(Vec<(String, String)>);
+
+impl LabelSelector {
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    pub fn matches(&self, labels: &BTreeMap<String, String>) -> bool {
+        self.0
+            .iter()
+            .all(|(key, value)| labels.get(key) == Some(value))
+    }
+}
+
+/// Checks that labels can be matched by a selector: keys must be non-empty and neither keys nor
+/// values may contain `,` or `=` or surrounding whitespace.
+pub fn validate_labels<'a>(
+    labels: impl IntoIterator<Item = (&'a String, &'a String)>,
+) -> Result<(), Error> {
+    for (key, value) in labels {
+        let bad = |s: &str| s.contains(|c| c == ',' || c == '=') || s.trim() != s;
+        if key.is_empty() || bad(key) || bad(value) {
+            return Err(Error::Invalid(format!("label: {}={}", key, value)));
+        }
+    }
+    Ok(())
+}
+
+impl FromStr for LabelSelector {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        s.split(',')
+            .filter(|term| !term.trim().is_empty())
+            .map(|term| {
+                let mut parts = term.splitn(2, '=');
+                match (parts.next(), parts.next()) {
+                    (Some(key), Some(value)) if !key.trim().is_empty() => {
+                        Ok((key.trim().to_string(), value.trim().to_string()))
+                    }
+                    _ => Err(Error::Invalid(format!("label selector term: {}", term))),
+                }
+            })
+            .collect::<Result<_, _>>()
+            .map(LabelSelector)
+    }
+}
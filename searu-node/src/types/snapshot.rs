@@ -0,0 +1,52 @@
+use rand::{distributions::Alphanumeric, Rng};
+use serde::{Deserialize, Serialize};
+use std::{borrow::Cow, path::PathBuf};
+
+use super::{Metadata, Object};
+
+/// A snapshot of a VM's memory and device state, taken by cloud-hypervisor into a directory on
+/// the node the VM ran on.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Snapshot {
+    /// Named by [`Snapshot::id`], in the project of the VM it was taken from.
+    pub metadata: Metadata,
+    /// The name of the VM it was taken from, in the same project.
+    pub vm: String,
+    /// The node holding the snapshot's files.
+    pub node: String,
+    pub path: PathBuf,
+    /// When the snapshot was taken, in seconds since the epoch.
+    pub created_at: i64,
+    /// Set while a VM is being restored from the snapshot, which keeps it from being deleted.
+    #[serde(default)]
+    pub restoring: bool,
+}
+
+impl Snapshot {
+    /// A new id for a snapshot of `vm`. Ids are unique across VMs, since snapshots share a
+    /// namespace like every other object.
+    pub fn id(vm: &str) -> String {
+        let suffix: String = rand::thread_rng()
+            .sample_iter(&Alphanumeric)
+            .take(8)
+            .map(char::from)
+            .collect();
+        format!("{}-{}", vm, suffix.to_lowercase())
+    }
+}
+
+impl Object for Snapshot {
+    const OBJECT_TYPE: &'static str = "snapshot";
+
+    fn metadata(&self) -> Cow<'_, Metadata> {
+        Cow::Borrowed(&self.metadata)
+    }
+
+    fn set_version(&mut self, rev: i64) {
+        self.metadata.version = Some(rev)
+    }
+
+    fn set_mod_revision(&mut self, rev: i64) {
+        self.metadata.mod_revision = Some(rev)
+    }
+}
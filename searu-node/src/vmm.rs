@@ -19,6 +19,9 @@ use std::path::PathBuf;
 use std::result;
 use std::str::FromStr;
 
+mod option_parser;
+pub use option_parser::{ByteSized, OptionParser, OptionParserError, Toggle};
+
 pub const DEFAULT_VCPUS: u8 = 1;
 pub const DEFAULT_MEMORY_MB: u64 = 512;
 pub const DEFAULT_RNG_SOURCE: &str = "/dev/urandom";
@@ -30,16 +33,138 @@ pub const DEFAULT_QUEUE_SIZE_VUBLK: u16 = 128;
 /// Errors associated with VM configuration parameters.
 #[derive(Debug)]
 pub enum Error {
+    /// Failed to parse cpus parameters
+    ParseCpus(OptionParserError),
+    /// Failed to parse memory parameters
+    ParseMemory(OptionParserError),
+    /// Failed to parse disk parameters
+    ParseDisk(OptionParserError),
+    /// A vhost-user disk needs a socket
+    ParseDiskVhostSocketRequired,
+    /// Failed to parse network parameters
+    ParseNetwork(OptionParserError),
+    /// A vhost-user network device needs a socket
+    ParseNetVhostSocketRequired,
+    /// Failed to parse rng parameters
+    ParseRng(OptionParserError),
+    /// Failed to parse balloon parameters
+    ParseBalloon(OptionParserError),
+    /// Failed to parse filesystem parameters
+    ParseFileSystem(OptionParserError),
+    /// Filesystem tag is missing
+    ParseFsTagMissing,
+    /// Filesystem socket is missing
+    ParseFsSockMissing,
+    /// Failed to parse persistent memory parameters
+    ParsePersistentMemory(OptionParserError),
+    /// Missing file value for persistent memory
+    ParsePmemFileMissing,
+    /// Failed to parse console parameters
+    ParseConsole(OptionParserError),
+    /// No mode given for console
+    ParseConsoleInvalidModeGiven,
+    /// Failed to parse device parameters
+    ParseDevice(OptionParserError),
+    /// Missing path from device
+    ParseDevicePathMissing,
+    /// Failed to parse vsock parameters
+    ParseVsock(OptionParserError),
+    /// Missing cid value for vsock
+    ParseVsockCidMissing,
+    /// Missing socket path for vsock
+    ParseVsockSockMissing,
     #[cfg(feature = "tdx")]
     /// Failed to parse TDX config
     ParseTdx(OptionParserError),
     #[cfg(feature = "tdx")]
     // No TDX firmware
     FirmwarePathMissing,
+    /// The config is well formed but can't be booted
+    Validation(ValidationError),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::Validation(err) => write!(f, "invalid vm config: {}", err),
+            err => write!(f, "{:?}", err),
+        }
+    }
+}
+
+impl From<ValidationError> for Error {
+    fn from(err: ValidationError) -> Self {
+        Error::Validation(err)
+    }
 }
 
 pub type Result<T> = result::Result<T, Error>;
 
+/// The rules [`VmConfig::validate`] checks, one variant each.
+#[derive(Debug, PartialEq)]
+pub enum ValidationError {
+    /// No kernel or firmware to boot
+    KernelMissing,
+    /// Only one of serial and console can use the tty
+    DoubleTtyMode,
+    /// A file console needs a path
+    ConsoleFileMissing,
+    /// More vcpus at boot than the maximum
+    CpusMaxLowerThanBoot,
+    /// The topology doesn't multiply out to the maximum vcpus
+    CpuTopologyCount,
+    /// vhost-user disks need shared memory
+    VhostUserDiskRequiresSharedMemory,
+    /// vhost-user network devices need shared memory
+    VhostUserNetRequiresSharedMemory,
+    /// Network devices passed as fds need shared memory
+    NetFdsRequiresSharedMemory,
+    /// virtio-fs needs shared memory
+    FsRequiresSharedMemory,
+    /// A hugepage size was given without hugepages
+    HugePageSizeWithoutHugePages,
+    /// Hugepage sizes must be a power of two
+    InvalidHugePageSize(u64),
+}
+
+impl fmt::Display for ValidationError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ValidationError::KernelMissing => write!(f, "no kernel to boot"),
+            ValidationError::DoubleTtyMode => {
+                write!(f, "serial and console can't both use the tty")
+            }
+            ValidationError::ConsoleFileMissing => {
+                write!(f, "a file console needs a file path")
+            }
+            ValidationError::CpusMaxLowerThanBoot => {
+                write!(f, "max vcpus is lower than boot vcpus")
+            }
+            ValidationError::CpuTopologyCount => {
+                write!(f, "cpu topology doesn't add up to max vcpus")
+            }
+            ValidationError::VhostUserDiskRequiresSharedMemory => {
+                write!(f, "vhost-user disks need shared memory")
+            }
+            ValidationError::VhostUserNetRequiresSharedMemory => {
+                write!(f, "vhost-user network devices need shared memory")
+            }
+            ValidationError::NetFdsRequiresSharedMemory => {
+                write!(f, "network devices passed as fds need shared memory")
+            }
+            ValidationError::FsRequiresSharedMemory => {
+                write!(f, "virtio-fs needs shared memory")
+            }
+            ValidationError::HugePageSizeWithoutHugePages => {
+                write!(f, "hugepage_size needs hugepages enabled")
+            }
+            ValidationError::InvalidHugePageSize(size) => {
+                write!(f, "hugepage_size {} isn't a power of two", size)
+            }
+        }
+    }
+}
+
 pub struct VmParams<'a> {
     pub cpus: &'a str,
     pub memory: &'a str,
@@ -159,6 +284,45 @@ impl Default for CpusConfig {
     }
 }
 
+impl CpusConfig {
+    pub fn parse(cpus: &str) -> Result<Self> {
+        let mut parser = OptionParser::new();
+        parser
+            .add("boot")
+            .add("max")
+            .add("topology")
+            .add("kvm_hyperv")
+            .add("max_phys_bits");
+        parser.parse(cpus).map_err(Error::ParseCpus)?;
+
+        let boot_vcpus: u8 = parser
+            .convert("boot")
+            .map_err(Error::ParseCpus)?
+            .unwrap_or(DEFAULT_VCPUS);
+        let max_vcpus: u8 = parser
+            .convert("max")
+            .map_err(Error::ParseCpus)?
+            .unwrap_or(boot_vcpus);
+        let topology = parser.convert("topology").map_err(Error::ParseCpus)?;
+        let kvm_hyperv = parser
+            .convert::<Toggle>("kvm_hyperv")
+            .map_err(Error::ParseCpus)?
+            .unwrap_or(Toggle(false))
+            .0;
+        let max_phys_bits = parser
+            .convert::<u8>("max_phys_bits")
+            .map_err(Error::ParseCpus)?;
+
+        Ok(CpusConfig {
+            boot_vcpus,
+            max_vcpus,
+            topology,
+            kvm_hyperv,
+            max_phys_bits,
+        })
+    }
+}
+
 #[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
 pub struct MemoryZoneConfig {
     pub id: String,
@@ -201,6 +365,70 @@ pub struct MemoryConfig {
 }
 
 impl MemoryConfig {
+    /// Parses the memory parameters, without zones.
+    pub fn parse(memory: &str) -> Result<Self> {
+        let mut parser = OptionParser::new();
+        parser
+            .add("size")
+            .add("mergeable")
+            .add("hotplug_method")
+            .add("hotplug_size")
+            .add("hotplugged_size")
+            .add("shared")
+            .add("hugepages")
+            .add("hugepage_size");
+        parser.parse(memory).map_err(Error::ParseMemory)?;
+
+        let size = parser
+            .convert::<ByteSized>("size")
+            .map_err(Error::ParseMemory)?
+            .unwrap_or(ByteSized(DEFAULT_MEMORY_MB << 20))
+            .0;
+        let mergeable = parser
+            .convert::<Toggle>("mergeable")
+            .map_err(Error::ParseMemory)?
+            .unwrap_or(Toggle(false))
+            .0;
+        let hotplug_method = parser
+            .convert("hotplug_method")
+            .map_err(Error::ParseMemory)?
+            .unwrap_or_default();
+        let hotplug_size = parser
+            .convert::<ByteSized>("hotplug_size")
+            .map_err(Error::ParseMemory)?
+            .map(|v| v.0);
+        let hotplugged_size = parser
+            .convert::<ByteSized>("hotplugged_size")
+            .map_err(Error::ParseMemory)?
+            .map(|v| v.0);
+        let shared = parser
+            .convert::<Toggle>("shared")
+            .map_err(Error::ParseMemory)?
+            .unwrap_or(Toggle(false))
+            .0;
+        let hugepages = parser
+            .convert::<Toggle>("hugepages")
+            .map_err(Error::ParseMemory)?
+            .unwrap_or(Toggle(false))
+            .0;
+        let hugepage_size = parser
+            .convert::<ByteSized>("hugepage_size")
+            .map_err(Error::ParseMemory)?
+            .map(|v| v.0);
+
+        Ok(MemoryConfig {
+            size,
+            mergeable,
+            hotplug_method,
+            hotplug_size,
+            hotplugged_size,
+            shared,
+            hugepages,
+            hugepage_size,
+            zones: None,
+        })
+    }
+
     pub fn total_size(&self) -> u64 {
         let mut size = self.size;
         if let Some(hotplugged_size) = self.hotplugged_size {
@@ -309,6 +537,79 @@ impl Default for DiskConfig {
     }
 }
 
+impl DiskConfig {
+    pub fn parse(disk: &str) -> Result<Self> {
+        let mut parser = OptionParser::new();
+        parser
+            .add("path")
+            .add("readonly")
+            .add("direct")
+            .add("iommu")
+            .add("queue_size")
+            .add("num_queues")
+            .add("vhost_user")
+            .add("socket")
+            .add("poll_queue")
+            .add("id");
+        parser.parse(disk).map_err(Error::ParseDisk)?;
+
+        let path = parser.get("path").map(PathBuf::from);
+        let readonly = parser
+            .convert::<Toggle>("readonly")
+            .map_err(Error::ParseDisk)?
+            .unwrap_or(Toggle(false))
+            .0;
+        let direct = parser
+            .convert::<Toggle>("direct")
+            .map_err(Error::ParseDisk)?
+            .unwrap_or(Toggle(false))
+            .0;
+        let iommu = parser
+            .convert::<Toggle>("iommu")
+            .map_err(Error::ParseDisk)?
+            .unwrap_or(Toggle(false))
+            .0;
+        let queue_size = parser
+            .convert("queue_size")
+            .map_err(Error::ParseDisk)?
+            .unwrap_or_else(default_diskconfig_queue_size);
+        let num_queues = parser
+            .convert("num_queues")
+            .map_err(Error::ParseDisk)?
+            .unwrap_or_else(default_diskconfig_num_queues);
+        let vhost_user = parser
+            .convert::<Toggle>("vhost_user")
+            .map_err(Error::ParseDisk)?
+            .unwrap_or(Toggle(false))
+            .0;
+        let vhost_socket = parser.get("socket");
+        let poll_queue = parser
+            .convert::<Toggle>("poll_queue")
+            .map_err(Error::ParseDisk)?
+            .unwrap_or_else(|| Toggle(default_diskconfig_poll_queue()))
+            .0;
+        let id = parser.get("id");
+
+        if vhost_user && vhost_socket.is_none() {
+            return Err(Error::ParseDiskVhostSocketRequired);
+        }
+
+        Ok(DiskConfig {
+            path,
+            readonly,
+            direct,
+            iommu,
+            num_queues,
+            queue_size,
+            vhost_user,
+            vhost_socket,
+            poll_queue,
+            id,
+            ..Default::default()
+        })
+    }
+}
+
 #[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
 pub enum VhostMode {
     Client,
@@ -414,6 +715,86 @@ impl Default for NetConfig {
     }
 }
 
+impl NetConfig {
+    pub fn parse(net: &str) -> Result<Self> {
+        let mut parser = OptionParser::new();
+        parser
+            .add("tap")
+            .add("ip")
+            .add("mask")
+            .add("mac")
+            .add("host_mac")
+            .add("iommu")
+            .add("queue_size")
+            .add("num_queues")
+            .add("vhost_user")
+            .add("socket")
+            .add("vhost_mode")
+            .add("id");
+        parser.parse(net).map_err(Error::ParseNetwork)?;
+
+        let tap = parser.get("tap");
+        let ip = parser
+            .convert("ip")
+            .map_err(Error::ParseNetwork)?
+            .unwrap_or_else(default_netconfig_ip);
+        let mask = parser
+            .convert("mask")
+            .map_err(Error::ParseNetwork)?
+            .unwrap_or_else(default_netconfig_mask);
+        let mac = parser
+            .convert("mac")
+            .map_err(Error::ParseNetwork)?
+            .unwrap_or_else(default_netconfig_mac);
+        let host_mac = parser.convert("host_mac").map_err(Error::ParseNetwork)?;
+        let iommu = parser
+            .convert::<Toggle>("iommu")
+            .map_err(Error::ParseNetwork)?
+            .unwrap_or(Toggle(false))
+            .0;
+        let queue_size = parser
+            .convert("queue_size")
+            .map_err(Error::ParseNetwork)?
+            .unwrap_or_else(default_netconfig_queue_size);
+        let num_queues = parser
+            .convert("num_queues")
+            .map_err(Error::ParseNetwork)?
+            .unwrap_or_else(default_netconfig_num_queues);
+        let vhost_user = parser
+            .convert::<Toggle>("vhost_user")
+            .map_err(Error::ParseNetwork)?
+            .unwrap_or(Toggle(false))
+            .0;
+        let vhost_socket = parser.get("socket");
+        let vhost_mode = parser
+            .convert("vhost_mode")
+            .map_err(Error::ParseNetwork)?
+            .unwrap_or_default();
+        let id = parser.get("id");
+
+        if vhost_user && vhost_socket.is_none() {
+            return Err(Error::ParseNetVhostSocketRequired);
+        }
+
+        Ok(NetConfig {
+            tap,
+            ip,
+            mask,
+            mac,
+            host_mac,
+            iommu,
+            num_queues,
+            queue_size,
+            vhost_user,
+            vhost_socket,
+            vhost_mode,
+            id,
+            fds: None,
+            rate_limiter_config: None,
+        })
+    }
+}
+
 #[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
 pub struct RngConfig {
     pub src: PathBuf,
@@ -421,6 +802,27 @@ pub struct RngConfig {
     pub iommu: bool,
 }
 
+impl RngConfig {
+    pub fn parse(rng: &str) -> Result<Self> {
+        let mut parser = OptionParser::new();
+        parser.add("src").add("iommu");
+        parser.parse(rng).map_err(Error::ParseRng)?;
+
+        let src = PathBuf::from(
+            parser
+                .get("src")
+                .unwrap_or_else(|| DEFAULT_RNG_SOURCE.to_owned()),
+        );
+        let iommu = parser
+            .convert::<Toggle>("iommu")
+            .map_err(Error::ParseRng)?
+            .unwrap_or(Toggle(false))
+            .0;
+
+        Ok(RngConfig { src, iommu })
+    }
+}
+
 impl Default for RngConfig {
     fn default() -> Self {
         RngConfig {
@@ -437,6 +839,20 @@ pub struct BalloonConfig {
 
 impl BalloonConfig {
     pub const SYNTAX: &'static str = "Balloon parameters \"size=<balloon_size>\"";
+
+    pub fn parse(balloon: &str) -> Result<Self> {
+        let mut parser = OptionParser::new();
+        parser.add("size");
+        parser.parse(balloon).map_err(Error::ParseBalloon)?;
+
+        let size = parser
+            .convert::<ByteSized>("size")
+            .map_err(Error::ParseBalloon)?
+            .map(|v| v.0)
+            .unwrap_or(0);
+
+        Ok(BalloonConfig { size })
+    }
 }
 
 #[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
@@ -485,6 +901,52 @@ impl Default for FsConfig {
     }
 }
 
+impl FsConfig {
+    pub fn parse(fs: &str) -> Result<Self> {
+        let mut parser = OptionParser::new();
+        parser
+            .add("tag")
+            .add("socket")
+            .add("num_queues")
+            .add("queue_size")
+            .add("dax")
+            .add("cache_size")
+            .add("id");
+        parser.parse(fs).map_err(Error::ParseFileSystem)?;
+
+        let tag = parser.get("tag").ok_or(Error::ParseFsTagMissing)?;
+        let socket = PathBuf::from(parser.get("socket").ok_or(Error::ParseFsSockMissing)?);
+        let queue_size = parser
+            .convert("queue_size")
+            .map_err(Error::ParseFileSystem)?
+            .unwrap_or_else(default_fsconfig_queue_size);
+        let num_queues = parser
+            .convert("num_queues")
+            .map_err(Error::ParseFileSystem)?
+            .unwrap_or_else(default_fsconfig_num_queues);
+        let dax = parser
+            .convert::<Toggle>("dax")
+            .map_err(Error::ParseFileSystem)?
+            .unwrap_or_else(|| Toggle(default_fsconfig_dax()))
+            .0;
+        let cache_size = parser
+            .convert::<ByteSized>("cache_size")
+            .map_err(Error::ParseFileSystem)?
+            .map_or_else(default_fsconfig_cache_size, |v| v.0);
+        let id = parser.get("id");
+
+        Ok(FsConfig {
+            tag,
+            socket,
+            num_queues,
+            queue_size,
+            dax,
+            cache_size,
+            id,
+        })
+    }
+}
+
 #[derive(Clone, Debug, PartialEq, Deserialize, Serialize, Default)]
 pub struct PmemConfig {
     pub file: PathBuf,
@@ -500,6 +962,51 @@ pub struct PmemConfig {
     pub id: Option<String>,
 }
 
+impl PmemConfig {
+    pub fn parse(pmem: &str) -> Result<Self> {
+        let mut parser = OptionParser::new();
+        parser
+            .add("size")
+            .add("file")
+            .add("mergeable")
+            .add("iommu")
+            .add("discard_writes")
+            .add("id");
+        parser.parse(pmem).map_err(Error::ParsePersistentMemory)?;
+
+        let file = PathBuf::from(parser.get("file").ok_or(Error::ParsePmemFileMissing)?);
+        let size = parser
+            .convert::<ByteSized>("size")
+            .map_err(Error::ParsePersistentMemory)?
+            .map(|v| v.0);
+        let mergeable = parser
+            .convert::<Toggle>("mergeable")
+            .map_err(Error::ParsePersistentMemory)?
+            .unwrap_or(Toggle(false))
+            .0;
+        let iommu = parser
+            .convert::<Toggle>("iommu")
+            .map_err(Error::ParsePersistentMemory)?
+            .unwrap_or(Toggle(false))
+            .0;
+        let discard_writes = parser
+            .convert::<Toggle>("discard_writes")
+            .map_err(Error::ParsePersistentMemory)?
+            .unwrap_or(Toggle(false))
+            .0;
+        let id = parser.get("id");
+
+        Ok(PmemConfig {
+            file,
+            size,
+            iommu,
+            mergeable,
+            discard_writes,
+            id,
+        })
+    }
+}
+
 #[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
 pub enum ConsoleOutputMode {
     Off,
@@ -529,6 +1036,48 @@ fn default_consoleconfig_file() -> Option<PathBuf> {
 }
 
 impl ConsoleConfig {
+    /// Parses one of `off`, `pty`, `tty`, `null` or `file=<path>`, optionally followed by
+    /// `iommu=on`.
+    pub fn parse(console: &str) -> Result<Self> {
+        let mut parser = OptionParser::new();
+        parser
+            .add_valueless("off")
+            .add_valueless("pty")
+            .add_valueless("tty")
+            .add_valueless("null")
+            .add("file")
+            .add("iommu");
+        parser.parse(console).map_err(Error::ParseConsole)?;
+
+        let mut file: Option<PathBuf> = default_consoleconfig_file();
+        let mut mode: ConsoleOutputMode = ConsoleOutputMode::Off;
+
+        if parser.is_set("off") {
+        } else if parser.is_set("pty") {
+            mode = ConsoleOutputMode::Pty
+        } else if parser.is_set("tty") {
+            mode = ConsoleOutputMode::Tty
+        } else if parser.is_set("null") {
+            mode = ConsoleOutputMode::Null
+        } else if parser.is_set("file") {
+            mode = ConsoleOutputMode::File;
+            file = Some(PathBuf::from(
+                parser
+                    .get("file")
+                    .ok_or(Error::ParseConsoleInvalidModeGiven)?,
+            ));
+        } else {
+            return Err(Error::ParseConsoleInvalidModeGiven);
+        }
+        let iommu = parser
+            .convert::<Toggle>("iommu")
+            .map_err(Error::ParseConsole)?
+            .unwrap_or(Toggle(false))
+            .0;
+
+        Ok(Self { file, mode, iommu })
+    }
+
     pub fn default_serial() -> Self {
         ConsoleConfig {
             file: None,
@@ -555,6 +1104,27 @@ pub struct DeviceConfig {
     pub id: Option<String>,
 }
 
+impl DeviceConfig {
+    pub fn parse(device: &str) -> Result<Self> {
+        let mut parser = OptionParser::new();
+        parser.add("path").add("id").add("iommu");
+        parser.parse(device).map_err(Error::ParseDevice)?;
+
+        let path = parser
+            .get("path")
+            .map(PathBuf::from)
+            .ok_or(Error::ParseDevicePathMissing)?;
+        let iommu = parser
+            .convert::<Toggle>("iommu")
+            .map_err(Error::ParseDevice)?
+            .unwrap_or(Toggle(false))
+            .0;
+        let id = parser.get("id");
+
+        Ok(DeviceConfig { path, iommu, id })
+    }
+}
+
 #[derive(Clone, Debug, PartialEq, Deserialize, Serialize, Default)]
 pub struct VsockConfig {
     pub cid: u64,
@@ -565,6 +1135,36 @@ pub struct VsockConfig {
     pub id: Option<String>,
 }
 
+impl VsockConfig {
+    pub fn parse(vsock: &str) -> Result<Self> {
+        let mut parser = OptionParser::new();
+        parser.add("socket").add("cid").add("iommu").add("id");
+        parser.parse(vsock).map_err(Error::ParseVsock)?;
+
+        let socket = parser
+            .get("socket")
+            .map(PathBuf::from)
+            .ok_or(Error::ParseVsockSockMissing)?;
+        let iommu = parser
+            .convert::<Toggle>("iommu")
+            .map_err(Error::ParseVsock)?
+            .unwrap_or(Toggle(false))
+            .0;
+        let cid = parser
+            .convert("cid")
+            .map_err(Error::ParseVsock)?
+            .ok_or(Error::ParseVsockCidMissing)?;
+        let id = parser.get("id");
+
+        Ok(VsockConfig {
+            cid,
+            socket,
+            iommu,
+            id,
+        })
+    }
+}
+
 #[cfg(feature = "tdx")]
 #[derive(Clone, Debug, PartialEq, Deserialize, Serialize, Default)]
 pub struct TdxConfig {
@@ -656,6 +1256,68 @@ pub struct VmConfig {
     pub tdx: Option<TdxConfig>,
 }
 
+impl VmConfig {
+    /// Checks the invariants cloud-hypervisor would otherwise reject the config for, so the
+    /// error names the rule instead of coming back from `vm.create`.
+    pub fn validate(&self) -> Result<()> {
+        if self.kernel.is_none() {
+            return Err(ValidationError::KernelMissing.into());
+        }
+
+        if self.console.mode == ConsoleOutputMode::Tty && self.serial.mode == ConsoleOutputMode::Tty
+        {
+            return Err(ValidationError::DoubleTtyMode.into());
+        }
+        for console in &[&self.console, &self.serial] {
+            if console.mode == ConsoleOutputMode::File && console.file.is_none() {
+                return Err(ValidationError::ConsoleFileMissing.into());
+            }
+        }
+
+        if self.cpus.max_vcpus < self.cpus.boot_vcpus {
+            return Err(ValidationError::CpusMaxLowerThanBoot.into());
+        }
+        if let Some(ref topology) = self.cpus.topology {
+            let count = u16::from(topology.threads_per_core)
+                * u16::from(topology.cores_per_die)
+                * u16::from(topology.dies_per_package)
+                * u16::from(topology.packages);
+            if count != u16::from(self.cpus.max_vcpus) {
+                return Err(ValidationError::CpuTopologyCount.into());
+            }
+        }
+
+        let shared = self.memory.shared;
+        for disk in self.disks.iter().flatten() {
+            if disk.vhost_user && !shared {
+                return Err(ValidationError::VhostUserDiskRequiresSharedMemory.into());
+            }
+        }
+        for net in self.net.iter().flatten() {
+            if net.vhost_user && !shared {
+                return Err(ValidationError::VhostUserNetRequiresSharedMemory.into());
+            }
+            if net.fds.is_some() && !shared {
+                return Err(ValidationError::NetFdsRequiresSharedMemory.into());
+            }
+        }
+        if self.fs.as_ref().map_or(false, |fs| !fs.is_empty()) && !shared {
+            return Err(ValidationError::FsRequiresSharedMemory.into());
+        }
+
+        if let Some(size) = self.memory.hugepage_size {
+            if !self.memory.hugepages {
+                return Err(ValidationError::HugePageSizeWithoutHugePages.into());
+            }
+            if !size.is_power_of_two() {
+                return Err(ValidationError::InvalidHugePageSize(size).into());
+            }
+        }
+
+        Ok(())
+    }
+}
+
 pub const MAC_ADDR_LEN: usize = 6;
 
 #[derive(Clone, Copy, Debug, PartialEq)]
@@ -789,3 +1451,135 @@ pub struct RateLimiterConfig {
     pub bandwidth: Option<TokenBucketConfig>,
     pub ops: Option<TokenBucketConfig>,
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn valid() -> VmConfig {
+        serde_json::from_value(serde_json::json!({"kernel": {"path": "/boot/vmlinux"}})).unwrap()
+    }
+
+    fn invalid(config: &VmConfig) -> ValidationError {
+        match config.validate() {
+            Err(Error::Validation(err)) => err,
+            result => panic!("expected a validation error, got {:?}", result),
+        }
+    }
+
+    #[test]
+    fn a_valid_config_passes() {
+        valid().validate().unwrap();
+    }
+
+    #[test]
+    fn a_kernel_is_required() {
+        let mut config = valid();
+        config.kernel = None;
+        assert_eq!(invalid(&config), ValidationError::KernelMissing);
+    }
+
+    #[test]
+    fn serial_and_console_cant_share_the_tty() {
+        let mut config = valid();
+        config.serial.mode = ConsoleOutputMode::Tty;
+        config.console.mode = ConsoleOutputMode::Tty;
+        assert_eq!(invalid(&config), ValidationError::DoubleTtyMode);
+    }
+
+    #[test]
+    fn a_file_console_needs_a_path() {
+        let mut config = valid();
+        config.serial.mode = ConsoleOutputMode::File;
+        assert_eq!(invalid(&config), ValidationError::ConsoleFileMissing);
+    }
+
+    #[test]
+    fn boot_vcpus_are_at_most_the_maximum() {
+        let mut config = valid();
+        config.cpus.boot_vcpus = 2;
+        config.cpus.max_vcpus = 1;
+        assert_eq!(invalid(&config), ValidationError::CpusMaxLowerThanBoot);
+    }
+
+    #[test]
+    fn the_topology_multiplies_out_to_the_maximum() {
+        let mut config = valid();
+        config.cpus.boot_vcpus = 2;
+        config.cpus.max_vcpus = 2;
+        config.cpus.topology = Some(CpuTopology {
+            threads_per_core: 2,
+            cores_per_die: 2,
+            dies_per_package: 1,
+            packages: 1,
+        });
+        assert_eq!(invalid(&config), ValidationError::CpuTopologyCount);
+    }
+
+    #[test]
+    fn vhost_user_disks_need_shared_memory() {
+        let mut config = valid();
+        config.disks = Some(vec![DiskConfig {
+            vhost_user: true,
+            ..Default::default()
+        }]);
+        assert_eq!(
+            invalid(&config),
+            ValidationError::VhostUserDiskRequiresSharedMemory
+        );
+    }
+
+    #[test]
+    fn vhost_user_nets_need_shared_memory() {
+        let mut config = valid();
+        config.net = Some(vec![NetConfig {
+            vhost_user: true,
+            ..Default::default()
+        }]);
+        assert_eq!(
+            invalid(&config),
+            ValidationError::VhostUserNetRequiresSharedMemory
+        );
+    }
+
+    #[test]
+    fn net_fds_need_shared_memory() {
+        let mut config = valid();
+        config.net = Some(vec![NetConfig {
+            fds: Some(vec![3]),
+            ..Default::default()
+        }]);
+        assert_eq!(
+            invalid(&config),
+            ValidationError::NetFdsRequiresSharedMemory
+        );
+    }
+
+    #[test]
+    fn fs_needs_shared_memory() {
+        let mut config = valid();
+        config.fs = Some(vec![FsConfig::default()]);
+        assert_eq!(invalid(&config), ValidationError::FsRequiresSharedMemory);
+    }
+
+    #[test]
+    fn a_hugepage_size_needs_hugepages() {
+        let mut config = valid();
+        config.memory.hugepage_size = Some(2 << 20);
+        assert_eq!(
+            invalid(&config),
+            ValidationError::HugePageSizeWithoutHugePages
+        );
+    }
+
+    #[test]
+    fn hugepage_sizes_are_powers_of_two() {
+        let mut config = valid();
+        config.memory.hugepages = true;
+        config.memory.hugepage_size = Some(3 << 20);
+        assert_eq!(
+            invalid(&config),
+            ValidationError::InvalidHugePageSize(3 << 20)
+        );
+    }
+}
@@ -0,0 +1,251 @@
+// Adapted from https://github.com/cloud-hypervisor/cloud-hypervisor/blob/master/option_parser/src/lib.rs
+//
+// Copyright © 2020 Intel Corporation
+//
+// SPDX-License-Identifier: Apache-2.0
+//
+
+use std::collections::HashMap;
+use std::fmt;
+use std::str::FromStr;
+
+/// Parses comma separated `key=value` lists, like `size=1G,shared=on`, against a set of known
+/// options.
+#[derive(Default)]
+pub struct OptionParser {
+    options: HashMap<String, OptionParserValue>,
+}
+
+struct OptionParserValue {
+    value: Option<String>,
+    requires_value: bool,
+}
+
+#[derive(Debug)]
+pub enum OptionParserError {
+    UnknownOption(String),
+    InvalidSyntax(String),
+    Conversion(String, String),
+}
+
+impl fmt::Display for OptionParserError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            OptionParserError::UnknownOption(s) => write!(f, "unknown option: {}", s),
+            OptionParserError::InvalidSyntax(s) => write!(f, "invalid syntax: {}", s),
+            OptionParserError::Conversion(field, value) => {
+                write!(f, "unable to parse {} for {}", value, field)
+            }
+        }
+    }
+}
+
+type OptionParserResult<T> = std::result::Result<T, OptionParserError>;
+
+impl OptionParser {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Parses `input`, failing on options that weren't added, on options missing their value,
+    /// on values given to valueless options and on values containing another `=`. A trailing
+    /// comma leaves an empty, unknown option.
+    pub fn parse(&mut self, input: &str) -> OptionParserResult<()> {
+        if input.trim().is_empty() {
+            return Ok(());
+        }
+
+        for option in input.trim().split(',') {
+            let parts: Vec<&str> = option.split('=').collect();
+
+            match self.options.get_mut(parts[0]) {
+                None => return Err(OptionParserError::UnknownOption(parts[0].to_owned())),
+                Some(value) => {
+                    if value.requires_value {
+                        if parts.len() != 2 {
+                            return Err(OptionParserError::InvalidSyntax(option.to_owned()));
+                        }
+                        value.value = Some(parts[1].trim().to_owned());
+                    } else if parts.len() != 1 {
+                        return Err(OptionParserError::InvalidSyntax(option.to_owned()));
+                    } else {
+                        value.value = Some(String::new());
+                    }
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    pub fn add(&mut self, option: &str) -> &mut Self {
+        self.options.insert(
+            option.to_owned(),
+            OptionParserValue {
+                value: None,
+                requires_value: true,
+            },
+        );
+
+        self
+    }
+
+    /// Adds an option given without a value, like `tty`.
+    pub fn add_valueless(&mut self, option: &str) -> &mut Self {
+        self.options.insert(
+            option.to_owned(),
+            OptionParserValue {
+                value: None,
+                requires_value: false,
+            },
+        );
+
+        self
+    }
+
+    pub fn get(&self, option: &str) -> Option<String> {
+        self.options
+            .get(option)
+            .and_then(|v| v.value.clone())
+            .and_then(|s| if s.is_empty() { None } else { Some(s) })
+    }
+
+    pub fn is_set(&self, option: &str) -> bool {
+        self.options
+            .get(option)
+            .and_then(|v| v.value.as_ref())
+            .is_some()
+    }
+
+    /// Gets an option parsed as `T`, or `None` if it wasn't given.
+    pub fn convert<T: FromStr>(&self, option: &str) -> OptionParserResult<Option<T>> {
+        match self.get(option) {
+            None => Ok(None),
+            Some(v) => Ok(Some(v.parse().map_err(|_| {
+                OptionParserError::Conversion(option.to_owned(), v.to_owned())
+            })?)),
+        }
+    }
+}
+
+/// A boolean option, given as `on`/`off` or `true`/`false`.
+pub struct Toggle(pub bool);
+
+pub enum ToggleParseError {
+    InvalidValue(String),
+}
+
+impl FromStr for Toggle {
+    type Err = ToggleParseError;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "" => Ok(Toggle(false)),
+            "on" => Ok(Toggle(true)),
+            "off" => Ok(Toggle(false)),
+            "true" => Ok(Toggle(true)),
+            "false" => Ok(Toggle(false)),
+            _ => Err(ToggleParseError::InvalidValue(s.to_owned())),
+        }
+    }
+}
+
+/// A size in bytes, optionally with a `K`, `M` or `G` suffix, like `128M` or `1G`.
+pub struct ByteSized(pub u64);
+
+#[derive(Debug)]
+pub enum ByteSizedParseError {
+    InvalidValue(String),
+}
+
+impl FromStr for ByteSized {
+    type Err = ByteSizedParseError;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        Ok(ByteSized({
+            let s = s.trim();
+            let shift = if s.ends_with('K') {
+                10
+            } else if s.ends_with('M') {
+                20
+            } else if s.ends_with('G') {
+                30
+            } else {
+                0
+            };
+
+            let s = s.trim_end_matches(|c| c == 'K' || c == 'M' || c == 'G');
+            s.parse::<u64>()
+                .ok()
+                .and_then(|size| size.checked_mul(1 << shift))
+                .ok_or_else(|| ByteSizedParseError::InvalidValue(s.to_owned()))?
+        }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parser() -> OptionParser {
+        let mut parser = OptionParser::new();
+        parser.add("size").add("mergeable").add_valueless("tty");
+        parser
+    }
+
+    #[test]
+    fn parses_known_options() {
+        let mut parser = parser();
+        parser.parse("size=128M,tty").unwrap();
+        assert_eq!(parser.get("size"), Some("128M".to_owned()));
+        assert!(parser.is_set("size"));
+        assert!(parser.is_set("tty"));
+        assert_eq!(parser.get("tty"), None);
+        assert!(!parser.is_set("mergeable"));
+        assert_eq!(
+            parser
+                .convert::<ByteSized>("size")
+                .unwrap()
+                .map(|size| size.0),
+            Some(128 << 20)
+        );
+    }
+
+    #[test]
+    fn rejects_malformed_options() {
+        let parse = |input: &str| parser().parse(input);
+        assert!(matches!(
+            parse("size=128M,hanging_param"),
+            Err(OptionParserError::UnknownOption(option)) if option == "hanging_param"
+        ));
+        assert!(matches!(
+            parse("size=128M,"),
+            Err(OptionParserError::UnknownOption(option)) if option.is_empty()
+        ));
+        assert!(matches!(
+            parse("size=128M,mergeable"),
+            Err(OptionParserError::InvalidSyntax(option)) if option == "mergeable"
+        ));
+        assert!(matches!(
+            parse("size=128M=1G"),
+            Err(OptionParserError::InvalidSyntax(option)) if option == "size=128M=1G"
+        ));
+        assert!(matches!(
+            parse("tty=x"),
+            Err(OptionParserError::InvalidSyntax(option)) if option == "tty=x"
+        ));
+    }
+
+    #[test]
+    fn sizes_take_suffixes() {
+        let size = |s: &str| s.parse::<ByteSized>().map(|size| size.0);
+        assert_eq!(size("512").unwrap(), 512);
+        assert_eq!(size("4K").unwrap(), 4 << 10);
+        assert_eq!(size("2M").unwrap(), 2 << 20);
+        assert_eq!(size("128M").unwrap(), 128 << 20);
+        assert_eq!(size("1G").unwrap(), 1 << 30);
+        assert!(size("1T").is_err());
+        assert!(size("M").is_err());
+        assert!(size(&format!("{}G", u64::MAX)).is_err());
+    }
+}
@@ -0,0 +1,346 @@
+//! A WebSocket endpoint, `/api/ws`, multiplexing watches of several object types over one
+//! connection. Rocket can't upgrade connections, so it's served by its own hyper server on
+//! [`Config::ws_addr`](crate::config::Config::ws_addr).
+//!
+//! Clients send `{"action": "subscribe", "type": "vm", "selector": "app=web"}` to start
+//! watching a type, with an optional label selector, and `{"action": "unsubscribe", "type":
+//! "vm"}` to stop. Every change they may see is sent as `{"type": "vm", "event": ...}`, and
+//! rejected messages are answered with `{"error": ...}`. Deletes name the object by its key
+//! name, `<project>/<name>` for VMs and VPCs.
+
+use std::{
+    collections::{HashMap, HashSet},
+    convert::Infallible,
+    net::SocketAddr,
+    sync::Arc,
+};
+
+use futures::{SinkExt, StreamExt};
+use hyper::{
+    header::{
+        AUTHORIZATION, CONNECTION, CONTENT_TYPE, SEC_WEBSOCKET_ACCEPT, SEC_WEBSOCKET_KEY, UPGRADE,
+    },
+    service::{make_service_fn, service_fn},
+    Body, Request, Response, StatusCode,
+};
+use serde::Deserialize;
+use tokio::{
+    sync::{
+        mpsc::{self, error::TrySendError},
+        Notify,
+    },
+    task::JoinHandle,
+};
+use tokio_tungstenite::{
+    tungstenite::{
+        handshake::derive_accept_key,
+        protocol::{frame::coding::CloseCode, CloseFrame, Role as WsRole},
+        Message,
+    },
+    WebSocketStream,
+};
+
+use crate::{
+    auth::Auth,
+    storage::{Event, Storage},
+    types::{
+        DhcpReservation, Error, ErrorResponse, JwtClaim, LabelSelector, Metadata, Node, Object,
+        Operation, Role, Snapshot, Vm, Vpc,
+    },
+};
+
+/// How many messages may wait to be written to a client. A client that falls further behind is
+/// disconnected rather than buffered for.
+const OUTBOX_CAPACITY: usize = 256;
+
+#[derive(Deserialize)]
+#[serde(tag = "action", rename_all = "snake_case")]
+enum ClientMessage {
+    Subscribe {
+        #[serde(rename = "type")]
+        object_type: String,
+        #[serde(default)]
+        selector: Option<String>,
+    },
+    Unsubscribe {
+        #[serde(rename = "type")]
+        object_type: String,
+    },
+}
+
+pub fn spawn(
+    addr: SocketAddr,
+    storage: Storage,
+    auth: Arc<Auth>,
+) -> JoinHandle<Result<(), anyhow::Error>> {
+    tokio::spawn(async move {
+        let make_service = make_service_fn(move |_| {
+            let storage = storage.clone();
+            let auth = auth.clone();
+            async move {
+                Ok::<_, Infallible>(service_fn(move |req| {
+                    handle(req, storage.clone(), auth.clone())
+                }))
+            }
+        });
+        println!("websockets listening on {}", addr);
+        hyper::Server::bind(&addr).serve(make_service).await?;
+        Ok(())
+    })
+}
+
+async fn handle(
+    req: Request<Body>,
+    storage: Storage,
+    auth: Arc<Auth>,
+) -> Result<Response<Body>, Infallible> {
+    match upgrade(req, storage, auth).await {
+        Ok(resp) => Ok(resp),
+        Err(err) => {
+            let body = serde_json::to_string(&ErrorResponse {
+                msg: err.to_string(),
+            })
+            .unwrap_or_default();
+            let mut resp = Response::new(Body::from(body));
+            *resp.status_mut() = StatusCode::from_u16(err.status().code)
+                .unwrap_or(StatusCode::INTERNAL_SERVER_ERROR);
+            resp.headers_mut().insert(
+                CONTENT_TYPE,
+                "application/json".parse().expect("valid header"),
+            );
+            Ok(resp)
+        }
+    }
+}
+
+/// Authenticates a WebSocket handshake and hands the upgraded connection to [`serve`].
+async fn upgrade(
+    mut req: Request<Body>,
+    storage: Storage,
+    auth: Arc<Auth>,
+) -> Result<Response<Body>, Error> {
+    if req.uri().path() != "/api/ws" {
+        return Err(Error::NotFound(req.uri().path().to_string()));
+    }
+    let token = token(&req).ok_or(Error::Unauthorized)?;
+    let claim = auth.verify(&token).await.map_err(|_| Error::Unauthorized)?;
+    let accept = req
+        .headers()
+        .get(SEC_WEBSOCKET_KEY)
+        .map(|key| derive_accept_key(key.as_bytes()))
+        .ok_or_else(|| Error::Invalid("not a websocket handshake".to_string()))?;
+    tokio::spawn(async move {
+        match hyper::upgrade::on(&mut req).await {
+            Ok(upgraded) => {
+                let ws = WebSocketStream::from_raw_socket(upgraded, WsRole::Server, None).await;
+                serve(ws, storage, claim).await;
+            }
+            Err(err) => println!("websocket upgrade failed: {}", err),
+        }
+    });
+    Ok(Response::builder()
+        .status(StatusCode::SWITCHING_PROTOCOLS)
+        .header(UPGRADE, "websocket")
+        .header(CONNECTION, "upgrade")
+        .header(SEC_WEBSOCKET_ACCEPT, accept)
+        .body(Body::empty())?)
+}
+
+/// The bearer token from the `Authorization` header or, since browsers can't set headers on
+/// WebSocket requests, the `token` query parameter.
+fn token(req: &Request<Body>) -> Option<String> {
+    if let Some(header) = req.headers().get(AUTHORIZATION) {
+        return header
+            .to_str()
+            .ok()?
+            .splitn(2, "Bearer ")
+            .nth(1)
+            .map(str::to_string);
+    }
+    req.uri()
+        .query()?
+        .split('&')
+        .find_map(|pair| pair.strip_prefix("token="))
+        .map(str::to_string)
+}
+
+async fn serve<S>(ws: WebSocketStream<S>, storage: Storage, claim: JwtClaim)
+where
+    S: tokio::io::AsyncRead + tokio::io::AsyncWrite + Unpin,
+{
+    let claim = Arc::new(claim);
+    let (mut sink, mut stream) = ws.split();
+    let (tx, mut rx) = mpsc::channel(OUTBOX_CAPACITY);
+    let slow = Arc::new(Notify::new());
+    let mut watches: HashMap<String, JoinHandle<()>> = HashMap::default();
+    loop {
+        tokio::select! {
+            msg = stream.next() => match msg {
+                Some(Ok(Message::Text(text))) => {
+                    let outbox = Outbox { tx: tx.clone(), slow: slow.clone() };
+                    if let Err(err) = subscribe(&text, &storage, &claim, outbox, &mut watches) {
+                        let error = serde_json::json!({ "error": err.to_string() });
+                        if sink.send(Message::Text(error.to_string())).await.is_err() {
+                            break;
+                        }
+                    }
+                }
+                Some(Ok(Message::Close(_))) | Some(Err(_)) | None => break,
+                // Pings are answered by tungstenite
+                Some(Ok(_)) => {}
+            },
+            Some(text) = rx.recv() => {
+                if sink.send(Message::Text(text)).await.is_err() {
+                    break;
+                }
+            }
+            _ = slow.notified() => {
+                let _ = sink
+                    .send(Message::Close(Some(CloseFrame {
+                        code: CloseCode::Policy,
+                        reason: "client too slow".into(),
+                    })))
+                    .await;
+                break;
+            }
+        }
+    }
+    for (_, watch) in watches {
+        watch.abort();
+    }
+}
+
+/// Where a connection's watches queue messages for the client.
+#[derive(Clone)]
+struct Outbox {
+    tx: mpsc::Sender<String>,
+    /// Notified when the queue is full, which disconnects the client.
+    slow: Arc<Notify>,
+}
+
+impl Outbox {
+    /// Queues `text`, returning whether the watch should keep going.
+    fn send(&self, text: String) -> bool {
+        match self.tx.try_send(text) {
+            Ok(()) => true,
+            Err(TrySendError::Full(_)) => {
+                self.slow.notify_one();
+                false
+            }
+            Err(TrySendError::Closed(_)) => false,
+        }
+    }
+}
+
+/// Applies a client's subscribe or unsubscribe message.
+fn subscribe(
+    text: &str,
+    storage: &Storage,
+    claim: &Arc<JwtClaim>,
+    outbox: Outbox,
+    watches: &mut HashMap<String, JoinHandle<()>>,
+) -> Result<(), Error> {
+    let message = serde_json::from_str(text).map_err(|err| Error::Invalid(err.to_string()))?;
+    let (object_type, selector) = match message {
+        ClientMessage::Subscribe {
+            object_type,
+            selector,
+        } => (object_type, selector),
+        ClientMessage::Unsubscribe { object_type } => {
+            if let Some(watch) = watches.remove(&object_type) {
+                watch.abort();
+            }
+            return Ok(());
+        }
+    };
+    let selector = match selector {
+        Some(selector) => selector.parse()?,
+        None => LabelSelector::default(),
+    };
+    let storage = storage.clone();
+    let claim = claim.clone();
+    let in_project = |claim: &JwtClaim, metadata: &Metadata| claim.can_access(&metadata.project);
+    let anyone = |_: &JwtClaim, _: &Metadata| true;
+    let watch = match object_type.as_str() {
+        Vm::OBJECT_TYPE => watch::<Vm>(storage, claim, selector, in_project, outbox),
+        Vpc::OBJECT_TYPE => watch::<Vpc>(storage, claim, selector, in_project, outbox),
+        Snapshot::OBJECT_TYPE => watch::<Snapshot>(storage, claim, selector, in_project, outbox),
+        DhcpReservation::OBJECT_TYPE => {
+            watch::<DhcpReservation>(storage, claim, selector, in_project, outbox)
+        }
+        Node::OBJECT_TYPE => watch::<Node>(storage, claim, selector, anyone, outbox),
+        Operation::OBJECT_TYPE if claim.role == Role::Admin => {
+            watch::<Operation>(storage, claim, selector, anyone, outbox)
+        }
+        Operation::OBJECT_TYPE => return Err(Error::Forbidden("admin role required".to_string())),
+        _ => {
+            return Err(Error::Invalid(format!(
+                "unknown object type: {}",
+                object_type
+            )))
+        }
+    };
+    if let Some(prev) = watches.insert(object_type, watch) {
+        prev.abort();
+    }
+    Ok(())
+}
+
+/// Streams the events for objects of type `O` that `visible` lets the claim see and that match
+/// `selector`. An object that stops matching is sent one last update, so clients can drop it.
+fn watch<O>(
+    storage: Storage,
+    claim: Arc<JwtClaim>,
+    selector: LabelSelector,
+    visible: fn(&JwtClaim, &Metadata) -> bool,
+    outbox: Outbox,
+) -> JoinHandle<()>
+where
+    O: Object + Send + 'static,
+{
+    tokio::spawn(async move {
+        let matches = |object: &O| {
+            let metadata = object.metadata();
+            visible(&claim, &metadata) && selector.matches(&metadata.labels)
+        };
+        let mut stream = match storage.watch::<O>().await {
+            Ok(stream) => stream,
+            Err(err) => {
+                outbox.send(serde_json::json!({ "error": err.to_string() }).to_string());
+                return;
+            }
+        };
+        // Deletes only carry the key name, so track which objects the client has been shown
+        let mut seen: HashSet<String> = match storage.list::<O>().await {
+            Ok(objects) => objects
+                .iter()
+                .filter(|object| matches(object))
+                .map(|object| object.key_name())
+                .collect(),
+            Err(err) => {
+                outbox.send(serde_json::json!({ "error": err.to_string() }).to_string());
+                return;
+            }
+        };
+        while let Some(event) = stream.next().await {
+            let send = match event {
+                Event::New(ref object)
+                | Event::Update {
+                    new: ref object, ..
+                } if matches(object) => {
+                    seen.insert(event.key_name());
+                    true
+                }
+                Event::New(_) => false,
+                Event::Update { .. } | Event::Delete(_) => seen.remove(&event.key_name()),
+            };
+            if !send {
+                continue;
+            }
+            let text = serde_json::json!({ "type": O::OBJECT_TYPE, "event": event }).to_string();
+            if !outbox.send(text) {
+                return;
+            }
+        }
+    })
+}
@@ -0,0 +1,265 @@
+use anyhow::anyhow;
+use hyper::{body::Buf, client::HttpConnector, Body, Method, Request};
+use serde_json::Value;
+
+pub struct Client {
+    inner: hyper::Client<HttpConnector, Body>,
+    server: String,
+    token: Option<String>,
+}
+
+impl Client {
+    pub fn new(server: String, token: Option<String>) -> Self {
+        Self {
+            inner: hyper::Client::new(),
+            server: server.trim_end_matches('/').to_string(),
+            token,
+        }
+    }
+
+    pub async fn request(
+        &self,
+        method: Method,
+        path: &str,
+        body: Option<Value>,
+    ) -> Result<Value, anyhow::Error> {
+        let mut builder = Request::builder()
+            .method(method)
+            .uri(format!("{}/api{}", self.server, path));
+        if let Some(ref token) = self.token {
+            builder = builder.header("Authorization", format!("Bearer {}", token));
+        }
+        let req = if let Some(body) = body {
+            builder
+                .header("Content-Type", "application/json")
+                .body(Body::from(serde_json::to_vec(&body)?))?
+        } else {
+            builder.body(Body::empty())?
+        };
+        let resp = self.inner.request(req).await?;
+        let status = resp.status();
+        let body = hyper::body::aggregate(resp.into_body()).await?;
+        let value: Value = if body.has_remaining() {
+            serde_json::from_reader(body.reader())?
+        } else {
+            Value::Null
+        };
+        if !status.is_success() {
+            let msg = value
+                .get("msg")
+                .and_then(Value::as_str)
+                .map(str::to_string)
+                .unwrap_or_else(|| status.to_string());
+            return Err(anyhow!("{}", msg));
+        }
+        Ok(value)
+    }
+
+    pub async fn login(&self, username: &str, password: &str) -> Result<String, anyhow::Error> {
+        let resp = self
+            .request(
+                Method::POST,
+                "/users/login",
+                Some(serde_json::json!({
+                    "username": username,
+                    "password": password,
+                })),
+            )
+            .await?;
+        resp.get("token")
+            .and_then(Value::as_str)
+            .map(str::to_string)
+            .ok_or_else(|| anyhow!("login response did not contain a token"))
+    }
+
+    pub async fn list(
+        &self,
+        kind: &str,
+        project: Option<&str>,
+    ) -> Result<Vec<Value>, anyhow::Error> {
+        let resp = self
+            .request(
+                Method::GET,
+                &format!("/{}{}", kind, project_query(project)),
+                None,
+            )
+            .await?;
+        match resp.get("objects") {
+            Some(Value::Array(objects)) => Ok(objects.clone()),
+            _ => Err(anyhow!("list response did not contain objects")),
+        }
+    }
+
+    pub async fn get(
+        &self,
+        kind: &str,
+        name: &str,
+        project: Option<&str>,
+    ) -> Result<Value, anyhow::Error> {
+        let path = format!("/{}/{}{}", kind, encode(name), project_query(project));
+        self.request(Method::GET, &path, None).await
+    }
+
+    pub async fn create(&self, kind: &str, object: Value) -> Result<Value, anyhow::Error> {
+        self.request(Method::POST, &format!("/{}", kind), Some(object))
+            .await
+    }
+
+    pub async fn delete(
+        &self,
+        kind: &str,
+        name: &str,
+        project: Option<&str>,
+    ) -> Result<(), anyhow::Error> {
+        let path = format!("/{}/{}{}", kind, encode(name), project_query(project));
+        self.request(Method::DELETE, &path, None).await?;
+        Ok(())
+    }
+}
+
+/// The query selecting `project`, empty without one so the server picks the token's first.
+fn project_query(project: Option<&str>) -> String {
+    project
+        .map(|project| format!("?project={}", encode(project)))
+        .unwrap_or_default()
+}
+
+/// Percent-encodes every byte outside `[A-Za-z0-9._~-]`, so names with spaces or `?` stay one
+/// path segment or query value.
+fn encode(s: &str) -> String {
+    let mut encoded = String::with_capacity(s.len());
+    for byte in s.bytes() {
+        match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'.' | b'_' | b'~' | b'-' => {
+                encoded.push(byte as char)
+            }
+            _ => encoded.push_str(&format!("%{:02X}", byte)),
+        }
+    }
+    encoded
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::TcpListener,
+        task::JoinHandle,
+    };
+
+    /// Answers one request with `status` and `body`, returning the server's address and a task
+    /// resolving to the raw request it got.
+    async fn serve_once(status: &'static str, body: &'static str) -> (String, JoinHandle<String>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let server = format!("http://{}", listener.local_addr().unwrap());
+        let task = tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let mut request = vec![];
+            let mut buf = [0; 1024];
+            loop {
+                let n = stream.read(&mut buf).await.unwrap();
+                request.extend_from_slice(&buf[..n]);
+                let text = String::from_utf8_lossy(&request).to_string();
+                if let Some(end) = text.find("\r\n\r\n") {
+                    let length = text[..end]
+                        .lines()
+                        .filter_map(|line| line.split_once(':'))
+                        .find(|(name, _)| name.eq_ignore_ascii_case("content-length"))
+                        .map_or(0, |(_, value)| value.trim().parse().unwrap());
+                    if request.len() >= end + 4 + length {
+                        break;
+                    }
+                }
+                if n == 0 {
+                    break;
+                }
+            }
+            let response = format!(
+                "HTTP/1.1 {}\r\ncontent-type: application/json\r\ncontent-length: {}\r\nconnection: close\r\n\r\n{}",
+                status,
+                body.len(),
+                body
+            );
+            stream.write_all(response.as_bytes()).await.unwrap();
+            String::from_utf8(request).unwrap()
+        });
+        (server, task)
+    }
+
+    #[tokio::test]
+    async fn get_encodes_the_name_and_project() {
+        let (server, request) = serve_once("200 OK", r#"{"metadata":{"name":"my vm"}}"#).await;
+        let client = Client::new(format!("{}/", server), Some("token".to_string()));
+
+        let vm = client.get("vms", "my vm", Some("team")).await.unwrap();
+        assert_eq!(vm["metadata"]["name"], "my vm");
+        let request = request.await.unwrap();
+        assert!(request.starts_with("GET /api/vms/my%20vm?project=team HTTP/1.1\r\n"));
+        assert!(request
+            .to_lowercase()
+            .contains("authorization: bearer token\r\n"));
+    }
+
+    #[tokio::test]
+    async fn create_posts_the_object_as_json() {
+        let (server, request) = serve_once("200 OK", r#"{"metadata":{"name":"net"}}"#).await;
+        let client = Client::new(server, None);
+
+        client
+            .create("vpcs", serde_json::json!({"metadata": {"name": "net"}}))
+            .await
+            .unwrap();
+        let request = request.await.unwrap();
+        assert!(request.starts_with("POST /api/vpcs HTTP/1.1\r\n"));
+        assert!(request
+            .to_lowercase()
+            .contains("content-type: application/json\r\n"));
+        assert!(!request.to_lowercase().contains("authorization"));
+        assert!(request.ends_with(r#"{"metadata":{"name":"net"}}"#));
+    }
+
+    #[tokio::test]
+    async fn list_and_delete_without_a_project() {
+        let (server, request) = serve_once("200 OK", r#"{"objects":[{"name":"a"}]}"#).await;
+        let objects = Client::new(server, None)
+            .list("projects", None)
+            .await
+            .unwrap();
+        assert_eq!(objects.len(), 1);
+        assert!(request
+            .await
+            .unwrap()
+            .starts_with("GET /api/projects HTTP/1.1\r\n"));
+
+        let (server, request) = serve_once("200 OK", "").await;
+        Client::new(server, None)
+            .delete("vpcs", "net", Some("team"))
+            .await
+            .unwrap();
+        assert!(request
+            .await
+            .unwrap()
+            .starts_with("DELETE /api/vpcs/net?project=team HTTP/1.1\r\n"));
+    }
+
+    #[tokio::test]
+    async fn login_returns_the_token_and_errors_carry_the_message() {
+        let (server, request) = serve_once("200 OK", r#"{"token":"abc"}"#).await;
+        let token = Client::new(server, None)
+            .login("alice", "secret")
+            .await
+            .unwrap();
+        assert_eq!(token, "abc");
+        let request = request.await.unwrap();
+        assert!(request.starts_with("POST /api/users/login HTTP/1.1\r\n"));
+        assert!(request.ends_with(r#"{"password":"secret","username":"alice"}"#));
+
+        let (server, _) = serve_once("404 Not Found", r#"{"msg":"not found: vm a"}"#).await;
+        let err = Client::new(server, None)
+            .get("vms", "a", None)
+            .await
+            .unwrap_err();
+        assert_eq!(err.to_string(), "not found: vm a");
+    }
+}
@@ -0,0 +1,35 @@
+use std::path::PathBuf;
+
+use serde::{Deserialize, Serialize};
+
+pub const DEFAULT_SERVER: &str = "http://localhost:8000";
+
+#[derive(Serialize, Deserialize, Default)]
+pub struct Config {
+    pub server: Option<String>,
+    pub token: Option<String>,
+}
+
+impl Config {
+    pub fn path() -> Result<PathBuf, anyhow::Error> {
+        let home = std::env::var("HOME")?;
+        Ok(PathBuf::from(home).join(".config/searu/seaructl.json"))
+    }
+
+    pub fn load() -> Result<Self, anyhow::Error> {
+        let path = Self::path()?;
+        if !path.exists() {
+            return Ok(Config::default());
+        }
+        Ok(serde_json::from_slice(&std::fs::read(path)?)?)
+    }
+
+    pub fn save(&self) -> Result<(), anyhow::Error> {
+        let path = Self::path()?;
+        if let Some(parent) = path.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+        std::fs::write(path, serde_json::to_vec_pretty(self)?)?;
+        Ok(())
+    }
+}
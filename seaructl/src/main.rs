@@ -0,0 +1,241 @@
+use std::{path::PathBuf, str::FromStr};
+
+use anyhow::anyhow;
+use structopt::StructOpt;
+
+use client::Client;
+use config::{Config, DEFAULT_SERVER};
+use output::OutputFormat;
+
+mod client;
+mod config;
+mod output;
+
+#[derive(StructOpt)]
+#[structopt(
+    name = "seaructl",
+    about = "Manage a searu cluster through its HTTP API"
+)]
+struct Opts {
+    /// Address of the searu API, overriding the saved server
+    #[structopt(long)]
+    server: Option<String>,
+    /// Output format, either "table" or "json"
+    #[structopt(short = "o", long = "output", default_value = "table")]
+    output: OutputFormat,
+    /// Project of the VMs and VPCs to act on, defaulting to the token's first project
+    #[structopt(long)]
+    project: Option<String>,
+    #[structopt(subcommand)]
+    cmd: Cmd,
+}
+
+#[derive(StructOpt)]
+enum Cmd {
+    /// Log in and save the issued token
+    Login {
+        #[structopt(short, long)]
+        username: String,
+        /// Password, read from SEARU_PASSWORD when omitted
+        #[structopt(short, long)]
+        password: Option<String>,
+    },
+    /// List objects of a kind, or fetch a single one by name
+    Get { kind: Kind, name: Option<String> },
+    /// Create an object from a JSON spec
+    Create {
+        kind: Kind,
+        #[structopt(short = "f", long = "filename", parse(from_os_str))]
+        file: PathBuf,
+    },
+    /// Delete an object by name
+    Delete { kind: Kind, name: String },
+}
+
+pub enum Kind {
+    Vms,
+    Vpcs,
+    Nodes,
+    Projects,
+    Reservations,
+}
+
+impl Kind {
+    fn path(&self) -> &'static str {
+        match self {
+            Kind::Vms => "vms",
+            Kind::Vpcs => "vpcs",
+            Kind::Nodes => "nodes",
+            Kind::Projects => "projects",
+            Kind::Reservations => "reservations",
+        }
+    }
+
+    pub fn columns(&self) -> &'static [(&'static str, &'static str)] {
+        match self {
+            Kind::Vms => &[
+                ("NAME", "/metadata/name"),
+                ("PROJECT", "/metadata/project"),
+                ("VPC", "/spec/vpc"),
+                ("CPUS", "/spec/cpus"),
+                ("MEMORY", "/spec/memory"),
+                ("NODE", "/status/node"),
+                ("STATE", "/status/state"),
+            ],
+            Kind::Vpcs => &[
+                ("NAME", "/metadata/name"),
+                ("PROJECT", "/metadata/project"),
+                ("SUBNET", "/spec/subnet"),
+                ("VNI", "/spec/vni"),
+                ("MULTICAST", "/spec/multicast_ip"),
+            ],
+            Kind::Nodes => &[
+                ("NAME", "/metadata/name"),
+                ("CPUS", "/cpu_count"),
+                ("MEMORY", "/memory"),
+            ],
+            Kind::Projects => &[("NAME", "/name")],
+            Kind::Reservations => &[
+                ("NAME", "/metadata/name"),
+                ("VPC", "/spec/vpc"),
+                ("MAC", "/spec/mac"),
+                ("IP", "/spec/ip"),
+            ],
+        }
+    }
+}
+
+impl FromStr for Kind {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "vm" | "vms" => Ok(Kind::Vms),
+            "vpc" | "vpcs" => Ok(Kind::Vpcs),
+            "node" | "nodes" => Ok(Kind::Nodes),
+            "project" | "projects" => Ok(Kind::Projects),
+            "reservation" | "reservations" => Ok(Kind::Reservations),
+            _ => Err(anyhow!("unknown kind: {}", s)),
+        }
+    }
+}
+
+#[tokio::main]
+async fn main() -> Result<(), anyhow::Error> {
+    let opts = Opts::from_args();
+    let mut config = Config::load()?;
+    let server = opts
+        .server
+        .clone()
+        .or_else(|| config.server.clone())
+        .unwrap_or_else(|| DEFAULT_SERVER.to_string());
+    let client = Client::new(server.clone(), config.token.clone());
+    match opts.cmd {
+        Cmd::Login { username, password } => {
+            let password = match password {
+                Some(password) => password,
+                None => std::env::var("SEARU_PASSWORD")
+                    .map_err(|_| anyhow!("pass --password or set SEARU_PASSWORD"))?,
+            };
+            let token = client.login(&username, &password).await?;
+            config.server = Some(server);
+            config.token = Some(token);
+            config.save()?;
+            println!("logged in as {}", username);
+        }
+        Cmd::Get { kind, name } => {
+            let objects = if let Some(name) = name {
+                vec![
+                    client
+                        .get(kind.path(), &name, opts.project.as_deref())
+                        .await?,
+                ]
+            } else {
+                client.list(kind.path(), opts.project.as_deref()).await?
+            };
+            opts.output.print(&kind, &objects)?;
+        }
+        Cmd::Create { kind, file } => {
+            let object = serde_json::from_slice(&std::fs::read(file)?)?;
+            let object = client.create(kind.path(), object).await?;
+            opts.output.print(&kind, &[object])?;
+        }
+        Cmd::Delete { kind, name } => {
+            client
+                .delete(kind.path(), &name, opts.project.as_deref())
+                .await?;
+            println!("deleted {} {}", kind.path(), name);
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(args: &[&str]) -> Result<Opts, structopt::clap::Error> {
+        Opts::from_iter_safe(std::iter::once("seaructl").chain(args.iter().copied()))
+    }
+
+    #[test]
+    fn parses_get_with_and_without_a_name() {
+        let opts = parse(&["get", "vms"]).unwrap();
+        assert!(matches!(opts.output, OutputFormat::Table));
+        assert!(matches!(
+            opts.cmd,
+            Cmd::Get {
+                kind: Kind::Vms,
+                name: None
+            }
+        ));
+
+        let opts = parse(&["-o", "json", "--project", "team", "get", "vpc", "net"]).unwrap();
+        assert!(matches!(opts.output, OutputFormat::Json));
+        assert_eq!(opts.project.as_deref(), Some("team"));
+        match opts.cmd {
+            Cmd::Get {
+                kind: Kind::Vpcs,
+                name: Some(name),
+            } => assert_eq!(name, "net"),
+            _ => panic!("expected get vpc net"),
+        }
+    }
+
+    #[test]
+    fn parses_create_login_and_delete() {
+        match parse(&["create", "vm", "-f", "spec.json"]).unwrap().cmd {
+            Cmd::Create {
+                kind: Kind::Vms,
+                file,
+            } => assert_eq!(file, PathBuf::from("spec.json")),
+            _ => panic!("expected create vm"),
+        }
+        match parse(&["login", "-u", "alice", "-p", "secret"])
+            .unwrap()
+            .cmd
+        {
+            Cmd::Login { username, password } => {
+                assert_eq!(username, "alice");
+                assert_eq!(password.as_deref(), Some("secret"));
+            }
+            _ => panic!("expected login"),
+        }
+        match parse(&["delete", "reservation", "web"]).unwrap().cmd {
+            Cmd::Delete {
+                kind: Kind::Reservations,
+                name,
+            } => assert_eq!(name, "web"),
+            _ => panic!("expected delete reservation"),
+        }
+    }
+
+    #[test]
+    fn rejects_bad_arguments() {
+        assert!(parse(&["get", "disks"]).is_err());
+        assert!(parse(&["-o", "yaml", "get", "vms"]).is_err());
+        assert!(parse(&["create", "vm"]).is_err());
+        assert!(parse(&["delete", "vm"]).is_err());
+        assert!(parse(&["login"]).is_err());
+    }
+}
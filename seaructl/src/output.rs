@@ -0,0 +1,73 @@
+use std::str::FromStr;
+
+use anyhow::anyhow;
+use serde_json::Value;
+
+use crate::Kind;
+
+pub enum OutputFormat {
+    Table,
+    Json,
+}
+
+impl FromStr for OutputFormat {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "table" => Ok(OutputFormat::Table),
+            "json" => Ok(OutputFormat::Json),
+            _ => Err(anyhow!("unknown output format: {}", s)),
+        }
+    }
+}
+
+impl OutputFormat {
+    pub fn print(&self, kind: &Kind, objects: &[Value]) -> Result<(), anyhow::Error> {
+        match self {
+            OutputFormat::Json => {
+                println!("{}", serde_json::to_string_pretty(objects)?);
+            }
+            OutputFormat::Table => print!("{}", table(kind.columns(), objects)),
+        }
+        Ok(())
+    }
+}
+
+fn cell(object: &Value, pointer: &str) -> String {
+    match object.pointer(pointer) {
+        None | Some(Value::Null) => "-".to_string(),
+        Some(Value::String(s)) => s.clone(),
+        Some(value) => value.to_string(),
+    }
+}
+
+fn table(columns: &[(&str, &str)], objects: &[Value]) -> String {
+    let mut rows = vec![columns
+        .iter()
+        .map(|(header, _)| header.to_string())
+        .collect::<Vec<_>>()];
+    for object in objects {
+        rows.push(
+            columns
+                .iter()
+                .map(|(_, pointer)| cell(object, pointer))
+                .collect(),
+        );
+    }
+    let widths: Vec<usize> = (0..columns.len())
+        .map(|i| rows.iter().map(|row| row[i].len()).max().unwrap_or(0))
+        .collect();
+    let mut out = String::new();
+    for row in rows {
+        let line = row
+            .iter()
+            .zip(&widths)
+            .map(|(cell, width)| format!("{:width$}", cell, width = width))
+            .collect::<Vec<_>>()
+            .join("   ");
+        out.push_str(line.trim_end());
+        out.push('\n');
+    }
+    out
+}